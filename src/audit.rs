@@ -0,0 +1,87 @@
+//! A process-wide hook for auditing uses of ambient authority.
+//!
+//! Some operations in this crate can't be expressed relative to a directory
+//! file descriptor, and instead go through a path in the global filesystem
+//! namespace; most commonly `/proc/self/fd/N` for APIs which only accept paths.
+//! Applications which need to verify that all filesystem access stays
+//! capability-relative can install a hook with [`set_hook`], which is
+//! invoked before each such access and may deny it by returning an error.
+//!
+//! Paths which a child process will access (e.g. via
+//! [`crate::cmdext::CapStdExtCommandExt::oom_score_adj`]) and programs which it
+//! will execute are reported when the command is configured, as the hook can't be called after `fork`; if one is
+//! denied, spawning the process fails.
+//!
+//! Note that this only covers this crate; in particular, uses of ambient
+//! authority via `cap_std` directly (such as [`cap_std::fs::Dir::open_ambient_dir`])
+//! are not reported.
+
+use std::io::{self, Result};
+use std::os::fd::{AsRawFd, BorrowedFd};
+use std::path::Path;
+use std::sync::OnceLock;
+
+use cap_tempfile::cap_std;
+
+/// A use of ambient authority, as reported to the hook installed via [`set_hook`].
+#[derive(Debug, Clone, Copy)]
+#[non_exhaustive]
+pub enum AmbientAccess<'a> {
+    /// An open file descriptor is being accessed via its `/proc/self/fd` path.
+    ProcSelfFd(&'a Path),
+    /// A path is being opened relative to the process's root or current
+    /// working directory, e.g. by [`crate::RootDir::open_ambient_root`].
+    Path(&'a Path),
+    /// A program is being executed by name, found via the `PATH` environment
+    /// variable; e.g. `newuidmap` for [`crate::cmdext::IdMapConfig::use_helpers`].
+    Exec(&'a Path),
+}
+
+type Hook = Box<dyn Fn(&AmbientAccess<'_>) -> Result<()> + Send + Sync>;
+
+static HOOK: OnceLock<Hook> = OnceLock::new();
+
+/// Install the process-wide audit hook.  If the hook returns an error, the
+/// operation fails with that error.
+///
+/// The hook can only be set once; an error of kind
+/// [`std::io::ErrorKind::AlreadyExists`] is returned if one is already installed.
+pub fn set_hook(
+    hook: impl Fn(&AmbientAccess<'_>) -> Result<()> + Send + Sync + 'static,
+) -> Result<()> {
+    HOOK.set(Box::new(hook)).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "An audit hook is already installed",
+        )
+    })
+}
+
+/// Report a use of ambient authority to the hook, if any.
+pub(crate) fn check(access: AmbientAccess<'_>) -> Result<()> {
+    match HOOK.get() {
+        Some(hook) => hook(&access),
+        None => Ok(()),
+    }
+}
+
+/// Return the `/proc/self/fd` path for the file descriptor, after checking it
+/// with the hook.
+pub(crate) fn proc_self_fd(fd: BorrowedFd) -> Result<String> {
+    let path = format!("/proc/self/fd/{}", fd.as_raw_fd());
+    check(AmbientAccess::ProcSelfFd(Path::new(&path)))?;
+    Ok(path)
+}
+
+/// Open a directory via an ambient path, after checking it with the hook.
+#[cfg_attr(
+    not(any(target_os = "android", target_os = "linux", target_os = "freebsd")),
+    allow(dead_code)
+)]
+pub(crate) fn open_ambient_dir(
+    path: &Path,
+    authority: cap_std::AmbientAuthority,
+) -> Result<cap_std::fs::Dir> {
+    check(AmbientAccess::Path(path))?;
+    cap_std::fs::Dir::open_ambient_dir(path, authority)
+}
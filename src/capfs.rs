@@ -0,0 +1,168 @@
+//! Traits abstracting over [`Dir`] and [`crate::RootDir`].
+//!
+//! Code which reads (or writes) files can be written against [`CapFsRead`] (or
+//! [`CapFsWrite`]), and then used with either a [`Dir`], which resolves symbolic
+//! links as `cap_std` does (disallowing absolute links and escapes), or a
+//! [`crate::RootDir`], which resolves them as if the directory were the root.
+//!
+//! Note that [`CapFsRead::open_optional`] and [`CapFsWrite::atomic_write`] have
+//! the same names as methods of [`crate::dirext::CapStdExtDirExt`]; if both traits
+//! are in scope, call them on a [`Dir`] with e.g.
+//! `CapFsRead::open_optional(&dir, path)`.
+
+use std::fs::File;
+use std::io::{Read, Result};
+use std::path::Path;
+
+use cap_std::fs::{Dir, Metadata, ReadDir};
+use cap_tempfile::cap_std;
+
+use crate::dirext::{map_optional, CapStdExtDirExt};
+
+/// Reading files and directories.
+pub trait CapFsRead {
+    /// Open a file read-only.
+    fn open(&self, path: impl AsRef<Path>) -> Result<File>;
+
+    /// Query the metadata of a file, following symbolic links.
+    fn metadata(&self, path: impl AsRef<Path>) -> Result<Metadata>;
+
+    /// Return the entries of a directory.
+    fn read_dir(&self, path: impl AsRef<Path>) -> Result<ReadDir>;
+
+    /// Open a file read-only, but return `Ok(None)` if it does not exist.
+    fn open_optional(&self, path: impl AsRef<Path>) -> Result<Option<File>> {
+        map_optional(self.open(path))
+    }
+
+    /// Return `true` if the path exists (following symbolic links), and `false` if
+    /// it does not; other errors are returned.
+    fn try_exists(&self, path: impl AsRef<Path>) -> Result<bool> {
+        Ok(map_optional(self.metadata(path))?.is_some())
+    }
+
+    /// Read the contents of a file into a vector.
+    fn read(&self, path: impl AsRef<Path>) -> Result<Vec<u8>> {
+        let mut r = Vec::new();
+        self.open(path)?.read_to_end(&mut r)?;
+        Ok(r)
+    }
+
+    /// Read the contents of a file into a vector, but return `Ok(None)` if it does
+    /// not exist.
+    fn read_optional(&self, path: impl AsRef<Path>) -> Result<Option<Vec<u8>>> {
+        let Some(mut f) = self.open_optional(path)? else {
+            return Ok(None);
+        };
+        let mut r = Vec::new();
+        f.read_to_end(&mut r)?;
+        Ok(Some(r))
+    }
+
+    /// Read the contents of a file as a string.
+    fn read_to_string(&self, path: impl AsRef<Path>) -> Result<String> {
+        let mut s = String::new();
+        self.open(path)?.read_to_string(&mut s)?;
+        Ok(s)
+    }
+
+    /// Read the contents of a file as a string, but return `Ok(None)` if it does
+    /// not exist.
+    fn read_to_string_optional(&self, path: impl AsRef<Path>) -> Result<Option<String>> {
+        let Some(mut f) = self.open_optional(path)? else {
+            return Ok(None);
+        };
+        let mut s = String::new();
+        f.read_to_string(&mut s)?;
+        Ok(Some(s))
+    }
+}
+
+/// Writing files and directories.
+pub trait CapFsWrite: CapFsRead {
+    /// Write the provided contents to a file, creating it if necessary, and
+    /// truncating it otherwise.
+    fn write(&self, path: impl AsRef<Path>, contents: impl AsRef<[u8]>) -> Result<()>;
+
+    /// Atomically write the provided contents to a file, as with
+    /// [`crate::dirext::CapStdExtDirExt::atomic_write`].
+    fn atomic_write(&self, path: impl AsRef<Path>, contents: impl AsRef<[u8]>) -> Result<()>;
+
+    /// Create a directory.
+    fn create_dir(&self, path: impl AsRef<Path>) -> Result<()>;
+
+    /// Remove a file or symbolic link.
+    fn remove_file(&self, path: impl AsRef<Path>) -> Result<()>;
+}
+
+impl CapFsRead for Dir {
+    fn open(&self, path: impl AsRef<Path>) -> Result<File> {
+        Dir::open(self, path).map(|f| f.into_std())
+    }
+
+    fn metadata(&self, path: impl AsRef<Path>) -> Result<Metadata> {
+        Dir::metadata(self, path)
+    }
+
+    fn read_dir(&self, path: impl AsRef<Path>) -> Result<ReadDir> {
+        Dir::read_dir(self, path)
+    }
+}
+
+impl CapFsWrite for Dir {
+    fn write(&self, path: impl AsRef<Path>, contents: impl AsRef<[u8]>) -> Result<()> {
+        Dir::write(self, path, contents)
+    }
+
+    fn atomic_write(&self, path: impl AsRef<Path>, contents: impl AsRef<[u8]>) -> Result<()> {
+        CapStdExtDirExt::atomic_write(self, path, contents)
+    }
+
+    fn create_dir(&self, path: impl AsRef<Path>) -> Result<()> {
+        Dir::create_dir(self, path)
+    }
+
+    fn remove_file(&self, path: impl AsRef<Path>) -> Result<()> {
+        Dir::remove_file(self, path)
+    }
+}
+
+#[cfg(any(target_os = "android", target_os = "linux", target_os = "freebsd"))]
+impl CapFsRead for crate::RootDir {
+    fn open(&self, path: impl AsRef<Path>) -> Result<File> {
+        crate::RootDir::open(self, path)
+    }
+
+    fn metadata(&self, path: impl AsRef<Path>) -> Result<Metadata> {
+        crate::RootDir::metadata(self, path)
+    }
+
+    fn read_dir(&self, path: impl AsRef<Path>) -> Result<ReadDir> {
+        self.open_dir_in_root(path.as_ref())?.entries()
+    }
+}
+
+/// Writes resolve the parent directory in the root; the final component is then
+/// created or replaced in that directory.
+#[cfg(any(target_os = "android", target_os = "linux", target_os = "freebsd"))]
+impl CapFsWrite for crate::RootDir {
+    fn write(&self, path: impl AsRef<Path>, contents: impl AsRef<[u8]>) -> Result<()> {
+        let (parent, name) = self.open_parent_in_root(path.as_ref())?;
+        parent.write(name, contents)
+    }
+
+    fn atomic_write(&self, path: impl AsRef<Path>, contents: impl AsRef<[u8]>) -> Result<()> {
+        let (parent, name) = self.open_parent_in_root(path.as_ref())?;
+        CapStdExtDirExt::atomic_write(&parent, name, contents)
+    }
+
+    fn create_dir(&self, path: impl AsRef<Path>) -> Result<()> {
+        let (parent, name) = self.open_parent_in_root(path.as_ref())?;
+        parent.create_dir(name)
+    }
+
+    fn remove_file(&self, path: impl AsRef<Path>) -> Result<()> {
+        let (parent, name) = self.open_parent_in_root(path.as_ref())?;
+        parent.remove_file(name)
+    }
+}
@@ -3,18 +3,197 @@
 //! The key APIs here are:
 //!
 //! - File descriptor passing
-//! - Changing to a file-descriptor relative directory
+//! - Changing to a file-descriptor relative directory, or root directory
+//! - Ensuring that no other file descriptors are inherited
+//! - Changing the user and groups, and dropping capabilities
+//! - Resource limits, scheduling priorities, CPU affinity and the umask
+//! - Unsharing namespaces, and setting up user namespace ID mappings
+//! - Restricting filesystem access with Landlock, and system calls with seccomp
+//! - Redirecting standard input and output to files in a directory, including
+//!   atomically replacing a file with the output of a process
 
-use cap_std::fs::Dir;
+use cap_std::fs::{Dir, OpenOptions};
 use cap_std::io_lifetimes;
 use cap_tempfile::cap_std;
+
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use crate::audit::AmbientAccess;
+use crate::dirext::CapStdExtDirExt;
 use io_lifetimes::OwnedFd;
 use rustix::fd::{AsFd, FromRawFd, IntoRawFd};
 use rustix::io::FdFlags;
 use std::os::fd::AsRawFd;
 use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::{ExitStatus, Stdio};
 use std::sync::Arc;
 
+#[cfg(any(target_os = "android", target_os = "linux", target_os = "freebsd"))]
+pub use rustix::process::CpuSet;
+pub use rustix::process::Resource;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+pub use rustix::thread::Capability;
+
+#[cfg(any(target_os = "android", target_os = "linux"))]
+bitflags::bitflags! {
+    /// Linux namespaces to unshare; see [`CapStdExtCommandExt::unshare`].
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct Namespaces: libc::c_int {
+        /// A user namespace (`CLONE_NEWUSER`).
+        const USER = libc::CLONE_NEWUSER;
+        /// A mount namespace (`CLONE_NEWNS`).
+        const MOUNT = libc::CLONE_NEWNS;
+        /// A PID namespace (`CLONE_NEWPID`).  Note that this applies to children
+        /// of the process, not the process itself.
+        const PID = libc::CLONE_NEWPID;
+        /// A network namespace (`CLONE_NEWNET`).
+        const NET = libc::CLONE_NEWNET;
+        /// A UTS (hostname) namespace (`CLONE_NEWUTS`).
+        const UTS = libc::CLONE_NEWUTS;
+        /// An IPC namespace (`CLONE_NEWIPC`).
+        const IPC = libc::CLONE_NEWIPC;
+        /// A cgroup namespace (`CLONE_NEWCGROUP`).
+        const CGROUP = libc::CLONE_NEWCGROUP;
+    }
+}
+
+/// An I/O scheduling class; see [`CapStdExtCommandExt::ioprio`].
+#[cfg(any(target_os = "android", target_os = "linux"))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoPriorityClass {
+    /// Real-time; this requires `CAP_SYS_ADMIN`.
+    RealTime,
+    /// Best-effort, the default.
+    BestEffort,
+    /// Idle: I/O is only performed when no other process needs the disk.
+    Idle,
+}
+
+/// A CPU scheduling policy; see [`CapStdExtCommandExt::sched_policy`].
+#[cfg(any(target_os = "android", target_os = "linux"))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum SchedPolicy {
+    /// The default time-sharing policy (`SCHED_OTHER`).
+    Other,
+    /// For CPU-intensive, non-interactive processes (`SCHED_BATCH`).
+    Batch,
+    /// For very low priority background jobs (`SCHED_IDLE`).
+    Idle,
+    /// Real-time first-in, first-out with the given priority (`SCHED_FIFO`);
+    /// this requires `CAP_SYS_NICE`.
+    Fifo(i32),
+    /// Real-time round-robin with the given priority (`SCHED_RR`); this requires
+    /// `CAP_SYS_NICE`.
+    RoundRobin(i32),
+}
+
+/// A contiguous range of IDs mapped into a user namespace.
+#[cfg(any(target_os = "android", target_os = "linux"))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct IdRange {
+    inside: u32,
+    outside: u32,
+    count: u32,
+}
+
+/// The UID and GID mappings for a new user namespace; see
+/// [`CapStdExtCommandExt::uid_gid_map`].
+#[cfg(any(target_os = "android", target_os = "linux"))]
+#[derive(Debug, Clone, Default)]
+pub struct IdMapConfig {
+    uids: Vec<IdRange>,
+    gids: Vec<IdRange>,
+    allow_setgroups: bool,
+    helpers: bool,
+}
+
+#[cfg(any(target_os = "android", target_os = "linux"))]
+impl IdMapConfig {
+    /// Create an empty configuration, with no mappings.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Map root in the namespace to the current effective UID and GID; this
+    /// works without privileges.
+    pub fn root_as_current_user() -> Self {
+        let uid = rustix::process::geteuid().as_raw();
+        let gid = rustix::process::getegid().as_raw();
+        Self::new().uid_range(0, uid, 1).gid_range(0, gid, 1)
+    }
+
+    /// Map `count` UIDs starting at `outside` to ones starting at `inside` in
+    /// the namespace.
+    pub fn uid_range(mut self, inside: u32, outside: u32, count: u32) -> Self {
+        self.uids.push(IdRange {
+            inside,
+            outside,
+            count,
+        });
+        self
+    }
+
+    /// Map `count` GIDs starting at `outside` to ones starting at `inside` in
+    /// the namespace.
+    pub fn gid_range(mut self, inside: u32, outside: u32, count: u32) -> Self {
+        self.gids.push(IdRange {
+            inside,
+            outside,
+            count,
+        });
+        self
+    }
+
+    /// Allow `setgroups(2)` in the namespace.  By default, `deny` is written to
+    /// `/proc/<pid>/setgroups`, which is required for an unprivileged process to
+    /// write a GID mapping.  This has no effect with [`Self::use_helpers`].
+    pub fn allow_setgroups(mut self) -> Self {
+        self.allow_setgroups = true;
+        self
+    }
+
+    /// Write the mappings with the setuid `newuidmap` and `newgidmap` helpers
+    /// (which check them against `/etc/subuid` and `/etc/subgid`), rather than
+    /// directly.  This allows an unprivileged process to map multiple ranges.
+    pub fn use_helpers(mut self) -> Self {
+        self.helpers = true;
+        self
+    }
+
+    /// Convert the configuration into the form used after fork, where
+    /// allocating is not safe.
+    fn prepare(&self) -> PreparedIdMap {
+        let text = |ranges: &[IdRange]| {
+            let mut r = Vec::new();
+            for m in ranges {
+                r.extend_from_slice(format!("{} {} {}\n", m.inside, m.outside, m.count).as_bytes());
+            }
+            r
+        };
+        let args = |ranges: &[IdRange]| {
+            ranges
+                .iter()
+                .flat_map(|m| [m.inside, m.outside, m.count])
+                // SAFETY(unwrap): Numbers don't contain nul bytes.
+                .map(|n| std::ffi::CString::new(n.to_string()).unwrap())
+                .collect::<Vec<_>>()
+        };
+        let helpers = self.helpers.then(|| {
+            // SAFETY(unwrap): These are constant strings without nul bytes.
+            let newuidmap = std::ffi::CString::new("newuidmap").unwrap();
+            let newgidmap = std::ffi::CString::new("newgidmap").unwrap();
+            [(newuidmap, args(&self.uids)), (newgidmap, args(&self.gids))]
+        });
+        PreparedIdMap {
+            uid_map: text(&self.uids),
+            gid_map: text(&self.gids),
+            deny_setgroups: !self.allow_setgroups,
+            helpers,
+        }
+    }
+}
+
 /// Extension trait for [`std::process::Command`].
 ///
 /// [`cap_std::fs::Dir`]: https://docs.rs/cap-std/latest/cap_std/fs/struct.Dir.html
@@ -22,8 +201,209 @@ pub trait CapStdExtCommandExt {
     /// Pass a file descriptor into the target process.
     fn take_fd_n(&mut self, fd: Arc<OwnedFd>, target: i32) -> &mut Self;
 
+    /// Pass multiple file descriptors into the target process, each at the given
+    /// target number.
+    ///
+    /// Unlike repeated calls to [`Self::take_fd_n`], this is correct even if a target
+    /// number is the same as the number of another source file descriptor (including
+    /// cycles, e.g. swapping two file descriptors).  If the same target is given more
+    /// than once, the last one wins.
+    fn take_fds(&mut self, fds: impl IntoIterator<Item = (Arc<OwnedFd>, i32)>) -> &mut Self;
+
     /// Use the given directory as the current working directory for the process.
     fn cwd_dir(&mut self, dir: Dir) -> &mut Self;
+
+    /// Run the process as the given user, group and supplementary groups, which
+    /// are set (in that order: groups, GID, then UID) before executing it.
+    /// Unlike [`std::os::unix::process::CommandExt::uid`], this sets the
+    /// supplementary groups rather than just clearing them.
+    ///
+    /// If `home` is provided, it is used as the current working directory; this is
+    /// changed after switching user, so that permission checks are done as that
+    /// user.
+    ///
+    /// On Linux, the permitted capabilities are retained across the switch (via
+    /// `PR_SET_KEEPCAPS`) so that [`Self::keep_capabilities`] can be used
+    /// afterwards; otherwise they are lost when the process is executed, as usual.
+    fn run_as(&mut self, uid: u32, gid: u32, groups: &[u32], home: Option<Dir>) -> &mut Self;
+
+    /// Set a resource limit for the process via `setrlimit(2)`; `None` means
+    /// unlimited.  Raising the hard limit requires privileges.
+    fn limit(&mut self, resource: Resource, soft: Option<u64>, hard: Option<u64>) -> &mut Self;
+
+    /// Set the file mode creation mask of the process, e.g. `0o027`, without
+    /// changing that of this process.
+    fn umask(&mut self, mode: u32) -> &mut Self;
+
+    /// Set the nice value of the process via `setpriority(2)`; higher values
+    /// are lower priority.  Lowering it requires privileges.
+    fn nice(&mut self, value: i32) -> &mut Self;
+
+    /// Set the I/O scheduling class and priority level (from 0, the highest,
+    /// to 7) of the process via `ioprio_set(2)`.  The level is ignored for
+    /// [`IoPriorityClass::Idle`].
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn ioprio(&mut self, class: IoPriorityClass, level: u8) -> &mut Self;
+
+    /// Set the CPU scheduling policy of the process via `sched_setscheduler(2)`.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn sched_policy(&mut self, policy: SchedPolicy) -> &mut Self;
+
+    /// Restrict the process to running on the given CPUs via
+    /// `sched_setaffinity(2)`.
+    #[cfg(any(target_os = "android", target_os = "linux", target_os = "freebsd"))]
+    fn cpu_affinity(&mut self, cpus: &CpuSet) -> &mut Self;
+
+    /// Set the OOM score adjustment of the process (from -1000 to 1000) by
+    /// writing `/proc/self/oom_score_adj`; higher values make it more likely to
+    /// be killed when memory runs out.  Lowering it requires `CAP_SYS_RESOURCE`.
+    ///
+    /// The access to `/proc/self/oom_score_adj` is reported to the
+    /// [`crate::audit`] hook when this is called.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn oom_score_adj(&mut self, score: i32) -> &mut Self;
+
+    /// Drop all capabilities from the process: the bounding, ambient, permitted,
+    /// effective and inheritable sets are cleared.  This is
+    /// [`Self::keep_capabilities`] with no capabilities.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn drop_capabilities(&mut self) -> &mut Self;
+
+    /// Drop all capabilities from the process except the provided ones, which are
+    /// also added to the ambient set so that they are retained across `exec` by a
+    /// non-root user.  It is an error (`EPERM`) if any of them is not currently
+    /// permitted.
+    ///
+    /// The bounding set can only be changed with `CAP_SETPCAP`; without it, the
+    /// bounding set is left as is.  This should be called after [`Self::run_as`],
+    /// which keeps the permitted capabilities when changing user.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn keep_capabilities(&mut self, capabilities: &[Capability]) -> &mut Self;
+
+    /// Set `PR_SET_NO_NEW_PRIVS` for the process, so that it (and its children)
+    /// cannot gain privileges via `exec`, e.g. from setuid binaries or file
+    /// capabilities.  This cannot be unset.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn no_new_privs(&mut self) -> &mut Self;
+
+    /// Restrict the process (and its children) to accessing only the provided
+    /// directories and their contents, each with the given rights, using Landlock;
+    /// see [`crate::landlock`].  This also sets `PR_SET_NO_NEW_PRIVS`.
+    ///
+    /// The ruleset is created immediately, so errors (such as Landlock being
+    /// unsupported) are returned here rather than when spawning.
+    #[cfg(all(feature = "landlock", any(target_os = "android", target_os = "linux")))]
+    fn landlock_restrict(
+        &mut self,
+        rules: &[(Dir, crate::landlock::AccessRights)],
+    ) -> std::io::Result<&mut Self>;
+
+    /// Apply a seccomp filter to the process (and its children); see
+    /// [`crate::seccomp`].  This also sets `PR_SET_NO_NEW_PRIVS`.
+    ///
+    /// The policy is compiled immediately, so errors are returned here rather
+    /// than when spawning.  As the filter also applies to any hooks added after
+    /// it, this should be called last.
+    #[cfg(all(feature = "seccomp", any(target_os = "android", target_os = "linux")))]
+    fn seccomp_filter(
+        &mut self,
+        policy: &crate::seccomp::SeccompPolicy,
+    ) -> std::io::Result<&mut Self>;
+
+    /// Move the process into new namespaces via `unshare(2)`, before any hooks
+    /// added later (such as [`Self::chroot_dir`]) run.
+    ///
+    /// If [`Namespaces::USER`] is included, the user namespace is created first on
+    /// its own, so that an unprivileged process gains the capabilities needed for
+    /// the others.  No UID or GID mappings are written.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn unshare(&mut self, namespaces: Namespaces) -> &mut Self;
+
+    /// Move the process into a new user namespace with the provided UID and GID
+    /// mappings.
+    ///
+    /// The mappings must be written from outside the namespace, so a helper
+    /// process is forked before unsharing, which writes them (or runs
+    /// `newuidmap` and `newgidmap`) once the namespace exists.
+    ///
+    /// This creates the user namespace itself, so [`Namespaces::USER`] should not
+    /// also be passed to [`Self::unshare`]; call that afterwards for any other
+    /// namespaces, so that they are owned by the new user namespace.
+    ///
+    /// The access to `/proc/self` (and, with [`IdMapConfig::use_helpers`], the
+    /// execution of the helpers via `PATH`) is reported to the [`crate::audit`]
+    /// hook when this is called.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn uid_gid_map(&mut self, config: &IdMapConfig) -> &mut Self;
+
+    /// Use the given directory as the root directory (and current working
+    /// directory) for the process, via `chroot(2)`.  This requires
+    /// `CAP_SYS_CHROOT`.
+    ///
+    /// Note that a chroot is not a security boundary against a privileged process.
+    fn chroot_dir(&mut self, dir: Dir) -> &mut Self;
+
+    /// Use the given directory as the root directory (and current working
+    /// directory) for the process via `pivot_root(2)`, detaching the old root.
+    /// This requires `CAP_SYS_ADMIN`, and the directory must be a mount point.
+    ///
+    /// This moves the process into a new mount namespace (in which all mounts are
+    /// made private) itself, as the directory must be entered before unsharing so
+    /// that it refers to a mount in the new namespace; so [`Namespaces::MOUNT`]
+    /// should not also be passed to [`Self::unshare`].
+    #[cfg(target_os = "linux")]
+    fn pivot_root_dir(&mut self, dir: Dir) -> &mut Self;
+
+    /// Open the file (read-only) relative to the directory, and use it as the
+    /// standard input of the process.
+    fn stdin_dir_path(&mut self, dir: &Dir, path: impl AsRef<Path>) -> std::io::Result<&mut Self>;
+
+    /// Open the file relative to the directory with the provided options, and use
+    /// it as the standard output of the process.  The options should allow
+    /// writing, e.g. `OpenOptions::new().write(true).create(true).append(true)`.
+    fn stdout_dir_path(
+        &mut self,
+        dir: &Dir,
+        path: impl AsRef<Path>,
+        options: &OpenOptions,
+    ) -> std::io::Result<&mut Self>;
+
+    /// Open the file relative to the directory with the provided options, and use
+    /// it as the standard error of the process; see [`Self::stdout_dir_path`].
+    fn stderr_dir_path(
+        &mut self,
+        dir: &Dir,
+        path: impl AsRef<Path>,
+        options: &OpenOptions,
+    ) -> std::io::Result<&mut Self>;
+
+    /// Run the process to completion with its standard output directed into a
+    /// temporary file in the directory; if it exits successfully, the file
+    /// atomically replaces `path`, as with
+    /// [`crate::dirext::CapStdExtDirExt::atomic_replace_with`].  Otherwise the
+    /// output is discarded and `path` is left unchanged.
+    ///
+    /// The exit status is returned in either case.  The standard output of the
+    /// command is reset to be inherited afterwards.
+    fn stdout_atomic_to(
+        &mut self,
+        dir: &Dir,
+        path: impl AsRef<Path>,
+    ) -> std::io::Result<ExitStatus>;
+
+    /// Ensure that the target process does not inherit any file descriptors
+    /// numbered above `max_fd`, e.g. ones leaked without `O_CLOEXEC` by other
+    /// code in this process.  Descriptors passed via [`Self::take_fd_n`] or
+    /// [`Self::take_fds`] with targets up to `max_fd` are kept regardless of the
+    /// order of the calls.
+    ///
+    /// The descriptors are marked close-on-exec (using `close_range(2)`, or by
+    /// enumerating `/proc/self/fd` on older kernels) rather than closed
+    /// immediately, so that they are closed by the final `exec`.  The possible
+    /// access to `/proc/self/fd` is reported to the [`crate::audit`] hook when
+    /// this is called.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn close_fds_above(&mut self, max_fd: i32) -> &mut Self;
 }
 
 #[allow(unsafe_code)]
@@ -49,6 +429,36 @@ impl CapStdExtCommandExt for std::process::Command {
         self
     }
 
+    fn take_fds(&mut self, fds: impl IntoIterator<Item = (Arc<OwnedFd>, i32)>) -> &mut Self {
+        let fds: Vec<_> = fds.into_iter().collect();
+        // First duplicate every source above all of the sources and targets, then
+        // move those copies into place; this way no target can clobber a source.
+        let min = fds
+            .iter()
+            .flat_map(|(fd, target)| [fd.as_raw_fd(), *target])
+            .max()
+            .map_or(0, |n| n.saturating_add(1));
+        // Allocate up front; allocating after fork is not safe.
+        let mut temps = Vec::with_capacity(fds.len());
+        unsafe {
+            self.pre_exec(move || {
+                temps.clear();
+                for (fd, _) in fds.iter() {
+                    temps.push(rustix::io::fcntl_dupfd_cloexec(&**fd, min)?);
+                }
+                for (temp, (_, target)) in temps.drain(..).zip(fds.iter()) {
+                    let mut target = OwnedFd::from_raw_fd(*target);
+                    // The duplicate does not have O_CLOEXEC set.
+                    rustix::io::dup2(&temp, &mut target)?;
+                    // Intentionally leak into the child.
+                    let _ = target.into_raw_fd();
+                }
+                Ok(())
+            });
+        }
+        self
+    }
+
     fn cwd_dir(&mut self, dir: Dir) -> &mut Self {
         unsafe {
             self.pre_exec(move || {
@@ -58,6 +468,664 @@ impl CapStdExtCommandExt for std::process::Command {
         }
         self
     }
+
+    fn run_as(&mut self, uid: u32, gid: u32, groups: &[u32], home: Option<Dir>) -> &mut Self {
+        let groups: Vec<libc::gid_t> = groups.to_vec();
+        let check = |r: libc::c_int| match r {
+            0 => Ok(()),
+            _ => Err(std::io::Error::last_os_error()),
+        };
+        unsafe {
+            self.pre_exec(move || {
+                // The type of the length varies by platform
+                check(libc::setgroups(groups.len() as _, groups.as_ptr()))?;
+                check(libc::setgid(gid))?;
+                #[cfg(any(target_os = "android", target_os = "linux"))]
+                {
+                    // Keep the permitted capabilities (but not the ambient ones,
+                    // which would otherwise be cleared) for keep_capabilities().
+                    check(libc::prctl(libc::PR_SET_KEEPCAPS, 1, 0, 0, 0))?;
+                    check(libc::setuid(uid))?;
+                    check(libc::prctl(libc::PR_SET_KEEPCAPS, 0, 0, 0, 0))?;
+                    if uid != 0 {
+                        let clear_all = libc::PR_CAP_AMBIENT_CLEAR_ALL as libc::c_ulong;
+                        check(libc::prctl(libc::PR_CAP_AMBIENT, clear_all, 0, 0, 0))?;
+                    }
+                }
+                #[cfg(not(any(target_os = "android", target_os = "linux")))]
+                check(libc::setuid(uid))?;
+                if let Some(home) = home.as_ref() {
+                    rustix::process::fchdir(home)?;
+                }
+                Ok(())
+            });
+        }
+        self
+    }
+
+    fn limit(&mut self, resource: Resource, soft: Option<u64>, hard: Option<u64>) -> &mut Self {
+        let limit = rustix::process::Rlimit {
+            current: soft,
+            maximum: hard,
+        };
+        unsafe {
+            self.pre_exec(move || {
+                rustix::process::setrlimit(resource, limit)?;
+                Ok(())
+            });
+        }
+        self
+    }
+
+    fn umask(&mut self, mode: u32) -> &mut Self {
+        let mode = rustix::fs::Mode::from_bits_truncate(mode as _);
+        unsafe {
+            self.pre_exec(move || {
+                rustix::process::umask(mode);
+                Ok(())
+            });
+        }
+        self
+    }
+
+    fn nice(&mut self, value: i32) -> &mut Self {
+        unsafe {
+            self.pre_exec(move || {
+                rustix::process::setpriority_process(None, value)?;
+                Ok(())
+            });
+        }
+        self
+    }
+
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn ioprio(&mut self, class: IoPriorityClass, level: u8) -> &mut Self {
+        // See linux/ioprio.h
+        const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+        const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+        let (class, level) = match class {
+            IoPriorityClass::RealTime => (1, level),
+            IoPriorityClass::BestEffort => (2, level),
+            IoPriorityClass::Idle => (3, 0),
+        };
+        let prio = class << IOPRIO_CLASS_SHIFT | libc::c_int::from(level);
+        unsafe {
+            self.pre_exec(move || {
+                if libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, prio) < 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        self
+    }
+
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn sched_policy(&mut self, policy: SchedPolicy) -> &mut Self {
+        let (policy, priority) = match policy {
+            SchedPolicy::Other => (libc::SCHED_OTHER, 0),
+            SchedPolicy::Batch => (libc::SCHED_BATCH, 0),
+            SchedPolicy::Idle => (libc::SCHED_IDLE, 0),
+            SchedPolicy::Fifo(p) => (libc::SCHED_FIFO, p),
+            SchedPolicy::RoundRobin(p) => (libc::SCHED_RR, p),
+        };
+        unsafe {
+            self.pre_exec(move || {
+                let param = libc::sched_param {
+                    sched_priority: priority,
+                };
+                if libc::sched_setscheduler(0, policy, &param) < 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        self
+    }
+
+    #[cfg(any(target_os = "android", target_os = "linux", target_os = "freebsd"))]
+    fn cpu_affinity(&mut self, cpus: &CpuSet) -> &mut Self {
+        let cpus = *cpus;
+        unsafe {
+            self.pre_exec(move || {
+                rustix::process::sched_setaffinity(None, &cpus)?;
+                Ok(())
+            });
+        }
+        self
+    }
+
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn oom_score_adj(&mut self, score: i32) -> &mut Self {
+        use rustix::fs::{Mode, OFlags};
+        audit_child_access(
+            self,
+            AmbientAccess::Path(Path::new("/proc/self/oom_score_adj")),
+        );
+        unsafe {
+            self.pre_exec(move || {
+                let mut buf = [0u8; 16];
+                let value = format_decimal(score, &mut buf);
+                let fd = rustix::fs::open(
+                    "/proc/self/oom_score_adj",
+                    OFlags::WRONLY | OFlags::CLOEXEC,
+                    Mode::empty(),
+                )?;
+                rustix::io::write(fd, value)?;
+                Ok(())
+            });
+        }
+        self
+    }
+
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn drop_capabilities(&mut self) -> &mut Self {
+        self.keep_capabilities(&[])
+    }
+
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn keep_capabilities(&mut self, capabilities: &[Capability]) -> &mut Self {
+        let keep = capabilities
+            .iter()
+            .fold(0u64, |acc, &cap| acc | 1 << cap as u32);
+        unsafe {
+            self.pre_exec(move || restrict_capabilities(keep));
+        }
+        self
+    }
+
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn no_new_privs(&mut self) -> &mut Self {
+        unsafe {
+            self.pre_exec(|| {
+                rustix::thread::set_no_new_privs(true)?;
+                Ok(())
+            });
+        }
+        self
+    }
+
+    #[cfg(all(feature = "landlock", any(target_os = "android", target_os = "linux")))]
+    fn landlock_restrict(
+        &mut self,
+        rules: &[(Dir, crate::landlock::AccessRights)],
+    ) -> std::io::Result<&mut Self> {
+        let ruleset = crate::landlock::create_ruleset(rules)?;
+        unsafe {
+            self.pre_exec(move || crate::landlock::restrict_self(&ruleset));
+        }
+        Ok(self)
+    }
+
+    #[cfg(all(feature = "seccomp", any(target_os = "android", target_os = "linux")))]
+    fn seccomp_filter(
+        &mut self,
+        policy: &crate::seccomp::SeccompPolicy,
+    ) -> std::io::Result<&mut Self> {
+        let program = policy.compile()?;
+        unsafe {
+            self.pre_exec(move || crate::seccomp::apply(&program));
+        }
+        Ok(self)
+    }
+
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn unshare(&mut self, namespaces: Namespaces) -> &mut Self {
+        fn unshare_raw(flags: libc::c_int) -> std::io::Result<()> {
+            if unsafe { libc::unshare(flags) } < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        }
+        unsafe {
+            self.pre_exec(move || {
+                let mut rest = namespaces;
+                if rest.contains(Namespaces::USER) {
+                    unshare_raw(Namespaces::USER.bits())?;
+                    rest.remove(Namespaces::USER);
+                }
+                if !rest.is_empty() {
+                    unshare_raw(rest.bits())?;
+                }
+                Ok(())
+            });
+        }
+        self
+    }
+
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn uid_gid_map(&mut self, config: &IdMapConfig) -> &mut Self {
+        audit_child_access(self, AmbientAccess::Path(Path::new("/proc/self")));
+        if config.helpers {
+            for helper in ["newuidmap", "newgidmap"] {
+                audit_child_access(self, AmbientAccess::Exec(Path::new(helper)));
+            }
+        }
+        let prepared = config.prepare();
+        unsafe {
+            self.pre_exec(move || prepared.unshare_and_map());
+        }
+        self
+    }
+
+    fn chroot_dir(&mut self, dir: Dir) -> &mut Self {
+        unsafe {
+            self.pre_exec(move || {
+                rustix::process::fchdir(dir.as_fd())?;
+                rustix::process::chroot(".")?;
+                Ok(())
+            });
+        }
+        self
+    }
+
+    #[cfg(target_os = "linux")]
+    fn pivot_root_dir(&mut self, dir: Dir) -> &mut Self {
+        unsafe {
+            self.pre_exec(move || {
+                rustix::process::fchdir(dir.as_fd())?;
+                // Unsharing maps the working directory to the copy of its mount in
+                // the new namespace; shared mounts can't be pivoted.
+                if libc::unshare(libc::CLONE_NEWNS) < 0
+                    || libc::mount(
+                        std::ptr::null(),
+                        b"/\0".as_ptr().cast(),
+                        std::ptr::null(),
+                        libc::MS_REC | libc::MS_PRIVATE,
+                        std::ptr::null(),
+                    ) < 0
+                {
+                    return Err(std::io::Error::last_os_error());
+                }
+                // Stack the old root on top of the new one, then detach it.
+                rustix::process::pivot_root(".", ".")?;
+                if libc::umount2(b".\0".as_ptr().cast(), libc::MNT_DETACH) < 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                rustix::process::chdir("/")?;
+                Ok(())
+            });
+        }
+        self
+    }
+
+    fn stdin_dir_path(&mut self, dir: &Dir, path: impl AsRef<Path>) -> std::io::Result<&mut Self> {
+        let f = dir.open(path)?;
+        Ok(self.stdin(Stdio::from(f.into_std())))
+    }
+
+    fn stdout_dir_path(
+        &mut self,
+        dir: &Dir,
+        path: impl AsRef<Path>,
+        options: &OpenOptions,
+    ) -> std::io::Result<&mut Self> {
+        let f = dir.open_with(path, options)?;
+        Ok(self.stdout(Stdio::from(f.into_std())))
+    }
+
+    fn stderr_dir_path(
+        &mut self,
+        dir: &Dir,
+        path: impl AsRef<Path>,
+        options: &OpenOptions,
+    ) -> std::io::Result<&mut Self> {
+        let f = dir.open_with(path, options)?;
+        Ok(self.stderr(Stdio::from(f.into_std())))
+    }
+
+    fn stdout_atomic_to(
+        &mut self,
+        dir: &Dir,
+        path: impl AsRef<Path>,
+    ) -> std::io::Result<ExitStatus> {
+        let r = dir.atomic_replace_with(path, |w| {
+            let f = w.get_ref().as_file().try_clone()?;
+            let status = self.stdout(Stdio::from(f.into_std())).status();
+            // Don't leave the temporary file as the output of later spawns.
+            self.stdout(Stdio::inherit());
+            let status = status?;
+            if status.success() {
+                Ok(status)
+            } else {
+                Err(AtomicStdoutError::Failed(status))
+            }
+        });
+        match r {
+            Ok(status) | Err(AtomicStdoutError::Failed(status)) => Ok(status),
+            Err(AtomicStdoutError::Io(e)) => Err(e),
+        }
+    }
+
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn close_fds_above(&mut self, max_fd: i32) -> &mut Self {
+        audit_child_access(self, AmbientAccess::Path(Path::new("/proc/self/fd")));
+        unsafe {
+            self.pre_exec(move || cloexec_fds_above(max_fd));
+        }
+        self
+    }
+}
+
+/// Used by [`CapStdExtCommandExt::stdout_atomic_to`] to discard the temporary
+/// file if the process fails.
+enum AtomicStdoutError {
+    Failed(ExitStatus),
+    Io(std::io::Error),
+}
+
+impl From<std::io::Error> for AtomicStdoutError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+/// Report a path which the process will access (or a program which it will
+/// execute) to the audit hook (see [`crate::audit`]); this is done here, as the hook can't be called after fork.
+/// If it is denied, spawning the process fails with the hook's error code (or
+/// `EPERM`).
+#[cfg(any(target_os = "android", target_os = "linux"))]
+#[allow(unsafe_code)]
+fn audit_child_access(cmd: &mut std::process::Command, access: AmbientAccess<'_>) {
+    if let Err(e) = crate::audit::check(access) {
+        let errno = e.raw_os_error().unwrap_or(libc::EPERM);
+        unsafe {
+            cmd.pre_exec(move || Err(std::io::Error::from_raw_os_error(errno)));
+        }
+    }
+}
+
+/// Drop all capabilities not in the `keep` mask, raising the remaining ones in the
+/// ambient set.
+#[cfg(any(target_os = "android", target_os = "linux"))]
+#[allow(unsafe_code)]
+fn restrict_capabilities(keep: u64) -> std::io::Result<()> {
+    use rustix::thread::{CapabilityFlags, CapabilitySets};
+
+    let prctl = |op: libc::c_int, arg2: libc::c_ulong, arg3: libc::c_ulong| {
+        let zero: libc::c_ulong = 0;
+        match unsafe { libc::prctl(op, arg2, arg3, zero, zero) } {
+            0 => Ok(()),
+            _ => Err(std::io::Error::last_os_error()),
+        }
+    };
+    let current = rustix::thread::capabilities(None)?;
+    let kept = CapabilityFlags::from_bits_retain(keep);
+    if !current.permitted.contains(kept) {
+        return Err(rustix::io::Errno::PERM.into());
+    }
+    if current.permitted.contains(CapabilityFlags::SETPCAP) {
+        // The effective set is cleared when switching user.
+        rustix::thread::set_capabilities(
+            None,
+            CapabilitySets {
+                effective: current.permitted,
+                ..current
+            },
+        )?;
+        for cap in (0..64).filter(|cap| keep & 1 << cap == 0) {
+            match prctl(libc::PR_CAPBSET_DROP, cap, 0) {
+                // The capability (and any higher one) is not known to this kernel
+                Err(e) if e.raw_os_error() == Some(libc::EINVAL) => break,
+                r => r?,
+            }
+        }
+    }
+    rustix::thread::set_capabilities(
+        None,
+        CapabilitySets {
+            effective: kept,
+            permitted: kept,
+            inheritable: kept,
+        },
+    )?;
+    let ambient = libc::PR_CAP_AMBIENT;
+    prctl(ambient, libc::PR_CAP_AMBIENT_CLEAR_ALL as libc::c_ulong, 0)?;
+    for cap in (0..64).filter(|cap| kept.bits() & 1 << cap != 0) {
+        prctl(ambient, libc::PR_CAP_AMBIENT_RAISE as libc::c_ulong, cap)?;
+    }
+    Ok(())
+}
+
+/// Set `O_CLOEXEC` on all file descriptors above `max_fd`.  This is called after
+/// fork, and so must not allocate.
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn cloexec_fds_above(max_fd: i32) -> std::io::Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        let first = max_fd.saturating_add(1).max(0) as libc::c_uint;
+        // SAFETY: This has no memory safety implications.
+        #[allow(unsafe_code)]
+        let r = unsafe {
+            libc::syscall(
+                libc::SYS_close_range,
+                first,
+                libc::c_uint::MAX,
+                libc::CLOSE_RANGE_CLOEXEC,
+            )
+        };
+        if r == 0 {
+            return Ok(());
+        }
+        let e = std::io::Error::last_os_error();
+        // Older kernels don't support close_range (ENOSYS), or the flag (EINVAL).
+        if !matches!(e.raw_os_error(), Some(libc::ENOSYS | libc::EINVAL)) {
+            return Err(e);
+        }
+    }
+    cloexec_fds_above_procfs(max_fd)
+}
+
+/// The fallback for [`cloexec_fds_above`], enumerating `/proc/self/fd`.
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn cloexec_fds_above_procfs(max_fd: i32) -> std::io::Result<()> {
+    use rustix::fs::{Mode, OFlags, RawDir};
+    use std::mem::MaybeUninit;
+
+    let dirfd = rustix::fs::open(
+        "/proc/self/fd",
+        OFlags::RDONLY | OFlags::DIRECTORY | OFlags::CLOEXEC,
+        Mode::empty(),
+    )?;
+    let mut buf = [MaybeUninit::<u8>::uninit(); 1024];
+    let mut iter = RawDir::new(&dirfd, &mut buf);
+    while let Some(entry) = iter.next() {
+        let entry = entry?;
+        let Some(fd) = std::str::from_utf8(entry.file_name().to_bytes())
+            .ok()
+            .and_then(|name| name.parse::<i32>().ok())
+        else {
+            continue;
+        };
+        if fd <= max_fd || fd == dirfd.as_raw_fd() {
+            continue;
+        }
+        // SAFETY: The fd is open, and we only borrow it to change its flags.
+        #[allow(unsafe_code)]
+        let fd = unsafe { rustix::fd::BorrowedFd::borrow_raw(fd) };
+        let fl = rustix::io::fcntl_getfd(fd)?;
+        rustix::io::fcntl_setfd(fd, fl | FdFlags::CLOEXEC)?;
+    }
+    Ok(())
+}
+
+/// The maximum number of arguments passed to `newuidmap` or `newgidmap`; the
+/// kernel allows up to 340 ranges.
+#[cfg(any(target_os = "android", target_os = "linux"))]
+const MAX_HELPER_ARGS: usize = 2 + 3 * 340 + 1;
+
+/// An [`IdMapConfig`] prepared by [`IdMapConfig::prepare`].
+#[cfg(any(target_os = "android", target_os = "linux"))]
+struct PreparedIdMap {
+    uid_map: Vec<u8>,
+    gid_map: Vec<u8>,
+    deny_setgroups: bool,
+    /// The helper programs, and their arguments after the PID.
+    helpers: Option<[(std::ffi::CString, Vec<std::ffi::CString>); 2]>,
+}
+
+#[cfg(any(target_os = "android", target_os = "linux"))]
+#[allow(unsafe_code)]
+impl PreparedIdMap {
+    /// Called after fork: create a user namespace, and have a helper process in
+    /// the parent namespace write the mappings.
+    fn unshare_and_map(&self) -> std::io::Result<()> {
+        use rustix::fs::{Mode, OFlags};
+
+        let (r, w) = rustix::pipe::pipe_with(rustix::pipe::PipeFlags::CLOEXEC)?;
+        let proc_self = rustix::fs::open(
+            "/proc/self",
+            OFlags::PATH | OFlags::DIRECTORY | OFlags::CLOEXEC,
+            Mode::empty(),
+        )?;
+        let pid = rustix::process::getpid();
+        let helper = unsafe { libc::fork() };
+        if helper < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        if helper == 0 {
+            drop(w);
+            // Wait until the namespace exists; on EOF, it was not created.
+            let mut buf = [0u8; 1];
+            let code = match rustix::io::read(&r, &mut buf) {
+                Ok(1) => match self.write_maps(&proc_self, pid) {
+                    Ok(()) => 0,
+                    Err(e) => e.raw_os_error().unwrap_or(libc::EPERM),
+                },
+                _ => libc::ECANCELED,
+            };
+            unsafe { libc::_exit(code) }
+        }
+        drop(r);
+        let unshared = if unsafe { libc::unshare(libc::CLONE_NEWUSER) } < 0 {
+            Err(std::io::Error::last_os_error())
+        } else {
+            rustix::io::write(&w, b"x")
+                .map(drop)
+                .map_err(std::io::Error::from)
+        };
+        drop(w);
+        let status = wait_exited(helper)?;
+        unshared?;
+        if status != 0 {
+            return Err(std::io::Error::from_raw_os_error(status));
+        }
+        Ok(())
+    }
+
+    /// Called in the helper process to write the mappings for `pid`.
+    fn write_maps(&self, proc_self: &OwnedFd, pid: rustix::process::Pid) -> std::io::Result<()> {
+        if let Some(helpers) = self.helpers.as_ref() {
+            for (prog, args) in helpers {
+                if !args.is_empty() {
+                    run_idmap_helper(prog, args, pid)?;
+                }
+            }
+            return Ok(());
+        }
+        let write = |name: &str, contents: &[u8]| -> std::io::Result<()> {
+            let fd = rustix::fs::openat(
+                proc_self,
+                name,
+                rustix::fs::OFlags::WRONLY | rustix::fs::OFlags::CLOEXEC,
+                rustix::fs::Mode::empty(),
+            )?;
+            // The kernel requires each map to be written in a single call.
+            if rustix::io::write(&fd, contents)? != contents.len() {
+                return Err(std::io::Error::from_raw_os_error(libc::EIO));
+            }
+            Ok(())
+        };
+        if !self.uid_map.is_empty() {
+            write("uid_map", &self.uid_map)?;
+        }
+        if self.deny_setgroups {
+            write("setgroups", b"deny")?;
+        }
+        if !self.gid_map.is_empty() {
+            write("gid_map", &self.gid_map)?;
+        }
+        Ok(())
+    }
+}
+
+/// Run `newuidmap` or `newgidmap` for `pid`, without allocating.
+#[cfg(any(target_os = "android", target_os = "linux"))]
+#[allow(unsafe_code)]
+fn run_idmap_helper(
+    prog: &std::ffi::CStr,
+    args: &[std::ffi::CString],
+    pid: rustix::process::Pid,
+) -> std::io::Result<()> {
+    if args.len() + 3 > MAX_HELPER_ARGS {
+        return Err(std::io::Error::from_raw_os_error(libc::E2BIG));
+    }
+    let mut pidbuf = [0u8; 16];
+    let pid = format_decimal(pid.as_raw_nonzero().get(), &mut pidbuf);
+    let mut argv = [std::ptr::null::<libc::c_char>(); MAX_HELPER_ARGS];
+    argv[0] = prog.as_ptr();
+    argv[1] = pid.as_ptr().cast();
+    for (slot, arg) in argv[2..].iter_mut().zip(args) {
+        *slot = arg.as_ptr();
+    }
+    let child = unsafe { libc::fork() };
+    if child < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    if child == 0 {
+        unsafe {
+            libc::execvp(prog.as_ptr(), argv.as_ptr());
+            libc::_exit(127)
+        }
+    }
+    match wait_exited(child)? {
+        0 => Ok(()),
+        _ => Err(std::io::Error::from_raw_os_error(libc::EPERM)),
+    }
+}
+
+/// Format the number as a decimal string at the end of the buffer, without
+/// allocating.  The returned slice is followed by a nul byte in the buffer.
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn format_decimal(n: i32, buf: &mut [u8; 16]) -> &[u8] {
+    let mut i = buf.len() - 1;
+    buf[i] = 0;
+    let mut rest = n.unsigned_abs();
+    loop {
+        i -= 1;
+        buf[i] = b'0' + (rest % 10) as u8;
+        rest /= 10;
+        if rest == 0 {
+            break;
+        }
+    }
+    if n < 0 {
+        i -= 1;
+        buf[i] = b'-';
+    }
+    &buf[i..buf.len() - 1]
+}
+
+/// Wait for the child process, returning its exit code (or `-1` if it was
+/// killed by a signal).
+#[cfg(any(target_os = "android", target_os = "linux"))]
+#[allow(unsafe_code)]
+fn wait_exited(pid: libc::pid_t) -> std::io::Result<i32> {
+    let mut status = 0;
+    loop {
+        if unsafe { libc::waitpid(pid, &mut status, 0) } >= 0 {
+            break;
+        }
+        let e = std::io::Error::last_os_error();
+        if e.kind() != std::io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+    Ok(if libc::WIFEXITED(status) {
+        libc::WEXITSTATUS(status)
+    } else {
+        -1
+    })
 }
 
 #[cfg(test)]
@@ -80,4 +1148,35 @@ mod tests {
         }
         Ok(())
     }
+
+    #[test]
+    fn test_format_decimal() {
+        let mut buf = [0u8; 16];
+        for n in [0, 7, 42, -1000, i32::MAX, i32::MIN] {
+            assert_eq!(format_decimal(n, &mut buf), n.to_string().as_bytes());
+            assert_eq!(buf[15], 0);
+        }
+    }
+
+    #[test]
+    fn test_cloexec_fds_above_procfs() -> anyhow::Result<()> {
+        // Use a high number, which won't be reused by the child
+        let fd = rustix::io::fcntl_dupfd_cloexec(std::io::stdin(), 100)?;
+        rustix::io::fcntl_setfd(&fd, FdFlags::empty())?;
+        let n = fd.as_raw_fd();
+        let fds = |c: &mut std::process::Command| -> anyhow::Result<String> {
+            let out = c.arg("/proc/self/fd").output()?;
+            assert!(out.status.success());
+            Ok(String::from_utf8(out.stdout)?)
+        };
+        let mut c = std::process::Command::new("ls");
+        assert!(fds(&mut c)?.lines().any(|l| l == n.to_string()));
+        let mut c = std::process::Command::new("ls");
+        #[allow(unsafe_code)]
+        unsafe {
+            c.pre_exec(move || cloexec_fds_above_procfs(2));
+        }
+        assert!(!fds(&mut c)?.lines().any(|l| l == n.to_string()));
+        Ok(())
+    }
 }
@@ -0,0 +1,95 @@
+//! Hashing file contents.
+//!
+//! The key APIs here are [`crate::dirext::CapStdExtDirExt::file_digest`] and
+//! [`crate::dirext::CapStdExtDirExt::tree_digests`], which return a [`Digest`].
+//! Files are read with ordinary buffered reads (rather than e.g. memory mapped),
+//! so concurrent modification or truncation can at worst produce a wrong digest.
+
+use std::fmt;
+use std::io::{self, Read, Result};
+
+use sha2::Digest as _;
+
+/// A hash algorithm.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Algorithm {
+    /// SHA-256.
+    Sha256,
+    /// SHA-512.
+    Sha512,
+}
+
+impl Algorithm {
+    /// The length of a digest in bytes.
+    pub fn output_len(self) -> usize {
+        match self {
+            Algorithm::Sha256 => 32,
+            Algorithm::Sha512 => 64,
+        }
+    }
+}
+
+/// The digest of some content.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Digest {
+    algorithm: Algorithm,
+    bytes: Vec<u8>,
+}
+
+impl Digest {
+    /// The algorithm used to compute the digest.
+    pub fn algorithm(&self) -> Algorithm {
+        self.algorithm
+    }
+
+    /// The raw bytes of the digest.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// The digest as a lowercase hexadecimal string.
+    pub fn to_hex(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl fmt::Display for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for b in &self.bytes {
+            write!(f, "{b:02x}")?;
+        }
+        Ok(())
+    }
+}
+
+enum Hasher {
+    Sha256(sha2::Sha256),
+    Sha512(sha2::Sha512),
+}
+
+/// Compute the digest of the contents of a reader.
+pub(crate) fn digest_reader(mut r: impl Read, algorithm: Algorithm) -> Result<Digest> {
+    let mut hasher = match algorithm {
+        Algorithm::Sha256 => Hasher::Sha256(sha2::Sha256::new()),
+        Algorithm::Sha512 => Hasher::Sha512(sha2::Sha512::new()),
+    };
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = match r.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        match &mut hasher {
+            Hasher::Sha256(h) => h.update(&buf[..n]),
+            Hasher::Sha512(h) => h.update(&buf[..n]),
+        }
+    }
+    let bytes = match hasher {
+        Hasher::Sha256(h) => h.finalize().to_vec(),
+        Hasher::Sha512(h) => h.finalize().to_vec(),
+    };
+    Ok(Digest { algorithm, bytes })
+}
@@ -5,343 +5,4204 @@
 //! - A helper to update timestamps
 //! - "atomic write" APIs that create a new file, then rename over the existing one
 //!   to avoid half-written updates to files.
+//! - Recursive directory walking
 //!
 //! [`cap_std::fs::Dir`]: https://docs.rs/cap-std/latest/cap_std/fs/struct.Dir.html
 
-use cap_std::fs::{Dir, File, Metadata};
+use cap_std::fs::{Dir, DirEntry, File, FileType, Metadata};
 use cap_tempfile::cap_std;
+use std::cmp::Ordering;
 use std::ffi::OsStr;
 use std::io::Result;
 use std::io::{self, Write};
-use std::ops::Deref;
-use std::path::Path;
+use std::ops::{ControlFlow, Deref};
+use std::path::{Path, PathBuf};
 
 #[cfg(feature = "fs_utf8")]
 use cap_std::fs_utf8;
 #[cfg(feature = "fs_utf8")]
 use fs_utf8::camino::Utf8Path;
 
-/// Extension trait for [`cap_std::fs::Dir`].
-///
-/// [`cap_std::fs::Dir`]: https://docs.rs/cap-std/latest/cap_std/fs/struct.Dir.html
-pub trait CapStdExtDirExt {
-    /// Open a file read-only, but return `Ok(None)` if it does not exist.
-    fn open_optional(&self, path: impl AsRef<Path>) -> Result<Option<File>>;
-
-    /// Open a directory, but return `Ok(None)` if it does not exist.
-    fn open_dir_optional(&self, path: impl AsRef<Path>) -> Result<Option<Dir>>;
+/// A function used to sort directory entries during a walk.
+type WalkSorter = Box<dyn Fn(&DirEntry, &DirEntry) -> Ordering + 'static>;
 
-    /// Create a special variant of [`cap_std::fs::Dir`] which uses `RESOLVE_IN_ROOT`
-    /// to support absolute symlinks.
-    #[cfg(any(target_os = "android", target_os = "linux"))]
-    fn open_dir_rooted_ext(&self, path: impl AsRef<Path>) -> Result<crate::RootDir>;
+/// Configuration for recursive directory walking.
+#[derive(Default)]
+pub struct WalkConfiguration<'p> {
+    /// Do not cross devices.
+    noxdev: bool,
+    /// Prefix prepended to the paths passed to the callback.
+    path_base: Option<&'p Path>,
+    /// Sort entries within each directory.
+    sorter: Option<WalkSorter>,
+    /// Read all directory entries before processing any of them.
+    buffered: bool,
+    /// Follow symbolic links.
+    follow_symlinks: bool,
+    /// Invoke the callback for directories after their contents.
+    contents_first: bool,
+    /// Fetch the metadata of each entry.
+    metadata: bool,
+    /// Detect files which were already visited via another hard link.
+    track_hardlinks: bool,
+    /// Filters applied to entries before the callback.
+    filter: WalkFilter,
+    /// Invoke the callback for the directory being walked.
+    include_root: bool,
+    /// The maximum number of subdirectories held open at once.
+    max_open_dirs: Option<usize>,
+    /// Whether the sorter orders entries by file name.
+    sorted_by_name: bool,
+    /// Skip entries up to and including this position.
+    resume: Option<WalkCursor>,
+}
 
-    /// Create the target directory, but do nothing if a directory already exists at that path.
-    /// The return value will be `true` if the directory was created.  An error will be
-    /// returned if the path is a non-directory.  Symbolic links will be followed.
-    fn ensure_dir_with(
-        &self,
-        p: impl AsRef<Path>,
-        builder: &cap_std::fs::DirBuilder,
-    ) -> Result<bool>;
+/// The default for [`WalkConfiguration::max_open_dirs`].
+const DEFAULT_MAX_OPEN_DIRS: usize = 128;
 
-    /// Gather metadata, but return `Ok(None)` if it does not exist.
-    fn metadata_optional(&self, path: impl AsRef<Path>) -> Result<Option<Metadata>>;
+/// Patterns used to filter entries during a walk.
+#[derive(Debug, Default)]
+struct WalkFilter {
+    include: Vec<String>,
+    exclude: Vec<String>,
+    skip_hidden: bool,
+}
 
-    /// Gather metadata (but do not follow symlinks), but return `Ok(None)` if it does not exist.
-    fn symlink_metadata_optional(&self, path: impl AsRef<Path>) -> Result<Option<Metadata>>;
+impl WalkFilter {
+    /// Returns `true` if the entry at the path (relative to the walk root) should be
+    /// skipped, and, if a directory, not descended into.
+    fn excludes(&self, path: &Path) -> bool {
+        if self.skip_hidden
+            && path
+                .file_name()
+                .is_some_and(|name| name.to_string_lossy().starts_with('.'))
+        {
+            return true;
+        }
+        self.excludes_pattern(path)
+    }
 
-    /// Remove (delete) a file, but return `Ok(false)` if the file does not exist.
-    fn remove_file_optional(&self, path: impl AsRef<Path>) -> Result<bool>;
+    #[cfg(unix)]
+    fn excludes_pattern(&self, path: &Path) -> bool {
+        use std::os::unix::ffi::OsStrExt;
+        let path = path.as_os_str().as_bytes();
+        self.exclude.iter().any(|p| glob_matches(p, path))
+    }
 
-    /// Remove a file or directory but return `Ok(false)` if the file does not exist.
-    /// Symbolic links are not followed.
-    fn remove_all_optional(&self, path: impl AsRef<Path>) -> Result<bool>;
+    #[cfg(not(unix))]
+    fn excludes_pattern(&self, _path: &Path) -> bool {
+        false
+    }
 
-    /// Set the access and modification times to the current time.  Symbolic links are not followed.
+    /// Returns `true` if the entry should be passed to the callback, considering
+    /// include patterns.  Directories are always included.
     #[cfg(unix)]
-    fn update_timestamps(&self, path: impl AsRef<Path>) -> Result<()>;
+    fn includes(&self, path: &Path, file_type: FileType) -> bool {
+        use std::os::unix::ffi::OsStrExt;
+        let path = path.as_os_str().as_bytes();
+        self.include.is_empty()
+            || file_type.is_dir()
+            || self.include.iter().any(|p| glob_matches(p, path))
+    }
 
-    /// Atomically write a file by calling the provided closure.
-    ///
-    /// This uses [`cap_tempfile::TempFile`], which is wrapped in a [`std::io::BufWriter`]
-    /// and passed to the closure.
-    ///
-    /// # Existing files and metadata
-    ///
-    /// If the target path already exists and is a regular file (not a symbolic link or directory),
-    /// then its access permissions (Unix mode) will be preserved.  However, other metadata
-    /// such as extended attributes will *not* be preserved automatically.  To do this will
-    /// require a higher level wrapper which queries the existing file and gathers such metadata
-    /// before replacement.
-    ///
-    /// # Example, including setting permissions
-    ///
-    /// The closure may also perform other file operations beyond writing, such as changing
-    /// file permissions:
-    ///
-    /// ```rust
-    /// # use std::io;
-    /// # use std::io::Write;
-    /// # use cap_tempfile::cap_std;
-    /// # fn main() -> io::Result<()> {
-    /// # let somedir = cap_tempfile::tempdir(cap_std::ambient_authority())?;
-    /// use cap_std_ext::prelude::*;
-    /// let contents = b"hello world\n";
-    /// somedir.atomic_replace_with("somefilename", |f| -> io::Result<_> {
-    ///     f.write_all(contents)?;
-    ///     f.flush()?;
-    ///     use cap_std::fs::PermissionsExt;
-    ///     let perms = cap_std::fs::Permissions::from_mode(0o600);
-    ///     f.get_mut().as_file_mut().set_permissions(perms)?;
-    ///     Ok(())
-    /// })
-    /// # }
-    /// ```
-    ///
-    /// Any existing file will be replaced.
-    fn atomic_replace_with<F, T, E>(
-        &self,
-        destname: impl AsRef<Path>,
-        f: F,
-    ) -> std::result::Result<T, E>
-    where
-        F: FnOnce(&mut std::io::BufWriter<cap_tempfile::TempFile>) -> std::result::Result<T, E>,
-        E: From<std::io::Error>;
+    #[cfg(not(unix))]
+    fn includes(&self, _path: &Path, _file_type: FileType) -> bool {
+        true
+    }
+}
 
-    /// Atomically write the provided contents to a file.
-    fn atomic_write(&self, destname: impl AsRef<Path>, contents: impl AsRef<[u8]>) -> Result<()>;
+/// Match a path against a glob pattern; see [`WalkConfiguration::exclude`].
+#[cfg(unix)]
+fn glob_matches(pattern: &str, path: &[u8]) -> bool {
+    fn split(p: &[u8]) -> Vec<&[u8]> {
+        p.split(|&c| c == b'/').filter(|c| !c.is_empty()).collect()
+    }
+    let pattern = pattern.as_bytes();
+    if !pattern.contains(&b'/') {
+        // SAFETY(unwrap): Splitting always yields at least one element.
+        let name = path.rsplit(|&c| c == b'/').next().unwrap();
+        return glob_component_matches(pattern, name);
+    }
+    glob_components_match(&split(pattern), &split(path))
+}
 
-    /// Atomically write the provided contents to a file, using specified permissions.
-    fn atomic_write_with_perms(
-        &self,
-        destname: impl AsRef<Path>,
-        contents: impl AsRef<[u8]>,
-        perms: cap_std::fs::Permissions,
-    ) -> Result<()>;
+#[cfg(unix)]
+fn glob_components_match(pattern: &[&[u8]], path: &[&[u8]]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some((first, rest)) if *first == b"**" => {
+            (0..=path.len()).any(|i| glob_components_match(rest, &path[i..]))
+        }
+        Some((first, rest)) => path.split_first().is_some_and(|(name, path)| {
+            glob_component_matches(first, name) && glob_components_match(rest, path)
+        }),
+    }
+}
 
-    #[cfg(any(target_os = "android", target_os = "linux"))]
-    /// Returns `Some(true)` if the target is known to be a mountpoint, or
-    /// `Some(false)` if the target is definitively known not to be a mountpoint.
-    ///
-    /// In some scenarios (such as an older kernel) this currently may not be possible
-    /// to determine, and `None` will be returned in those cases.
-    fn is_mountpoint(&self, path: impl AsRef<Path>) -> Result<Option<bool>>;
+#[cfg(unix)]
+pub(crate) fn glob_component_matches(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((b'*', rest)) => (0..=name.len()).any(|i| glob_component_matches(rest, &name[i..])),
+        Some((b'?', rest)) => !name.is_empty() && glob_component_matches(rest, &name[1..]),
+        Some((c, rest)) => name.first() == Some(c) && glob_component_matches(rest, &name[1..]),
+    }
 }
 
-#[cfg(feature = "fs_utf8")]
-/// Extension trait for [`cap_std::fs_utf8::Dir`].
-///
-/// [`cap_std::fs_utf8::Dir`]: https://docs.rs/cap-std/latest/cap_std/fs_utf8/struct.Dir.html
-pub trait CapStdExtDirExtUtf8 {
-    /// Open a file read-only, but return `Ok(None)` if it does not exist.
-    fn open_optional(&self, path: impl AsRef<Utf8Path>) -> Result<Option<fs_utf8::File>>;
+impl std::fmt::Debug for WalkConfiguration<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WalkConfiguration")
+            .field("noxdev", &self.noxdev)
+            .field("path_base", &self.path_base)
+            .field("sorter", &self.sorter.as_ref().map(|_| "<function>"))
+            .field("buffered", &self.buffered)
+            .field("follow_symlinks", &self.follow_symlinks)
+            .field("contents_first", &self.contents_first)
+            .field("metadata", &self.metadata)
+            .field("track_hardlinks", &self.track_hardlinks)
+            .field("filter", &self.filter)
+            .field("include_root", &self.include_root)
+            .field("max_open_dirs", &self.max_open_dirs)
+            .field("resume", &self.resume)
+            .finish()
+    }
+}
 
-    /// Open a directory, but return `Ok(None)` if it does not exist.
-    fn open_dir_optional(&self, path: impl AsRef<Utf8Path>) -> Result<Option<fs_utf8::Dir>>;
+impl<'p> WalkConfiguration<'p> {
+    /// Do not cross mount points.
+    pub fn noxdev(mut self) -> Self {
+        self.noxdev = true;
+        self
+    }
 
-    /// Create the target directory, but do nothing if a directory already exists at that path.
-    /// The return value will be `true` if the directory was created.  An error will be
-    /// returned if the path is a non-directory.  Symbolic links will be followed.
-    fn ensure_dir_with(
-        &self,
-        p: impl AsRef<Utf8Path>,
-        builder: &cap_std::fs::DirBuilder,
-    ) -> Result<bool>;
+    /// Whether [`Self::noxdev`] was set.
+    #[cfg(any(target_os = "android", target_os = "linux", target_os = "freebsd"))]
+    pub(crate) fn has_noxdev(&self) -> bool {
+        self.noxdev
+    }
 
-    /// Gather metadata, but return `Ok(None)` if it does not exist.
-    fn metadata_optional(&self, path: impl AsRef<Utf8Path>) -> Result<Option<Metadata>>;
+    /// Prefix the paths passed to the callback with this path.
+    pub fn path_base(mut self, base: &'p Path) -> Self {
+        self.path_base = Some(base);
+        self
+    }
 
-    /// Gather metadata (but do not follow symlinks), but return `Ok(None)` if it does not exist.
-    fn symlink_metadata_optional(&self, path: impl AsRef<Utf8Path>) -> Result<Option<Metadata>>;
+    /// Follow symbolic links, presenting the "logical" view of the tree: links
+    /// are reported with the file type of their target, and links to directories
+    /// are descended into, with paths reported via the link.
+    ///
+    /// Links which are dangling, or which point outside of the directory being
+    /// walked, are reported as symbolic links.  A directory which is already
+    /// being walked (i.e. an ancestor, by device and inode number) is reported
+    /// but not descended into again, which prevents cycles.
+    #[cfg(unix)]
+    pub fn follow_symlinks(mut self) -> Self {
+        self.follow_symlinks = true;
+        self
+    }
 
-    /// Remove (delete) a file, but return `Ok(false)` if the file does not exist.
-    fn remove_file_optional(&self, path: impl AsRef<Utf8Path>) -> Result<bool>;
+    /// Invoke the callback for a directory after its contents have been walked
+    /// (post-order), rather than before.  This allows e.g. removing a tree or
+    /// accumulating sizes bottom-up directly from the walk.
+    ///
+    /// In this mode, returning [`ControlFlow::Break`] for any entry (including a
+    /// directory) skips the remaining entries in the containing directory; the
+    /// callback is still invoked for the containing directory itself.
+    pub fn contents_first(mut self) -> Self {
+        self.contents_first = true;
+        self
+    }
 
-    /// Remove a file or directory but return `Ok(false)` if the file does not exist.
-    /// Symbolic links are not followed.
-    fn remove_all_optional(&self, path: impl AsRef<Utf8Path>) -> Result<bool>;
+    /// Fetch the metadata of each entry, provided as [`WalkComponent::metadata`].
+    /// This avoids callbacks needing to look it up separately.
+    pub fn metadata(mut self) -> Self {
+        self.metadata = true;
+        self
+    }
 
-    /// Set the access and modification times to the current time.  Symbolic links are not followed.
+    /// Detect files with multiple hard links which were already visited during the walk
+    /// via another link, which are reported via [`WalkComponent::first_seen`] and
+    /// [`WalkComponent::first_path`].  This is useful for e.g. size accounting or
+    /// archiving.
     #[cfg(unix)]
-    fn update_timestamps(&self, path: impl AsRef<Utf8Path>) -> Result<()>;
+    pub fn track_hardlinks(mut self) -> Self {
+        self.track_hardlinks = true;
+        self
+    }
 
-    /// Atomically write a file by calling the provided closure.
+    /// Skip entries matching the glob pattern, without invoking the callback or
+    /// descending into them.  Patterns are matched against paths relative to the
+    /// directory being walked (i.e. without the path base).
     ///
-    /// This uses [`cap_tempfile::TempFile`], which is wrapped in a [`std::io::BufWriter`]
-    /// and passed to the closure.
+    /// A pattern without a `/` matches the file name at any depth.  Otherwise, it
+    /// is matched against the whole path, where `**` matches any number of path
+    /// components (including none).  Within a component, `*` matches any sequence
+    /// of characters, and `?` any single character; other characters (including
+    /// a leading `.`) match only themselves.
+    #[cfg(unix)]
+    pub fn exclude(mut self, pattern: impl Into<String>) -> Self {
+        self.filter.exclude.push(pattern.into());
+        self
+    }
+
+    /// Only invoke the callback for non-directories matching the glob pattern (or
+    /// any other include pattern); the pattern syntax is as for [`Self::exclude`],
+    /// which takes precedence.  Directories are always walked and passed to the
+    /// callback, as their contents may match.
+    #[cfg(unix)]
+    pub fn include(mut self, pattern: impl Into<String>) -> Self {
+        self.filter.include.push(pattern.into());
+        self
+    }
+
+    /// Invoke the callback for the directory being walked itself, before any other
+    /// entries (or after them, with [`Self::contents_first`]).  Its path is the
+    /// path base (empty by default); as it has no parent, [`WalkComponent::dir`] is
+    /// the directory itself and [`WalkComponent::filename`] is `.`.
     ///
-    /// # Existing files and metadata
+    /// Returning [`ControlFlow::Break`] for the root skips the rest of the walk.
+    pub fn include_root(mut self) -> Self {
+        self.include_root = true;
+        self
+    }
+
+    /// Limit the number of subdirectories held open at once during the walk; the
+    /// default is 128.  When a tree is deeper than this, the remaining entries of
+    /// the outermost open directories are read into memory and the directories
+    /// are closed, to be reopened (and verified to be the same directory, on Unix)
+    /// once the walk returns to them.  The directory being walked is always held
+    /// open, and does not count towards the limit.
     ///
-    /// If the target path already exists and is a regular file (not a symbolic link or directory),
-    /// then its access permissions (Unix mode) will be preserved.  However, other metadata
-    /// such as extended attributes will *not* be preserved automatically.  To do this will
-    /// require a higher level wrapper which queries the existing file and gathers such metadata
-    /// before replacement.
+    /// This does not apply to [`CapStdExtDirExt::walk_parallel`].
+    pub fn max_open_dirs(mut self, n: usize) -> Self {
+        self.max_open_dirs = Some(n);
+        self
+    }
+
+    /// Skip entries whose names start with `.` (including their contents), without
+    /// invoking the callback.
+    pub fn skip_hidden(mut self) -> Self {
+        self.filter.skip_hidden = true;
+        self
+    }
+
+    /// Return the path relative to the root, without the configured path base.
+    fn relative<'a>(&self, path: &'a Path) -> &'a Path {
+        // SAFETY(unwrap): All walked paths are prefixed with the path base.
+        path.strip_prefix(self.path_base.unwrap_or(Path::new("")))
+            .unwrap()
+    }
+
+    /// Sort the entries of each directory using the provided comparison function.
+    pub fn sort_by<F>(mut self, cmp: F) -> Self
+    where
+        F: Fn(&DirEntry, &DirEntry) -> Ordering + 'static,
+    {
+        self.sorter = Some(Box::new(cmp));
+        self.sorted_by_name = false;
+        self
+    }
+
+    /// Sort the entries of each directory by file name.
+    pub fn sort_by_file_name(self) -> Self {
+        let mut r = self.sort_by(|a, b| a.file_name().cmp(&b.file_name()));
+        r.sorted_by_name = true;
+        r
+    }
+
+    /// Resume a walk after the entry at which the cursor was taken with
+    /// [`WalkComponent::cursor`]; entries which precede it (or, with
+    /// [`Self::contents_first`], which were visited before it) are skipped.  The
+    /// walk must be sorted by file name, via [`Self::sort_by_file_name`] or
+    /// [`Self::deterministic`]; otherwise it fails with an error of kind
+    /// [`std::io::ErrorKind::InvalidInput`].
     ///
-    /// # Example, including setting permissions
+    /// The tree may change between the two walks; entries are compared by name,
+    /// so the walk resumes at the right position even if the cursor's entry (or
+    /// one of its parents) has since been removed.
+    pub fn resume_after(mut self, cursor: WalkCursor) -> Self {
+        self.resume = Some(cursor);
+        self
+    }
+
+    /// Configure the walk to produce the same sequence of callbacks for the same
+    /// directory tree contents, independent of the order in which the operating
+    /// system returns directory entries.
     ///
-    /// The closure may also perform other file operations beyond writing, such as changing
-    /// file permissions:
+    /// # Stability guarantee
     ///
-    /// ```rust
-    /// # use std::io;
-    /// # use std::io::Write;
-    /// # use cap_tempfile::cap_std;
-    /// # fn main() -> io::Result<()> {
-    /// # let somedir = cap_tempfile::tempdir(cap_std::ambient_authority())?;
-    /// # let somedir = cap_std::fs_utf8::Dir::from_cap_std((&*somedir).try_clone()?);
-    /// use cap_std_ext::prelude::*;
-    /// let contents = b"hello world\n";
-    /// somedir.atomic_replace_with("somefilename", |f| -> io::Result<_> {
-    ///     f.write_all(contents)?;
-    ///     f.flush()?;
-    ///     use cap_std::fs::PermissionsExt;
-    ///     let perms = cap_std::fs::Permissions::from_mode(0o600);
-    ///     f.get_mut().as_file_mut().set_permissions(perms)?;
-    ///     Ok(())
-    /// })
-    /// # }
-    /// ```
+    /// The following is considered a stable part of the API, and is intended to be
+    /// used as a basis for any output which should be byte-identical across runs
+    /// and platforms (archives, manifests, digests, etc.):
     ///
-    /// Any existing file will be replaced.
-    fn atomic_replace_with<F, T, E>(
-        &self,
-        destname: impl AsRef<Utf8Path>,
-        f: F,
-    ) -> std::result::Result<T, E>
-    where
-        F: FnOnce(&mut std::io::BufWriter<cap_tempfile::TempFile>) -> std::result::Result<T, E>,
-        E: From<std::io::Error>;
+    /// - Entries within a directory are visited in ascending order of the raw bytes
+    ///   of their file name. (Names are unique within a directory, so there are no ties;
+    ///   the sort is nevertheless stable.)
+    /// - All entries of a directory are read before the callback is invoked for any
+    ///   of them. An error reading a directory is therefore always returned before any
+    ///   of its entries are processed, and never depends on the order in which the
+    ///   operating system enumerates entries.
+    #[cfg(unix)]
+    pub fn deterministic(self) -> Self {
+        use std::os::unix::ffi::OsStrExt;
+        let mut r = self.sort_by(|a, b| a.file_name().as_bytes().cmp(b.file_name().as_bytes()));
+        r.buffered = true;
+        r.sorted_by_name = true;
+        r
+    }
+}
 
-    /// Atomically write the provided contents to a file.
-    fn atomic_write(
-        &self,
-        destname: impl AsRef<Utf8Path>,
-        contents: impl AsRef<[u8]>,
-    ) -> Result<()>;
+/// The return value of a [`CapStdExtDirExt::walk`] callback.
+pub type WalkResult<E> = std::result::Result<ControlFlow<()>, E>;
 
-    /// Atomically write the provided contents to a file, using specified permissions.
-    fn atomic_write_with_perms(
-        &self,
-        destname: impl AsRef<Utf8Path>,
-        contents: impl AsRef<[u8]>,
-        perms: cap_std::fs::Permissions,
-    ) -> Result<()>;
+/// An entry discovered while walking a directory tree.
+#[derive(Debug)]
+pub struct WalkComponent<'p, 'w> {
+    /// The relative path to the entry (including the configured path base, if any).
+    pub path: &'p Path,
+    /// The parent directory.
+    pub dir: &'w Dir,
+    /// The file name of the entry.
+    pub filename: &'w OsStr,
+    /// The file type of the entry; symbolic links are not followed, unless
+    /// [`WalkConfiguration::follow_symlinks`] is set.
+    pub file_type: FileType,
+    /// The metadata of the entry, if [`WalkConfiguration::metadata`] is set.  As with
+    /// the file type, this is for the symbolic link itself unless links are followed.
+    pub metadata: Option<&'w Metadata>,
+    /// If [`WalkConfiguration::track_hardlinks`] is set, this is `false` for a file
+    /// which was already visited via another hard link during the walk; otherwise
+    /// it is always `true`.
+    pub first_seen: bool,
+    /// For a file which was already visited via another hard link, the path at which
+    /// it was first visited.
+    pub first_path: Option<&'w Path>,
+}
 
-    /// Read all filenames in this directory, sorted
-    fn filenames_sorted(&self) -> Result<Vec<String>> {
-        self.filenames_sorted_by(|a, b| a.cmp(b))
+impl WalkComponent<'_, '_> {
+    /// Return a cursor for this position in the walk, from which a later walk can
+    /// be resumed via [`WalkConfiguration::resume_after`].
+    pub fn cursor(&self) -> WalkCursor {
+        WalkCursor {
+            path: self.path.to_owned(),
+        }
     }
+}
 
-    /// Read all filenames in this directory, sorted by the provided comparison function.
-    fn filenames_sorted_by<C>(&self, compare: C) -> Result<Vec<String>>
-    where
-        C: FnMut(&str, &str) -> std::cmp::Ordering,
-    {
-        self.filenames_filtered_sorted_by(|_, _| true, compare)
+/// A position in a walk sorted by file name; see [`WalkComponent::cursor`].
+///
+/// This is simply the path of an entry (including the configured path base), so it
+/// can be persisted e.g. via [`std::os::unix::ffi::OsStrExt::as_bytes`] and
+/// recreated with [`WalkCursor::new`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WalkCursor {
+    path: PathBuf,
+}
+
+impl WalkCursor {
+    /// Create a cursor from the path of an entry, as passed in [`WalkComponent::path`].
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
     }
 
-    /// Read all filenames in this directory, applying a filter and sorting the result.
-    fn filenames_filtered_sorted<F>(&self, f: F) -> Result<Vec<String>>
-    where
-        F: FnMut(&fs_utf8::DirEntry, &str) -> bool,
-    {
-        self.filenames_filtered_sorted_by(f, |a, b| a.cmp(b))
+    /// The path of the entry at which the cursor was taken.
+    pub fn path(&self) -> &Path {
+        &self.path
     }
+}
 
-    /// Read all filenames in this directory, applying a filter and sorting the result with a custom comparison function.
-    fn filenames_filtered_sorted_by<F, C>(&self, f: F, compare: C) -> Result<Vec<String>>
-    where
-        F: FnMut(&fs_utf8::DirEntry, &str) -> bool,
-        C: FnMut(&str, &str) -> std::cmp::Ordering;
+/// The level of durability requested for an atomic write.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub enum Durability {
+    /// Do not explicitly synchronize anything to persistent storage; the new
+    /// contents are guaranteed to replace the old atomically, but after a system
+    /// crash either may be present (or on some filesystems, an empty file).
+    #[default]
+    None,
+    /// `fsync` the file before it is renamed into place, and `fsync` the
+    /// parent directory afterward.
+    Standard,
+    /// Like [`Durability::Standard`], but additionally request that storage
+    /// device caches are flushed.  On Apple platforms, `fsync` does not do this,
+    /// and `F_FULLFSYNC` is used instead (falling back to `fsync` if the filesystem
+    /// does not support it); the weaker `F_BARRIERFSYNC` is not used.  On all other
+    /// platforms this is the same as [`Durability::Standard`]; on Linux, `fsync`
+    /// already flushes device caches.
+    FullBarrier,
 }
 
-pub(crate) fn map_optional<R>(r: Result<R>) -> Result<Option<R>> {
-    match r {
-        Ok(v) => Ok(Some(v)),
-        Err(e) => {
-            if e.kind() == std::io::ErrorKind::NotFound {
-                Ok(None)
+/// Options for [`CapStdExtDirExt::atomic_replace_with_options`].
+#[derive(Debug, Default)]
+pub struct AtomicWriteOptions {
+    durability: Durability,
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    scratch_dir: Option<Dir>,
+    backups: usize,
+}
+
+impl AtomicWriteOptions {
+    /// Set the durability level; the default is [`Durability::None`].
+    pub fn durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    /// Create the temporary file in the provided directory, rather than in the
+    /// destination's parent directory; it is then renamed directly into place.  This
+    /// avoids temporary names ever appearing in the destination directory (which may
+    /// be watched, or subject to a quota).
+    ///
+    /// The directory must be on the same filesystem as the destination; otherwise an
+    /// error (`EXDEV`) is returned.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    pub fn scratch_dir(mut self, dir: Dir) -> Self {
+        self.scratch_dir = Some(dir);
+        self
+    }
+
+    /// Keep up to this many previous versions of the file.  Before the new contents
+    /// are installed, the existing file is linked (or copied, if the filesystem does
+    /// not support hard links) to `NAME.bak`, so that the destination itself always
+    /// exists; older backups are renamed to `NAME.bak.1`, `NAME.bak.2` and so on,
+    /// and those beyond the count are removed.  The default is zero.
+    ///
+    /// Backups are only made of existing non-directories, and only once the new
+    /// contents have been successfully written.
+    pub fn backups(mut self, count: usize) -> Self {
+        self.backups = count;
+        self
+    }
+}
+
+/// An opaque token identifying the state of a directory's entries at the time
+/// of [`CapStdExtDirExt::entries_snapshot`].
+#[cfg(unix)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirGeneration {
+    dev: u64,
+    ino: u64,
+    modified: std::time::SystemTime,
+    /// The directory was modified so recently before the snapshot that a
+    /// further modification might not change the timestamp.
+    racy: bool,
+}
+
+#[cfg(unix)]
+impl DirGeneration {
+    /// Timestamps may be updated at a coarser granularity than the clock;
+    /// any directory modified within this window of a snapshot is treated as racy.
+    const RACY_WINDOW: std::time::Duration = std::time::Duration::from_secs(1);
+
+    fn new(meta: &Metadata, now: std::time::SystemTime) -> Result<Self> {
+        use cap_std::fs::MetadataExt;
+        let modified = meta.modified()?.into_std();
+        let racy = now
+            .duration_since(modified)
+            .map_or(true, |d| d < Self::RACY_WINDOW);
+        Ok(Self {
+            dev: meta.dev(),
+            ino: meta.ino(),
+            modified,
+            racy,
+        })
+    }
+}
+
+/// The result of [`CapStdExtDirExt::entries_snapshot`].
+#[cfg(unix)]
+#[derive(Debug, Clone)]
+pub struct EntriesSnapshot {
+    /// The names of the directory entries, sorted.
+    pub names: Vec<std::ffi::OsString>,
+    /// A token which can be passed to [`CapStdExtDirExt::has_changed_since`].
+    pub generation: DirGeneration,
+}
+
+/// A temporary directory with a recognizable name, which is removed (along with its
+/// contents) when dropped; see [`CapStdExtDirExt::tempdir_in_with_prefix`].
+#[derive(Debug)]
+pub struct NamedTempDir<'d> {
+    parent: &'d Dir,
+    name: String,
+    /// Only `None` once consumed by `keep` or `close`.
+    dir: Option<Dir>,
+}
+
+impl NamedTempDir<'_> {
+    /// The name of the directory within its parent.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Keep the directory rather than removing it, returning its name and the
+    /// directory itself.
+    pub fn keep(mut self) -> (String, Dir) {
+        // SAFETY(unwrap): This is only taken when consumed.
+        let dir = self.dir.take().unwrap();
+        (std::mem::take(&mut self.name), dir)
+    }
+
+    /// Remove the directory and its contents, returning any error; dropping the
+    /// directory ignores errors.
+    pub fn close(mut self) -> Result<()> {
+        self.dir.take();
+        self.parent.remove_dir_all(&self.name)
+    }
+}
+
+impl Deref for NamedTempDir<'_> {
+    type Target = Dir;
+
+    fn deref(&self) -> &Dir {
+        // SAFETY(unwrap): This is only taken when consumed.
+        self.dir.as_ref().unwrap()
+    }
+}
+
+impl Drop for NamedTempDir<'_> {
+    fn drop(&mut self) {
+        if self.dir.take().is_some() {
+            let _ = self.parent.remove_dir_all(&self.name);
+        }
+    }
+}
+
+/// An iterator over the immediate subdirectories of a directory; see
+/// [`CapStdExtDirExt::subdirs`].
+#[derive(Debug)]
+pub struct Subdirs {
+    entries: cap_std::fs::ReadDir,
+}
+
+impl Iterator for Subdirs {
+    type Item = Result<(std::ffi::OsString, Dir)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let entry = match self.entries.next()? {
+                Ok(e) => e,
+                Err(e) => return Some(Err(e)),
+            };
+            match entry.file_type() {
+                Ok(t) if t.is_dir() => {}
+                Ok(_) => continue,
+                Err(e) => return Some(Err(e)),
+            }
+            match entry.open_dir() {
+                Ok(d) => return Some(Ok((entry.file_name(), d))),
+                // It was concurrently removed
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+/// A persistent handle for a file, as returned by `name_to_handle_at(2)`.
+/// See [`CapStdExtDirExt::file_handle`].
+#[cfg(any(target_os = "android", target_os = "linux"))]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct FileHandle {
+    handle_type: i32,
+    bytes: Vec<u8>,
+    mount_id: i32,
+}
+
+#[cfg(any(target_os = "android", target_os = "linux"))]
+impl FileHandle {
+    /// Reconstruct a handle from its parts, e.g. after loading it from persistent storage.
+    pub fn from_raw_parts(handle_type: i32, bytes: Vec<u8>, mount_id: i32) -> Self {
+        Self {
+            handle_type,
+            bytes,
+            mount_id,
+        }
+    }
+
+    /// The filesystem-specific type of the handle.
+    pub fn handle_type(&self) -> i32 {
+        self.handle_type
+    }
+
+    /// The opaque handle contents.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// The identifier of the mount containing the file at the time the handle was
+    /// obtained (as found in `/proc/self/mountinfo`).
+    pub fn mount_id(&self) -> i32 {
+        self.mount_id
+    }
+}
+
+#[cfg(any(target_os = "android", target_os = "linux"))]
+bitflags::bitflags! {
+    /// Inode flags, as shown by `lsattr` and changed by `chattr`; see
+    /// [`CapStdExtDirExt::get_inode_flags`].
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct InodeFlags: u32 {
+        /// The file can only be opened for appending (`a`).
+        const APPEND = rustix::fs::IFlags::APPEND.bits();
+        /// The file is compressed by the filesystem (`c`).
+        const COMPRESSED = rustix::fs::IFlags::COMPRESSED.bits();
+        /// Changes to the directory are written synchronously (`D`).
+        const DIRSYNC = rustix::fs::IFlags::DIRSYNC.bits();
+        /// The file cannot be modified, renamed, removed or linked to (`i`).
+        const IMMUTABLE = rustix::fs::IFlags::IMMUTABLE.bits();
+        /// File data is written to the journal (`j`).
+        const JOURNAL_DATA = rustix::fs::IFlags::JOURNALING.bits();
+        /// The access time is not updated (`A`).
+        const NOATIME = rustix::fs::IFlags::NOATIME.bits();
+        /// The file is not subject to copy-on-write, on filesystems which support it (`C`).
+        const NOCOW = rustix::fs::IFlags::NOCOW.bits();
+        /// The file is skipped by `dump` (`d`).
+        const NODUMP = rustix::fs::IFlags::NODUMP.bits();
+        /// The file does not have a partial block merged with other files (`t`).
+        const NOTAIL = rustix::fs::IFlags::NOTAIL.bits();
+        /// New files in the directory inherit its project ID (`P`).
+        const PROJECT_INHERIT = rustix::fs::IFlags::PROJECT_INHERIT.bits();
+        /// The file's blocks are zeroed when it is removed (`s`).
+        const SECURE_REMOVAL = rustix::fs::IFlags::SECURE_REMOVAL.bits();
+        /// Changes to the file are written synchronously (`S`).
+        const SYNC = rustix::fs::IFlags::SYNC.bits();
+        /// The directory is the top of a hierarchy for the Orlov block allocator (`T`).
+        const TOPDIR = rustix::fs::IFlags::TOPDIR.bits();
+        /// The file's contents are saved when it is removed, allowing undeletion (`u`).
+        const UNDELETE = rustix::fs::IFlags::UNRM.bits();
+
+        // Other flags are preserved
+        const _ = !0;
+    }
+}
+
+/// Which extended attributes to copy in [`CapStdExtDirExt::copy_xattrs`]; by
+/// default, all of them.
+#[cfg(any(target_os = "android", target_os = "linux"))]
+#[derive(Debug, Clone, Default)]
+pub struct XattrFilter {
+    excluded: Vec<std::ffi::OsString>,
+    excluded_prefixes: Vec<std::ffi::OsString>,
+}
+
+#[cfg(any(target_os = "android", target_os = "linux"))]
+impl XattrFilter {
+    /// Skip the attribute with exactly this name, e.g. `security.selinux`.
+    pub fn exclude(mut self, name: impl AsRef<OsStr>) -> Self {
+        self.excluded.push(name.as_ref().to_owned());
+        self
+    }
+
+    /// Skip all attributes whose names start with this prefix, e.g. `trusted.`.
+    pub fn exclude_prefix(mut self, prefix: impl AsRef<OsStr>) -> Self {
+        self.excluded_prefixes.push(prefix.as_ref().to_owned());
+        self
+    }
+
+    /// Returns `true` if the attribute should be copied.
+    pub fn matches(&self, name: &OsStr) -> bool {
+        use std::os::unix::ffi::OsStrExt;
+        !self.excluded.iter().any(|n| n == name)
+            && !self
+                .excluded_prefixes
+                .iter()
+                .any(|p| name.as_bytes().starts_with(p.as_bytes()))
+    }
+}
+
+/// File capabilities, as stored in the `security.capability` extended attribute;
+/// see [`CapStdExtDirExt::get_filecaps`].
+///
+/// The capability sets are bitmasks in which bit N corresponds to capability number
+/// N; for example `CAP_NET_BIND_SERVICE` (10) is `1 << 10`.
+#[cfg(any(target_os = "android", target_os = "linux"))]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct FileCaps {
+    /// Capabilities which are permitted when executing the file.
+    pub permitted: u64,
+    /// Capabilities which are inherited from the executing process, if it has them
+    /// in its inheritable set.
+    pub inheritable: u64,
+    /// Whether the permitted capabilities are also made effective on execution; this
+    /// is needed for programs which are not aware of capabilities.
+    pub effective: bool,
+    /// For capabilities set within a user namespace, the user ID (in the initial
+    /// namespace) of the namespace's root user.  This is only supported by version 3
+    /// of the format.
+    pub rootid: Option<u32>,
+}
+
+#[cfg(any(target_os = "android", target_os = "linux"))]
+impl FileCaps {
+    const REVISION_MASK: u32 = 0xFF00_0000;
+    const REVISION_1: u32 = 0x0100_0000;
+    const REVISION_2: u32 = 0x0200_0000;
+    const REVISION_3: u32 = 0x0300_0000;
+    const FLAGS_EFFECTIVE: u32 = 0x0000_0001;
+
+    /// Decode the value of a `security.capability` extended attribute.  Versions 1,
+    /// 2 and 3 of the format are supported.
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Invalid file capabilities");
+        let word = |i: usize| -> Result<u32> {
+            let b = buf.get(i * 4..(i + 1) * 4).ok_or_else(invalid)?;
+            // SAFETY(unwrap): The slice has the correct length.
+            Ok(u32::from_le_bytes(b.try_into().unwrap()))
+        };
+        let magic = word(0)?;
+        let (words, len) = match magic & Self::REVISION_MASK {
+            Self::REVISION_1 => (1, 12),
+            Self::REVISION_2 => (2, 20),
+            Self::REVISION_3 => (2, 24),
+            _ => return Err(invalid()),
+        };
+        if buf.len() != len {
+            return Err(invalid());
+        }
+        let mut r = Self {
+            effective: magic & Self::FLAGS_EFFECTIVE != 0,
+            ..Default::default()
+        };
+        for i in 0..words {
+            let shift = 32 * i;
+            r.permitted |= u64::from(word(1 + 2 * i)?) << shift;
+            r.inheritable |= u64::from(word(2 + 2 * i)?) << shift;
+        }
+        if magic & Self::REVISION_MASK == Self::REVISION_3 {
+            r.rootid = Some(word(5)?);
+        }
+        Ok(r)
+    }
+
+    /// Encode as the value of a `security.capability` extended attribute.  Version 3
+    /// of the format is used if a root ID is set, and version 2 otherwise.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut magic = if self.rootid.is_some() {
+            Self::REVISION_3
+        } else {
+            Self::REVISION_2
+        };
+        if self.effective {
+            magic |= Self::FLAGS_EFFECTIVE;
+        }
+        let words = [
+            magic,
+            self.permitted as u32,
+            self.inheritable as u32,
+            (self.permitted >> 32) as u32,
+            (self.inheritable >> 32) as u32,
+        ];
+        words
+            .into_iter()
+            .chain(self.rootid)
+            .flat_map(u32::to_le_bytes)
+            .collect()
+    }
+}
+
+/// A hash algorithm for fs-verity; see [`VerityOptions`].
+#[cfg(any(target_os = "android", target_os = "linux"))]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum VerityHashAlgorithm {
+    /// SHA-256.
+    #[default]
+    Sha256,
+    /// SHA-512.
+    Sha512,
+}
+
+#[cfg(any(target_os = "android", target_os = "linux"))]
+impl VerityHashAlgorithm {
+    /// The `FS_VERITY_HASH_ALG_*` value.
+    fn raw(self) -> u32 {
+        match self {
+            Self::Sha256 => 1,
+            Self::Sha512 => 2,
+        }
+    }
+}
+
+/// Options for [`CapStdExtDirExt::fsverity_enable`].
+#[cfg(any(target_os = "android", target_os = "linux"))]
+#[derive(Debug, Clone)]
+pub struct VerityOptions {
+    algorithm: VerityHashAlgorithm,
+    block_size: u32,
+    salt: Vec<u8>,
+}
+
+#[cfg(any(target_os = "android", target_os = "linux"))]
+impl Default for VerityOptions {
+    fn default() -> Self {
+        Self {
+            algorithm: VerityHashAlgorithm::default(),
+            block_size: 4096,
+            salt: Vec::new(),
+        }
+    }
+}
+
+#[cfg(any(target_os = "android", target_os = "linux"))]
+impl VerityOptions {
+    /// Set the hash algorithm; the default is SHA-256.
+    pub fn algorithm(mut self, algorithm: VerityHashAlgorithm) -> Self {
+        self.algorithm = algorithm;
+        self
+    }
+
+    /// Set the Merkle tree block size; the default is 4096.  Not all kernels
+    /// support block sizes other than the page size.
+    pub fn block_size(mut self, block_size: u32) -> Self {
+        self.block_size = block_size;
+        self
+    }
+
+    /// Set a salt which is prepended to each block before hashing; the
+    /// default is none.
+    pub fn salt(mut self, salt: impl Into<Vec<u8>>) -> Self {
+        self.salt = salt.into();
+        self
+    }
+}
+
+/// The fs-verity digest of a file; see [`CapStdExtDirExt::fsverity_digest`].
+#[cfg(any(target_os = "android", target_os = "linux"))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerityDigest {
+    /// The hash algorithm used.
+    pub algorithm: VerityHashAlgorithm,
+    /// The digest (in binary form).
+    pub digest: Vec<u8>,
+}
+
+/// How [`link_tree`] handles an existing file in the destination.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum LinkConflict {
+    /// Return an error of kind [`std::io::ErrorKind::AlreadyExists`].
+    #[default]
+    Error,
+    /// Leave the existing file (or directory) in place.
+    Skip,
+    /// Replace the existing file.  Existing directories are never replaced;
+    /// this results in an error.
+    Replace,
+}
+
+/// Options for [`link_tree`].
+#[derive(Debug, Clone)]
+pub struct LinkOptions {
+    source: PathBuf,
+    dest: Option<PathBuf>,
+    conflict: LinkConflict,
+}
+
+impl LinkOptions {
+    /// Create absolute symbolic links, where `source` is the absolute path at
+    /// which the source tree is found (e.g. `/usr/share/foo`).
+    pub fn absolute(source: impl Into<PathBuf>) -> Self {
+        Self {
+            source: source.into(),
+            dest: None,
+            conflict: LinkConflict::default(),
+        }
+    }
+
+    /// Create relative symbolic links, where `source` and `dest` are the paths
+    /// at which the source and destination trees are found, in the same namespace
+    /// (e.g. `/usr/share/foo` and `/etc/foo`).
+    pub fn relative(source: impl Into<PathBuf>, dest: impl Into<PathBuf>) -> Self {
+        Self {
+            source: source.into(),
+            dest: Some(dest.into()),
+            conflict: LinkConflict::default(),
+        }
+    }
+
+    /// Set how existing files in the destination are handled.
+    pub fn conflict(mut self, conflict: LinkConflict) -> Self {
+        self.conflict = conflict;
+        self
+    }
+}
+
+/// The result of [`CapStdExtDirExt::resolve_symlinks`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ResolvedPath {
+    /// The final target, relative to the directory.  If resolution did not complete,
+    /// this is the last path which was inspected.
+    pub target: PathBuf,
+    /// The symbolic links which were followed, in order.
+    pub chain: Vec<PathBuf>,
+    /// Resolution would have left the directory (via `..` or an absolute symbolic link).
+    pub escaped: bool,
+    /// Resolution stopped because the maximum number of symbolic links was followed.
+    pub looped: bool,
+    /// The target (or one of its parent directories) does not exist.
+    pub dangling: bool,
+}
+
+/// A symbolic link examined by [`CapStdExtDirExt::resolve_alternatives`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct AlternativeLink {
+    /// The path of the link, as provided.
+    pub path: PathBuf,
+    /// The target of the link, or `None` if the path does not exist or is not a
+    /// symbolic link.
+    pub target: Option<PathBuf>,
+    /// The final resolved path relative to the directory, or `None` if the link
+    /// does not fully resolve to an existing file.
+    pub resolved: Option<PathBuf>,
+}
+
+/// How symbolic link targets are interpreted by [`CapStdExtDirExt::find_broken_links`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LinkResolution {
+    /// Links must resolve beneath the directory; absolute targets and targets
+    /// which leave the directory via `..` are reported as escaping.
+    #[default]
+    Beneath,
+    /// The directory is the root of a filesystem tree (e.g. an image being assembled):
+    /// absolute targets are resolved relative to it, as with [`crate::RootDir`].
+    Rooted,
+}
+
+/// The problem found with a [`BrokenLink`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum BrokenLinkKind {
+    /// The symbolic link target (or one of its parent directories) does not exist.
+    Dangling,
+    /// The symbolic link target is outside of the directory.
+    Escaped,
+    /// Resolving the symbolic link required following too many links.
+    Looped,
+    /// A regular file with multiple hard links, but no contents; this is typically
+    /// the result of an interrupted or failed deduplication or checkout.
+    EmptyHardlink,
+}
+
+/// A problematic link found by [`CapStdExtDirExt::find_broken_links`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BrokenLink {
+    /// The path of the link, relative to the directory.
+    pub path: PathBuf,
+    /// The problem which was found.
+    pub kind: BrokenLinkKind,
+}
+
+/// The form of symbolic link targets produced by [`CapStdExtDirExt::rewrite_symlinks`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymlinkStyle {
+    /// Targets relative to the directory containing the link, e.g. `../lib/foo`.
+    Relative,
+    /// Absolute targets, e.g. `/usr/lib/foo`.
+    Absolute,
+}
+
+/// A file attribute which may be reported by `statx`; see [`StatxInfo::attribute`].
+#[cfg(any(target_os = "android", target_os = "linux"))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum StatxAttribute {
+    /// The file is compressed by the filesystem.
+    Compressed,
+    /// The file cannot be modified (see `chattr +i`).
+    Immutable,
+    /// The file can only be opened in append mode.
+    Append,
+    /// The file is not a candidate for backup.
+    Nodump,
+    /// The file requires a key to be decrypted.
+    Encrypted,
+    /// The directory is an automount trigger.
+    Automount,
+    /// The path is the root of a mount.
+    MountRoot,
+    /// The file has fs-verity enabled.
+    Verity,
+    /// The file is in the DAX (CPU direct access) state.
+    Dax,
+}
+
+#[cfg(any(target_os = "android", target_os = "linux"))]
+impl StatxAttribute {
+    fn flag(self) -> u64 {
+        let v = match self {
+            Self::Compressed => libc::STATX_ATTR_COMPRESSED,
+            Self::Immutable => libc::STATX_ATTR_IMMUTABLE,
+            Self::Append => libc::STATX_ATTR_APPEND,
+            Self::Nodump => libc::STATX_ATTR_NODUMP,
+            Self::Encrypted => libc::STATX_ATTR_ENCRYPTED,
+            Self::Automount => libc::STATX_ATTR_AUTOMOUNT,
+            Self::MountRoot => libc::STATX_ATTR_MOUNT_ROOT,
+            Self::Verity => libc::STATX_ATTR_VERITY,
+            Self::Dax => libc::STATX_ATTR_DAX,
+        };
+        // SAFETY(unwrap): These are all positive constants.
+        v.try_into().unwrap()
+    }
+}
+
+/// Extended metadata returned by [`CapStdExtDirExt::statx`], including fields
+/// which are not exposed by [`cap_std::fs::Metadata`].
+#[cfg(any(target_os = "android", target_os = "linux"))]
+#[derive(Debug, Clone)]
+pub struct StatxInfo(rustix::fs::Statx);
+
+#[cfg(any(target_os = "android", target_os = "linux"))]
+impl StatxInfo {
+    fn has(&self, flag: rustix::fs::StatxFlags) -> bool {
+        rustix::fs::StatxFlags::from_bits_retain(self.0.stx_mask).contains(flag)
+    }
+
+    /// The inode number.
+    pub fn ino(&self) -> u64 {
+        self.0.stx_ino
+    }
+
+    /// The device containing the file, as `(major, minor)`.
+    pub fn dev(&self) -> (u32, u32) {
+        (self.0.stx_dev_major, self.0.stx_dev_minor)
+    }
+
+    /// The identifier of the mount containing the file (as found in
+    /// `/proc/self/mountinfo`).  Returns `None` on kernels older than 5.8.
+    pub fn mnt_id(&self) -> Option<u64> {
+        self.has(rustix::fs::StatxFlags::MNT_ID)
+            .then_some(self.0.stx_mnt_id)
+    }
+
+    /// The creation time of the file, if supported by the filesystem.
+    pub fn birth_time(&self) -> Option<std::time::SystemTime> {
+        self.has(rustix::fs::StatxFlags::BTIME).then(|| {
+            let ts = &self.0.stx_btime;
+            let nsec = std::time::Duration::from_nanos(ts.tv_nsec.into());
+            let sec = std::time::Duration::from_secs(ts.tv_sec.unsigned_abs());
+            if ts.tv_sec >= 0 {
+                std::time::UNIX_EPOCH + sec + nsec
             } else {
-                Err(e)
+                std::time::UNIX_EPOCH - sec + nsec
+            }
+        })
+    }
+
+    /// Returns `Some(true)` if the attribute is set, `Some(false)` if it is not set,
+    /// or `None` if the filesystem or kernel does not support reporting it.
+    pub fn attribute(&self, attr: StatxAttribute) -> Option<bool> {
+        let flag = attr.flag();
+        let supported = self.0.stx_attributes_mask & flag > 0;
+        supported.then_some(self.0.stx_attributes & flag > 0)
+    }
+
+    /// Access the underlying `statx` result.
+    pub fn as_raw(&self) -> &rustix::fs::Statx {
+        &self.0
+    }
+}
+
+/// Extension trait for [`cap_std::fs::Dir`].
+///
+/// [`cap_std::fs::Dir`]: https://docs.rs/cap-std/latest/cap_std/fs/struct.Dir.html
+pub trait CapStdExtDirExt {
+    /// Open a file read-only, but return `Ok(None)` if it does not exist.
+    fn open_optional(&self, path: impl AsRef<Path>) -> Result<Option<File>>;
+
+    /// Open a file read-only, wrapped in a [`std::io::BufReader`].
+    fn open_buffered(&self, path: impl AsRef<Path>) -> Result<io::BufReader<File>>;
+
+    /// Open a file read-only, wrapped in a [`std::io::BufReader`], but return
+    /// `Ok(None)` if it does not exist.
+    fn open_buffered_optional(&self, path: impl AsRef<Path>)
+        -> Result<Option<io::BufReader<File>>>;
+
+    /// Open a directory, but return `Ok(None)` if it does not exist.
+    fn open_dir_optional(&self, path: impl AsRef<Path>) -> Result<Option<Dir>>;
+
+    /// Create a special variant of [`cap_std::fs::Dir`] which uses `RESOLVE_IN_ROOT`
+    /// to support absolute symlinks.
+    #[cfg(any(target_os = "android", target_os = "linux", target_os = "freebsd"))]
+    fn open_dir_rooted_ext(&self, path: impl AsRef<Path>) -> Result<crate::RootDir>;
+
+    /// Open a file with the provided flags (e.g. `O_NOATIME`, `O_DIRECT`, `O_PATH` or
+    /// `O_NOFOLLOW`).  `O_CLOEXEC` is always added.
+    ///
+    /// Path resolution is constrained beneath this directory in the same way as
+    /// other cap-std operations.  If `O_CREAT` is specified, the file is created
+    /// with mode `0o666` (as modified by the process umask).
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn open_with_oflags(&self, path: impl AsRef<Path>, flags: rustix::fs::OFlags) -> Result<File>;
+
+    /// Open a file read-only, with path resolution performed by the kernel via `openat2`
+    /// using `RESOLVE_BENEATH | RESOLVE_NO_MAGICLINKS`.  Any attempt to escape this
+    /// directory (via `..`, absolute paths or symbolic links) results in an error.
+    ///
+    /// Unlike [`crate::RootDir`], absolute symbolic links are not resolved relative
+    /// to this directory; they are an error.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn open_beneath(&self, path: impl AsRef<Path>) -> Result<File>;
+
+    /// Like [`Self::open_beneath`], but return `Ok(None)` if the file does not exist.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn open_beneath_optional(&self, path: impl AsRef<Path>) -> Result<Option<File>>;
+
+    /// Resolve a path to its canonical form relative to this directory, following
+    /// symbolic links and processing `.` and `..` components entirely within the
+    /// directory.  The target must exist.
+    ///
+    /// Unlike [`std::fs::canonicalize`], the result is a relative path (`.` for the
+    /// directory itself), and it is an error (of kind [`std::io::ErrorKind::PermissionDenied`])
+    /// if resolution would at any point leave the directory, including via absolute
+    /// symbolic links.
+    fn canonicalize_beneath(&self, path: impl AsRef<Path>) -> Result<PathBuf>;
+
+    /// Follow symbolic links in the provided path inside this directory, reporting the
+    /// final target along with the chain of links taken.  At most `max_hops` symbolic
+    /// links are followed.
+    ///
+    /// Unlike [`Self::canonicalize_beneath`], escaping the directory, loops and dangling
+    /// links are not errors; they are reported in the result.  This is intended for
+    /// auditing trees for problematic links.
+    fn resolve_symlinks(&self, path: impl AsRef<Path>, max_hops: usize) -> Result<ResolvedPath>;
+
+    /// Read and resolve each of the provided symbolic links (such as those in
+    /// `etc/alternatives`), treating this directory as the root of the filesystem tree
+    /// (as with [`LinkResolution::Rooted`]).  Paths which are missing or are not
+    /// symbolic links are reported as such, rather than as errors.
+    fn resolve_alternatives(
+        &self,
+        paths: impl IntoIterator<Item = impl AsRef<Path>>,
+    ) -> Result<Vec<AlternativeLink>>;
+
+    /// Recursively check the tree at the provided path beneath this directory for
+    /// symbolic links which do not resolve (see [`LinkResolution`] for how targets
+    /// are interpreted), as well as empty regular files with multiple hard links.
+    ///
+    /// Mount points are not crossed.  Links are reported in the order they are found.
+    #[cfg(unix)]
+    fn find_broken_links(
+        &self,
+        path: impl AsRef<Path>,
+        resolution: LinkResolution,
+    ) -> Result<Vec<BrokenLink>>;
+
+    /// Obtain a persistent handle for the file at the given path beneath this directory,
+    /// along with the ID of its mount.  The path is resolved as with [`Self::open_beneath`].
+    ///
+    /// Not all filesystems support file handles; in that case an error of kind
+    /// [`std::io::ErrorKind::Unsupported`] is returned.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn file_handle(&self, path: impl AsRef<Path>) -> Result<FileHandle>;
+
+    /// Reopen a file from a handle previously returned by [`Self::file_handle`], using
+    /// this directory to identify the filesystem.  `O_CLOEXEC` is always added to the flags.
+    ///
+    /// Note that the kernel requires `CAP_DAC_READ_SEARCH` for this, and the
+    /// resulting file may be anywhere on the filesystem containing this directory,
+    /// not necessarily beneath it.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn open_by_handle(&self, handle: &FileHandle, flags: rustix::fs::OFlags) -> Result<File>;
+
+    /// Return the inode flags (as shown by `lsattr`) of the file or directory at the
+    /// given path, using `FS_IOC_GETFLAGS`.  The path is resolved as with
+    /// [`Self::open_beneath`].
+    ///
+    /// Flags not known to [`InodeFlags`] are preserved in the result.  If the
+    /// filesystem does not support inode flags, an error of kind
+    /// [`std::io::ErrorKind::Unsupported`] is returned.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn get_inode_flags(&self, path: impl AsRef<Path>) -> Result<InodeFlags>;
+
+    /// Replace the inode flags (as with `chattr`) of the file or directory at the given
+    /// path, using `FS_IOC_SETFLAGS`.  To change individual flags, combine the result of
+    /// [`Self::get_inode_flags`] with the desired changes.
+    ///
+    /// Setting or clearing [`InodeFlags::IMMUTABLE`] or [`InodeFlags::APPEND`] requires
+    /// `CAP_LINUX_IMMUTABLE`.  Note that some filesystems only honor [`InodeFlags::NOCOW`]
+    /// for empty files, or directories (in which case it is inherited by new files).
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn set_inode_flags(&self, path: impl AsRef<Path>, flags: InodeFlags) -> Result<()>;
+
+    /// Return the project ID (used for project quotas on e.g. XFS and ext4) of the file
+    /// or directory at the given path, using `FS_IOC_FSGETXATTR`.  The path is resolved
+    /// as with [`Self::open_beneath`].
+    ///
+    /// If the filesystem does not support this, an error of kind
+    /// [`std::io::ErrorKind::Unsupported`] is returned.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn get_project_id(&self, path: impl AsRef<Path>) -> Result<u32>;
+
+    /// Set the project ID of the file or directory at the given path, using
+    /// `FS_IOC_FSSETXATTR`.  Directories are also marked so that new entries inherit
+    /// the project ID.  If `recursive` is set, this applies to all regular files and
+    /// directories beneath the path as well, without crossing mount points.
+    ///
+    /// Changing the project ID generally requires `CAP_FOWNER` (or ownership of the file),
+    /// and may not be possible from within a user namespace.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn set_project_id(&self, path: impl AsRef<Path>, id: u32, recursive: bool) -> Result<()>;
+
+    /// Return the value of an extended attribute of the file or directory at the given
+    /// path, or `None` if it is not set.  The path is resolved as with [`Self::open_beneath`],
+    /// except that a symbolic link in the final component is not followed (as with
+    /// `lgetxattr`); this applies to the other extended attribute methods too.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn getxattr(&self, path: impl AsRef<Path>, key: impl AsRef<OsStr>) -> Result<Option<Vec<u8>>>;
+
+    /// Set an extended attribute of the file or directory at the given path.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn setxattr(
+        &self,
+        path: impl AsRef<Path>,
+        key: impl AsRef<OsStr>,
+        value: impl AsRef<[u8]>,
+    ) -> Result<()>;
+
+    /// List the names of the extended attributes of the file or directory at the given path.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn listxattrs(&self, path: impl AsRef<Path>) -> Result<Vec<std::ffi::OsString>>;
+
+    /// Return the names and values of all extended attributes of the file or directory
+    /// at the given path.  Attributes which are removed concurrently are omitted.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn xattrs(&self, path: impl AsRef<Path>) -> Result<Vec<(std::ffi::OsString, Vec<u8>)>>;
+
+    /// Copy the extended attributes of the file or directory at `src` onto `dest` (relative
+    /// to `dest_dir`), skipping any not matched by the filter.  Existing attributes
+    /// of the destination which are not present on the source are left as is.
+    ///
+    /// This is useful to preserve metadata when replacing a file with e.g.
+    /// [`Self::atomic_replace_with`], which creates a new file.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn copy_xattrs(
+        &self,
+        src: impl AsRef<Path>,
+        dest_dir: &Dir,
+        dest: impl AsRef<Path>,
+        filter: &XattrFilter,
+    ) -> Result<()>;
+
+    /// Remove an extended attribute of the file or directory at the given path, returning
+    /// `false` if it was not set.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn removexattr(&self, path: impl AsRef<Path>, key: impl AsRef<OsStr>) -> Result<bool>;
+
+    /// Return the file capabilities of the file at the given path (see `capabilities(7)`),
+    /// or `None` if it has none.  The path is resolved as with [`Self::open_beneath`].
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn get_filecaps(&self, path: impl AsRef<Path>) -> Result<Option<FileCaps>>;
+
+    /// Set the file capabilities of the file at the given path (as with `setcap`).  This
+    /// requires `CAP_SETFCAP`.  Note that changing the owner of a file, or writing to it,
+    /// clears its capabilities.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn set_filecaps(&self, path: impl AsRef<Path>, caps: &FileCaps) -> Result<()>;
+
+    /// Enable fs-verity on the regular file at the given path.  This makes the file
+    /// permanently read-only; its contents are then verified against a Merkle tree
+    /// as they are read.
+    ///
+    /// The file must not be open for writing (including by this process).  If the
+    /// filesystem does not support fs-verity, an error of kind
+    /// [`std::io::ErrorKind::Unsupported`] is returned.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn fsverity_enable(&self, path: impl AsRef<Path>, options: &VerityOptions) -> Result<()>;
+
+    /// Return the fs-verity digest of the file at the given path, or `None` if fs-verity
+    /// is not enabled on it.  If the filesystem does not support fs-verity, an error of
+    /// kind [`std::io::ErrorKind::Unsupported`] is returned.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn fsverity_digest(&self, path: impl AsRef<Path>) -> Result<Option<VerityDigest>>;
+
+    /// Return `true` if fs-verity is enabled on the file at the given path.  Symbolic
+    /// links are not followed.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn is_verity(&self, path: impl AsRef<Path>) -> Result<bool>;
+
+    /// Open a directory, but return `Ok(None)` if it is on a different device
+    /// (i.e. it is a mount point, or is reached by crossing one).
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn open_dir_noxdev(&self, path: impl AsRef<Path>) -> Result<Option<Dir>>;
+
+    /// Create the target directory, but do nothing if a directory already exists at that path.
+    /// The return value will be `true` if the directory was created.  An error will be
+    /// returned if the path is a non-directory.  Symbolic links will be followed.
+    fn ensure_dir_with(
+        &self,
+        p: impl AsRef<Path>,
+        builder: &cap_std::fs::DirBuilder,
+    ) -> Result<bool>;
+
+    /// Ensure the target directory and all of its parents exist (creating any that
+    /// are missing using the provided builder), and open it.
+    ///
+    /// Concurrent removal of any of the directories by another process is handled
+    /// by retrying a bounded number of times.  An error will be returned if any
+    /// path component is a non-directory.
+    fn open_or_create_dir_all(
+        &self,
+        path: impl AsRef<Path>,
+        builder: &cap_std::fs::DirBuilder,
+    ) -> Result<Dir>;
+
+    /// Create a new subdirectory whose name starts with the provided prefix followed by
+    /// a randomly generated suffix, and open it.  The name is returned along with the
+    /// directory.
+    ///
+    /// This is safe to use concurrently from multiple threads and processes; a name
+    /// collision is handled by retrying with a new suffix.  The prefix must not contain
+    /// a path separator.
+    fn create_unique_dir(&self, prefix: &str) -> Result<(String, Dir)>;
+
+    /// Create a temporary subdirectory as with [`Self::create_unique_dir`], which is
+    /// removed when the returned guard is dropped, unless [`NamedTempDir::keep`] is
+    /// used.  Unlike [`cap_tempfile::TempDir`], the name starts with the given
+    /// prefix, which makes leftovers recognizable e.g. for garbage collection.
+    fn tempdir_in_with_prefix(&self, prefix: &str) -> Result<NamedTempDir<'_>>;
+
+    /// Remove regular files and directories (recursively) directly in this
+    /// directory whose names start with the prefix and which were last modified
+    /// longer ago than `older_than`, returning how many were removed.  This is
+    /// intended for cleaning up temporary files left behind by writers which
+    /// crashed, such as those of [`Self::tempdir_in_with_prefix`], or the `.tmp`
+    /// files used by [`cap_tempfile::TempFile`] where `O_TMPFILE` is unavailable.
+    ///
+    /// An empty prefix is rejected with an error of kind
+    /// [`std::io::ErrorKind::InvalidInput`].  Entries removed concurrently are
+    /// ignored.
+    fn cleanup_stale_tempfiles(
+        &self,
+        prefix: &str,
+        older_than: std::time::Duration,
+    ) -> Result<usize>;
+
+    /// Create a regular file (with mode `0600`) for reading and writing, or open
+    /// it if it already exists and is safe to reuse; this is intended for
+    /// world-writable directories with the sticky bit set, such as `/tmp`.
+    ///
+    /// An existing file is only opened if it is not a symbolic link, is a regular
+    /// file owned by the effective user ID, has no other hard links, and is not
+    /// writable by the group or others.  Otherwise an error of kind
+    /// [`std::io::ErrorKind::PermissionDenied`] is returned.
+    #[cfg(unix)]
+    fn create_or_open_owned(&self, path: impl AsRef<Path>) -> Result<File>;
+
+    /// Create a directory (with mode `0700`), or open it if it already exists and is
+    /// safe to reuse; this is intended for world-writable directories with the sticky
+    /// bit set, such as `/tmp`.
+    ///
+    /// An existing directory is only opened if it is not a symbolic link, is owned by
+    /// the effective user ID, and is not writable by the group or others.  Otherwise
+    /// an error of kind [`std::io::ErrorKind::PermissionDenied`] is returned.
+    #[cfg(unix)]
+    fn ensure_owned_dir(&self, path: impl AsRef<Path>) -> Result<Dir>;
+
+    /// Return `true` if the two paths refer to the same file, i.e. they have the same
+    /// device and inode numbers.  This is the case for hardlinks and for paths which
+    /// reach the same directory via bind mounts.  Symbolic links are followed.
+    #[cfg(unix)]
+    fn is_same_file(&self, a: impl AsRef<Path>, b: impl AsRef<Path>) -> Result<bool>;
+
+    /// Return `true` if the two paths refer to the same file.  If either path is a
+    /// symbolic link, the link itself is compared.
+    #[cfg(unix)]
+    fn is_same_file_nofollow(&self, a: impl AsRef<Path>, b: impl AsRef<Path>) -> Result<bool>;
+
+    /// Read the names of all entries in this directory, along with a generation token
+    /// which can later be passed to [`Self::has_changed_since`] to cheaply check
+    /// whether the set of entries may have changed.
+    #[cfg(unix)]
+    fn entries_snapshot(&self) -> Result<EntriesSnapshot>;
+
+    /// Return `true` if the entries of this directory may have changed since the
+    /// generation was taken.  This is based on the directory modification time, so
+    /// changes to the contents of existing files are not detected.
+    ///
+    /// This may return `true` spuriously (e.g. if the directory was modified just
+    /// before the snapshot was taken, or on filesystems with coarse timestamps), but
+    /// will not miss a change to the set of entries on filesystems which update the
+    /// directory modification time.
+    #[cfg(unix)]
+    fn has_changed_since(&self, generation: &DirGeneration) -> Result<bool>;
+
+    /// Create (if necessary) and open the lock file at the given path, and take an
+    /// advisory lock on it (see [`crate::fileext::CapStdExtFileLockExt`]), blocking
+    /// until it is available.
+    ///
+    /// When the returned [`crate::fileext::LockFile`] holds an exclusive lock, the file
+    /// is removed when it is dropped, so stale lock files do not accumulate; a file
+    /// left behind by shared lock holders is removed by the next exclusive holder.
+    /// Lock files removed concurrently by a previous holder are handled transparently.
+    #[cfg(not(windows))]
+    fn lockfile(
+        &self,
+        path: impl AsRef<Path>,
+        mode: crate::fileext::LockMode,
+    ) -> Result<crate::fileext::LockFile>;
+
+    /// Like [`Self::lockfile`], but return `Ok(None)` if a conflicting lock is held.
+    #[cfg(not(windows))]
+    fn try_lockfile(
+        &self,
+        path: impl AsRef<Path>,
+        mode: crate::fileext::LockMode,
+    ) -> Result<Option<crate::fileext::LockFile>>;
+
+    /// Like [`Self::lockfile`], but return `Ok(None)` if the lock is not available
+    /// within the timeout.  See [`crate::fileext::CapStdExtFileLockExt::lock_exclusive_timeout`].
+    #[cfg(not(windows))]
+    fn lockfile_timeout(
+        &self,
+        path: impl AsRef<Path>,
+        mode: crate::fileext::LockMode,
+        timeout: std::time::Duration,
+    ) -> Result<Option<crate::fileext::LockFile>>;
+
+    /// Gather metadata, but return `Ok(None)` if it does not exist.
+    fn metadata_optional(&self, path: impl AsRef<Path>) -> Result<Option<Metadata>>;
+
+    /// Gather metadata (but do not follow symlinks), but return `Ok(None)` if it does not exist.
+    fn symlink_metadata_optional(&self, path: impl AsRef<Path>) -> Result<Option<Metadata>>;
+
+    /// Remove (delete) a file, but return `Ok(false)` if the file does not exist.
+    fn remove_file_optional(&self, path: impl AsRef<Path>) -> Result<bool>;
+
+    /// Remove a file or directory but return `Ok(false)` if the file does not exist.
+    /// Symbolic links are not followed.
+    fn remove_all_optional(&self, path: impl AsRef<Path>) -> Result<bool>;
+
+    /// Search for an executable program in the provided directories (e.g. `["usr/bin", "bin"]`),
+    /// in the same way as a shell would using `PATH`.  The ambient `PATH` environment
+    /// variable is never consulted.
+    ///
+    /// The search directories are relative to this directory, and as with all other
+    /// operations on [`Dir`], symbolic links may not escape it (this is an error);
+    /// to search a filesystem image containing absolute symbolic links, use
+    /// [`crate::RootDir::find_executable`] instead.
+    ///
+    /// If `name` contains a `/`, then it is checked directly instead of searching.
+    /// A candidate is only accepted if it is a regular file with an execute bit set,
+    /// and it starts with an ELF header or a `#!` interpreter line.
+    ///
+    /// Returns the path of the first match, or `Ok(None)` if there is none.
+    #[cfg(unix)]
+    fn find_executable(
+        &self,
+        name: impl AsRef<Path>,
+        search_paths: impl IntoIterator<Item = impl AsRef<Path>>,
+    ) -> Result<Option<PathBuf>>;
+
+    /// Set the access and modification times to the current time.  Symbolic links are not followed.
+    #[cfg(unix)]
+    fn update_timestamps(&self, path: impl AsRef<Path>) -> Result<()>;
+
+    /// Atomically write a file by calling the provided closure.
+    ///
+    /// This uses [`cap_tempfile::TempFile`], which is wrapped in a [`std::io::BufWriter`]
+    /// and passed to the closure.
+    ///
+    /// # Existing files and metadata
+    ///
+    /// If the target path already exists and is a regular file (not a symbolic link or directory),
+    /// then its access permissions (Unix mode) will be preserved.  However, other metadata
+    /// such as extended attributes will *not* be preserved automatically.  To do this will
+    /// require a higher level wrapper which queries the existing file and gathers such metadata
+    /// before replacement.
+    ///
+    /// # Example, including setting permissions
+    ///
+    /// The closure may also perform other file operations beyond writing, such as changing
+    /// file permissions:
+    ///
+    /// ```rust
+    /// # use std::io;
+    /// # use std::io::Write;
+    /// # use cap_tempfile::cap_std;
+    /// # fn main() -> io::Result<()> {
+    /// # let somedir = cap_tempfile::tempdir(cap_std::ambient_authority())?;
+    /// use cap_std_ext::prelude::*;
+    /// let contents = b"hello world\n";
+    /// somedir.atomic_replace_with("somefilename", |f| -> io::Result<_> {
+    ///     f.write_all(contents)?;
+    ///     f.flush()?;
+    ///     use cap_std::fs::PermissionsExt;
+    ///     let perms = cap_std::fs::Permissions::from_mode(0o600);
+    ///     f.get_mut().as_file_mut().set_permissions(perms)?;
+    ///     Ok(())
+    /// })
+    /// # }
+    /// ```
+    ///
+    /// Any existing file will be replaced.
+    fn atomic_replace_with<F, T, E>(
+        &self,
+        destname: impl AsRef<Path>,
+        f: F,
+    ) -> std::result::Result<T, E>
+    where
+        F: FnOnce(&mut std::io::BufWriter<cap_tempfile::TempFile>) -> std::result::Result<T, E>,
+        E: From<std::io::Error>;
+
+    /// Atomically write a file by calling the provided closure, as with
+    /// [`Self::atomic_replace_with`], using the provided options.
+    fn atomic_replace_with_options<F, T, E>(
+        &self,
+        destname: impl AsRef<Path>,
+        options: &AtomicWriteOptions,
+        f: F,
+    ) -> std::result::Result<T, E>
+    where
+        F: FnOnce(&mut std::io::BufWriter<cap_tempfile::TempFile>) -> std::result::Result<T, E>,
+        E: From<std::io::Error>;
+
+    /// Atomically write the provided contents to a file.
+    fn atomic_write(&self, destname: impl AsRef<Path>, contents: impl AsRef<[u8]>) -> Result<()>;
+
+    /// Atomically write the provided contents to a file, using specified permissions.
+    fn atomic_write_with_perms(
+        &self,
+        destname: impl AsRef<Path>,
+        contents: impl AsRef<[u8]>,
+        perms: cap_std::fs::Permissions,
+    ) -> Result<()>;
+
+    /// Atomically write the concatenation of the provided buffers to a file, using
+    /// vectored writes rather than first copying them into a single buffer.
+    fn atomic_write_vectored(&self, destname: impl AsRef<Path>, bufs: &[io::IoSlice])
+        -> Result<()>;
+
+    /// Atomically write the contents of a reader to a file, returning the number of
+    /// bytes written.  If provided, the expected size is used to preallocate space
+    /// (on Linux), which reduces fragmentation for large files; it is not an error
+    /// if it is inaccurate.
+    fn atomic_write_from_reader(
+        &self,
+        destname: impl AsRef<Path>,
+        reader: impl io::Read,
+        size_hint: Option<u64>,
+    ) -> Result<u64>;
+
+    /// Read the current contents of a file (or `None` if it does not exist), and
+    /// pass them to the provided function; if it returns new contents, they are
+    /// atomically written back, as with [`Self::atomic_write`].  The mode of an
+    /// existing file is preserved.  Returns `true` if the file was written.
+    ///
+    /// Note that concurrent modifications between the read and the write are not
+    /// detected; the last writer wins.
+    fn edit_file<F>(&self, path: impl AsRef<Path>, f: F) -> Result<bool>
+    where
+        F: FnOnce(Option<Vec<u8>>) -> Result<Option<Vec<u8>>>;
+
+    /// Read and parse a JSON file, returning `None` if it does not exist.  A parse
+    /// error is returned as an error of kind [`std::io::ErrorKind::InvalidData`].
+    #[cfg(feature = "serde")]
+    fn read_json_optional<T: serde::de::DeserializeOwned>(
+        &self,
+        path: impl AsRef<Path>,
+    ) -> Result<Option<T>>;
+
+    /// Read and parse a TOML file, returning `None` if it does not exist.  A parse
+    /// error is returned as an error of kind [`std::io::ErrorKind::InvalidData`].
+    #[cfg(feature = "serde")]
+    fn read_toml_optional<T: serde::de::DeserializeOwned>(
+        &self,
+        path: impl AsRef<Path>,
+    ) -> Result<Option<T>>;
+
+    /// Atomically write a value serialized as JSON to a file, followed by a newline.
+    /// If `pretty` is set, the output is indented.
+    #[cfg(feature = "serde")]
+    fn atomic_write_json<T: serde::Serialize + ?Sized>(
+        &self,
+        path: impl AsRef<Path>,
+        value: &T,
+        pretty: bool,
+    ) -> Result<()>;
+
+    /// Write the provided contents to a file (creating it if necessary, and truncating
+    /// it otherwise), but fail with `ELOOP` if the final path component is a symbolic
+    /// link.  Other components are resolved as with [`Self::open_beneath`].
+    ///
+    /// This avoids being redirected to an unexpected file by a symbolic link planted
+    /// in a directory writable by another user.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn write_nofollow(&self, path: impl AsRef<Path>, contents: impl AsRef<[u8]>) -> Result<()>;
+
+    /// Like [`Self::atomic_write`], but fail with `ELOOP` if the destination
+    /// is an existing symbolic link rather than replacing it.
+    fn atomic_write_nofollow(
+        &self,
+        destname: impl AsRef<Path>,
+        contents: impl AsRef<[u8]>,
+    ) -> Result<()>;
+
+    /// Atomically create or replace a directory tree: the provided closure populates
+    /// a new, empty staging directory (created alongside the destination), which then
+    /// replaces the destination in a single step using `renameat2(RENAME_EXCHANGE)`.
+    /// The previous contents (if any) are then removed.
+    ///
+    /// If the closure returns an error, the staging directory is removed and the
+    /// destination is left unchanged; so readers see either the complete old tree,
+    /// or the complete new one.  It is an error (`ENOTDIR`) if the destination
+    /// exists but is not a directory (including a symbolic link to one).
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn atomic_replace_dir_with<F, T, E>(
+        &self,
+        destname: impl AsRef<Path>,
+        f: F,
+    ) -> std::result::Result<T, E>
+    where
+        F: FnOnce(&Dir) -> std::result::Result<T, E>,
+        E: From<std::io::Error>;
+
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    /// Returns `Some(true)` if the target is known to be a mountpoint, or
+    /// `Some(false)` if the target is definitively known not to be a mountpoint.
+    ///
+    /// In some scenarios (such as an older kernel) this currently may not be possible
+    /// to determine, and `None` will be returned in those cases.
+    fn is_mountpoint(&self, path: impl AsRef<Path>) -> Result<Option<bool>>;
+
+    /// Gather extended metadata via `statx`, including the mount ID, birth time
+    /// and file attributes.  The flags may include e.g. [`rustix::fs::AtFlags::SYMLINK_NOFOLLOW`].
+    ///
+    /// The path is resolved beneath this directory; it is an error if it (or a
+    /// symbolic link in it) would lead outside of it.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn statx(&self, path: impl AsRef<Path>, flags: rustix::fs::AtFlags) -> Result<StatxInfo>;
+
+    /// Rewrite the interpreter in the `#!` line of scripts beneath the given directory,
+    /// according to the provided mapping from old to new interpreter paths.  Any
+    /// arguments following the interpreter are preserved.  This is useful when
+    /// relocating a tree which was built for a different root.
+    ///
+    /// Each file is replaced atomically, and its permission bits are preserved (but not
+    /// its ownership or extended attributes).  Symbolic links are not followed, and
+    /// only regular files are considered.
+    ///
+    /// Returns the paths of the rewritten files.
+    #[cfg(unix)]
+    fn rewrite_shebangs(
+        &self,
+        path: impl AsRef<Path>,
+        mapping: &std::collections::HashMap<PathBuf, PathBuf>,
+    ) -> Result<Vec<PathBuf>>;
+
+    /// Rewrite symbolic links beneath the given path so that their targets have the
+    /// provided style, treating this directory as the root of the filesystem tree (so
+    /// that e.g. `/usr/lib/foo` refers to `usr/lib/foo` in this directory).  This is useful
+    /// when a tree moves between being used via `chroot` and being the real root.
+    ///
+    /// Targets which contain `..` after another component (e.g. `/usr/lib/../foo`) are
+    /// left unchanged, as resolving them depends on whether the preceding components are
+    /// themselves symbolic links.  Each link is replaced atomically.
+    ///
+    /// Returns the paths of the rewritten links.
+    #[cfg(unix)]
+    fn rewrite_symlinks(&self, path: impl AsRef<Path>, style: SymlinkStyle)
+        -> Result<Vec<PathBuf>>;
+
+    /// Watch this directory (and optionally its subdirectories) for changes using
+    /// `inotify`.  Events are reported with paths relative to this directory.
+    #[cfg(all(feature = "watch", any(target_os = "android", target_os = "linux")))]
+    fn watch(&self, config: &crate::watch::WatchConfig) -> Result<crate::watch::Watcher>;
+
+    /// Watch the mount (or filesystem) containing this directory using `fanotify`,
+    /// reporting events with paths relative to this directory.  This requires
+    /// `CAP_SYS_ADMIN`.
+    #[cfg(all(feature = "fanotify", any(target_os = "android", target_os = "linux")))]
+    fn fanotify(
+        &self,
+        config: &crate::fanotify::FanotifyConfig,
+    ) -> Result<crate::fanotify::FanotifyWatcher>;
+
+    /// Initiate readahead (see `readahead(2)`) of the given byte range of a file,
+    /// populating the page cache without reading the data through userspace.
+    ///
+    /// This is intended for warming files that are known to be needed soon,
+    /// e.g. early in the boot process.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn readahead(&self, path: impl AsRef<Path>, offset: u64, len: usize) -> Result<()>;
+
+    /// Read the full contents of a file, like [`Dir::read`], but allocate the buffer
+    /// up front based on the size of the file (and on Unix, fill it with positioned
+    /// reads).  This avoids repeatedly growing the buffer, and is the recommended way
+    /// to read large files.
+    ///
+    /// If the file changes size while being read, the contents actually read are
+    /// returned.
+    fn read_exact_to_vec(&self, path: impl AsRef<Path>) -> Result<Vec<u8>>;
+
+    /// Open a file and create a read-only memory mapping of its full contents.
+    ///
+    /// # Safety
+    ///
+    /// The file must not be modified or truncated while it is mapped; see
+    /// [`crate::mmap::Mmap::map`].
+    #[cfg(all(feature = "mmap", not(windows)))]
+    #[allow(unsafe_code)]
+    unsafe fn map_readonly(&self, path: impl AsRef<Path>) -> Result<crate::mmap::Mmap>;
+
+    /// Compute the digest of the contents of a file.
+    #[cfg(feature = "digest")]
+    fn file_digest(
+        &self,
+        path: impl AsRef<Path>,
+        algorithm: crate::digest::Algorithm,
+    ) -> Result<crate::digest::Digest>;
+
+    /// Walk the directory, computing the digest of each regular file; the result is
+    /// keyed by relative path.  Symbolic links are not followed, and other types of
+    /// file are skipped.
+    #[cfg(feature = "digest")]
+    fn tree_digests(
+        &self,
+        algorithm: crate::digest::Algorithm,
+    ) -> Result<std::collections::BTreeMap<PathBuf, crate::digest::Digest>>;
+
+    /// Begin a transaction, staging changes to this directory and applying them
+    /// together; see [`crate::transaction::DirTransaction`].  This creates a
+    /// temporary directory beneath this one.
+    #[cfg(not(windows))]
+    fn transaction(&self) -> Result<crate::transaction::DirTransaction<'_>>;
+
+    /// Start building a query for entries beneath this directory matching some
+    /// criteria, similar to `find(1)`; see [`crate::query::Query`].
+    #[cfg(not(windows))]
+    fn find(&self) -> crate::query::Query<'_>;
+
+    /// Iterate over the immediate subdirectories of this directory, opening each
+    /// one.  Symbolic links (including links to directories) are skipped, as are
+    /// directories removed while iterating.
+    fn subdirs(&self) -> Result<Subdirs>;
+
+    /// Read all entries of this directory, returning them sorted by file name (on
+    /// Unix, by the raw bytes of the name).  Unlike [`Dir::entries`], the order does
+    /// not depend on the filesystem.
+    fn entries_sorted(&self) -> Result<std::vec::IntoIter<DirEntry>>;
+
+    /// Read all entries of this directory, returning them sorted using the provided
+    /// comparison function.
+    fn entries_sorted_by<F>(&self, cmp: F) -> Result<std::vec::IntoIter<DirEntry>>
+    where
+        F: FnMut(&DirEntry, &DirEntry) -> Ordering;
+
+    /// Recursively walk this directory, invoking the callback for each entry.
+    ///
+    /// If the callback returns [`ControlFlow::Break`] while inspecting a directory,
+    /// traversal of that directory is skipped. If [`ControlFlow::Break`] is returned
+    /// when inspecting a non-directory, then all further entries in the containing
+    /// directory are skipped.  See [`WalkConfiguration::contents_first`] for how this
+    /// differs when directories are visited after their contents.
+    ///
+    /// Symbolic links are not followed, unless [`WalkConfiguration::follow_symlinks`] is set.
+    fn walk<C, E>(&self, config: &WalkConfiguration, callback: C) -> std::result::Result<(), E>
+    where
+        C: FnMut(&WalkComponent) -> WalkResult<E>,
+        E: From<std::io::Error>;
+
+    /// Like [`Self::walk`], but process subdirectories concurrently on a pool of
+    /// threads (one per available CPU).  The callback is therefore invoked in no
+    /// particular order, possibly from multiple threads at once.
+    ///
+    /// Sorting and [`WalkConfiguration::contents_first`] are not supported,
+    /// and result in an error of kind [`std::io::ErrorKind::InvalidInput`].
+    ///
+    /// After the first error, no further entries are processed.  If multiple
+    /// errors occurred concurrently, the one for the lowest path is returned.
+    fn walk_parallel<C, E>(
+        &self,
+        config: &WalkConfiguration,
+        callback: C,
+    ) -> std::result::Result<(), E>
+    where
+        C: Fn(&WalkComponent) -> WalkResult<E> + Sync,
+        E: From<std::io::Error> + Send;
+}
+
+#[cfg(feature = "fs_utf8")]
+/// Extension trait for [`cap_std::fs_utf8::Dir`].
+///
+/// [`cap_std::fs_utf8::Dir`]: https://docs.rs/cap-std/latest/cap_std/fs_utf8/struct.Dir.html
+pub trait CapStdExtDirExtUtf8 {
+    /// Open a file read-only, but return `Ok(None)` if it does not exist.
+    fn open_optional(&self, path: impl AsRef<Utf8Path>) -> Result<Option<fs_utf8::File>>;
+
+    /// Open a directory, but return `Ok(None)` if it does not exist.
+    fn open_dir_optional(&self, path: impl AsRef<Utf8Path>) -> Result<Option<fs_utf8::Dir>>;
+
+    /// Create the target directory, but do nothing if a directory already exists at that path.
+    /// The return value will be `true` if the directory was created.  An error will be
+    /// returned if the path is a non-directory.  Symbolic links will be followed.
+    fn ensure_dir_with(
+        &self,
+        p: impl AsRef<Utf8Path>,
+        builder: &cap_std::fs::DirBuilder,
+    ) -> Result<bool>;
+
+    /// Gather metadata, but return `Ok(None)` if it does not exist.
+    fn metadata_optional(&self, path: impl AsRef<Utf8Path>) -> Result<Option<Metadata>>;
+
+    /// Gather metadata (but do not follow symlinks), but return `Ok(None)` if it does not exist.
+    fn symlink_metadata_optional(&self, path: impl AsRef<Utf8Path>) -> Result<Option<Metadata>>;
+
+    /// Remove (delete) a file, but return `Ok(false)` if the file does not exist.
+    fn remove_file_optional(&self, path: impl AsRef<Utf8Path>) -> Result<bool>;
+
+    /// Remove a file or directory but return `Ok(false)` if the file does not exist.
+    /// Symbolic links are not followed.
+    fn remove_all_optional(&self, path: impl AsRef<Utf8Path>) -> Result<bool>;
+
+    /// Set the access and modification times to the current time.  Symbolic links are not followed.
+    #[cfg(unix)]
+    fn update_timestamps(&self, path: impl AsRef<Utf8Path>) -> Result<()>;
+
+    /// Atomically write a file by calling the provided closure.
+    ///
+    /// This uses [`cap_tempfile::TempFile`], which is wrapped in a [`std::io::BufWriter`]
+    /// and passed to the closure.
+    ///
+    /// # Existing files and metadata
+    ///
+    /// If the target path already exists and is a regular file (not a symbolic link or directory),
+    /// then its access permissions (Unix mode) will be preserved.  However, other metadata
+    /// such as extended attributes will *not* be preserved automatically.  To do this will
+    /// require a higher level wrapper which queries the existing file and gathers such metadata
+    /// before replacement.
+    ///
+    /// # Example, including setting permissions
+    ///
+    /// The closure may also perform other file operations beyond writing, such as changing
+    /// file permissions:
+    ///
+    /// ```rust
+    /// # use std::io;
+    /// # use std::io::Write;
+    /// # use cap_tempfile::cap_std;
+    /// # fn main() -> io::Result<()> {
+    /// # let somedir = cap_tempfile::tempdir(cap_std::ambient_authority())?;
+    /// # let somedir = cap_std::fs_utf8::Dir::from_cap_std((&*somedir).try_clone()?);
+    /// use cap_std_ext::prelude::*;
+    /// let contents = b"hello world\n";
+    /// somedir.atomic_replace_with("somefilename", |f| -> io::Result<_> {
+    ///     f.write_all(contents)?;
+    ///     f.flush()?;
+    ///     use cap_std::fs::PermissionsExt;
+    ///     let perms = cap_std::fs::Permissions::from_mode(0o600);
+    ///     f.get_mut().as_file_mut().set_permissions(perms)?;
+    ///     Ok(())
+    /// })
+    /// # }
+    /// ```
+    ///
+    /// Any existing file will be replaced.
+    fn atomic_replace_with<F, T, E>(
+        &self,
+        destname: impl AsRef<Utf8Path>,
+        f: F,
+    ) -> std::result::Result<T, E>
+    where
+        F: FnOnce(&mut std::io::BufWriter<cap_tempfile::TempFile>) -> std::result::Result<T, E>,
+        E: From<std::io::Error>;
+
+    /// Atomically write the provided contents to a file.
+    fn atomic_write(
+        &self,
+        destname: impl AsRef<Utf8Path>,
+        contents: impl AsRef<[u8]>,
+    ) -> Result<()>;
+
+    /// Atomically write the provided contents to a file, using specified permissions.
+    fn atomic_write_with_perms(
+        &self,
+        destname: impl AsRef<Utf8Path>,
+        contents: impl AsRef<[u8]>,
+        perms: cap_std::fs::Permissions,
+    ) -> Result<()>;
+
+    /// Read all filenames in this directory, sorted
+    fn filenames_sorted(&self) -> Result<Vec<String>> {
+        self.filenames_sorted_by(|a, b| a.cmp(b))
+    }
+
+    /// Read all filenames in this directory, sorted by the provided comparison function.
+    fn filenames_sorted_by<C>(&self, compare: C) -> Result<Vec<String>>
+    where
+        C: FnMut(&str, &str) -> std::cmp::Ordering,
+    {
+        self.filenames_filtered_sorted_by(|_, _| true, compare)
+    }
+
+    /// Read all filenames in this directory, applying a filter and sorting the result.
+    fn filenames_filtered_sorted<F>(&self, f: F) -> Result<Vec<String>>
+    where
+        F: FnMut(&fs_utf8::DirEntry, &str) -> bool,
+    {
+        self.filenames_filtered_sorted_by(f, |a, b| a.cmp(b))
+    }
+
+    /// Read all filenames in this directory, applying a filter and sorting the result with a custom comparison function.
+    fn filenames_filtered_sorted_by<F, C>(&self, f: F, compare: C) -> Result<Vec<String>>
+    where
+        F: FnMut(&fs_utf8::DirEntry, &str) -> bool,
+        C: FnMut(&str, &str) -> std::cmp::Ordering;
+}
+
+pub(crate) fn map_optional<R>(r: Result<R>) -> Result<Option<R>> {
+    match r {
+        Ok(v) => Ok(Some(v)),
+        Err(e) => {
+            if e.kind() == std::io::ErrorKind::NotFound {
+                Ok(None)
+            } else {
+                Err(e)
+            }
+        }
+    }
+}
+
+/// Return `true` if the file is a regular file with an execute bit set, which
+/// starts with an ELF or interpreter (`#!`) header.
+#[cfg(unix)]
+fn is_executable(mut f: std::fs::File) -> Result<bool> {
+    use std::io::Read;
+    use std::os::unix::fs::PermissionsExt;
+    let meta = f.metadata()?;
+    if !meta.is_file() || meta.permissions().mode() & 0o111 == 0 {
+        return Ok(false);
+    }
+    let mut header = [0u8; 4];
+    let mut n = 0;
+    while n < header.len() {
+        match f.read(&mut header[n..])? {
+            0 => break,
+            v => n += v,
+        }
+    }
+    let header = &header[..n];
+    Ok(header.starts_with(b"\x7fELF") || header.starts_with(b"#!"))
+}
+
+/// Search for an executable `name` in `search_paths` (or check `name` directly if it
+/// has more than one component), opening each candidate with `open`.
+#[cfg(unix)]
+pub(crate) fn find_executable_with(
+    name: &Path,
+    search_paths: impl IntoIterator<Item = impl AsRef<Path>>,
+    open: impl Fn(&Path) -> Result<std::fs::File>,
+) -> Result<Option<PathBuf>> {
+    let candidates: Vec<PathBuf> = if name.components().count() > 1 {
+        vec![name.to_owned()]
+    } else {
+        search_paths
+            .into_iter()
+            .map(|p| p.as_ref().join(name))
+            .collect()
+    };
+    for candidate in candidates {
+        let Some(f) = map_optional(open(&candidate))? else {
+            continue;
+        };
+        if is_executable(f)? {
+            return Ok(Some(candidate));
+        }
+    }
+    Ok(None)
+}
+
+enum DirOwnedOrBorrowed<'d> {
+    Owned(Dir),
+    Borrowed(&'d Dir),
+}
+
+impl<'d> Deref for DirOwnedOrBorrowed<'d> {
+    type Target = Dir;
+
+    fn deref(&self) -> &Self::Target {
+        match self {
+            Self::Owned(d) => d,
+            Self::Borrowed(d) => d,
+        }
+    }
+}
+
+/// Given a directory reference and a path, if the path includes a subdirectory (e.g. on Unix has a `/`)
+/// then open up the target directory, and return the file name.
+///
+/// Otherwise, reborrow the directory and return the file name.
+///
+/// It is an error if the target path does not name a file.
+fn subdir_of<'d, 'p>(d: &'d Dir, p: &'p Path) -> io::Result<(DirOwnedOrBorrowed<'d>, &'p OsStr)> {
+    let name = p
+        .file_name()
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "Not a file name"))?;
+    let r = if let Some(subdir) = p
+        .parent()
+        .filter(|v| !v.as_os_str().is_empty())
+        .map(|p| d.open_dir(p))
+    {
+        DirOwnedOrBorrowed::Owned(subdir?)
+    } else {
+        DirOwnedOrBorrowed::Borrowed(d)
+    };
+    Ok((r, name))
+}
+
+/// An entry read from a directory during a walk.
+struct WalkEntry {
+    name: std::ffi::OsString,
+    file_type: FileType,
+}
+
+/// The entries of a directory remaining to be walked.
+enum WalkEntries {
+    /// Entries are read as needed; this holds the directory open.
+    Lazy(cap_std::fs::ReadDir),
+    Buffered(std::vec::IntoIter<WalkEntry>),
+}
+
+impl WalkEntries {
+    /// Read the entries of a directory, applying sorting from the configuration.
+    /// If the configuration does not require sorting or buffering, the entries
+    /// are read lazily.
+    fn new(d: &Dir, config: &WalkConfiguration) -> Result<Self> {
+        let entries = d.entries()?;
+        if config.sorter.is_none() && !config.buffered {
+            return Ok(Self::Lazy(entries));
+        }
+        let mut entries = entries.collect::<Result<Vec<_>>>()?;
+        if let Some(sorter) = config.sorter.as_ref() {
+            entries.sort_by(sorter);
+        }
+        let entries = entries
+            .into_iter()
+            .map(|e| -> Result<_> {
+                Ok(WalkEntry {
+                    name: e.file_name(),
+                    file_type: e.file_type()?,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self::Buffered(entries.into_iter()))
+    }
+
+    fn empty() -> Self {
+        Self::Buffered(Vec::new().into_iter())
+    }
+
+    /// Read all remaining entries, so that the directory can be closed.
+    fn buffer(&mut self) -> Result<()> {
+        if let Self::Lazy(_) = self {
+            let entries = std::iter::from_fn(|| self.next()).collect::<Result<Vec<_>>>()?;
+            *self = Self::Buffered(entries.into_iter());
+        }
+        Ok(())
+    }
+
+    fn next(&mut self) -> Option<Result<WalkEntry>> {
+        match self {
+            Self::Lazy(entries) => {
+                let entry = entries.next()?;
+                Some(entry.and_then(|e| {
+                    Ok(WalkEntry {
+                        name: e.file_name(),
+                        file_type: e.file_type()?,
+                    })
+                }))
+            }
+            Self::Buffered(entries) => entries.next().map(Ok),
+        }
+    }
+}
+
+/// The component passed to the callback for the root of a walk; see
+/// [`WalkConfiguration::include_root`].
+fn root_component<'p, 'w>(
+    d: &'w Dir,
+    path: &'p Path,
+    meta: &'w Metadata,
+    config: &WalkConfiguration,
+) -> WalkComponent<'p, 'w> {
+    WalkComponent {
+        path,
+        dir: d,
+        filename: OsStr::new("."),
+        file_type: meta.file_type(),
+        metadata: config.metadata.then_some(meta),
+        first_seen: true,
+        first_path: None,
+    }
+}
+
+/// The device and inode numbers of a directory.
+#[cfg(unix)]
+pub(crate) fn dir_id(d: &Dir) -> Result<(u64, u64)> {
+    use cap_std::fs::MetadataExt;
+    let meta = d.dir_metadata()?;
+    Ok((meta.dev(), meta.ino()))
+}
+
+/// The first path seen for each file with multiple hard links, by device and inode number.
+type HardlinkMap = std::collections::HashMap<(u64, u64), PathBuf>;
+
+/// If the file has multiple links and was already seen, return the first path at
+/// which it was seen; otherwise record it.
+#[cfg(unix)]
+fn hardlink_first_path(seen: &mut HardlinkMap, meta: &Metadata, path: &Path) -> Option<PathBuf> {
+    use cap_std::fs::MetadataExt;
+    use std::collections::hash_map::Entry;
+    if meta.is_dir() || meta.nlink() < 2 {
+        return None;
+    }
+    match seen.entry((meta.dev(), meta.ino())) {
+        Entry::Occupied(e) => Some(e.get().clone()),
+        Entry::Vacant(e) => {
+            e.insert(path.to_owned());
+            None
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn hardlink_first_path(_: &mut HardlinkMap, _: &Metadata, _: &Path) -> Option<PathBuf> {
+    None
+}
+
+/// A directory being walked.
+enum WalkFrameDir {
+    /// The directory passed to [`CapStdExtDirExt::walk`], which is borrowed.
+    Root,
+    Open(Dir),
+    /// Closed to stay within [`WalkConfiguration::max_open_dirs`]; it is
+    /// reopened when the walk returns to it.
+    Closed,
+}
+
+/// The state of a directory in the walk stack.
+struct WalkFrame {
+    dir: WalkFrameDir,
+    /// The device and inode numbers of the directory, if known; used to detect
+    /// cycles, and to verify the directory when reopening it.
+    id: Option<(u64, u64)>,
+    entries: WalkEntries,
+    /// With [`WalkConfiguration::contents_first`], the entry for the directory
+    /// itself, passed to the callback after its contents.
+    pending: Option<WalkPendingDir>,
+    /// Whether this directory is on the path of [`WalkConfiguration::resume_after`],
+    /// and entries preceding it should be skipped.
+    on_cursor: bool,
+}
+
+/// A directory entry whose callback is deferred until after its contents.
+struct WalkPendingDir {
+    name: std::ffi::OsString,
+    file_type: FileType,
+    metadata: Option<Metadata>,
+}
+
+impl WalkFrame {
+    fn open(d: Dir, id: Option<(u64, u64)>, config: &WalkConfiguration) -> Result<Self> {
+        Ok(Self {
+            entries: WalkEntries::new(&d, config)?,
+            dir: WalkFrameDir::Open(d),
+            id,
+            pending: None,
+            on_cursor: false,
+        })
+    }
+
+    fn dir<'a>(&'a self, root: &'a Dir) -> &'a Dir {
+        match &self.dir {
+            WalkFrameDir::Root => root,
+            WalkFrameDir::Open(d) => d,
+            // The directory at the top of the stack is always reopened first.
+            WalkFrameDir::Closed => unreachable!("Walking a closed directory"),
+        }
+    }
+}
+
+/// Open a directory by its path relative to the root of a walk, following symbolic
+/// links; if `in_root` is set, they are resolved as if the walk root were `/`.
+fn walk_open_path(root: &Dir, relpath: &Path, in_root: bool) -> Result<Dir> {
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    if in_root {
+        use rustix::fd::AsFd;
+        let d = crate::rootdir::open_beneath_with(
+            &root.as_fd(),
+            relpath,
+            rustix::fs::OFlags::DIRECTORY,
+        )?;
+        return Ok(Dir::from_std_file(d));
+    }
+    let _ = in_root;
+    root.open_dir(relpath)
+}
+
+/// Get the metadata of a path relative to the root of a walk, following symbolic
+/// links as with [`walk_open_path`].
+fn walk_follow_metadata(root: &Dir, relpath: &Path, in_root: bool) -> Result<Metadata> {
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    if in_root {
+        use rustix::fd::AsFd;
+        // With O_PATH, no permission on the file itself is required.
+        let f =
+            crate::rootdir::open_beneath_with(&root.as_fd(), relpath, rustix::fs::OFlags::PATH)?;
+        return Metadata::from_file(&f);
+    }
+    let _ = in_root;
+    root.metadata(relpath)
+}
+
+/// Open a subdirectory to walk; returns `None` if it should not be descended into,
+/// because it is on another device (with [`WalkConfiguration::noxdev`]), or it is
+/// already being walked.
+fn walk_open_subdir(
+    root: &Dir,
+    frames: &[WalkFrame],
+    name: &OsStr,
+    path: &Path,
+    config: &WalkConfiguration,
+    in_root: bool,
+) -> Result<Option<WalkFrame>> {
+    // SAFETY(unwrap): The root is always present.
+    let parent = frames.last().unwrap();
+    #[cfg(unix)]
+    if config.follow_symlinks {
+        // Links are resolved relative to the root rather than the containing
+        // directory, so that they may refer to e.g. a parent directory.  This may
+        // be a symbolic link, so we can't use `open_dir_noxdev`.
+        let subdir = walk_open_path(root, config.relative(path), in_root)?;
+        let id = dir_id(&subdir)?;
+        // SAFETY(unwrap): Directories' IDs are always recorded when following links.
+        let parent_dev = parent.id.unwrap().0;
+        if (config.noxdev && id.0 != parent_dev) || frames.iter().any(|f| f.id == Some(id)) {
+            return Ok(None);
+        }
+        return WalkFrame::open(subdir, Some(id), config).map(Some);
+    }
+    let _ = (path, in_root);
+    let d = parent.dir(root);
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    if config.noxdev {
+        return d
+            .open_dir_noxdev(name)?
+            .map(|d| WalkFrame::open(d, None, config))
+            .transpose();
+    }
+    WalkFrame::open(d.open_dir(name)?, None, config).map(Some)
+}
+
+/// Close the outermost open subdirectory, if more than the configured maximum
+/// are open.
+fn walk_close_dirs(frames: &mut [WalkFrame], config: &WalkConfiguration) -> Result<()> {
+    let open = frames
+        .iter()
+        .filter(|f| matches!(f.dir, WalkFrameDir::Open(_)))
+        .count();
+    if open <= config.max_open_dirs.unwrap_or(DEFAULT_MAX_OPEN_DIRS) {
+        return Ok(());
+    }
+    // The innermost directory is never closed.
+    let Some(frame) = frames
+        .iter_mut()
+        .rev()
+        .skip(1)
+        .filter(|f| matches!(f.dir, WalkFrameDir::Open(_)))
+        .last()
+    else {
+        return Ok(());
+    };
+    frame.entries.buffer()?;
+    #[cfg(unix)]
+    if frame.id.is_none() {
+        if let WalkFrameDir::Open(d) = &frame.dir {
+            frame.id = Some(dir_id(d)?);
+        }
+    }
+    frame.dir = WalkFrameDir::Closed;
+    Ok(())
+}
+
+/// Reopen a directory which was closed, at the given path; `child` is the
+/// subdirectory of it which was just walked.
+fn walk_reopen_dir(
+    root: &Dir,
+    frame: &mut WalkFrame,
+    child: Option<&Dir>,
+    path: &Path,
+    config: &WalkConfiguration,
+    in_root: bool,
+) -> Result<()> {
+    #[cfg(unix)]
+    if let (Some(child), Some(id)) = (child, frame.id) {
+        use rustix::fs::{Mode, OFlags};
+        // This is usually the same directory, and avoids resolving a long path.
+        let parent = rustix::fs::openat(
+            child,
+            "..",
+            OFlags::RDONLY | OFlags::DIRECTORY | OFlags::CLOEXEC,
+            Mode::empty(),
+        )?;
+        let parent = Dir::from_std_file(parent.into());
+        if dir_id(&parent)? == id {
+            frame.dir = WalkFrameDir::Open(parent);
+            return Ok(());
+        }
+    }
+    let _ = child;
+    let relpath = config.relative(path);
+    let relpath = if relpath.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        relpath
+    };
+    let d = walk_open_path(root, relpath, in_root)?;
+    #[cfg(unix)]
+    if frame.id.is_some_and(|id| dir_id(&d).ok() != Some(id)) {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("Directory changed during walk: {}", path.display()),
+        ));
+    }
+    frame.dir = WalkFrameDir::Open(d);
+    Ok(())
+}
+
+/// Walk a directory, as with [`CapStdExtDirExt::walk`]; if `in_root` is set,
+/// symbolic links are resolved as if the directory were the root (see
+/// `RootDir::walk`).
+pub(crate) fn walk_dir<C, E>(
+    d: &Dir,
+    config: &WalkConfiguration,
+    in_root: bool,
+    mut callback: C,
+) -> std::result::Result<(), E>
+where
+    C: FnMut(&WalkComponent) -> WalkResult<E>,
+    E: From<std::io::Error>,
+{
+    let mut path = config.path_base.map(ToOwned::to_owned).unwrap_or_default();
+    if let Some(cursor) = config.resume.as_ref() {
+        if !config.sorted_by_name {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Resuming a walk requires sorting by file name",
+            )
+            .into());
+        }
+        if !cursor.path().starts_with(&path) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Walk cursor is outside the path base: {}",
+                    cursor.path().display()
+                ),
+            )
+            .into());
+        }
+        // With contents first, the root is the last entry
+        if config.contents_first && config.relative(cursor.path()).as_os_str().is_empty() {
+            return Ok(());
+        }
+    }
+    let root = config.include_root.then(|| d.dir_metadata()).transpose()?;
+    let visit_root = |callback: &mut C, path: &Path| match root.as_ref() {
+        Some(meta) => callback(&root_component(d, path, meta, config)),
+        None => Ok(ControlFlow::Continue(())),
+    };
+    // Otherwise, it is the first
+    if !config.contents_first
+        && config.resume.is_none()
+        && visit_root(&mut callback, &path)?.is_break()
+    {
+        return Ok(());
+    }
+    walk_impl(d, &mut path, &mut callback, config, in_root)?;
+    if config.contents_first {
+        // There is nothing further to skip
+        let _ = visit_root(&mut callback, &path)?;
+    }
+    Ok(())
+}
+
+/// The implementation of [`CapStdExtDirExt::walk`].  This uses an explicit stack
+/// rather than recursion, and limits the number of open directories, so that
+/// deep trees exhaust neither the call stack nor file descriptors.
+fn walk_impl<E>(
+    root: &Dir,
+    path: &mut PathBuf,
+    callback: &mut dyn FnMut(&WalkComponent) -> WalkResult<E>,
+    config: &WalkConfiguration,
+    in_root: bool,
+) -> std::result::Result<(), E>
+where
+    E: From<std::io::Error>,
+{
+    let mut hardlinks = config.track_hardlinks.then(HardlinkMap::new);
+    let resume = config
+        .resume
+        .as_ref()
+        .map(|c| config.relative(c.path()).iter().collect::<Vec<_>>())
+        .unwrap_or_default();
+    let mut root_id = None;
+    #[cfg(unix)]
+    if config.follow_symlinks {
+        root_id = Some(dir_id(root)?);
+    }
+    let mut frames = vec![WalkFrame {
+        dir: WalkFrameDir::Root,
+        id: root_id,
+        entries: WalkEntries::new(root, config)?,
+        pending: None,
+        on_cursor: !resume.is_empty(),
+    }];
+    loop {
+        let depth = frames.len();
+        // SAFETY(unwrap): The loop ends when the root is finished.
+        let frame = frames.last_mut().unwrap();
+        let Some(entry) = frame.entries.next() else {
+            // This directory is finished
+            let frame = frames.pop().unwrap();
+            let Some(parent) = frames.last_mut() else {
+                return Ok(());
+            };
+            let child = match &frame.dir {
+                WalkFrameDir::Open(d) => Some(d),
+                _ => None,
+            };
+            if matches!(parent.dir, WalkFrameDir::Closed) {
+                // SAFETY(unwrap): The path always includes the directory's name.
+                walk_reopen_dir(root, parent, child, path.parent().unwrap(), config, in_root)?;
+            }
+            let flow = match frame.pending {
+                Some(p) => callback(&WalkComponent {
+                    path,
+                    dir: parent.dir(root),
+                    filename: &p.name,
+                    file_type: p.file_type,
+                    metadata: p.metadata.as_ref(),
+                    first_seen: true,
+                    first_path: None,
+                })?,
+                None => ControlFlow::Continue(()),
+            };
+            path.pop();
+            if flow.is_break() {
+                parent.entries = WalkEntries::empty();
+            }
+            continue;
+        };
+        let entry = entry?;
+        // Whether this entry is on the path of the cursor, and if so, whether it
+        // is the entry at which the cursor was taken.
+        let mut resumed = None;
+        if frame.on_cursor {
+            let last = depth == resume.len();
+            match entry.name.as_os_str().cmp(resume[depth - 1]) {
+                Ordering::Less => continue,
+                // The entry and its contents were already visited
+                Ordering::Equal if last && config.contents_first => continue,
+                Ordering::Equal => resumed = Some(last),
+                Ordering::Greater => frame.on_cursor = false,
+            }
+        }
+        let d = frame.dir(root);
+        path.push(&entry.name);
+        if config.filter.excludes(config.relative(path)) {
+            path.pop();
+            continue;
+        }
+        let mut file_type = entry.file_type;
+        let mut metadata = (config.metadata || config.track_hardlinks)
+            .then(|| d.symlink_metadata(&entry.name))
+            .transpose()?;
+        if config.follow_symlinks && file_type.is_symlink() {
+            // Links which can't be resolved are reported as is.
+            if let Ok(meta) = walk_follow_metadata(root, config.relative(path), in_root) {
+                file_type = meta.file_type();
+                metadata = metadata.map(|_| meta);
+            }
+        }
+        if !config.filter.includes(config.relative(path), file_type) {
+            path.pop();
+            continue;
+        }
+        let first_path = match (hardlinks.as_mut(), metadata.as_ref()) {
+            (Some(hardlinks), Some(meta)) => hardlink_first_path(hardlinks, meta, path),
+            _ => None,
+        };
+        let metadata = metadata.filter(|_| config.metadata);
+        let mut visit = |d: &Dir| {
+            callback(&WalkComponent {
+                path,
+                dir: d,
+                filename: &entry.name,
+                file_type,
+                metadata: metadata.as_ref(),
+                first_seen: first_path.is_none(),
+                first_path: first_path.as_deref(),
+            })
+        };
+        if !config.contents_first {
+            let flow = match resumed {
+                // This was visited before the cursor was taken
+                Some(_) => ControlFlow::Continue(()),
+                None => visit(d)?,
+            };
+            if !file_type.is_dir() || flow.is_break() {
+                path.pop();
+                if flow.is_break() && !file_type.is_dir() {
+                    frame.entries = WalkEntries::empty();
+                }
+                continue;
+            }
+        }
+        let subdir = if file_type.is_dir() {
+            walk_open_subdir(root, &frames, &entry.name, path, config, in_root)?
+        } else {
+            None
+        };
+        let Some(mut subdir) = subdir else {
+            if config.contents_first {
+                // SAFETY(unwrap): We are processing its entries.
+                let frame = frames.last_mut().unwrap();
+                let flow = visit(frame.dir(root))?;
+                if flow.is_break() {
+                    frame.entries = WalkEntries::empty();
+                }
+            }
+            path.pop();
+            continue;
+        };
+        if config.contents_first {
+            subdir.pending = Some(WalkPendingDir {
+                name: entry.name,
+                file_type,
+                metadata,
+            });
+        }
+        subdir.on_cursor = resumed == Some(false);
+        frames.push(subdir);
+        walk_close_dirs(&mut frames, config)?;
+    }
+}
+
+/// Marks a job of a parallel walk as finished when dropped.
+struct ParallelWalkActive<'a, 'w, C, E>(&'a ParallelWalk<'w, C, E>)
+where
+    C: Fn(&WalkComponent) -> WalkResult<E> + Sync,
+    E: From<std::io::Error> + Send;
+
+impl<C, E> Drop for ParallelWalkActive<'_, '_, C, E>
+where
+    C: Fn(&WalkComponent) -> WalkResult<E> + Sync,
+    E: From<std::io::Error> + Send,
+{
+    fn drop(&mut self) {
+        self.0.finish_job();
+    }
+}
+
+/// A directory queued for a parallel walk.
+struct ParallelWalkJob {
+    dir: Dir,
+    path: PathBuf,
+    /// When following symbolic links, the directories being walked.
+    ancestors: Vec<(u64, u64)>,
+}
+
+/// State shared between the threads of a parallel walk.
+struct ParallelWalk<'a, C, E> {
+    root: &'a Dir,
+    noxdev: bool,
+    path_base: &'a Path,
+    follow_symlinks: bool,
+    metadata: bool,
+    filter: &'a WalkFilter,
+    /// When tracking hard links, the first path seen for each multiply-linked file.
+    hardlinks: Option<std::sync::Mutex<HardlinkMap>>,
+    callback: C,
+    /// Pending jobs, and the number of jobs being processed.
+    queue: std::sync::Mutex<(std::collections::VecDeque<ParallelWalkJob>, usize)>,
+    cond: std::sync::Condvar,
+    /// Set once an error occurs; no further entries are processed.
+    stop: std::sync::atomic::AtomicBool,
+    /// The error with the lowest path, if any.
+    error: std::sync::Mutex<Option<(PathBuf, E)>>,
+}
+
+impl<C, E> ParallelWalk<'_, C, E>
+where
+    C: Fn(&WalkComponent) -> WalkResult<E> + Sync,
+    E: From<std::io::Error> + Send,
+{
+    fn worker(&self) {
+        use std::sync::atomic::Ordering;
+        loop {
+            let job = {
+                // SAFETY(unwrap): We don't panic while holding the lock.
+                let mut queue = self.queue.lock().unwrap();
+                loop {
+                    if let Some(job) = queue.0.pop_front() {
+                        queue.1 += 1;
+                        break Some(job);
+                    }
+                    if queue.1 == 0 {
+                        break None;
+                    }
+                    queue = self.cond.wait(queue).unwrap();
+                }
+            };
+            let Some(job) = job else {
+                self.cond.notify_all();
+                return;
+            };
+            // Ensure other threads don't wait forever if the callback panics
+            let _active = ParallelWalkActive(self);
+            if !self.stop.load(Ordering::Relaxed) {
+                if let Err((path, e)) = self.process(job) {
+                    self.stop.store(true, Ordering::Relaxed);
+                    let mut error = self.error.lock().unwrap();
+                    if error.as_ref().map_or(true, |(p, _)| path < *p) {
+                        *error = Some((path, e));
+                    }
+                }
+            }
+        }
+    }
+
+    fn finish_job(&self) {
+        let mut queue = self.queue.lock().unwrap_or_else(|e| e.into_inner());
+        queue.1 -= 1;
+        if queue.1 == 0 && queue.0.is_empty() {
+            self.cond.notify_all();
+        }
+    }
+
+    fn push(&self, job: ParallelWalkJob) {
+        self.queue.lock().unwrap().0.push_back(job);
+        self.cond.notify_one();
+    }
+
+    /// Return the path relative to the root, without the configured path base.
+    fn relative<'p>(&self, path: &'p Path) -> &'p Path {
+        // SAFETY(unwrap): All walked paths are prefixed with the path base.
+        path.strip_prefix(self.path_base).unwrap()
+    }
+
+    /// Walk the entries of a single directory, queueing its subdirectories.  On
+    /// error, the path of the entry (or directory) is returned with the error.
+    fn process(&self, job: ParallelWalkJob) -> std::result::Result<(), (PathBuf, E)> {
+        let ParallelWalkJob {
+            dir,
+            mut path,
+            ancestors,
+        } = job;
+        let entries = match dir.entries() {
+            Ok(entries) => entries,
+            Err(e) => return Err((path, e.into())),
+        };
+        for entry in entries {
+            if self.stop.load(std::sync::atomic::Ordering::Relaxed) {
+                break;
+            }
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => return Err((path, e.into())),
+            };
+            let filename = entry.file_name();
+            path.push(&filename);
+            match self.process_entry(&dir, &entry, &filename, &path, &ancestors) {
+                Ok(flow) => {
+                    path.pop();
+                    if flow.is_break() {
+                        break;
+                    }
+                }
+                Err(e) => return Err((path, e)),
+            }
+        }
+        Ok(())
+    }
+
+    fn process_entry(
+        &self,
+        d: &Dir,
+        entry: &DirEntry,
+        filename: &OsStr,
+        path: &Path,
+        ancestors: &[(u64, u64)],
+    ) -> WalkResult<E> {
+        if self.filter.excludes(self.relative(path)) {
+            return Ok(ControlFlow::Continue(()));
+        }
+        let mut file_type = entry.file_type()?;
+        let mut metadata = (self.metadata || self.hardlinks.is_some())
+            .then(|| entry.metadata())
+            .transpose()?;
+        if self.follow_symlinks && file_type.is_symlink() {
+            // Links which can't be resolved are reported as is.
+            if let Ok(meta) = self.root.metadata(self.relative(path)) {
+                file_type = meta.file_type();
+                metadata = metadata.map(|_| meta);
+            }
+        }
+        if !self.filter.includes(self.relative(path), file_type) {
+            return Ok(ControlFlow::Continue(()));
+        }
+        let first_path = match (self.hardlinks.as_ref(), metadata.as_ref()) {
+            (Some(hardlinks), Some(meta)) => {
+                hardlink_first_path(&mut hardlinks.lock().unwrap(), meta, path)
+            }
+            _ => None,
+        };
+        let metadata = metadata.filter(|_| self.metadata);
+        let flow = (self.callback)(&WalkComponent {
+            path,
+            dir: d,
+            filename,
+            file_type,
+            metadata: metadata.as_ref(),
+            first_seen: first_path.is_none(),
+            first_path: first_path.as_deref(),
+        })?;
+        if !file_type.is_dir() {
+            return Ok(flow);
+        }
+        if flow.is_continue() {
+            if let Some(job) = self.open_subdir(d, filename, path, ancestors)? {
+                self.push(job);
+            }
+        }
+        Ok(ControlFlow::Continue(()))
+    }
+
+    fn open_subdir(
+        &self,
+        d: &Dir,
+        name: &OsStr,
+        path: &Path,
+        ancestors: &[(u64, u64)],
+    ) -> Result<Option<ParallelWalkJob>> {
+        #[cfg(unix)]
+        if self.follow_symlinks {
+            // This may be a symbolic link, so we can't use `open_dir_noxdev`.
+            let subdir = self.root.open_dir(self.relative(path))?;
+            let id = dir_id(&subdir)?;
+            // SAFETY(unwrap): The root is always present.
+            let parent = *ancestors.last().unwrap();
+            if (self.noxdev && id.0 != parent.0) || ancestors.contains(&id) {
+                return Ok(None);
+            }
+            let mut ancestors = ancestors.to_vec();
+            ancestors.push(id);
+            return Ok(Some(ParallelWalkJob {
+                dir: subdir,
+                path: path.to_owned(),
+                ancestors,
+            }));
+        }
+        #[cfg(any(target_os = "android", target_os = "linux"))]
+        let subdir = if self.noxdev {
+            match d.open_dir_noxdev(name)? {
+                Some(d) => d,
+                None => return Ok(None),
+            }
+        } else {
+            d.open_dir(name)?
+        };
+        #[cfg(not(any(target_os = "android", target_os = "linux")))]
+        let subdir = d.open_dir(name)?;
+        Ok(Some(ParallelWalkJob {
+            dir: subdir,
+            path: path.to_owned(),
+            ancestors: Vec::new(),
+        }))
+    }
+}
+
+/// The maximum number of symbolic links followed during path resolution,
+/// matching `MAXSYMLINKS` on Linux.
+const MAX_SYMLINK_HOPS: usize = 40;
+
+/// How resolution of a path beneath a directory ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ResolutionEnd {
+    /// The path was fully resolved.
+    Complete,
+    /// The path (or a symbolic link target) referred outside of the directory.
+    Escaped,
+    /// Too many symbolic links were followed.
+    Looped,
+    /// A path component did not exist.
+    NotFound,
+}
+
+/// The result of resolving a path beneath a directory.
+#[derive(Debug)]
+struct Resolution {
+    /// The path resolved so far, relative to the directory.  If resolution
+    /// did not complete, this is the last path inspected.
+    path: PathBuf,
+    /// The symbolic links which were followed, in order.
+    chain: Vec<PathBuf>,
+    /// How resolution ended.
+    end: ResolutionEnd,
+}
+
+/// A path component pending resolution.
+enum PendingComponent {
+    Parent,
+    Normal(std::ffi::OsString),
+}
+
+/// Queue the components of `path` for resolution (ahead of anything already queued).
+/// Returns `false` if the path is absolute.
+fn queue_components(
+    pending: &mut std::collections::VecDeque<PendingComponent>,
+    path: &Path,
+) -> bool {
+    use std::path::Component;
+    for c in path.components().rev() {
+        match c {
+            Component::ParentDir => pending.push_front(PendingComponent::Parent),
+            Component::Normal(name) => pending.push_front(PendingComponent::Normal(name.into())),
+            Component::CurDir | Component::RootDir | Component::Prefix(_) => {}
+        }
+    }
+    !path.has_root()
+}
+
+/// Resolve a path (including symbolic links and `..`) beneath a directory,
+/// entirely in userspace and without ever leaving the directory.
+///
+/// If `rooted` is set, the directory is treated as the root of the filesystem
+/// (as with `chroot`): absolute paths are resolved relative to it, and `..` in
+/// the directory itself refers to the directory.  Otherwise, both of these
+/// end resolution as escaping the directory.
+fn resolve_beneath(d: &Dir, path: &Path, max_hops: usize, rooted: bool) -> Result<Resolution> {
+    let mut pending = std::collections::VecDeque::new();
+    let mut resolved = PathBuf::new();
+    let mut chain = Vec::new();
+    let end = |path, chain, end| Ok(Resolution { path, chain, end });
+    if !queue_components(&mut pending, path) && !rooted {
+        return end(path.to_owned(), chain, ResolutionEnd::Escaped);
+    }
+    while let Some(c) = pending.pop_front() {
+        let name = match c {
+            PendingComponent::Parent => {
+                if !resolved.pop() && !rooted {
+                    return end(resolved, chain, ResolutionEnd::Escaped);
+                }
+                continue;
+            }
+            PendingComponent::Normal(name) => name,
+        };
+        let candidate = resolved.join(name);
+        let Some(meta) = d.symlink_metadata_optional(&candidate)? else {
+            return end(candidate, chain, ResolutionEnd::NotFound);
+        };
+        if meta.is_symlink() {
+            if chain.len() >= max_hops {
+                return end(candidate, chain, ResolutionEnd::Looped);
+            }
+            let target = d.read_link_contents(&candidate)?;
+            chain.push(candidate.clone());
+            if !queue_components(&mut pending, &target) {
+                if !rooted {
+                    return end(candidate, chain, ResolutionEnd::Escaped);
+                }
+                resolved.clear();
+            }
+        } else if !meta.is_dir() && !pending.is_empty() {
+            return Err(io::Error::from_raw_os_error(libc::ENOTDIR));
+        } else {
+            resolved = candidate;
+        }
+    }
+    end(resolved, chain, ResolutionEnd::Complete)
+}
+
+/// Compute a relative path from the directory `from` to `to`.  Both paths must be
+/// in the same form (e.g. absolute) and must not contain `..` components.
+#[cfg(unix)]
+fn relative_path(from: &Path, to: &Path) -> PathBuf {
+    let common = from
+        .components()
+        .zip(to.components())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let ups = from.components().count() - common;
+    let mut r: PathBuf = std::iter::repeat("..").take(ups).collect();
+    r.extend(to.components().skip(common));
+    r
+}
+
+/// Recreate the hierarchy of `src` in `dest` as a "symlink farm" (in the style of
+/// GNU Stow): directories are created as real directories (merging with any
+/// existing ones), and each non-directory is linked to its counterpart in the
+/// source.  See [`LinkOptions`] for how link targets are computed.
+///
+/// Existing symbolic links which already point to the expected target are left
+/// unchanged; other existing files are handled according to [`LinkOptions::conflict`].
+#[cfg(unix)]
+pub fn link_tree(src: &Dir, dest: &Dir, options: &LinkOptions) -> Result<()> {
+    src.walk(&WalkConfiguration::default(), |e| -> Result<_> {
+        let existing = dest.symlink_metadata_optional(e.path)?;
+        if e.file_type.is_dir() {
+            match existing {
+                None => dest.create_dir(e.path)?,
+                Some(m) if m.is_dir() => {}
+                Some(_) => match options.conflict {
+                    LinkConflict::Error => return Err(link_conflict(e.path)),
+                    // Nothing beneath here can be linked
+                    LinkConflict::Skip => return Ok(ControlFlow::Break(())),
+                    LinkConflict::Replace => {
+                        dest.remove_file(e.path)?;
+                        dest.create_dir(e.path)?;
+                    }
+                },
+            }
+            return Ok(ControlFlow::Continue(()));
+        }
+        let target = options.source.join(e.path);
+        let target = match options.dest.as_deref() {
+            Some(d) => {
+                // SAFETY(unwrap): Paths from a walk always have a parent.
+                let parent = d.join(e.path.parent().unwrap());
+                relative_path(&parent, &target)
+            }
+            None => target,
+        };
+        match existing {
+            None => {}
+            Some(m) if m.is_symlink() && dest.read_link_contents(e.path)? == target => {
+                return Ok(ControlFlow::Continue(()));
+            }
+            Some(m) => match options.conflict {
+                LinkConflict::Skip => return Ok(ControlFlow::Continue(())),
+                LinkConflict::Replace if !m.is_dir() => dest.remove_file(e.path)?,
+                _ => return Err(link_conflict(e.path)),
+            },
+        }
+        dest.symlink_contents(&target, e.path)?;
+        Ok(ControlFlow::Continue(()))
+    })
+}
+
+#[cfg(unix)]
+fn link_conflict(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::AlreadyExists,
+        format!("Conflicting file in destination: {}", path.display()),
+    )
+}
+
+/// Give the open (and possibly anonymous) temporary file a name in the scratch
+/// directory, then rename it over the destination.
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn replace_from_scratch(f: &File, scratch: &Dir, d: &Dir, name: &OsStr) -> Result<()> {
+    use rustix::fd::AsFd;
+
+    // Linking via procfs works for files created with `O_TMPFILE`, without needing
+    // `CAP_DAC_READ_SEARCH` for `AT_EMPTY_PATH`.
+    let procpath = crate::audit::proc_self_fd(f.as_fd())?;
+    let tmpname = loop {
+        let tmpname = format!(".tmp-{}", unique_suffix());
+        match rustix::fs::linkat(
+            rustix::fs::CWD,
+            procpath.as_str(),
+            scratch,
+            tmpname.as_str(),
+            rustix::fs::AtFlags::SYMLINK_FOLLOW,
+        ) {
+            Ok(()) => break tmpname,
+            Err(rustix::io::Errno::EXIST) => continue,
+            Err(e) => return Err(e.into()),
+        }
+    };
+    if let Err(e) = scratch.rename(&tmpname, d, name) {
+        let _ = scratch.remove_file(&tmpname);
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// Return `true` if the two open files are the same file, i.e. they have the same
+/// device and inode numbers.
+#[cfg(unix)]
+pub fn is_same_file_fd(a: impl rustix::fd::AsFd, b: impl rustix::fd::AsFd) -> Result<bool> {
+    let a = rustix::fs::fstat(a)?;
+    let b = rustix::fs::fstat(b)?;
+    Ok((a.st_dev, a.st_ino) == (b.st_dev, b.st_ino))
+}
+
+#[cfg(unix)]
+fn is_same_metadata(a: &Metadata, b: &Metadata) -> bool {
+    use cap_std::fs::MetadataExt;
+    (a.dev(), a.ino()) == (b.dev(), b.ino())
+}
+
+/// The maximum length of a `#!` line which will be parsed; this matches the
+/// limit of the Linux kernel (`BINPRM_BUF_SIZE`).
+#[cfg(unix)]
+const SHEBANG_MAX: usize = 256;
+
+/// Find the interpreter in a `#!` line at the start of the buffer, returning its
+/// byte range.
+#[cfg(unix)]
+fn parse_shebang(buf: &[u8]) -> Option<std::ops::Range<usize>> {
+    let line = buf.strip_prefix(b"#!")?;
+    let line = &line[..line.iter().position(|&c| c == b'\n').unwrap_or(line.len())];
+    let is_space = |c: &u8| *c == b' ' || *c == b'\t';
+    let start = line.iter().position(|c| !is_space(c))?;
+    let len = line[start..]
+        .iter()
+        .position(|c| is_space(c) || *c == b'\r')
+        .unwrap_or(line.len() - start);
+    Some(start + 2..start + 2 + len)
+}
+
+/// Verify that a file we opened in a potentially shared directory is owned by
+/// us and cannot be modified by anyone else.
+#[cfg(unix)]
+fn verify_owned(fd: rustix::fd::BorrowedFd, expected: rustix::fs::FileType) -> Result<()> {
+    use rustix::fs::FileType;
+
+    let st = rustix::fs::fstat(fd)?;
+    let reason = if FileType::from_raw_mode(st.st_mode) != expected {
+        "unexpected file type"
+    } else if st.st_uid != rustix::process::geteuid().as_raw() {
+        "not owned by the current user"
+    } else if st.st_mode & 0o022 != 0 {
+        "writable by other users"
+    } else if expected == FileType::RegularFile && st.st_nlink != 1 {
+        "has multiple hard links"
+    } else {
+        return Ok(());
+    };
+    Err(io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        format!("Refusing to use existing file: {reason}"),
+    ))
+}
+
+/// Compute the target of a symbolic link in the directory `parent` (relative to
+/// the root of the tree), converted to the provided style.  Returns `None` if the
+/// target already has that style, or cannot be converted.
+#[cfg(unix)]
+fn restyle_link_target(parent: &Path, target: &Path, style: SymlinkStyle) -> Option<PathBuf> {
+    use std::path::Component;
+    if target.has_root() == (style == SymlinkStyle::Absolute) {
+        return None;
+    }
+    // The path of the target from the root; the link's parent directory is known
+    // to be a real directory, so leading `..` components can be resolved lexically.
+    let mut resolved = if target.has_root() {
+        PathBuf::new()
+    } else {
+        parent.to_owned()
+    };
+    let mut seen_normal = false;
+    for c in target.components() {
+        match c {
+            Component::ParentDir if seen_normal => return None,
+            // As with `chroot`, `..` in the root refers to the root
+            Component::ParentDir => {
+                resolved.pop();
+            }
+            Component::Normal(name) => {
+                seen_normal = true;
+                resolved.push(name);
+            }
+            Component::CurDir | Component::RootDir | Component::Prefix(_) => {}
+        }
+    }
+    let r = match style {
+        SymlinkStyle::Absolute => Path::new("/").join(resolved),
+        SymlinkStyle::Relative => relative_path(parent, &resolved),
+    };
+    if r.as_os_str().is_empty() {
+        Some(".".into())
+    } else {
+        Some(r)
+    }
+}
+
+/// Generate a random-looking suffix suitable for use in a temporary name.
+/// The standard library's hasher is randomly seeded per process; mixing in a
+/// counter ensures repeated calls within a process differ too.
+fn unique_suffix() -> String {
+    use std::hash::{BuildHasher, Hasher};
+    use std::sync::atomic::{AtomicU64, Ordering};
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let mut h = std::collections::hash_map::RandomState::new().build_hasher();
+    h.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+    h.write_u32(std::process::id());
+    format!("{:016x}", h.finish())
+}
+
+fn escape_error() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        "a path led outside of the filesystem",
+    )
+}
+
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn statx_impl(d: &Dir, path: &Path, flags: rustix::fs::AtFlags) -> Result<StatxInfo> {
+    use rustix::fs::{AtFlags, Mode, OFlags, ResolveFlags, StatxFlags};
+
+    // Resolve the path beneath the directory first; statx itself would follow
+    // absolute paths, `..` and symbolic links out of it.
+    let mut oflags = OFlags::PATH | OFlags::CLOEXEC;
+    if flags.contains(AtFlags::SYMLINK_NOFOLLOW) {
+        oflags |= OFlags::NOFOLLOW;
+    }
+    let fd = openat2_with_retry(
+        d,
+        path,
+        oflags,
+        Mode::empty(),
+        ResolveFlags::BENEATH | ResolveFlags::NO_MAGICLINKS,
+    )?;
+    let flags = (flags - AtFlags::SYMLINK_NOFOLLOW) | AtFlags::EMPTY_PATH;
+    let mask = StatxFlags::BASIC_STATS | StatxFlags::BTIME | StatxFlags::MNT_ID;
+    Ok(rustix::fs::statx(&fd, "", flags, mask).map(StatxInfo)?)
+}
+
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn is_mountpoint_impl_statx(root: &Dir, path: &Path) -> Result<Option<bool>> {
+    // https://github.com/systemd/systemd/blob/8fbf0a214e2fe474655b17a4b663122943b55db0/src/basic/mountpoint-util.c#L176
+    use rustix::fs::AtFlags;
+
+    match statx_impl(
+        root,
+        path,
+        AtFlags::NO_AUTOMOUNT | AtFlags::SYMLINK_NOFOLLOW,
+    ) {
+        Ok(r) => Ok(r.attribute(StatxAttribute::MountRoot)),
+        Err(e) if e.raw_os_error() == Some(libc::ENOSYS) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Make a backup of an existing file, keeping at most `count` backups; see
+/// [`AtomicWriteOptions::backups`].
+fn rotate_backups(d: &Dir, name: &OsStr, count: usize) -> Result<()> {
+    let backup = |i: usize| {
+        let mut r = name.to_owned();
+        r.push(".bak");
+        if i > 0 {
+            r.push(format!(".{i}"));
+        }
+        r
+    };
+    d.remove_file_optional(backup(count - 1))?;
+    for i in (1..count).rev() {
+        match d.rename(backup(i - 1), d, backup(i)) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            r => r?,
+        }
+    }
+    let backup = backup(0);
+    if d.hard_link(name, d, &backup).is_err() {
+        d.copy(name, d, &backup)?;
+    }
+    Ok(())
+}
+
+/// Write all of the buffers, as [`Write::write_all`] does for a single buffer.
+fn write_all_vectored(w: &mut impl Write, bufs: &[io::IoSlice]) -> Result<()> {
+    // The current buffer, and the offset within it
+    let (mut i, mut offset) = (0, 0);
+    while i < bufs.len() {
+        if offset == bufs[i].len() {
+            (i, offset) = (i + 1, 0);
+            continue;
+        }
+        let slices = std::iter::once(io::IoSlice::new(&bufs[i][offset..]))
+            .chain(bufs[i + 1..].iter().map(|b| io::IoSlice::new(b)))
+            .collect::<Vec<_>>();
+        let mut n = match w.write_vectored(&slices) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        while n > 0 {
+            let remaining = bufs[i].len() - offset;
+            if n < remaining {
+                offset += n;
+                break;
             }
+            n -= remaining;
+            (i, offset) = (i + 1, 0);
         }
     }
+    Ok(())
 }
 
-enum DirOwnedOrBorrowed<'d> {
-    Owned(Dir),
-    Borrowed(&'d Dir),
+/// Synchronize the contents of a file according to the durability level.
+fn sync_file(f: &File, durability: Durability) -> Result<()> {
+    match durability {
+        Durability::None => Ok(()),
+        Durability::Standard => f.sync_all(),
+        #[cfg(any(target_os = "ios", target_os = "macos"))]
+        Durability::FullBarrier => match rustix::fs::fcntl_fullfsync(f) {
+            Ok(()) => Ok(()),
+            // Not all filesystems support F_FULLFSYNC
+            Err(_) => f.sync_all(),
+        },
+        #[cfg(not(any(target_os = "ios", target_os = "macos")))]
+        Durability::FullBarrier => f.sync_all(),
+    }
 }
 
-impl<'d> Deref for DirOwnedOrBorrowed<'d> {
-    type Target = Dir;
+/// Synchronize a directory (i.e. the names in it) according to the durability level.
+pub(crate) fn sync_dir(d: &Dir, durability: Durability) -> Result<()> {
+    use rustix::fd::AsFd;
+    use rustix::fs::{Mode, OFlags};
+    if durability == Durability::None {
+        return Ok(());
+    }
+    // Explicitly open the directory for reading, as the existing file descriptor
+    // may be an `O_PATH` one which cannot be synchronized.
+    let d = rustix::fs::openat(
+        d.as_fd(),
+        ".",
+        OFlags::RDONLY | OFlags::DIRECTORY | OFlags::CLOEXEC,
+        Mode::empty(),
+    )?;
+    let f = File::from_std(d.into());
+    sync_file(&f, durability)
+}
 
-    fn deref(&self) -> &Self::Target {
-        match self {
-            Self::Owned(d) => d,
-            Self::Borrowed(d) => d,
+/// Apply the mode and ownership of an existing file to a new file which will
+/// replace it.  Changing the ownership may require privileges.
+#[cfg(unix)]
+#[allow(unsafe_code)]
+pub(crate) fn copy_mode_and_owner(f: &File, existing: &Metadata) -> Result<()> {
+    use cap_std::fs::MetadataExt;
+    use std::os::fd::AsRawFd;
+    f.set_permissions(existing.permissions())?;
+    let ours = f.metadata()?;
+    if (ours.uid(), ours.gid()) != (existing.uid(), existing.gid()) {
+        // SAFETY: The file descriptor is valid for the duration of this call.
+        let r = unsafe { libc::fchown(f.as_raw_fd(), existing.uid(), existing.gid()) };
+        if r < 0 {
+            return Err(io::Error::last_os_error());
         }
+        // Changing the owner may clear the setuid and setgid bits.
+        f.set_permissions(existing.permissions())?;
     }
+    Ok(())
 }
 
-/// Given a directory reference and a path, if the path includes a subdirectory (e.g. on Unix has a `/`)
-/// then open up the target directory, and return the file name.
-///
-/// Otherwise, reborrow the directory and return the file name.
-///
-/// It is an error if the target path does not name a file.
-fn subdir_of<'d, 'p>(d: &'d Dir, p: &'p Path) -> io::Result<(DirOwnedOrBorrowed<'d>, &'p OsStr)> {
-    let name = p
-        .file_name()
-        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "Not a file name"))?;
-    let r = if let Some(subdir) = p
-        .parent()
-        .filter(|v| !v.as_os_str().is_empty())
-        .map(|p| d.open_dir(p))
-    {
-        DirOwnedOrBorrowed::Owned(subdir?)
-    } else {
-        DirOwnedOrBorrowed::Borrowed(d)
+/// Wrapper for `openat2` which retries on `EAGAIN` and `EINTR`.
+#[cfg(any(target_os = "android", target_os = "linux"))]
+pub(crate) fn openat2_with_retry(
+    start: impl rustix::fd::AsFd,
+    path: &Path,
+    oflags: rustix::fs::OFlags,
+    mode: rustix::fs::Mode,
+    resolve: rustix::fs::ResolveFlags,
+) -> Result<rustix::fd::OwnedFd> {
+    use rustix::path::Arg;
+
+    let start = start.as_fd();
+    // We loop forever on EAGAIN right now. The cap-std version loops just 4 times,
+    // which seems really arbitrary.
+    path.into_with_c_str(|path_c_str| loop {
+        match rustix::fs::openat2(start, path_c_str, oflags, mode, resolve) {
+            Err(rustix::io::Errno::AGAIN | rustix::io::Errno::INTR) => continue,
+            r => return r,
+        }
+    })
+    .map_err(Into::into)
+}
+
+#[cfg(any(target_os = "android", target_os = "linux"))]
+#[allow(unsafe_code)]
+fn readahead_impl(fd: rustix::fd::BorrowedFd, offset: u64, len: usize) -> Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let offset = offset
+        .try_into()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Offset out of range"))?;
+    // SAFETY: The file descriptor is valid for the duration of this call.
+    let r = unsafe { libc::readahead(fd.as_raw_fd(), offset, len) };
+    if r < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// The maximum size of a file handle supported by the kernel.
+#[cfg(any(target_os = "android", target_os = "linux"))]
+const MAX_HANDLE_SZ: usize = 128;
+
+/// The C `struct file_handle`, with space for the largest possible handle.
+#[cfg(any(target_os = "android", target_os = "linux"))]
+#[repr(C)]
+struct RawFileHandle {
+    handle_bytes: libc::c_uint,
+    handle_type: libc::c_int,
+    f_handle: [u8; MAX_HANDLE_SZ],
+}
+
+#[cfg(any(target_os = "android", target_os = "linux"))]
+#[allow(unsafe_code)]
+fn name_to_handle_impl(fd: rustix::fd::BorrowedFd) -> Result<FileHandle> {
+    use std::os::fd::AsRawFd;
+
+    let mut raw = RawFileHandle {
+        handle_bytes: MAX_HANDLE_SZ as libc::c_uint,
+        handle_type: 0,
+        f_handle: [0; MAX_HANDLE_SZ],
     };
-    Ok((r, name))
+    let mut mount_id: libc::c_int = 0;
+    let empty = b"\0";
+    // SAFETY: The file descriptor is valid for the duration of this call, the path is
+    // NUL terminated, and the handle buffer is of the size we specified.
+    let r = unsafe {
+        libc::syscall(
+            libc::SYS_name_to_handle_at,
+            fd.as_raw_fd(),
+            empty.as_ptr().cast::<libc::c_char>(),
+            &mut raw as *mut RawFileHandle,
+            &mut mount_id as *mut libc::c_int,
+            libc::AT_EMPTY_PATH,
+        )
+    };
+    if r < 0 {
+        let e = io::Error::last_os_error();
+        return Err(match e.raw_os_error() {
+            Some(libc::EOPNOTSUPP) => io::Error::new(io::ErrorKind::Unsupported, e),
+            _ => e,
+        });
+    }
+    let len = (raw.handle_bytes as usize).min(MAX_HANDLE_SZ);
+    Ok(FileHandle {
+        handle_type: raw.handle_type,
+        bytes: raw.f_handle[..len].to_vec(),
+        mount_id,
+    })
 }
 
-fn is_mountpoint_impl_statx(root: &Dir, path: &Path) -> Result<Option<bool>> {
-    // https://github.com/systemd/systemd/blob/8fbf0a214e2fe474655b17a4b663122943b55db0/src/basic/mountpoint-util.c#L176
-    use rustix::fs::{AtFlags, StatxFlags};
-    use std::os::fd::AsFd;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+#[allow(unsafe_code)]
+fn open_by_handle_impl(
+    mount_fd: rustix::fd::BorrowedFd,
+    handle: &FileHandle,
+    flags: rustix::fs::OFlags,
+) -> Result<rustix::fd::OwnedFd> {
+    use std::os::fd::{AsRawFd, FromRawFd};
+
+    if handle.bytes.len() > MAX_HANDLE_SZ {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "File handle too large",
+        ));
+    }
+    let mut raw = RawFileHandle {
+        handle_bytes: handle.bytes.len() as libc::c_uint,
+        handle_type: handle.handle_type,
+        f_handle: [0; MAX_HANDLE_SZ],
+    };
+    raw.f_handle[..handle.bytes.len()].copy_from_slice(&handle.bytes);
+    let flags = flags | rustix::fs::OFlags::CLOEXEC;
+    loop {
+        // SAFETY: The file descriptor is valid for the duration of this call, and
+        // the handle is initialized.
+        let r = unsafe {
+            libc::syscall(
+                libc::SYS_open_by_handle_at,
+                mount_fd.as_raw_fd(),
+                &mut raw as *mut RawFileHandle,
+                flags.bits() as libc::c_int,
+            )
+        };
+        if r < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e);
+        }
+        // SAFETY: The kernel returned a new file descriptor which we now own.
+        return Ok(unsafe { rustix::fd::OwnedFd::from_raw_fd(r as libc::c_int) });
+    }
+}
 
-    // SAFETY(unwrap): We can infallibly convert an i32 into a u64.
-    let mountroot_flag: u64 = libc::STATX_ATTR_MOUNT_ROOT.try_into().unwrap();
-    match rustix::fs::statx(
-        root.as_fd(),
+/// Open a file or directory beneath a directory for use with the inode flags
+/// and attributes ioctls, or extended attributes.
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn open_for_attributes(d: &Dir, path: &Path) -> Result<rustix::fd::OwnedFd> {
+    use rustix::fs::{Mode, OFlags, ResolveFlags};
+    // O_NONBLOCK avoids hanging on FIFOs.
+    openat2_with_retry(
+        d,
         path,
-        AtFlags::NO_AUTOMOUNT | AtFlags::SYMLINK_NOFOLLOW,
-        StatxFlags::empty(),
-    ) {
-        Ok(r) => {
-            let present = (r.stx_attributes_mask & mountroot_flag) > 0;
-            Ok(present.then_some(r.stx_attributes & mountroot_flag > 0))
+        OFlags::RDONLY | OFlags::NONBLOCK | OFlags::CLOEXEC,
+        Mode::empty(),
+        ResolveFlags::BENEATH | ResolveFlags::NO_MAGICLINKS,
+    )
+}
+
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn inode_flags_error(e: rustix::io::Errno) -> io::Error {
+    match e {
+        rustix::io::Errno::NOTTY | rustix::io::Errno::OPNOTSUPP => {
+            io::Error::new(io::ErrorKind::Unsupported, io::Error::from(e))
+        }
+        e => e.into(),
+    }
+}
+
+/// The target of extended attribute operations.
+///
+/// Where possible, this is a readable file descriptor used with the `f*xattr`
+/// calls.  However, this requires read permission, and opening e.g. devices may
+/// have side effects; so for files which are not regular files or directories, or
+/// which can't be opened for reading, we fall back to opening with `O_PATH` and
+/// using the path-based calls via procfs.
+#[cfg(any(target_os = "android", target_os = "linux"))]
+pub(crate) enum XattrTarget {
+    Fd(rustix::fd::OwnedFd),
+    Proc {
+        _fd: rustix::fd::OwnedFd,
+        path: String,
+    },
+}
+
+#[cfg(any(target_os = "android", target_os = "linux"))]
+impl XattrTarget {
+    fn open(d: &Dir, path: &Path) -> Result<Self> {
+        use rustix::fs::ResolveFlags;
+        Self::open_with(d, path, ResolveFlags::BENEATH | ResolveFlags::NO_MAGICLINKS)
+    }
+
+    /// Open the target, resolving the path with the provided flags.
+    pub(crate) fn open_with(
+        d: &Dir,
+        path: &Path,
+        resolve: rustix::fs::ResolveFlags,
+    ) -> Result<Self> {
+        use rustix::fs::{FileType, Mode, OFlags};
+        // A symbolic link in the final component is not followed; as with
+        // lgetxattr() etc., the link itself is the target.
+        let pathfd = openat2_with_retry(
+            d,
+            path,
+            OFlags::PATH | OFlags::NOFOLLOW | OFlags::CLOEXEC,
+            Mode::empty(),
+            resolve,
+        )?;
+        let st = rustix::fs::fstat(&pathfd)?;
+        let fd = match FileType::from_raw_mode(st.st_mode) {
+            // This is not subject to races, and needs only search permission.
+            FileType::Directory => match rustix::fs::openat(
+                &pathfd,
+                ".",
+                OFlags::RDONLY | OFlags::DIRECTORY | OFlags::CLOEXEC,
+                Mode::empty(),
+            ) {
+                Ok(fd) => Some(fd),
+                Err(rustix::io::Errno::ACCESS) => None,
+                Err(e) => return Err(e.into()),
+            },
+            FileType::RegularFile => {
+                match openat2_with_retry(
+                    d,
+                    path,
+                    OFlags::RDONLY
+                        | OFlags::NOFOLLOW
+                        | OFlags::NONBLOCK
+                        | OFlags::NOCTTY
+                        | OFlags::CLOEXEC,
+                    Mode::empty(),
+                    resolve,
+                ) {
+                    // Ensure the path wasn't replaced in between
+                    Ok(fd) if is_same_file_fd(&fd, &pathfd)? => Some(fd),
+                    Ok(_) => None,
+                    Err(e) if e.kind() == io::ErrorKind::PermissionDenied => None,
+                    // Replaced by a symbolic link in between
+                    Err(e) if e.raw_os_error() == Some(libc::ELOOP) => None,
+                    Err(e) => return Err(e),
+                }
+            }
+            _ => None,
+        };
+        match fd {
+            Some(fd) => Ok(Self::Fd(fd)),
+            None => {
+                let path = crate::audit::proc_self_fd(rustix::fd::AsFd::as_fd(&pathfd))?;
+                Ok(Self::Proc { _fd: pathfd, path })
+            }
+        }
+    }
+
+    fn get_raw(&self, key: &OsStr, buf: &mut [u8]) -> rustix::io::Result<usize> {
+        match self {
+            Self::Fd(fd) => rustix::fs::fgetxattr(fd, key, buf),
+            Self::Proc { path, .. } => rustix::fs::getxattr(path.as_str(), key, buf),
+        }
+    }
+
+    pub(crate) fn get(&self, key: &OsStr) -> Result<Option<Vec<u8>>> {
+        loop {
+            let size = match self.get_raw(key, &mut []) {
+                Ok(n) => n,
+                Err(rustix::io::Errno::NODATA) => return Ok(None),
+                Err(e) => return Err(e.into()),
+            };
+            let mut buf = vec![0u8; size];
+            match self.get_raw(key, &mut buf) {
+                Ok(n) => {
+                    buf.truncate(n);
+                    return Ok(Some(buf));
+                }
+                Err(rustix::io::Errno::NODATA) => return Ok(None),
+                // The value grew; try again
+                Err(rustix::io::Errno::RANGE) => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    pub(crate) fn list(&self) -> Result<Vec<std::ffi::OsString>> {
+        use std::os::unix::ffi::OsStrExt;
+
+        // The element type is `c_char`, which varies by rustix backend
+        let list_raw = |buf: &mut [_]| match self {
+            Self::Fd(fd) => rustix::fs::flistxattr(fd, buf),
+            Self::Proc { path, .. } => rustix::fs::listxattr(path.as_str(), buf),
+        };
+        let buf = loop {
+            let size = list_raw(&mut [])?;
+            let mut buf = vec![0; size];
+            match list_raw(&mut buf) {
+                Ok(n) => {
+                    buf.truncate(n);
+                    break buf;
+                }
+                // The list grew; try again
+                Err(rustix::io::Errno::RANGE) => continue,
+                Err(e) => return Err(e.into()),
+            }
+        };
+        // `c_char` is signed on some platforms
+        #[allow(clippy::unnecessary_cast)]
+        let buf = buf.into_iter().map(|c| c as u8).collect::<Vec<u8>>();
+        Ok(buf
+            .split(|&c| c == 0)
+            .filter(|name| !name.is_empty())
+            .map(|name| OsStr::from_bytes(name).to_owned())
+            .collect())
+    }
+
+    pub(crate) fn set(&self, key: &OsStr, value: &[u8]) -> Result<()> {
+        let flags = rustix::fs::XattrFlags::empty();
+        match self {
+            Self::Fd(fd) => rustix::fs::fsetxattr(fd, key, value, flags)?,
+            Self::Proc { path, .. } => rustix::fs::setxattr(path.as_str(), key, value, flags)?,
+        }
+        Ok(())
+    }
+
+    /// Returns `false` if the attribute was not set.
+    pub(crate) fn remove(&self, key: &OsStr) -> Result<bool> {
+        let r = match self {
+            Self::Fd(fd) => rustix::fs::fremovexattr(fd, key),
+            Self::Proc { path, .. } => rustix::fs::removexattr(path.as_str(), key),
+        };
+        match r {
+            Ok(()) => Ok(true),
+            Err(rustix::io::Errno::NODATA) => Ok(false),
+            Err(e) => Err(e.into()),
         }
-        Err(e) if e == rustix::io::Errno::NOSYS => Ok(None),
-        Err(e) => Err(e.into()),
     }
 }
 
+/// The extended attribute which holds file capabilities.
+#[cfg(any(target_os = "android", target_os = "linux"))]
+const SECURITY_CAPABILITY: &str = "security.capability";
+
+/// `struct fsxattr` from `linux/fs.h`.
+#[cfg(any(target_os = "android", target_os = "linux"))]
+#[repr(C)]
+struct FsXattr {
+    fsx_xflags: u32,
+    fsx_extsize: u32,
+    fsx_nextents: u32,
+    fsx_projid: u32,
+    fsx_cowextsize: u32,
+    fsx_pad: [u8; 8],
+}
+
+/// `FS_XFLAG_PROJINHERIT`
+#[cfg(any(target_os = "android", target_os = "linux"))]
+const FS_XFLAG_PROJINHERIT: u32 = 0x200;
+
+#[cfg(any(target_os = "android", target_os = "linux"))]
+#[allow(unsafe_code)]
+fn fsgetxattr(fd: rustix::fd::BorrowedFd) -> Result<FsXattr> {
+    use rustix::ioctl::Opcode;
+    use std::os::fd::AsRawFd;
+
+    let mut attr = FsXattr {
+        fsx_xflags: 0,
+        fsx_extsize: 0,
+        fsx_nextents: 0,
+        fsx_projid: 0,
+        fsx_cowextsize: 0,
+        fsx_pad: [0; 8],
+    };
+    // FS_IOC_FSGETXATTR
+    let opcode = Opcode::read::<FsXattr>(b'X', 31).raw();
+    // SAFETY: The file descriptor is valid for the duration of this call, and the
+    // structure is of the expected size.
+    let r = unsafe { libc::ioctl(fd.as_raw_fd(), opcode as _, &mut attr as *mut FsXattr) };
+    if r < 0 {
+        let e = io::Error::last_os_error();
+        return Err(match e.raw_os_error() {
+            Some(libc::EOPNOTSUPP) | Some(libc::ENOTTY) => {
+                io::Error::new(io::ErrorKind::Unsupported, e)
+            }
+            _ => e,
+        });
+    }
+    Ok(attr)
+}
+
+#[cfg(any(target_os = "android", target_os = "linux"))]
+#[allow(unsafe_code)]
+fn set_project_id_impl(fd: rustix::fd::BorrowedFd, id: u32) -> Result<()> {
+    use rustix::ioctl::Opcode;
+    use std::os::fd::AsRawFd;
+
+    let mut attr = fsgetxattr(fd)?;
+    attr.fsx_projid = id;
+    if rustix::fs::fstat(fd)?.st_mode & libc::S_IFMT == libc::S_IFDIR {
+        attr.fsx_xflags |= FS_XFLAG_PROJINHERIT;
+    }
+    // FS_IOC_FSSETXATTR
+    let opcode = Opcode::write::<FsXattr>(b'X', 32).raw();
+    // SAFETY: The file descriptor is valid for the duration of this call, and the
+    // structure is initialized.
+    let r = unsafe { libc::ioctl(fd.as_raw_fd(), opcode as _, &attr as *const FsXattr) };
+    if r < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// The maximum size of an fs-verity digest (`FS_VERITY_MAX_DIGEST_SIZE`).
+#[cfg(any(target_os = "android", target_os = "linux"))]
+const FS_VERITY_MAX_DIGEST_SIZE: usize = 64;
+
+/// `struct fsverity_enable_arg` from `linux/fsverity.h`.
+#[cfg(any(target_os = "android", target_os = "linux"))]
+#[repr(C)]
+struct FsverityEnableArg {
+    version: u32,
+    hash_algorithm: u32,
+    block_size: u32,
+    salt_size: u32,
+    salt_ptr: u64,
+    sig_size: u32,
+    reserved1: u32,
+    sig_ptr: u64,
+    reserved2: [u64; 11],
+}
+
+/// `struct fsverity_digest` from `linux/fsverity.h`, with space for the digest.
+#[cfg(any(target_os = "android", target_os = "linux"))]
+#[repr(C)]
+struct FsverityDigest {
+    digest_algorithm: u16,
+    digest_size: u16,
+    digest: [u8; FS_VERITY_MAX_DIGEST_SIZE],
+}
+
+#[cfg(any(target_os = "android", target_os = "linux"))]
+#[allow(unsafe_code)]
+fn fsverity_enable_impl(fd: rustix::fd::BorrowedFd, options: &VerityOptions) -> Result<()> {
+    use rustix::ioctl::Opcode;
+    use std::os::fd::AsRawFd;
+
+    let arg = FsverityEnableArg {
+        version: 1,
+        hash_algorithm: options.algorithm.raw(),
+        block_size: options.block_size,
+        salt_size: options.salt.len() as u32,
+        salt_ptr: options.salt.as_ptr() as u64,
+        sig_size: 0,
+        reserved1: 0,
+        sig_ptr: 0,
+        reserved2: [0; 11],
+    };
+    // FS_IOC_ENABLE_VERITY
+    let opcode = Opcode::write::<FsverityEnableArg>(b'f', 133).raw();
+    // SAFETY: The file descriptor is valid for the duration of this call, and the
+    // argument (including the salt it points to) is valid and initialized.
+    let r = unsafe {
+        libc::ioctl(
+            fd.as_raw_fd(),
+            opcode as _,
+            &arg as *const FsverityEnableArg,
+        )
+    };
+    if r < 0 {
+        let e = io::Error::last_os_error();
+        return Err(match e.raw_os_error() {
+            Some(libc::EOPNOTSUPP) | Some(libc::ENOTTY) => {
+                io::Error::new(io::ErrorKind::Unsupported, e)
+            }
+            _ => e,
+        });
+    }
+    Ok(())
+}
+
+#[cfg(any(target_os = "android", target_os = "linux"))]
+#[allow(unsafe_code)]
+fn fsverity_digest_impl(fd: rustix::fd::BorrowedFd) -> Result<Option<VerityDigest>> {
+    use rustix::ioctl::Opcode;
+    use std::os::fd::AsRawFd;
+
+    let mut arg = FsverityDigest {
+        digest_algorithm: 0,
+        digest_size: FS_VERITY_MAX_DIGEST_SIZE as u16,
+        digest: [0; FS_VERITY_MAX_DIGEST_SIZE],
+    };
+    // FS_IOC_MEASURE_VERITY; the size is that of the header only.
+    let opcode = Opcode::read_write::<[u16; 2]>(b'f', 134).raw();
+    // SAFETY: The file descriptor is valid for the duration of this call, and
+    // the digest buffer is of the size we specified.
+    let r = unsafe { libc::ioctl(fd.as_raw_fd(), opcode as _, &mut arg as *mut FsverityDigest) };
+    if r < 0 {
+        let e = io::Error::last_os_error();
+        return match e.raw_os_error() {
+            // Not a verity file
+            Some(libc::ENODATA) => Ok(None),
+            Some(libc::EOPNOTSUPP) | Some(libc::ENOTTY) => {
+                Err(io::Error::new(io::ErrorKind::Unsupported, e))
+            }
+            _ => Err(e),
+        };
+    }
+    let algorithm = match arg.digest_algorithm {
+        1 => VerityHashAlgorithm::Sha256,
+        2 => VerityHashAlgorithm::Sha512,
+        o => {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("Unknown fs-verity hash algorithm {o}"),
+            ))
+        }
+    };
+    let len = (arg.digest_size as usize).min(FS_VERITY_MAX_DIGEST_SIZE);
+    Ok(Some(VerityDigest {
+        algorithm,
+        digest: arg.digest[..len].to_vec(),
+    }))
+}
+
 impl CapStdExtDirExt for Dir {
     fn open_optional(&self, path: impl AsRef<Path>) -> Result<Option<File>> {
         map_optional(self.open(path.as_ref()))
     }
 
-    fn open_dir_optional(&self, path: impl AsRef<Path>) -> Result<Option<Dir>> {
-        map_optional(self.open_dir(path.as_ref()))
+    fn open_buffered(&self, path: impl AsRef<Path>) -> Result<io::BufReader<File>> {
+        self.open(path.as_ref()).map(io::BufReader::new)
+    }
+
+    fn open_buffered_optional(
+        &self,
+        path: impl AsRef<Path>,
+    ) -> Result<Option<io::BufReader<File>>> {
+        Ok(self.open_optional(path)?.map(io::BufReader::new))
+    }
+
+    fn open_dir_optional(&self, path: impl AsRef<Path>) -> Result<Option<Dir>> {
+        map_optional(self.open_dir(path.as_ref()))
+    }
+
+    #[cfg(any(target_os = "android", target_os = "linux", target_os = "freebsd"))]
+    fn open_dir_rooted_ext(&self, path: impl AsRef<Path>) -> Result<crate::RootDir> {
+        crate::RootDir::new(self, path)
+    }
+
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn open_with_oflags(&self, path: impl AsRef<Path>, flags: rustix::fs::OFlags) -> Result<File> {
+        use rustix::fs::{Mode, OFlags, ResolveFlags};
+        // openat2 rejects a mode unless a file may be created.
+        let mode = if flags.contains(OFlags::CREATE) || flags.contains(OFlags::TMPFILE) {
+            Mode::from_raw_mode(0o666)
+        } else {
+            Mode::empty()
+        };
+        let fd = openat2_with_retry(
+            self,
+            path.as_ref(),
+            flags | OFlags::CLOEXEC,
+            mode,
+            ResolveFlags::BENEATH | ResolveFlags::NO_MAGICLINKS,
+        )?;
+        Ok(File::from_std(fd.into()))
+    }
+
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn open_beneath(&self, path: impl AsRef<Path>) -> Result<File> {
+        use rustix::fd::AsFd;
+        use rustix::fs::ResolveFlags;
+        let f = crate::rootdir::open_rdonly_with(
+            &self.as_fd(),
+            path.as_ref(),
+            ResolveFlags::BENEATH | ResolveFlags::NO_MAGICLINKS,
+        )?;
+        Ok(File::from_std(f))
+    }
+
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn open_beneath_optional(&self, path: impl AsRef<Path>) -> Result<Option<File>> {
+        map_optional(self.open_beneath(path))
+    }
+
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn file_handle(&self, path: impl AsRef<Path>) -> Result<FileHandle> {
+        use rustix::fd::AsFd;
+        use rustix::fs::{Mode, OFlags, ResolveFlags};
+        let fd = openat2_with_retry(
+            self,
+            path.as_ref(),
+            OFlags::PATH | OFlags::CLOEXEC,
+            Mode::empty(),
+            ResolveFlags::BENEATH | ResolveFlags::NO_MAGICLINKS,
+        )?;
+        name_to_handle_impl(fd.as_fd())
+    }
+
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn get_inode_flags(&self, path: impl AsRef<Path>) -> Result<InodeFlags> {
+        let fd = open_for_attributes(self, path.as_ref())?;
+        let flags = rustix::fs::ioctl_getflags(&fd).map_err(inode_flags_error)?;
+        Ok(InodeFlags::from_bits_retain(flags.bits()))
+    }
+
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn set_inode_flags(&self, path: impl AsRef<Path>, flags: InodeFlags) -> Result<()> {
+        let fd = open_for_attributes(self, path.as_ref())?;
+        let flags = rustix::fs::IFlags::from_bits_retain(flags.bits());
+        rustix::fs::ioctl_setflags(&fd, flags).map_err(inode_flags_error)
+    }
+
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn get_project_id(&self, path: impl AsRef<Path>) -> Result<u32> {
+        use rustix::fd::AsFd;
+        let fd = open_for_attributes(self, path.as_ref())?;
+        Ok(fsgetxattr(fd.as_fd())?.fsx_projid)
+    }
+
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn set_project_id(&self, path: impl AsRef<Path>, id: u32, recursive: bool) -> Result<()> {
+        use rustix::fd::AsFd;
+        use rustix::fs::{Mode, OFlags};
+        let path = path.as_ref();
+        let fd = open_for_attributes(self, path)?;
+        set_project_id_impl(fd.as_fd(), id)?;
+        let is_dir = rustix::fs::fstat(&fd)?.st_mode & libc::S_IFMT == libc::S_IFDIR;
+        if !(recursive && is_dir) {
+            return Ok(());
+        }
+        let d = Dir::from_std_file(std::fs::File::from(fd));
+        d.walk(&WalkConfiguration::default().noxdev(), |e| -> Result<_> {
+            // Other file types cannot be safely opened
+            if !(e.file_type.is_dir() || e.file_type.is_file()) {
+                return Ok(ControlFlow::Continue(()));
+            }
+            let fd = rustix::fs::openat(
+                e.dir,
+                e.filename,
+                OFlags::RDONLY | OFlags::NONBLOCK | OFlags::NOFOLLOW | OFlags::CLOEXEC,
+                Mode::empty(),
+            )?;
+            set_project_id_impl(fd.as_fd(), id)?;
+            Ok(ControlFlow::Continue(()))
+        })
+    }
+
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn getxattr(&self, path: impl AsRef<Path>, key: impl AsRef<OsStr>) -> Result<Option<Vec<u8>>> {
+        XattrTarget::open(self, path.as_ref())?.get(key.as_ref())
+    }
+
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn setxattr(
+        &self,
+        path: impl AsRef<Path>,
+        key: impl AsRef<OsStr>,
+        value: impl AsRef<[u8]>,
+    ) -> Result<()> {
+        XattrTarget::open(self, path.as_ref())?.set(key.as_ref(), value.as_ref())
+    }
+
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn listxattrs(&self, path: impl AsRef<Path>) -> Result<Vec<std::ffi::OsString>> {
+        XattrTarget::open(self, path.as_ref())?.list()
+    }
+
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn xattrs(&self, path: impl AsRef<Path>) -> Result<Vec<(std::ffi::OsString, Vec<u8>)>> {
+        let path = XattrTarget::open(self, path.as_ref())?;
+        let mut r = Vec::new();
+        for name in path.list()? {
+            // Skip attributes which were removed after listing
+            if let Some(value) = path.get(&name)? {
+                r.push((name, value));
+            }
+        }
+        Ok(r)
+    }
+
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn copy_xattrs(
+        &self,
+        src: impl AsRef<Path>,
+        dest_dir: &Dir,
+        dest: impl AsRef<Path>,
+        filter: &XattrFilter,
+    ) -> Result<()> {
+        let src = XattrTarget::open(self, src.as_ref())?;
+        let dest = XattrTarget::open(dest_dir, dest.as_ref())?;
+        for name in src.list()? {
+            if !filter.matches(&name) {
+                continue;
+            }
+            // Skip attributes which were removed after listing
+            let Some(value) = src.get(&name)? else {
+                continue;
+            };
+            dest.set(&name, &value)?;
+        }
+        Ok(())
+    }
+
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn removexattr(&self, path: impl AsRef<Path>, key: impl AsRef<OsStr>) -> Result<bool> {
+        XattrTarget::open(self, path.as_ref())?.remove(key.as_ref())
+    }
+
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn get_filecaps(&self, path: impl AsRef<Path>) -> Result<Option<FileCaps>> {
+        let fd = open_for_attributes(self, path.as_ref())?;
+        // The largest (version 3) format
+        let mut buf = [0u8; 24];
+        match rustix::fs::fgetxattr(&fd, SECURITY_CAPABILITY, &mut buf) {
+            Ok(n) => FileCaps::decode(&buf[..n]).map(Some),
+            Err(rustix::io::Errno::NODATA) => Ok(None),
+            Err(rustix::io::Errno::RANGE) => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Invalid file capabilities",
+            )),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn set_filecaps(&self, path: impl AsRef<Path>, caps: &FileCaps) -> Result<()> {
+        let fd = open_for_attributes(self, path.as_ref())?;
+        rustix::fs::fsetxattr(
+            &fd,
+            SECURITY_CAPABILITY,
+            &caps.encode(),
+            rustix::fs::XattrFlags::empty(),
+        )?;
+        Ok(())
+    }
+
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn fsverity_enable(&self, path: impl AsRef<Path>, options: &VerityOptions) -> Result<()> {
+        use rustix::fd::AsFd;
+        let f = self.open(path)?;
+        fsverity_enable_impl(f.as_fd(), options)
+    }
+
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn fsverity_digest(&self, path: impl AsRef<Path>) -> Result<Option<VerityDigest>> {
+        use rustix::fd::AsFd;
+        let f = self.open(path)?;
+        fsverity_digest_impl(f.as_fd())
+    }
+
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn is_verity(&self, path: impl AsRef<Path>) -> Result<bool> {
+        use rustix::fs::AtFlags;
+        let path = path.as_ref();
+        let info = self.statx(path, AtFlags::SYMLINK_NOFOLLOW)?;
+        if let Some(v) = info.attribute(StatxAttribute::Verity) {
+            return Ok(v);
+        }
+        // Older kernels don't report the attribute; only regular files can
+        // have fs-verity enabled, so we can safely open them to check.
+        if rustix::fs::FileType::from_raw_mode(info.as_raw().stx_mode.into())
+            != rustix::fs::FileType::RegularFile
+        {
+            return Ok(false);
+        }
+        match self.fsverity_digest(path) {
+            Ok(d) => Ok(d.is_some()),
+            Err(e) if e.kind() == io::ErrorKind::Unsupported => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn open_by_handle(&self, handle: &FileHandle, flags: rustix::fs::OFlags) -> Result<File> {
+        use rustix::fd::AsFd;
+        use rustix::fs::{Mode, OFlags};
+        // The kernel does not accept an `O_PATH` file descriptor here, which
+        // the existing one may be.
+        let mount_fd = rustix::fs::openat(
+            self.as_fd(),
+            ".",
+            OFlags::RDONLY | OFlags::DIRECTORY | OFlags::CLOEXEC,
+            Mode::empty(),
+        )?;
+        let fd = open_by_handle_impl(mount_fd.as_fd(), handle, flags)?;
+        Ok(File::from_std(fd.into()))
+    }
+
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn open_dir_noxdev(&self, path: impl AsRef<Path>) -> Result<Option<Dir>> {
+        use rustix::fs::{Mode, OFlags, ResolveFlags};
+        match openat2_with_retry(
+            self,
+            path.as_ref(),
+            OFlags::CLOEXEC | OFlags::DIRECTORY | OFlags::NOFOLLOW,
+            Mode::empty(),
+            ResolveFlags::NO_XDEV | ResolveFlags::BENEATH,
+        ) {
+            Ok(r) => Ok(Some(Dir::from_std_file(r.into()))),
+            Err(e) if e.raw_os_error() == Some(libc::EXDEV) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn canonicalize_beneath(&self, path: impl AsRef<Path>) -> Result<PathBuf> {
+        let path = path.as_ref();
+        let r = resolve_beneath(self, path, MAX_SYMLINK_HOPS, false)?;
+        match r.end {
+            ResolutionEnd::Complete if r.path.as_os_str().is_empty() => Ok(".".into()),
+            ResolutionEnd::Complete => Ok(r.path),
+            ResolutionEnd::Escaped => Err(escape_error()),
+            ResolutionEnd::Looped => Err(io::Error::from_raw_os_error(libc::ELOOP)),
+            ResolutionEnd::NotFound => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No such file or directory: {}", r.path.display()),
+            )),
+        }
+    }
+
+    fn resolve_symlinks(&self, path: impl AsRef<Path>, max_hops: usize) -> Result<ResolvedPath> {
+        let r = resolve_beneath(self, path.as_ref(), max_hops, false)?;
+        Ok(ResolvedPath {
+            target: r.path,
+            chain: r.chain,
+            escaped: r.end == ResolutionEnd::Escaped,
+            looped: r.end == ResolutionEnd::Looped,
+            dangling: r.end == ResolutionEnd::NotFound,
+        })
+    }
+
+    fn resolve_alternatives(
+        &self,
+        paths: impl IntoIterator<Item = impl AsRef<Path>>,
+    ) -> Result<Vec<AlternativeLink>> {
+        paths
+            .into_iter()
+            .map(|path| {
+                let path = path.as_ref();
+                let is_symlink = self
+                    .symlink_metadata_optional(path)?
+                    .is_some_and(|m| m.is_symlink());
+                if !is_symlink {
+                    return Ok(AlternativeLink {
+                        path: path.to_owned(),
+                        target: None,
+                        resolved: None,
+                    });
+                }
+                let target = self.read_link_contents(path)?;
+                let resolved = match resolve_beneath(self, path, MAX_SYMLINK_HOPS, true) {
+                    Ok(r) if r.end == ResolutionEnd::Complete => Some(r.path),
+                    Ok(_) => None,
+                    // A non-directory was used as a directory in the target
+                    Err(e) if e.raw_os_error() == Some(libc::ENOTDIR) => None,
+                    Err(e) => return Err(e),
+                };
+                Ok(AlternativeLink {
+                    path: path.to_owned(),
+                    target: Some(target),
+                    resolved,
+                })
+            })
+            .collect()
     }
 
-    #[cfg(any(target_os = "android", target_os = "linux"))]
-    fn open_dir_rooted_ext(&self, path: impl AsRef<Path>) -> Result<crate::RootDir> {
-        crate::RootDir::new(self, path)
+    #[cfg(unix)]
+    fn find_broken_links(
+        &self,
+        path: impl AsRef<Path>,
+        resolution: LinkResolution,
+    ) -> Result<Vec<BrokenLink>> {
+        use cap_std::fs::MetadataExt;
+
+        let path = path.as_ref();
+        let rooted = resolution == LinkResolution::Rooted;
+        let d = self.open_dir(path)?;
+        let mut config = WalkConfiguration::default().noxdev();
+        if !path.as_os_str().is_empty() && path != Path::new(".") {
+            config = config.path_base(path);
+        }
+        let mut r = Vec::new();
+        d.walk(&config, |e| -> Result<_> {
+            let kind = if e.file_type.is_symlink() {
+                match resolve_beneath(self, e.path, MAX_SYMLINK_HOPS, rooted) {
+                    Ok(res) => match res.end {
+                        ResolutionEnd::Complete => None,
+                        ResolutionEnd::Escaped => Some(BrokenLinkKind::Escaped),
+                        ResolutionEnd::Looped => Some(BrokenLinkKind::Looped),
+                        ResolutionEnd::NotFound => Some(BrokenLinkKind::Dangling),
+                    },
+                    // A non-directory was used as a directory in the target
+                    Err(e) if e.raw_os_error() == Some(libc::ENOTDIR) => {
+                        Some(BrokenLinkKind::Dangling)
+                    }
+                    Err(e) => return Err(e),
+                }
+            } else if e.file_type.is_file() {
+                let m = e.dir.symlink_metadata(e.filename)?;
+                (m.nlink() > 1 && m.len() == 0).then_some(BrokenLinkKind::EmptyHardlink)
+            } else {
+                None
+            };
+            if let Some(kind) = kind {
+                r.push(BrokenLink {
+                    path: e.path.to_owned(),
+                    kind,
+                });
+            }
+            Ok(ControlFlow::Continue(()))
+        })?;
+        Ok(r)
     }
 
     fn ensure_dir_with(
@@ -364,6 +4225,261 @@ impl CapStdExtDirExt for Dir {
         }
     }
 
+    fn open_or_create_dir_all(
+        &self,
+        path: impl AsRef<Path>,
+        builder: &cap_std::fs::DirBuilder,
+    ) -> Result<Dir> {
+        // The number of times we will retry if a directory is concurrently removed.
+        const MAX_ATTEMPTS: u32 = 16;
+        let path = path.as_ref();
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            match self.open_dir_optional(path)? {
+                Some(d) => return Ok(d),
+                None if attempt >= MAX_ATTEMPTS => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("Directory concurrently removed: {}", path.display()),
+                    ))
+                }
+                None => {}
+            }
+            let mut prefix = PathBuf::new();
+            for c in path.components() {
+                prefix.push(c);
+                match self.ensure_dir_with(&prefix, builder) {
+                    Ok(_) => {}
+                    // A parent was removed; go back to the start.
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => break,
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+    }
+
+    fn create_unique_dir(&self, prefix: &str) -> Result<(String, Dir)> {
+        // The number of names we will try before giving up.
+        const MAX_ATTEMPTS: u32 = 128;
+        if prefix.contains(std::path::is_separator) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid prefix: {prefix}"),
+            ));
+        }
+        for _ in 0..MAX_ATTEMPTS {
+            let name = format!("{prefix}{}", unique_suffix());
+            match self.create_dir(&name) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+            let d = self.open_dir(&name)?;
+            return Ok((name, d));
+        }
+        Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("Failed to find a unique name with prefix: {prefix}"),
+        ))
+    }
+
+    fn tempdir_in_with_prefix(&self, prefix: &str) -> Result<NamedTempDir<'_>> {
+        let (name, dir) = self.create_unique_dir(prefix)?;
+        Ok(NamedTempDir {
+            parent: self,
+            name,
+            dir: Some(dir),
+        })
+    }
+
+    fn cleanup_stale_tempfiles(
+        &self,
+        prefix: &str,
+        older_than: std::time::Duration,
+    ) -> Result<usize> {
+        if prefix.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "An empty prefix would match all entries",
+            ));
+        }
+        let cutoff = std::time::SystemTime::now()
+            .checked_sub(older_than)
+            .unwrap_or(std::time::SystemTime::UNIX_EPOCH);
+        let mut removed = 0;
+        for entry in self.entries()? {
+            let entry = entry?;
+            let name = entry.file_name();
+            if !name.to_string_lossy().starts_with(prefix) {
+                continue;
+            }
+            let meta = match entry.metadata() {
+                Ok(m) => m,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            if meta.modified()?.into_std() >= cutoff {
+                continue;
+            }
+            let r = if meta.is_dir() {
+                self.remove_dir_all(&name)
+            } else if meta.is_file() {
+                self.remove_file(&name)
+            } else {
+                continue;
+            };
+            match r {
+                Ok(()) => removed += 1,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(removed)
+    }
+
+    #[cfg(unix)]
+    fn is_same_file(&self, a: impl AsRef<Path>, b: impl AsRef<Path>) -> Result<bool> {
+        Ok(is_same_metadata(&self.metadata(a)?, &self.metadata(b)?))
+    }
+
+    #[cfg(unix)]
+    fn is_same_file_nofollow(&self, a: impl AsRef<Path>, b: impl AsRef<Path>) -> Result<bool> {
+        Ok(is_same_metadata(
+            &self.symlink_metadata(a)?,
+            &self.symlink_metadata(b)?,
+        ))
+    }
+
+    #[cfg(unix)]
+    fn entries_snapshot(&self) -> Result<EntriesSnapshot> {
+        // Capture the timestamp before reading, so that a modification
+        // concurrent with the read is either seen or results in a change.
+        let now = std::time::SystemTime::now();
+        let generation = DirGeneration::new(&self.dir_metadata()?, now)?;
+        let mut names = self
+            .entries()?
+            .map(|e| e.map(|e| e.file_name()))
+            .collect::<Result<Vec<_>>>()?;
+        names.sort();
+        Ok(EntriesSnapshot { names, generation })
+    }
+
+    #[cfg(unix)]
+    fn has_changed_since(&self, generation: &DirGeneration) -> Result<bool> {
+        if generation.racy {
+            return Ok(true);
+        }
+        let current = DirGeneration::new(&self.dir_metadata()?, std::time::SystemTime::now())?;
+        Ok((current.dev, current.ino, current.modified)
+            != (generation.dev, generation.ino, generation.modified))
+    }
+
+    #[cfg(unix)]
+    fn create_or_open_owned(&self, path: impl AsRef<Path>) -> Result<File> {
+        use rustix::fd::AsFd;
+        use rustix::fs::{FileType, Mode, OFlags};
+        use rustix::io::Errno;
+        // The number of times we will retry if the file is concurrently removed.
+        const MAX_ATTEMPTS: u32 = 16;
+        let (d, name) = subdir_of(self, path.as_ref())?;
+        let flags = OFlags::RDWR | OFlags::NOFOLLOW | OFlags::NOCTTY | OFlags::CLOEXEC;
+        for _ in 0..MAX_ATTEMPTS {
+            match rustix::fs::openat(
+                &*d,
+                name,
+                flags | OFlags::CREATE | OFlags::EXCL,
+                Mode::from_raw_mode(0o600),
+            ) {
+                Ok(fd) => return Ok(File::from_std(fd.into())),
+                Err(Errno::EXIST) => {}
+                Err(e) => return Err(e.into()),
+            }
+            // Avoid blocking if this is e.g. a FIFO; we verify the type after opening.
+            let fd = match rustix::fs::openat(&*d, name, flags | OFlags::NONBLOCK, Mode::empty()) {
+                Ok(fd) => fd,
+                Err(Errno::NOENT) => continue,
+                Err(Errno::LOOP | Errno::ISDIR) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::PermissionDenied,
+                        "Refusing to use existing file: not a regular file",
+                    ))
+                }
+                Err(e) => return Err(e.into()),
+            };
+            verify_owned(fd.as_fd(), FileType::RegularFile)?;
+            rustix::fs::fcntl_setfl(&fd, OFlags::empty())?;
+            return Ok(File::from_std(fd.into()));
+        }
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("File concurrently removed: {}", path.as_ref().display()),
+        ))
+    }
+
+    #[cfg(unix)]
+    fn ensure_owned_dir(&self, path: impl AsRef<Path>) -> Result<Dir> {
+        use rustix::fd::AsFd;
+        use rustix::fs::{FileType, Mode, OFlags};
+        use rustix::io::Errno;
+        let (d, name) = subdir_of(self, path.as_ref())?;
+        match rustix::fs::mkdirat(&*d, name, Mode::from_raw_mode(0o700)) {
+            Ok(()) | Err(Errno::EXIST) => {}
+            Err(e) => return Err(e.into()),
+        }
+        let fd = match rustix::fs::openat(
+            &*d,
+            name,
+            OFlags::RDONLY | OFlags::DIRECTORY | OFlags::NOFOLLOW | OFlags::CLOEXEC,
+            Mode::empty(),
+        ) {
+            Ok(fd) => fd,
+            Err(Errno::LOOP | Errno::NOTDIR) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "Refusing to use existing file: not a directory",
+                ))
+            }
+            Err(e) => return Err(e.into()),
+        };
+        // Even a newly created directory is verified, as another user may have
+        // created it between the mkdir and the open.
+        verify_owned(fd.as_fd(), FileType::Directory)?;
+        Ok(Dir::from_std_file(fd.into()))
+    }
+
+    #[cfg(not(windows))]
+    fn lockfile(
+        &self,
+        path: impl AsRef<Path>,
+        mode: crate::fileext::LockMode,
+    ) -> Result<crate::fileext::LockFile> {
+        use crate::fileext::{LockFile, LockWait};
+        // SAFETY(unwrap): A blocking lock is always acquired on success.
+        LockFile::acquire(self, path.as_ref(), mode, LockWait::Forever).map(|v| v.unwrap())
+    }
+
+    #[cfg(not(windows))]
+    fn try_lockfile(
+        &self,
+        path: impl AsRef<Path>,
+        mode: crate::fileext::LockMode,
+    ) -> Result<Option<crate::fileext::LockFile>> {
+        use crate::fileext::{LockFile, LockWait};
+        LockFile::acquire(self, path.as_ref(), mode, LockWait::Never)
+    }
+
+    #[cfg(not(windows))]
+    fn lockfile_timeout(
+        &self,
+        path: impl AsRef<Path>,
+        mode: crate::fileext::LockMode,
+        timeout: std::time::Duration,
+    ) -> Result<Option<crate::fileext::LockFile>> {
+        use crate::fileext::{LockFile, LockWait};
+        LockFile::acquire(self, path.as_ref(), mode, LockWait::timeout(timeout))
+    }
+
     fn metadata_optional(&self, path: impl AsRef<Path>) -> Result<Option<Metadata>> {
         map_optional(self.metadata(path.as_ref()))
     }
@@ -397,6 +4513,21 @@ impl CapStdExtDirExt for Dir {
         Ok(true)
     }
 
+    #[cfg(unix)]
+    fn find_executable(
+        &self,
+        name: impl AsRef<Path>,
+        search_paths: impl IntoIterator<Item = impl AsRef<Path>>,
+    ) -> Result<Option<PathBuf>> {
+        use cap_std::fs::{OpenOptions, OpenOptionsExt};
+        // Don't block on e.g. a FIFO
+        let mut opts = OpenOptions::new();
+        opts.read(true).custom_flags(libc::O_NONBLOCK);
+        find_executable_with(name.as_ref(), search_paths, |candidate| {
+            self.open_with(candidate, &opts).map(File::into_std)
+        })
+    }
+
     #[cfg(unix)]
     fn update_timestamps(&self, path: impl AsRef<Path>) -> Result<()> {
         use rustix::fd::AsFd;
@@ -428,19 +4559,44 @@ impl CapStdExtDirExt for Dir {
         destname: impl AsRef<Path>,
         f: F,
     ) -> std::result::Result<T, E>
+    where
+        F: FnOnce(&mut std::io::BufWriter<cap_tempfile::TempFile>) -> std::result::Result<T, E>,
+        E: From<std::io::Error>,
+    {
+        self.atomic_replace_with_options(destname, &AtomicWriteOptions::default(), f)
+    }
+
+    fn atomic_replace_with_options<F, T, E>(
+        &self,
+        destname: impl AsRef<Path>,
+        options: &AtomicWriteOptions,
+        f: F,
+    ) -> std::result::Result<T, E>
     where
         F: FnOnce(&mut std::io::BufWriter<cap_tempfile::TempFile>) -> std::result::Result<T, E>,
         E: From<std::io::Error>,
     {
         let destname = destname.as_ref();
         let (d, name) = subdir_of(self, destname)?;
-        let existing_metadata = d.symlink_metadata_optional(destname)?;
+        let existing_metadata = d.symlink_metadata_optional(name)?;
         // If the target is already a file, then acquire its mode, which we will preserve by default.
         // We don't follow symlinks here for replacement, and so we definitely don't want to pick up its mode.
         let existing_perms = existing_metadata
+            .as_ref()
             .filter(|m| m.is_file())
             .map(|m| m.permissions());
-        let mut t = cap_tempfile::TempFile::new(&d)?;
+        #[cfg(any(target_os = "android", target_os = "linux"))]
+        let scratch_dir = options.scratch_dir.as_ref();
+        #[cfg(not(any(target_os = "android", target_os = "linux")))]
+        let scratch_dir: Option<&Dir> = None;
+        #[cfg(unix)]
+        if let Some(scratch) = scratch_dir {
+            use cap_std::fs::MetadataExt;
+            if scratch.dir_metadata()?.dev() != d.dir_metadata()?.dev() {
+                return Err(io::Error::from_raw_os_error(libc::EXDEV).into());
+            }
+        }
+        let mut t = cap_tempfile::TempFile::new(scratch_dir.unwrap_or(&d))?;
         // Apply the permissions, if we have them
         if let Some(existing_perms) = existing_perms {
             t.as_file_mut().set_permissions(existing_perms)?;
@@ -450,9 +4606,17 @@ impl CapStdExtDirExt for Dir {
         // Call the provided closure to generate the file content
         let r = f(&mut bufw)?;
         // Flush the buffer, and rename the temporary file into place
-        bufw.into_inner()
-            .map_err(From::from)
-            .and_then(|t| t.replace(name))?;
+        let t = bufw.into_inner().map_err(From::from)?;
+        sync_file(t.as_file(), options.durability)?;
+        if options.backups > 0 && existing_metadata.is_some_and(|m| !m.is_dir()) {
+            rotate_backups(&d, name, options.backups)?;
+        }
+        match scratch_dir {
+            #[cfg(any(target_os = "android", target_os = "linux"))]
+            Some(scratch) => replace_from_scratch(t.as_file(), scratch, &d, name)?,
+            _ => t.replace(name)?,
+        }
+        sync_dir(&d, options.durability)?;
         Ok(r)
     }
 
@@ -460,6 +4624,106 @@ impl CapStdExtDirExt for Dir {
         self.atomic_replace_with(destname, |f| f.write_all(contents.as_ref()))
     }
 
+    fn atomic_write_vectored(
+        &self,
+        destname: impl AsRef<Path>,
+        bufs: &[io::IoSlice],
+    ) -> Result<()> {
+        // Nothing has been buffered yet, so write to the file directly.
+        self.atomic_replace_with(destname, |f| write_all_vectored(f.get_mut(), bufs))
+    }
+
+    fn atomic_write_from_reader(
+        &self,
+        destname: impl AsRef<Path>,
+        mut reader: impl io::Read,
+        size_hint: Option<u64>,
+    ) -> Result<u64> {
+        self.atomic_replace_with(destname, |f| {
+            #[cfg(any(target_os = "android", target_os = "linux"))]
+            if let Some(size) = size_hint.filter(|&n| n > 0) {
+                use rustix::fs::FallocateFlags;
+                // This is only an optimization, and not all filesystems support it.
+                let _ = rustix::fs::fallocate(
+                    f.get_ref().as_file(),
+                    FallocateFlags::KEEP_SIZE,
+                    0,
+                    size,
+                );
+            }
+            #[cfg(not(any(target_os = "android", target_os = "linux")))]
+            let _ = size_hint;
+            io::copy(&mut reader, f)
+        })
+    }
+
+    fn edit_file<F>(&self, path: impl AsRef<Path>, f: F) -> Result<bool>
+    where
+        F: FnOnce(Option<Vec<u8>>) -> Result<Option<Vec<u8>>>,
+    {
+        let path = path.as_ref();
+        let existing = match self.open_optional(path)? {
+            Some(mut fd) => {
+                let mut buf = Vec::new();
+                io::Read::read_to_end(&mut fd, &mut buf)?;
+                Some(buf)
+            }
+            None => None,
+        };
+        match f(existing)? {
+            Some(contents) => {
+                self.atomic_write(path, contents)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    fn read_json_optional<T: serde::de::DeserializeOwned>(
+        &self,
+        path: impl AsRef<Path>,
+    ) -> Result<Option<T>> {
+        let Some(f) = self.open_buffered_optional(path)? else {
+            return Ok(None);
+        };
+        serde_json::from_reader(f)
+            .map(Some)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    #[cfg(feature = "serde")]
+    fn read_toml_optional<T: serde::de::DeserializeOwned>(
+        &self,
+        path: impl AsRef<Path>,
+    ) -> Result<Option<T>> {
+        let Some(mut f) = self.open_optional(path)? else {
+            return Ok(None);
+        };
+        let mut buf = String::new();
+        io::Read::read_to_string(&mut f, &mut buf)?;
+        toml::from_str(&buf)
+            .map(Some)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    #[cfg(feature = "serde")]
+    fn atomic_write_json<T: serde::Serialize + ?Sized>(
+        &self,
+        path: impl AsRef<Path>,
+        value: &T,
+        pretty: bool,
+    ) -> Result<()> {
+        self.atomic_replace_with(path, |f| -> io::Result<_> {
+            if pretty {
+                serde_json::to_writer_pretty(&mut *f, value)?;
+            } else {
+                serde_json::to_writer(&mut *f, value)?;
+            }
+            f.write_all(b"\n")
+        })
+    }
+
     fn atomic_write_with_perms(
         &self,
         destname: impl AsRef<Path>,
@@ -483,8 +4747,385 @@ impl CapStdExtDirExt for Dir {
         })
     }
 
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn atomic_replace_dir_with<F, T, E>(
+        &self,
+        destname: impl AsRef<Path>,
+        f: F,
+    ) -> std::result::Result<T, E>
+    where
+        F: FnOnce(&Dir) -> std::result::Result<T, E>,
+        E: From<std::io::Error>,
+    {
+        use rustix::fs::RenameFlags;
+
+        let destname = destname.as_ref();
+        let (d, name) = subdir_of(self, destname)?;
+        let not_dir = || io::Error::from_raw_os_error(libc::ENOTDIR);
+        if d.symlink_metadata_optional(name)?
+            .is_some_and(|m| !m.is_dir())
+        {
+            return Err(not_dir().into());
+        }
+        let prefix = format!(".{}.tmp-", name.to_string_lossy());
+        let (tmpname, staging) = d.create_unique_dir(&prefix)?;
+        let r = match f(&staging) {
+            Ok(r) => r,
+            Err(e) => {
+                let _ = d.remove_dir_all(&tmpname);
+                return Err(e);
+            }
+        };
+        drop(staging);
+        let promote = |flags| rustix::fs::renameat_with(&*d, &tmpname, &*d, name, flags);
+        match promote(RenameFlags::NOREPLACE) {
+            Ok(()) => return Ok(r),
+            Err(rustix::io::Errno::EXIST) => {}
+            Err(e) => {
+                let _ = d.remove_dir_all(&tmpname);
+                return Err(io::Error::from(e).into());
+            }
+        }
+        if let Err(e) = promote(RenameFlags::EXCHANGE) {
+            let _ = d.remove_dir_all(&tmpname);
+            return Err(io::Error::from(e).into());
+        }
+        // The previous contents are now at the temporary name.  If the destination
+        // was concurrently replaced by a non-directory, put it back.
+        if !d.symlink_metadata(&tmpname)?.is_dir() {
+            promote(RenameFlags::EXCHANGE).map_err(io::Error::from)?;
+            d.remove_dir_all(&tmpname)?;
+            return Err(not_dir().into());
+        }
+        d.remove_dir_all(&tmpname)?;
+        Ok(r)
+    }
+
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn write_nofollow(&self, path: impl AsRef<Path>, contents: impl AsRef<[u8]>) -> Result<()> {
+        use rustix::fs::OFlags;
+        let mut f = self.open_with_oflags(
+            path,
+            OFlags::WRONLY | OFlags::CREATE | OFlags::TRUNC | OFlags::NOFOLLOW,
+        )?;
+        f.write_all(contents.as_ref())
+    }
+
+    fn atomic_write_nofollow(
+        &self,
+        destname: impl AsRef<Path>,
+        contents: impl AsRef<[u8]>,
+    ) -> Result<()> {
+        let destname = destname.as_ref();
+        let check = || -> Result<()> {
+            match self.symlink_metadata_optional(destname)? {
+                Some(m) if m.is_symlink() => Err(io::Error::from_raw_os_error(libc::ELOOP)),
+                _ => Ok(()),
+            }
+        };
+        check()?;
+        self.atomic_replace_with(destname, |f| {
+            f.write_all(contents.as_ref())?;
+            // Check again just before the rename, in case a link was
+            // created while we were writing.
+            check()
+        })
+    }
+
+    #[cfg(any(target_os = "android", target_os = "linux"))]
     fn is_mountpoint(&self, path: impl AsRef<Path>) -> Result<Option<bool>> {
-        is_mountpoint_impl_statx(self, path.as_ref()).map_err(Into::into)
+        is_mountpoint_impl_statx(self, path.as_ref())
+    }
+
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn statx(&self, path: impl AsRef<Path>, flags: rustix::fs::AtFlags) -> Result<StatxInfo> {
+        statx_impl(self, path.as_ref(), flags)
+    }
+
+    #[cfg(unix)]
+    fn rewrite_shebangs(
+        &self,
+        path: impl AsRef<Path>,
+        mapping: &std::collections::HashMap<PathBuf, PathBuf>,
+    ) -> Result<Vec<PathBuf>> {
+        use std::io::Read;
+        use std::os::unix::ffi::OsStrExt;
+
+        let path = path.as_ref();
+        let d = self.open_dir(path)?;
+        let replacement_for = |buf: &[u8]| {
+            let r = parse_shebang(buf)?;
+            let interp = Path::new(OsStr::from_bytes(&buf[r.clone()]));
+            mapping.get(interp).map(|v| (r, v))
+        };
+        // Gather the files to rewrite first, so that we aren't creating
+        // temporary files in directories as we iterate over them.
+        let mut targets = Vec::new();
+        d.walk(
+            &WalkConfiguration::default(),
+            |e| -> WalkResult<io::Error> {
+                if !e.file_type.is_file() {
+                    return Ok(ControlFlow::Continue(()));
+                }
+                let mut buf = Vec::with_capacity(SHEBANG_MAX);
+                e.dir
+                    .open(e.filename)?
+                    .take(SHEBANG_MAX as u64)
+                    .read_to_end(&mut buf)?;
+                if replacement_for(&buf).is_some() {
+                    targets.push(e.path.to_owned());
+                }
+                Ok(ControlFlow::Continue(()))
+            },
+        )?;
+        let mut rewritten = Vec::new();
+        for target in targets {
+            let buf = d.read(&target)?;
+            // The file may have been changed concurrently
+            let Some((r, replacement)) = replacement_for(&buf) else {
+                continue;
+            };
+            d.atomic_replace_with(&target, |w| -> Result<()> {
+                w.write_all(&buf[..r.start])?;
+                w.write_all(replacement.as_os_str().as_bytes())?;
+                w.write_all(&buf[r.end..])
+            })?;
+            rewritten.push(path.join(target));
+        }
+        Ok(rewritten)
+    }
+
+    #[cfg(unix)]
+    fn rewrite_symlinks(
+        &self,
+        path: impl AsRef<Path>,
+        style: SymlinkStyle,
+    ) -> Result<Vec<PathBuf>> {
+        // The location of the links must not itself depend on symbolic links.
+        let path = self.canonicalize_beneath(path)?;
+        let d = self.open_dir(&path)?;
+        let base = if path == Path::new(".") {
+            PathBuf::new()
+        } else {
+            path
+        };
+        // Gather the links to rewrite first, so that we aren't creating
+        // temporary links in directories as we iterate over them.
+        let mut targets = Vec::new();
+        d.walk(
+            &WalkConfiguration::default().path_base(&base),
+            |e| -> WalkResult<io::Error> {
+                if !e.file_type.is_symlink() {
+                    return Ok(ControlFlow::Continue(()));
+                }
+                let target = e.dir.read_link_contents(e.filename)?;
+                // SAFETY(unwrap): Paths from a walk always have a parent.
+                let parent = e.path.parent().unwrap();
+                if let Some(new_target) = restyle_link_target(parent, &target, style) {
+                    targets.push((e.path.to_owned(), new_target));
+                }
+                Ok(ControlFlow::Continue(()))
+            },
+        )?;
+        let mut rewritten = Vec::new();
+        for (link, target) in targets {
+            // SAFETY(unwrap): Paths from a walk always have a parent and file name.
+            let parent = link.parent().unwrap();
+            let name = link.file_name().unwrap();
+            let mut tmpname = std::ffi::OsString::from(".");
+            tmpname.push(name);
+            tmpname.push(format!(".tmp-{}", unique_suffix()));
+            let tmp = parent.join(tmpname);
+            self.symlink_contents(&target, &tmp)?;
+            if let Err(e) = self.rename(&tmp, self, &link) {
+                let _ = self.remove_file(&tmp);
+                return Err(e);
+            }
+            rewritten.push(link);
+        }
+        Ok(rewritten)
+    }
+
+    #[cfg(all(feature = "watch", any(target_os = "android", target_os = "linux")))]
+    fn watch(&self, config: &crate::watch::WatchConfig) -> Result<crate::watch::Watcher> {
+        crate::watch::Watcher::new(self, config)
+    }
+
+    #[cfg(all(feature = "fanotify", any(target_os = "android", target_os = "linux")))]
+    fn fanotify(
+        &self,
+        config: &crate::fanotify::FanotifyConfig,
+    ) -> Result<crate::fanotify::FanotifyWatcher> {
+        crate::fanotify::FanotifyWatcher::new(self, config)
+    }
+
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn readahead(&self, path: impl AsRef<Path>, offset: u64, len: usize) -> Result<()> {
+        use rustix::fd::AsFd;
+
+        let f = self.open(path.as_ref())?;
+        readahead_impl(f.as_fd(), offset, len)
+    }
+
+    fn read_exact_to_vec(&self, path: impl AsRef<Path>) -> Result<Vec<u8>> {
+        let mut f = self.open(path.as_ref())?;
+        let len = usize::try_from(f.metadata()?.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "File too large"))?;
+        let mut buf = vec![0u8; len];
+        let mut pos = 0;
+        while pos < len {
+            #[cfg(unix)]
+            let r = rustix::io::pread(&f, &mut buf[pos..], pos as u64).map_err(io::Error::from);
+            #[cfg(not(unix))]
+            let r = io::Read::read(&mut f, &mut buf[pos..]);
+            match r {
+                Ok(0) => break,
+                Ok(n) => pos += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        buf.truncate(pos);
+        // The file may have grown since we checked its size.
+        if pos == len {
+            #[cfg(unix)]
+            io::Seek::seek(&mut f, io::SeekFrom::Start(pos as u64))?;
+            io::Read::read_to_end(&mut f, &mut buf)?;
+        }
+        Ok(buf)
+    }
+
+    #[cfg(all(feature = "mmap", not(windows)))]
+    #[allow(unsafe_code)]
+    unsafe fn map_readonly(&self, path: impl AsRef<Path>) -> Result<crate::mmap::Mmap> {
+        let f = self.open(path.as_ref())?;
+        // SAFETY: This has the same requirements as our caller.
+        unsafe { crate::mmap::Mmap::map(&f) }
+    }
+
+    #[cfg(feature = "digest")]
+    fn file_digest(
+        &self,
+        path: impl AsRef<Path>,
+        algorithm: crate::digest::Algorithm,
+    ) -> Result<crate::digest::Digest> {
+        crate::digest::digest_reader(self.open(path.as_ref())?, algorithm)
+    }
+
+    #[cfg(feature = "digest")]
+    fn tree_digests(
+        &self,
+        algorithm: crate::digest::Algorithm,
+    ) -> Result<std::collections::BTreeMap<PathBuf, crate::digest::Digest>> {
+        let mut r = std::collections::BTreeMap::new();
+        self.walk(
+            &WalkConfiguration::default().sort_by_file_name(),
+            |e| -> Result<_> {
+                if e.file_type.is_file() {
+                    let digest = e.dir.file_digest(e.filename, algorithm)?;
+                    r.insert(e.path.to_owned(), digest);
+                }
+                Ok(ControlFlow::Continue(()))
+            },
+        )?;
+        Ok(r)
+    }
+
+    #[cfg(not(windows))]
+    fn transaction(&self) -> Result<crate::transaction::DirTransaction<'_>> {
+        crate::transaction::DirTransaction::new(self)
+    }
+
+    #[cfg(not(windows))]
+    fn find(&self) -> crate::query::Query<'_> {
+        crate::query::Query::new(self)
+    }
+
+    fn subdirs(&self) -> Result<Subdirs> {
+        Ok(Subdirs {
+            entries: self.entries()?,
+        })
+    }
+
+    fn entries_sorted(&self) -> Result<std::vec::IntoIter<DirEntry>> {
+        self.entries_sorted_by(|a, b| a.file_name().cmp(&b.file_name()))
+    }
+
+    fn entries_sorted_by<F>(&self, cmp: F) -> Result<std::vec::IntoIter<DirEntry>>
+    where
+        F: FnMut(&DirEntry, &DirEntry) -> Ordering,
+    {
+        let mut entries = self.entries()?.collect::<Result<Vec<_>>>()?;
+        entries.sort_by(cmp);
+        Ok(entries.into_iter())
+    }
+
+    fn walk<C, E>(&self, config: &WalkConfiguration, callback: C) -> std::result::Result<(), E>
+    where
+        C: FnMut(&WalkComponent) -> WalkResult<E>,
+        E: From<std::io::Error>,
+    {
+        walk_dir(self, config, false, callback)
+    }
+
+    fn walk_parallel<C, E>(
+        &self,
+        config: &WalkConfiguration,
+        callback: C,
+    ) -> std::result::Result<(), E>
+    where
+        C: Fn(&WalkComponent) -> WalkResult<E> + Sync,
+        E: From<std::io::Error> + Send,
+    {
+        if config.sorter.is_some() || config.contents_first {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Sorting and contents-first order are not supported in a parallel walk",
+            )
+            .into());
+        }
+        let path_base = config.path_base.unwrap_or(Path::new(""));
+        let mut ancestors = Vec::new();
+        #[cfg(unix)]
+        if config.follow_symlinks {
+            ancestors.push(dir_id(self)?);
+        }
+        if config.include_root {
+            let meta = self.dir_metadata()?;
+            if callback(&root_component(self, path_base, &meta, config))?.is_break() {
+                return Ok(());
+            }
+        }
+        let walk = ParallelWalk {
+            root: self,
+            noxdev: config.noxdev,
+            path_base,
+            follow_symlinks: config.follow_symlinks,
+            metadata: config.metadata,
+            filter: &config.filter,
+            hardlinks: config.track_hardlinks.then(Default::default),
+            callback,
+            queue: Default::default(),
+            cond: Default::default(),
+            stop: Default::default(),
+            error: Default::default(),
+        };
+        walk.push(ParallelWalkJob {
+            dir: self.try_clone()?,
+            path: path_base.to_owned(),
+            ancestors,
+        });
+        let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+        std::thread::scope(|s| {
+            for _ in 0..threads {
+                s.spawn(|| walk.worker());
+            }
+        });
+        // SAFETY(unwrap): All threads have exited without panicking.
+        match walk.error.into_inner().unwrap() {
+            Some((_, e)) => Err(e),
+            None => Ok(()),
+        }
     }
 }
 
@@ -0,0 +1,312 @@
+//! Watching a whole mount or filesystem for changes using `fanotify`.
+//!
+//! Unlike [`crate::watch`], this observes every file on the mount (or filesystem)
+//! containing a directory, without needing a watch per directory; however it
+//! requires `CAP_SYS_ADMIN`.  Events are translated back to paths beneath the
+//! provided directory, where possible.
+//!
+//! The key API here is [`crate::dirext::CapStdExtDirExt::fanotify`].
+
+use std::io::{self, Result};
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use cap_std::fs::Dir;
+use cap_tempfile::cap_std;
+use rustix::fs::{Mode, OFlags};
+
+/// A type of event reported by `fanotify`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum FanotifyEventKind {
+    /// A file was read.
+    Access,
+    /// A file was modified.
+    Modify,
+    /// A file which was opened for writing was closed.
+    CloseWrite,
+    /// A file which was opened read-only was closed.
+    CloseNoWrite,
+    /// A file was opened.
+    Open,
+    /// A file is being opened; this is a permission event, which requires
+    /// [`FanotifyConfig::permission_events`].
+    OpenPermission,
+    /// A file is being read; this is a permission event, which requires
+    /// [`FanotifyConfig::permission_events`].
+    AccessPermission,
+}
+
+impl FanotifyEventKind {
+    fn mask(self) -> u64 {
+        match self {
+            Self::Access => libc::FAN_ACCESS,
+            Self::Modify => libc::FAN_MODIFY,
+            Self::CloseWrite => libc::FAN_CLOSE_WRITE,
+            Self::CloseNoWrite => libc::FAN_CLOSE_NOWRITE,
+            Self::Open => libc::FAN_OPEN,
+            Self::OpenPermission => libc::FAN_OPEN_PERM,
+            Self::AccessPermission => libc::FAN_ACCESS_PERM,
+        }
+    }
+
+    fn is_permission(self) -> bool {
+        matches!(self, Self::OpenPermission | Self::AccessPermission)
+    }
+}
+
+/// Configuration for [`crate::dirext::CapStdExtDirExt::fanotify`].
+#[derive(Debug, Clone, Default)]
+pub struct FanotifyConfig {
+    filesystem: bool,
+    permission: bool,
+    events: Vec<FanotifyEventKind>,
+}
+
+impl FanotifyConfig {
+    /// Watch the entire filesystem containing the directory (`FAN_MARK_FILESYSTEM`),
+    /// instead of only the mount (`FAN_MARK_MOUNT`).
+    pub fn filesystem(mut self) -> Self {
+        self.filesystem = true;
+        self
+    }
+
+    /// Add a type of event to watch for.
+    pub fn event(mut self, kind: FanotifyEventKind) -> Self {
+        self.events.push(kind);
+        self
+    }
+
+    /// Allow permission events.  For these, the accessing process is blocked until
+    /// a response is given via [`FanotifyEvent::respond`]; a slow or stuck watcher
+    /// will therefore block access to files across the whole mount.
+    pub fn permission_events(mut self) -> Self {
+        self.permission = true;
+        self
+    }
+}
+
+/// An event reported by a [`FanotifyWatcher`].
+///
+/// If this is a permission event and it is dropped without calling [`Self::respond`],
+/// access is allowed.
+#[derive(Debug)]
+pub struct FanotifyEvent {
+    /// The path of the file relative to the watched directory, or `None` if it
+    /// is not beneath the directory (or could not be determined).
+    pub path: Option<PathBuf>,
+    /// The process which caused the event.
+    pub pid: i32,
+    mask: u64,
+    file: OwnedFd,
+    /// Set for permission events which have not yet been responded to.
+    pending: Option<Arc<OwnedFd>>,
+}
+
+impl FanotifyEvent {
+    /// Returns `true` if the event includes the given kind; multiple
+    /// events for the same file may be merged by the kernel.
+    pub fn is(&self, kind: FanotifyEventKind) -> bool {
+        self.mask & kind.mask() != 0
+    }
+
+    /// An open file descriptor for the file the event refers to.
+    pub fn file(&self) -> BorrowedFd<'_> {
+        self.file.as_fd()
+    }
+
+    /// Returns `true` if this is a permission event which requires a response.
+    pub fn is_permission(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    /// Allow or deny a permission event.  For other events, this does nothing.
+    pub fn respond(mut self, allow: bool) -> Result<()> {
+        self.respond_impl(allow)
+    }
+
+    #[allow(unsafe_code)]
+    fn respond_impl(&mut self, allow: bool) -> Result<()> {
+        let Some(fanotify) = self.pending.take() else {
+            return Ok(());
+        };
+        let response = libc::fanotify_response {
+            fd: self.file.as_raw_fd(),
+            response: if allow {
+                libc::FAN_ALLOW
+            } else {
+                libc::FAN_DENY
+            },
+        };
+        // SAFETY: The response is a plain C struct, which is the expected input.
+        let buf = unsafe {
+            std::slice::from_raw_parts(
+                (&response as *const libc::fanotify_response).cast::<u8>(),
+                std::mem::size_of::<libc::fanotify_response>(),
+            )
+        };
+        rustix::io::write(&*fanotify, buf)?;
+        Ok(())
+    }
+}
+
+impl Drop for FanotifyEvent {
+    fn drop(&mut self) {
+        let _ = self.respond_impl(true);
+    }
+}
+
+/// A `fanotify` instance; see [`crate::dirext::CapStdExtDirExt::fanotify`].
+#[derive(Debug)]
+pub struct FanotifyWatcher {
+    fanotify: Arc<OwnedFd>,
+    root: Dir,
+    buf: Vec<u8>,
+}
+
+/// Resolve the path of an open file descriptor via procfs.
+fn fd_path(fd: BorrowedFd) -> Result<PathBuf> {
+    std::fs::read_link(crate::audit::proc_self_fd(fd)?)
+}
+
+impl FanotifyWatcher {
+    #[allow(unsafe_code)]
+    pub(crate) fn new(d: &Dir, config: &FanotifyConfig) -> Result<Self> {
+        let mut mask = 0;
+        for &kind in &config.events {
+            if kind.is_permission() && !config.permission {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Permission events were not enabled",
+                ));
+            }
+            mask |= kind.mask();
+        }
+        let class = if config.permission {
+            libc::FAN_CLASS_CONTENT
+        } else {
+            libc::FAN_CLASS_NOTIF
+        };
+        let event_flags = libc::O_RDONLY | libc::O_CLOEXEC | libc::O_LARGEFILE;
+        // SAFETY: This has no memory safety requirements.
+        let fd = unsafe { libc::fanotify_init(libc::FAN_CLOEXEC | class, event_flags as u32) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: The kernel returned a new file descriptor which we now own.
+        let fanotify = unsafe { OwnedFd::from_raw_fd(fd) };
+        let scope = if config.filesystem {
+            libc::FAN_MARK_FILESYSTEM
+        } else {
+            libc::FAN_MARK_MOUNT
+        };
+        // The kernel does not accept an `O_PATH` file descriptor here, which
+        // the existing one may be.
+        let dfd = rustix::fs::openat(
+            d.as_fd(),
+            ".",
+            OFlags::RDONLY | OFlags::DIRECTORY | OFlags::CLOEXEC,
+            Mode::empty(),
+        )?;
+        // SAFETY: The file descriptors are valid, and a NULL path means that
+        // the directory file descriptor itself is marked.
+        let r = unsafe {
+            libc::fanotify_mark(
+                fanotify.as_raw_fd(),
+                libc::FAN_MARK_ADD | scope,
+                mask,
+                dfd.as_raw_fd(),
+                std::ptr::null(),
+            )
+        };
+        if r < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            fanotify: Arc::new(fanotify),
+            root: d.try_clone()?,
+            buf: vec![0; 64 * 1024],
+        })
+    }
+
+    /// Read at least one event, blocking until available.  The file descriptor
+    /// (via [`AsFd`]) can be used with e.g. `poll` to wait for events.
+    ///
+    /// A queue overflow results in an error of kind [`std::io::ErrorKind::Other`].
+    /// On any error, the events which were read are dropped, which allows any
+    /// permission requests among them.
+    #[allow(unsafe_code)]
+    pub fn read_events(&mut self) -> Result<Vec<FanotifyEvent>> {
+        // Determine this each time, as the directory may have been moved.  This
+        // must happen before reading, as nothing may fail between reading events
+        // and taking ownership of their file descriptors.
+        let root = fd_path(self.root.as_fd())?;
+        let n = loop {
+            match rustix::io::read(&*self.fanotify, &mut self.buf) {
+                Ok(n) => break n,
+                Err(rustix::io::Errno::INTR) => continue,
+                Err(e) => return Err(e.into()),
+            }
+        };
+        let metadata_len = std::mem::size_of::<libc::fanotify_event_metadata>();
+        let mut events = Vec::new();
+        let mut error = None;
+        let mut offset = 0;
+        while offset + metadata_len <= n {
+            // SAFETY: We checked that the buffer contains enough bytes, and
+            // the struct has no invalid bit patterns.
+            let meta = unsafe {
+                std::ptr::read_unaligned(
+                    self.buf[offset..]
+                        .as_ptr()
+                        .cast::<libc::fanotify_event_metadata>(),
+                )
+            };
+            // We cannot interpret the rest of the buffer in these cases.
+            if meta.vers != libc::FANOTIFY_METADATA_VERSION {
+                error = Some("Unsupported fanotify metadata version");
+                break;
+            }
+            let event_len = meta.event_len as usize;
+            if event_len < metadata_len || offset + event_len > n {
+                error = Some("Invalid fanotify event length");
+                break;
+            }
+            offset += event_len;
+            if meta.mask & libc::FAN_Q_OVERFLOW != 0 {
+                error.get_or_insert("fanotify event queue overflow");
+                continue;
+            }
+            if meta.fd == libc::FAN_NOFD {
+                continue;
+            }
+            // SAFETY: The kernel gave us a new file descriptor which we now own.
+            let file = unsafe { OwnedFd::from_raw_fd(meta.fd) };
+            let is_permission = meta.mask & (libc::FAN_OPEN_PERM | libc::FAN_ACCESS_PERM) != 0;
+            events.push(FanotifyEvent {
+                path: None,
+                pid: meta.pid,
+                mask: meta.mask,
+                file,
+                pending: is_permission.then(|| Arc::clone(&self.fanotify)),
+            });
+        }
+        if let Some(msg) = error {
+            // Dropping the events allows any permission requests.
+            return Err(io::Error::new(io::ErrorKind::Other, msg));
+        }
+        for event in events.iter_mut() {
+            event.path = fd_path(event.file.as_fd())
+                .ok()
+                .and_then(|p| p.strip_prefix(&root).ok().map(Path::to_owned));
+        }
+        Ok(events)
+    }
+}
+
+impl AsFd for FanotifyWatcher {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fanotify.as_fd()
+    }
+}
@@ -0,0 +1,356 @@
+//! Extensions for [`cap_std::fs::File`].
+//!
+//! The key APIs here are:
+//!
+//! - Advisory whole-file locking, with guards that unlock on drop, and
+//!   optional timeouts
+//! - Lock files (see [`crate::dirext::CapStdExtDirExt::lockfile`])
+//!
+//! [`cap_std::fs::File`]: https://docs.rs/cap-std/latest/cap_std/fs/struct.File.html
+
+use cap_std::fs::{Dir, File};
+use cap_tempfile::cap_std;
+use rustix::fd::{AsFd, BorrowedFd};
+use std::ffi::OsString;
+use std::io::{self, Result, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// The type of an advisory lock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockMode {
+    /// A shared (read) lock; multiple holders are permitted.
+    Shared,
+    /// An exclusive (write) lock.
+    Exclusive,
+}
+
+/// How long to wait for a conflicting lock to be released.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum LockWait {
+    /// Block until the lock is available.
+    Forever,
+    /// Fail immediately.
+    Never,
+    /// Poll until the deadline passes.
+    Until(Instant),
+}
+
+impl LockWait {
+    pub(crate) fn timeout(timeout: Duration) -> Self {
+        // An overflowing deadline is effectively forever
+        Instant::now()
+            .checked_add(timeout)
+            .map_or(Self::Forever, Self::Until)
+    }
+}
+
+/// The mechanism used to acquire a lock, which must also be used to release it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum LockKind {
+    /// Open file description locks (`F_OFD_SETLK`).
+    #[cfg(target_os = "linux")]
+    Ofd,
+    /// `flock(2)` locks.
+    Flock,
+}
+
+/// A held advisory lock on a file; the lock is released when this is dropped.
+#[derive(Debug)]
+#[must_use = "the lock is released when the guard is dropped"]
+pub struct FileLockGuard<'f> {
+    fd: BorrowedFd<'f>,
+    mode: LockMode,
+    kind: LockKind,
+}
+
+impl<'f> FileLockGuard<'f> {
+    /// The mode of the lock which is held.
+    pub fn mode(&self) -> LockMode {
+        self.mode
+    }
+
+    /// Release the lock, returning any error which occurs.  Dropping the guard
+    /// also releases the lock, but ignores errors.
+    pub fn unlock(self) -> Result<()> {
+        let r = unlock(self.fd, self.kind);
+        std::mem::forget(self);
+        r
+    }
+}
+
+impl<'f> Drop for FileLockGuard<'f> {
+    fn drop(&mut self) {
+        let _ = unlock(self.fd, self.kind);
+    }
+}
+
+/// Extension trait for [`cap_std::fs::File`] providing advisory whole-file locks.
+///
+/// On Linux, open file description locks (`F_OFD_SETLK`) are used; these are associated
+/// with the open file (like `flock(2)`) rather than the process, so they are not released
+/// when an unrelated file descriptor for the same file is closed, and they conflict
+/// between threads which open the file separately.  They also interoperate with
+/// traditional POSIX record locks.  If the kernel does not support them, or on other
+/// operating systems, `flock(2)` is used instead.
+///
+/// As with POSIX record locks, a shared lock requires the file to be open for reading,
+/// and an exclusive lock requires it to be open for writing.
+///
+/// [`cap_std::fs::File`]: https://docs.rs/cap-std/latest/cap_std/fs/struct.File.html
+pub trait CapStdExtFileLockExt {
+    /// Acquire a shared lock, blocking until it is available.
+    fn lock_shared(&self) -> Result<FileLockGuard<'_>>;
+
+    /// Acquire an exclusive lock, blocking until it is available.
+    fn lock_exclusive(&self) -> Result<FileLockGuard<'_>>;
+
+    /// Acquire a shared lock, returning `Ok(None)` if a conflicting lock is held.
+    fn try_lock_shared(&self) -> Result<Option<FileLockGuard<'_>>>;
+
+    /// Acquire an exclusive lock, returning `Ok(None)` if a conflicting lock is held.
+    fn try_lock_exclusive(&self) -> Result<Option<FileLockGuard<'_>>>;
+
+    /// Acquire a shared lock, returning `Ok(None)` if it is not available within
+    /// the timeout.  See [`Self::lock_exclusive_timeout`].
+    fn lock_shared_timeout(&self, timeout: Duration) -> Result<Option<FileLockGuard<'_>>>;
+
+    /// Acquire an exclusive lock, returning `Ok(None)` if it is not available within
+    /// the timeout.
+    ///
+    /// This polls for the lock with exponential backoff (up to an interval of
+    /// 100ms), rather than interrupting a blocking wait with a signal; so it is
+    /// safe to use from any thread, but is not fair with respect to other waiters.
+    fn lock_exclusive_timeout(&self, timeout: Duration) -> Result<Option<FileLockGuard<'_>>>;
+}
+
+#[cfg(target_os = "linux")]
+#[allow(unsafe_code)]
+fn ofd_lock(fd: BorrowedFd, l_type: libc::c_int, wait: bool) -> Result<()> {
+    use std::os::fd::AsRawFd;
+
+    // SAFETY: All-zeroes is a valid value for this plain C struct.
+    let mut lock: libc::flock = unsafe { std::mem::zeroed() };
+    // The zero `l_start` and `l_len` cover the whole file.
+    lock.l_type = l_type as libc::c_short;
+    lock.l_whence = libc::SEEK_SET as libc::c_short;
+    let cmd = if wait {
+        libc::F_OFD_SETLKW
+    } else {
+        libc::F_OFD_SETLK
+    };
+    // SAFETY: The file descriptor is valid for the duration of this call, and
+    // the lock structure is initialized.
+    let r = unsafe { libc::fcntl(fd.as_raw_fd(), cmd, &lock) };
+    if r < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn flock(fd: BorrowedFd, op: rustix::fs::FlockOperation) -> Result<()> {
+    Ok(rustix::fs::flock(fd, op)?)
+}
+
+/// Acquire a lock, returning `Ok(None)` if `wait` is false and a conflicting lock is held.
+pub(crate) fn lock(fd: BorrowedFd, mode: LockMode, wait: bool) -> Result<Option<LockKind>> {
+    use rustix::fs::FlockOperation;
+
+    let r = loop {
+        #[cfg(target_os = "linux")]
+        {
+            let l_type = match mode {
+                LockMode::Shared => libc::F_RDLCK,
+                LockMode::Exclusive => libc::F_WRLCK,
+            };
+            match ofd_lock(fd, l_type, wait) {
+                Ok(()) => break Ok(LockKind::Ofd),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                // Older kernels; fall back to flock()
+                Err(e) if e.raw_os_error() == Some(libc::EINVAL) => {}
+                Err(e) => break Err(e),
+            }
+        }
+        let op = match (mode, wait) {
+            (LockMode::Shared, true) => FlockOperation::LockShared,
+            (LockMode::Exclusive, true) => FlockOperation::LockExclusive,
+            (LockMode::Shared, false) => FlockOperation::NonBlockingLockShared,
+            (LockMode::Exclusive, false) => FlockOperation::NonBlockingLockExclusive,
+        };
+        match flock(fd, op) {
+            Ok(()) => break Ok(LockKind::Flock),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => break Err(e),
+        }
+    };
+    match r {
+        Ok(kind) => Ok(Some(kind)),
+        // POSIX permits either of these for a conflicting lock
+        Err(e) if !wait && matches!(e.raw_os_error(), Some(libc::EAGAIN) | Some(libc::EACCES)) => {
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Acquire a lock, waiting as specified; returns `Ok(None)` if a conflicting lock
+/// is still held when we stop waiting.
+pub(crate) fn lock_with(
+    fd: BorrowedFd,
+    mode: LockMode,
+    wait: LockWait,
+) -> Result<Option<LockKind>> {
+    /// The initial and maximum intervals between attempts.
+    const INITIAL_BACKOFF: Duration = Duration::from_millis(1);
+    const MAX_BACKOFF: Duration = Duration::from_millis(100);
+
+    let deadline = match wait {
+        LockWait::Forever => return lock(fd, mode, true),
+        LockWait::Never => return lock(fd, mode, false),
+        LockWait::Until(deadline) => deadline,
+    };
+    let mut backoff = INITIAL_BACKOFF;
+    loop {
+        if let Some(kind) = lock(fd, mode, false)? {
+            return Ok(Some(kind));
+        }
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Ok(None);
+        }
+        std::thread::sleep(backoff.min(remaining));
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+pub(crate) fn unlock(fd: BorrowedFd, kind: LockKind) -> Result<()> {
+    match kind {
+        #[cfg(target_os = "linux")]
+        LockKind::Ofd => ofd_lock(fd, libc::F_UNLCK, false),
+        LockKind::Flock => flock(fd, rustix::fs::FlockOperation::Unlock),
+    }
+}
+
+fn lock_guard(fd: BorrowedFd, mode: LockMode, wait: LockWait) -> Result<Option<FileLockGuard>> {
+    Ok(lock_with(fd, mode, wait)?.map(|kind| FileLockGuard { fd, mode, kind }))
+}
+
+impl CapStdExtFileLockExt for File {
+    fn lock_shared(&self) -> Result<FileLockGuard<'_>> {
+        // SAFETY(unwrap): A blocking lock is always acquired on success.
+        lock_guard(self.as_fd(), LockMode::Shared, LockWait::Forever).map(|g| g.unwrap())
+    }
+
+    fn lock_exclusive(&self) -> Result<FileLockGuard<'_>> {
+        // SAFETY(unwrap): A blocking lock is always acquired on success.
+        lock_guard(self.as_fd(), LockMode::Exclusive, LockWait::Forever).map(|g| g.unwrap())
+    }
+
+    fn try_lock_shared(&self) -> Result<Option<FileLockGuard<'_>>> {
+        lock_guard(self.as_fd(), LockMode::Shared, LockWait::Never)
+    }
+
+    fn try_lock_exclusive(&self) -> Result<Option<FileLockGuard<'_>>> {
+        lock_guard(self.as_fd(), LockMode::Exclusive, LockWait::Never)
+    }
+
+    fn lock_shared_timeout(&self, timeout: Duration) -> Result<Option<FileLockGuard<'_>>> {
+        lock_guard(self.as_fd(), LockMode::Shared, LockWait::timeout(timeout))
+    }
+
+    fn lock_exclusive_timeout(&self, timeout: Duration) -> Result<Option<FileLockGuard<'_>>> {
+        lock_guard(
+            self.as_fd(),
+            LockMode::Exclusive,
+            LockWait::timeout(timeout),
+        )
+    }
+}
+
+/// A held lock on a lock file in a directory; see [`crate::dirext::CapStdExtDirExt::lockfile`].
+///
+/// When this is dropped, the lock is released; if it is an exclusive lock, the
+/// lock file is removed first.
+#[derive(Debug)]
+pub struct LockFile {
+    dir: Dir,
+    name: OsString,
+    file: File,
+    mode: LockMode,
+    kind: LockKind,
+}
+
+impl LockFile {
+    /// Open (creating if necessary) and lock the file at the given path.
+    pub(crate) fn acquire(
+        d: &Dir,
+        path: &Path,
+        mode: LockMode,
+        wait: LockWait,
+    ) -> Result<Option<Self>> {
+        use cap_std::fs::MetadataExt;
+
+        let name = path
+            .file_name()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Not a file name"))?
+            .to_owned();
+        let dir = match path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            Some(p) => d.open_dir(p)?,
+            None => d.try_clone()?,
+        };
+        let mut opts = cap_std::fs::OpenOptions::new();
+        opts.read(true).write(true).create(true);
+        loop {
+            let file = dir.open_with(&name, &opts)?;
+            let Some(kind) = lock_with(file.as_fd(), mode, wait)? else {
+                return Ok(None);
+            };
+            // A previous holder may have removed the file while we were waiting for
+            // the lock; in that case we need to start again with a new file.
+            let ours = file.metadata()?;
+            match crate::dirext::map_optional(dir.symlink_metadata(&name))? {
+                Some(m) if (m.dev(), m.ino()) == (ours.dev(), ours.ino()) => {
+                    return Ok(Some(Self {
+                        dir,
+                        name,
+                        file,
+                        mode,
+                        kind,
+                    }));
+                }
+                _ => unlock(file.as_fd(), kind)?,
+            }
+        }
+    }
+
+    /// The mode of the lock which is held.
+    pub fn mode(&self) -> LockMode {
+        self.mode
+    }
+
+    /// The underlying lock file.
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    /// Replace the contents of the lock file with the process ID of the
+    /// current process, followed by a newline.
+    pub fn write_pid(&mut self) -> Result<()> {
+        use std::io::{Seek, SeekFrom};
+        self.file.set_len(0)?;
+        self.file.seek(SeekFrom::Start(0))?;
+        writeln!(self.file, "{}", std::process::id())
+    }
+}
+
+impl Drop for LockFile {
+    fn drop(&mut self) {
+        // We may only remove the file if no one else holds a lock on it, which is
+        // only known for an exclusive lock.  Converting a shared lock to an exclusive
+        // one here is not an option, as that is not atomic for flock().
+        if self.mode == LockMode::Exclusive {
+            let _ = self.dir.remove_file(&self.name);
+        }
+        let _ = unlock(self.file.as_fd(), self.kind);
+    }
+}
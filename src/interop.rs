@@ -0,0 +1,58 @@
+//! Conversions between `cap-std` types and those of `std::fs` (and, with the
+//! `tokio` feature, `tokio::fs`).
+//!
+//! These exist so that code mixing the two ecosystems does not need to
+//! construct types from raw file descriptors.
+
+use std::fs;
+use std::io::{self, Result};
+use std::os::fd::{AsFd, OwnedFd};
+use std::path::Path;
+
+use cap_std::fs::{Dir, File, OpenOptions};
+use cap_tempfile::cap_std;
+use rustix::fs::FileType;
+
+/// Open a file beneath the directory with the given options, returning it as a
+/// [`std::fs::File`].  The lookup is confined to the directory in the same way as
+/// [`Dir::open_with`].
+pub fn into_std_file_at(
+    d: &Dir,
+    path: impl AsRef<Path>,
+    options: &OpenOptions,
+) -> Result<fs::File> {
+    d.open_with(path, options).map(File::into_std)
+}
+
+/// Create a [`Dir`] from an owned file descriptor, which must refer to a
+/// directory; otherwise an `ENOTDIR` error is returned and the file descriptor
+/// is closed.
+///
+/// This accepts `O_PATH` file descriptors.
+pub fn dir_from_fd(fd: OwnedFd) -> Result<Dir> {
+    let st = rustix::fs::fstat(fd.as_fd())?;
+    if FileType::from_raw_mode(st.st_mode) != FileType::Directory {
+        return Err(io::Error::from_raw_os_error(libc::ENOTDIR));
+    }
+    Ok(Dir::from_std_file(fs::File::from(fd)))
+}
+
+/// Convert a file opened via `cap-std` into a [`tokio::fs::File`].
+#[cfg(feature = "tokio")]
+pub fn into_tokio_file(f: File) -> tokio::fs::File {
+    tokio::fs::File::from_std(f.into_std())
+}
+
+/// Open a file beneath the directory with the given options, returning it as a
+/// [`tokio::fs::File`].
+///
+/// Note that this performs the open synchronously; use e.g.
+/// `tokio::task::spawn_blocking` if the directory may be on slow storage.
+#[cfg(feature = "tokio")]
+pub fn into_tokio_file_at(
+    d: &Dir,
+    path: impl AsRef<Path>,
+    options: &OpenOptions,
+) -> Result<tokio::fs::File> {
+    d.open_with(path, options).map(into_tokio_file)
+}
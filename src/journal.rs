@@ -0,0 +1,278 @@
+//! A write-ahead journal for multi-step changes to a directory, which can be
+//! completed after a crash.
+//!
+//! A [`Journal`] stages a sequence of writes, renames and removals.  On
+//! [`Journal::commit`], the sequence is first durably recorded in a journal file
+//! in the directory, then performed; the journal file is removed once done.
+//! After a crash, [`Journal::recover`] completes a committed sequence (rolling
+//! forward), or discards one which was not yet committed (rolling back).
+//!
+//! The names [`JOURNAL_NAME`] and [`STAGING_NAME`] in the directory are reserved.
+
+use std::ffi::OsStr;
+use std::io::{self, Read, Result, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use cap_std::fs::Dir;
+use cap_tempfile::cap_std;
+
+use crate::dirext::{
+    copy_mode_and_owner, sync_dir, AtomicWriteOptions, CapStdExtDirExt, Durability,
+};
+
+/// The name of the journal file, which exists while a committed sequence of
+/// changes is being performed.
+pub const JOURNAL_NAME: &str = ".cap-std-ext-journal";
+/// The name of the directory holding staged file contents.
+pub const STAGING_NAME: &str = ".cap-std-ext-journal.d";
+
+/// The first line of the journal file.
+const JOURNAL_HEADER: &[u8] = b"cap-std-ext-journal 1\n";
+
+/// A step recorded in a [`Journal`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Step {
+    /// Rename the staged file `write-N` (where `N` is the index of the step) to the path.
+    Write(PathBuf),
+    Rename(PathBuf, PathBuf),
+    Remove(PathBuf),
+}
+
+impl Step {
+    fn serialize(&self, buf: &mut Vec<u8>) {
+        let (op, paths): (&[u8], &[&Path]) = match self {
+            Step::Write(p) => (b"write", &[p]),
+            Step::Rename(from, to) => (b"rename", &[from, to]),
+            Step::Remove(p) => (b"remove", &[p]),
+        };
+        buf.extend_from_slice(op);
+        buf.push(0);
+        for p in paths {
+            buf.extend_from_slice(p.as_os_str().as_bytes());
+            buf.push(0);
+        }
+    }
+
+    fn parse_all(buf: &[u8]) -> Result<Vec<Step>> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Invalid journal");
+        let buf = buf.strip_prefix(JOURNAL_HEADER).ok_or_else(invalid)?;
+        if buf.is_empty() {
+            return Ok(Vec::new());
+        }
+        let buf = buf.strip_suffix(b"\0").ok_or_else(invalid)?;
+        let mut fields = buf.split(|&c| c == 0);
+        let mut path = || -> Result<PathBuf> {
+            let p = fields.next().ok_or_else(invalid)?;
+            Ok(PathBuf::from(OsStr::from_bytes(p)))
+        };
+        let mut r = Vec::new();
+        loop {
+            let op = match path() {
+                Ok(op) => op,
+                Err(_) => return Ok(r),
+            };
+            let step = match op.as_os_str().as_bytes() {
+                b"write" => Step::Write(path()?),
+                b"rename" => Step::Rename(path()?, path()?),
+                b"remove" => Step::Remove(path()?),
+                _ => return Err(invalid()),
+            };
+            r.push(step);
+        }
+    }
+}
+
+/// The result of [`Journal::recover`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Recovery {
+    /// There was nothing to recover.
+    Clean,
+    /// A sequence of changes which was not committed was discarded.
+    RolledBack,
+    /// A committed sequence of changes was completed.
+    RolledForward,
+}
+
+/// A sequence of changes to a directory, staged and then performed on
+/// [`Journal::commit`]; see the [module documentation](self).
+///
+/// Dropping the journal without committing discards the staged changes.
+#[derive(Debug)]
+pub struct Journal<'d> {
+    dir: &'d Dir,
+    staging: Dir,
+    steps: Vec<Step>,
+    committed: bool,
+}
+
+impl<'d> Journal<'d> {
+    /// Begin a sequence of changes to the directory.  If a journal (or staging
+    /// directory) already exists, an error of kind
+    /// [`std::io::ErrorKind::AlreadyExists`] is returned; use [`Journal::recover`]
+    /// first.
+    pub fn begin(dir: &'d Dir) -> Result<Self> {
+        if dir.try_exists(JOURNAL_NAME)? {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "A journal exists; recovery is required",
+            ));
+        }
+        dir.create_dir(STAGING_NAME)?;
+        Ok(Self {
+            dir,
+            staging: dir.open_dir(STAGING_NAME)?,
+            steps: Vec::new(),
+            committed: false,
+        })
+    }
+
+    /// Stage writing a file with the given contents, replacing it if it exists.
+    /// The contents are written (and synchronized) immediately.
+    ///
+    /// If the file exists at this point, its mode and ownership are preserved.
+    pub fn write(&mut self, path: impl AsRef<Path>, contents: impl AsRef<[u8]>) -> Result<()> {
+        let path = path.as_ref();
+        let mut f = self.staging.create(format!("write-{}", self.steps.len()))?;
+        if let Some(m) = self
+            .dir
+            .symlink_metadata_optional(path)?
+            .filter(|m| m.is_file())
+        {
+            copy_mode_and_owner(&f, &m)?;
+        }
+        f.write_all(contents.as_ref())?;
+        f.sync_all()?;
+        self.steps.push(Step::Write(path.to_owned()));
+        Ok(())
+    }
+
+    /// Stage renaming a file or directory.  If the source does not exist when the
+    /// step is performed, it is assumed to have been renamed already.
+    pub fn rename(&mut self, from: impl AsRef<Path>, to: impl AsRef<Path>) {
+        let step = Step::Rename(from.as_ref().to_owned(), to.as_ref().to_owned());
+        self.steps.push(step);
+    }
+
+    /// Stage removing a file, symbolic link or directory (including its contents).
+    /// It is not an error if it does not exist.
+    pub fn remove(&mut self, path: impl AsRef<Path>) {
+        self.steps.push(Step::Remove(path.as_ref().to_owned()));
+    }
+
+    /// Durably record the staged changes in the journal, then perform them.  Once
+    /// the journal is written, an error (or crash) leaves it in place, so that
+    /// [`Journal::recover`] completes the changes.
+    pub fn commit(mut self) -> Result<()> {
+        self.record()?;
+        replay(self.dir, &self.staging, &self.steps)?;
+        finish(self.dir)
+    }
+
+    /// Write the journal file; this is the point at which the changes are committed.
+    fn record(&mut self) -> Result<()> {
+        sync_dir(&self.staging, Durability::Standard)?;
+        let mut buf = JOURNAL_HEADER.to_vec();
+        for step in &self.steps {
+            step.serialize(&mut buf);
+        }
+        self.dir.atomic_replace_with_options(
+            JOURNAL_NAME,
+            &AtomicWriteOptions::default().durability(Durability::Standard),
+            |w| w.write_all(&buf),
+        )?;
+        self.committed = true;
+        Ok(())
+    }
+
+    /// Complete or discard changes interrupted by a crash, depending on whether they
+    /// were committed.  This should be called before [`Journal::begin`], e.g. at
+    /// startup.
+    pub fn recover(dir: &Dir) -> Result<Recovery> {
+        let Some(mut f) = dir.open_optional(JOURNAL_NAME)? else {
+            return Ok(match dir.remove_all_optional(STAGING_NAME)? {
+                true => Recovery::RolledBack,
+                false => Recovery::Clean,
+            });
+        };
+        let mut buf = Vec::new();
+        f.read_to_end(&mut buf)?;
+        let steps = Step::parse_all(&buf)?;
+        // An empty journal means the steps were all performed, and the staging
+        // directory may be (partially) removed.
+        if !steps.is_empty() {
+            let staging = dir.open_dir(STAGING_NAME)?;
+            replay(dir, &staging, &steps)?;
+        }
+        finish(dir)?;
+        Ok(Recovery::RolledForward)
+    }
+}
+
+impl Drop for Journal<'_> {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = self.dir.remove_dir_all(STAGING_NAME);
+        }
+    }
+}
+
+/// The parent directory of a path in the journal's directory.
+fn parent_of(path: &Path) -> &Path {
+    match path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    }
+}
+
+/// Perform the steps which have not been completed yet.  Each completed step is
+/// marked with a file `done-N` in the staging directory; as steps are performed
+/// in order, a step without a marker whose effect is already visible (e.g. whose
+/// staged file is gone) was completed just before a crash.
+fn replay(dir: &Dir, staging: &Dir, steps: &[Step]) -> Result<()> {
+    for (i, step) in steps.iter().enumerate() {
+        let marker = format!("done-{i}");
+        if staging.try_exists(&marker)? {
+            continue;
+        }
+        match step {
+            Step::Write(dest) => {
+                let staged = format!("write-{i}");
+                if staging.try_exists(&staged)? {
+                    staging.rename(&staged, dir, dest)?;
+                }
+                sync_dir(&dir.open_dir(parent_of(dest))?, Durability::Standard)?;
+            }
+            Step::Rename(from, to) => {
+                if dir.symlink_metadata_optional(from)?.is_some() {
+                    dir.rename(from, dir, to)?;
+                }
+                sync_dir(&dir.open_dir(parent_of(from))?, Durability::Standard)?;
+                sync_dir(&dir.open_dir(parent_of(to))?, Durability::Standard)?;
+            }
+            Step::Remove(path) => {
+                dir.remove_all_optional(path)?;
+                sync_dir(&dir.open_dir(parent_of(path))?, Durability::Standard)?;
+            }
+        }
+        staging.create(&marker)?;
+        sync_dir(staging, Durability::Standard)?;
+    }
+    Ok(())
+}
+
+/// Remove the staging directory and then the journal, once all steps have been
+/// performed.  The journal is first emptied, so that a partially removed staging
+/// directory is never replayed; and as it is removed last, a staging directory
+/// without a journal only ever belongs to an uncommitted sequence.
+fn finish(dir: &Dir) -> Result<()> {
+    dir.atomic_replace_with_options(
+        JOURNAL_NAME,
+        &AtomicWriteOptions::default().durability(Durability::Standard),
+        |w| w.write_all(JOURNAL_HEADER),
+    )?;
+    dir.remove_all_optional(STAGING_NAME)?;
+    sync_dir(dir, Durability::Standard)?;
+    dir.remove_file(JOURNAL_NAME)?;
+    sync_dir(dir, Durability::Standard)
+}
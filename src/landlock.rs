@@ -0,0 +1,161 @@
+//! Restricting the filesystem access of child processes using Landlock.
+//!
+//! The key API here is [`crate::cmdext::CapStdExtCommandExt::landlock_restrict`],
+//! which limits a process to the directories (and their contents) it is given,
+//! with the provided [`AccessRights`].  Rights which the running kernel does not
+//! support are not restricted; if Landlock is unavailable entirely, an error of
+//! kind [`std::io::ErrorKind::Unsupported`] is returned.
+
+use std::io::{self, Result};
+use std::os::fd::{AsFd, AsRawFd, FromRawFd, OwnedFd};
+
+use cap_std::fs::Dir;
+use cap_tempfile::cap_std;
+
+bitflags::bitflags! {
+    /// Filesystem access rights, as `LANDLOCK_ACCESS_FS_*`.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct AccessRights: u64 {
+        /// Execute a file.
+        const EXECUTE = 1 << 0;
+        /// Open a file for writing.
+        const WRITE_FILE = 1 << 1;
+        /// Open a file for reading.
+        const READ_FILE = 1 << 2;
+        /// List a directory.
+        const READ_DIR = 1 << 3;
+        /// Remove a directory.
+        const REMOVE_DIR = 1 << 4;
+        /// Remove (or rename away) a file.
+        const REMOVE_FILE = 1 << 5;
+        /// Create a character device.
+        const MAKE_CHAR = 1 << 6;
+        /// Create a directory.
+        const MAKE_DIR = 1 << 7;
+        /// Create a regular file.
+        const MAKE_REG = 1 << 8;
+        /// Create a Unix domain socket.
+        const MAKE_SOCK = 1 << 9;
+        /// Create a named pipe.
+        const MAKE_FIFO = 1 << 10;
+        /// Create a block device.
+        const MAKE_BLOCK = 1 << 11;
+        /// Create a symbolic link.
+        const MAKE_SYM = 1 << 12;
+        /// Link or rename a file into a different directory (Landlock ABI 2).
+        const REFER = 1 << 13;
+        /// Truncate a file (Landlock ABI 3).
+        const TRUNCATE = 1 << 14;
+
+        /// Read and execute files, and list directories.
+        const READ = Self::EXECUTE.bits() | Self::READ_FILE.bits() | Self::READ_DIR.bits();
+        /// All of the rights.
+        const READ_WRITE = (1 << 15) - 1;
+    }
+}
+
+/// `LANDLOCK_CREATE_RULESET_VERSION`
+const CREATE_RULESET_VERSION: u32 = 1 << 0;
+/// `LANDLOCK_RULE_PATH_BENEATH`
+const RULE_PATH_BENEATH: libc::c_int = 1;
+
+/// `struct landlock_ruleset_attr`, as of ABI 1.
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+}
+
+/// `struct landlock_path_beneath_attr`
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+}
+
+/// The rights supported by the running kernel.
+fn supported_rights() -> Result<AccessRights> {
+    // SAFETY: Querying the version takes no pointers.
+    #[allow(unsafe_code)]
+    let abi = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            std::ptr::null::<RulesetAttr>(),
+            0usize,
+            CREATE_RULESET_VERSION,
+        )
+    };
+    let abi = match abi {
+        n if n < 0 => {
+            let e = io::Error::last_os_error();
+            return Err(match e.raw_os_error() {
+                Some(libc::ENOSYS | libc::EOPNOTSUPP) => io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "Landlock is not supported or enabled",
+                ),
+                _ => e,
+            });
+        }
+        1 => 13,
+        2 => 14,
+        _ => 15,
+    };
+    Ok(AccessRights::from_bits_truncate((1 << abi) - 1))
+}
+
+/// Create a ruleset allowing only the provided access beneath each directory.
+pub(crate) fn create_ruleset(rules: &[(Dir, AccessRights)]) -> Result<OwnedFd> {
+    let handled = supported_rights()?;
+    let attr = RulesetAttr {
+        handled_access_fs: handled.bits(),
+    };
+    // SAFETY: The attribute is valid for the duration of the call.
+    #[allow(unsafe_code)]
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            &attr as *const RulesetAttr,
+            std::mem::size_of::<RulesetAttr>(),
+            0u32,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: The kernel returned a new file descriptor.
+    #[allow(unsafe_code)]
+    let ruleset = unsafe { OwnedFd::from_raw_fd(fd as i32) };
+    for (dir, access) in rules {
+        let attr = PathBeneathAttr {
+            allowed_access: (*access & handled).bits(),
+            parent_fd: dir.as_fd().as_raw_fd(),
+        };
+        // SAFETY: The attribute is valid for the duration of the call.
+        #[allow(unsafe_code)]
+        let r = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_add_rule,
+                ruleset.as_raw_fd(),
+                RULE_PATH_BENEATH,
+                &attr as *const PathBeneathAttr,
+                0u32,
+            )
+        };
+        if r < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(ruleset)
+}
+
+/// Enforce the ruleset on the current thread; this is called after fork.
+pub(crate) fn restrict_self(ruleset: &OwnedFd) -> Result<()> {
+    // This is required for unprivileged processes.
+    rustix::thread::set_no_new_privs(true)?;
+    // SAFETY: This takes no pointers.
+    #[allow(unsafe_code)]
+    let r = unsafe { libc::syscall(libc::SYS_landlock_restrict_self, ruleset.as_raw_fd(), 0u32) };
+    if r < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
@@ -4,7 +4,7 @@
 #![deny(missing_debug_implementations)]
 #![forbid(unused_must_use)]
 #![deny(unsafe_code)]
-#![cfg_attr(feature = "dox", feature(doc_cfg))]
+#![cfg_attr(docsrs, feature(doc_cfg))]
 
 // Re-export our dependencies
 pub use cap_primitives;
@@ -13,12 +13,42 @@ pub use cap_std::fs_utf8::camino;
 pub use cap_tempfile;
 pub use cap_tempfile::cap_std;
 
+#[cfg(not(windows))]
+pub mod audit;
+pub mod capfs;
 #[cfg(not(windows))]
 pub mod cmdext;
+#[cfg(feature = "digest")]
+pub mod digest;
 pub mod dirext;
-
+#[cfg(all(feature = "fanotify", any(target_os = "android", target_os = "linux")))]
+pub mod fanotify;
+#[cfg(not(windows))]
+pub mod fileext;
+#[cfg(not(windows))]
+pub mod interop;
+#[cfg(not(windows))]
+pub mod journal;
+#[cfg(all(feature = "landlock", any(target_os = "android", target_os = "linux")))]
+pub mod landlock;
 #[cfg(any(target_os = "android", target_os = "linux"))]
+pub mod memfd;
+#[cfg(all(feature = "mmap", not(windows)))]
+pub mod mmap;
+#[cfg(not(windows))]
+pub mod overlay;
+#[cfg(not(windows))]
+pub mod query;
+#[cfg(all(feature = "seccomp", any(target_os = "android", target_os = "linux")))]
+pub mod seccomp;
+#[cfg(not(windows))]
+pub mod transaction;
+#[cfg(all(feature = "watch", any(target_os = "android", target_os = "linux")))]
+pub mod watch;
+
+#[cfg(any(target_os = "android", target_os = "linux", target_os = "freebsd"))]
 mod rootdir;
+#[cfg(any(target_os = "android", target_os = "linux", target_os = "freebsd"))]
 pub use rootdir::*;
 
 /// Prelude, intended for glob import.
@@ -28,4 +58,6 @@ pub mod prelude {
     pub use super::dirext::CapStdExtDirExt;
     #[cfg(feature = "fs_utf8")]
     pub use super::dirext::CapStdExtDirExtUtf8;
+    #[cfg(not(windows))]
+    pub use super::fileext::CapStdExtFileLockExt;
 }
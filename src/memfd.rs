@@ -0,0 +1,42 @@
+//! Sealed anonymous in-memory files, created with `memfd_create(2)`.
+//!
+//! A sealed memfd is a convenient way to hand immutable data to a child process:
+//! the child cannot modify it (nor can anyone else), and no filesystem is involved.
+//! See [`sealed_fd_for_command`], whose result can be passed to
+//! [`crate::cmdext::CapStdExtCommandExt::take_fd_n`].
+
+use std::io::{Result, Seek, Write};
+use std::os::fd::OwnedFd;
+use std::sync::Arc;
+
+use cap_std::fs::File;
+use cap_tempfile::cap_std;
+use rustix::fs::MemfdFlags;
+
+pub use rustix::fs::SealFlags;
+
+/// Seals which make a memfd immutable: its contents can't be written, its size
+/// can't be changed, and no further seals can be changed.
+pub const IMMUTABLE_SEALS: SealFlags = SealFlags::SEAL
+    .union(SealFlags::SHRINK)
+    .union(SealFlags::GROW)
+    .union(SealFlags::WRITE);
+
+/// Create a memfd with the given name (which is only used for debugging, e.g. in
+/// `/proc/self/fd`) and contents, then apply the seals.  The returned file is
+/// positioned at the start.
+pub fn memfd_create_sealed(name: &str, contents: &[u8], seals: SealFlags) -> Result<File> {
+    let fd = rustix::fs::memfd_create(name, MemfdFlags::CLOEXEC | MemfdFlags::ALLOW_SEALING)?;
+    let mut f = File::from_std(fd.into());
+    f.write_all(contents)?;
+    f.rewind()?;
+    rustix::fs::fcntl_add_seals(&f, seals)?;
+    Ok(f)
+}
+
+/// Create an immutable memfd with the given contents, in the form accepted by
+/// [`crate::cmdext::CapStdExtCommandExt::take_fd_n`].
+pub fn sealed_fd_for_command(name: &str, contents: &[u8]) -> Result<Arc<OwnedFd>> {
+    let f = memfd_create_sealed(name, contents, IMMUTABLE_SEALS)?;
+    Ok(Arc::new(f.into_std().into()))
+}
@@ -0,0 +1,120 @@
+//! Read-only memory mappings of files.
+//!
+//! The key API here is [`crate::dirext::CapStdExtDirExt::map_readonly`], which
+//! returns an [`Mmap`].
+
+use std::ffi::c_void;
+use std::fmt;
+use std::io::{self, Result};
+use std::ops::Deref;
+use std::ptr::NonNull;
+
+use rustix::fd::AsFd;
+use rustix::mm::{MapFlags, ProtFlags};
+
+/// A read-only, private memory mapping of a file.  The mapping is
+/// removed when this value is dropped.
+///
+/// The mapping reflects the file contents, and not a snapshot of them; see
+/// [`Mmap::map`] for the requirements this places on callers.
+pub struct Mmap {
+    ptr: NonNull<c_void>,
+    len: usize,
+}
+
+// SAFETY: The mapping is read-only and not tied to a thread.
+#[allow(unsafe_code)]
+unsafe impl Send for Mmap {}
+// SAFETY: The mapping is read-only.
+#[allow(unsafe_code)]
+unsafe impl Sync for Mmap {}
+
+impl Mmap {
+    /// Map the full contents of the provided file.
+    ///
+    /// # Safety
+    ///
+    /// If another process (or another part of this process) modifies the file
+    /// while it is mapped, the changes may become visible through the mapping,
+    /// which is undefined behavior as the mapping is exposed as a `&[u8]`.
+    /// If the file is truncated, accessing the truncated range will result
+    /// in `SIGBUS`.  The caller must ensure that the file is not modified or
+    /// truncated for the lifetime of the returned value, e.g. because it is
+    /// a read-only index or a content-addressed object.
+    #[allow(unsafe_code)]
+    pub unsafe fn map(f: impl AsFd) -> Result<Self> {
+        let f = f.as_fd();
+        let len = rustix::fs::fstat(f)?.st_size;
+        let len = usize::try_from(len)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "File too large to map"))?;
+        // Zero-length mappings are invalid.
+        if len == 0 {
+            return Ok(Self {
+                ptr: NonNull::dangling(),
+                len,
+            });
+        }
+        // SAFETY: We are creating a new mapping, not replacing an existing one.
+        let ptr = unsafe {
+            rustix::mm::mmap(
+                std::ptr::null_mut(),
+                len,
+                ProtFlags::READ,
+                MapFlags::PRIVATE,
+                f,
+                0,
+            )?
+        };
+        // SAFETY(unwrap): A successful mmap never returns NULL when not given MAP_FIXED.
+        let ptr = NonNull::new(ptr).unwrap();
+        Ok(Self { ptr, len })
+    }
+
+    /// Returns the length of the mapping in bytes.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns `true` if the mapping is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl Deref for Mmap {
+    type Target = [u8];
+
+    #[allow(unsafe_code)]
+    fn deref(&self) -> &[u8] {
+        // SAFETY: The pointer is valid for reads of `len` bytes for the lifetime
+        // of this value (or dangling with a length of zero).
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr().cast(), self.len) }
+    }
+}
+
+impl AsRef<[u8]> for Mmap {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl Drop for Mmap {
+    #[allow(unsafe_code)]
+    fn drop(&mut self) {
+        if self.len == 0 {
+            return;
+        }
+        // SAFETY: This is a mapping we created and which is not otherwise referenced.
+        // There is nothing useful to do with an error here.
+        let _ = unsafe { rustix::mm::munmap(self.ptr.as_ptr(), self.len) };
+    }
+}
+
+impl fmt::Debug for Mmap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Mmap")
+            .field("ptr", &self.ptr)
+            .field("len", &self.len)
+            .finish()
+    }
+}
@@ -0,0 +1,414 @@
+//! A copy-on-write view merging two directories, in the style of overlayfs, for
+//! use where mounting an overlay filesystem is not possible.
+//!
+//! Reads fall through from the upper directory to the lower one; changes are only
+//! made to the upper directory, copying files up from the lower one as needed.
+//! Removed entries are recorded in the upper directory using the whiteout
+//! convention of OCI image layers: a file `.wh.NAME` hides `NAME` in the lower
+//! directory, and a file `.wh..wh..opq` in a directory hides all of the
+//! corresponding lower directory's contents.
+//!
+//! Paths are looked up separately in each layer, so symbolic links are only
+//! followed within a layer (as with e.g. [`Dir::metadata`]), and never in
+//! intermediate components of a path in a way that crosses layers.
+
+use std::collections::BTreeSet;
+use std::ffi::{OsStr, OsString};
+use std::io::{self, Result};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+
+use cap_std::fs::{Dir, File, Metadata};
+use cap_tempfile::cap_std;
+
+use crate::dirext::CapStdExtDirExt;
+
+/// The prefix of whiteout files.
+pub const WHITEOUT_PREFIX: &str = ".wh.";
+/// The name of the file marking a directory as opaque.
+pub const OPAQUE_WHITEOUT: &str = ".wh..wh..opq";
+
+/// The layer in which an entry was found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Layer {
+    Upper,
+    Lower,
+}
+
+/// A merged view of a lower and an upper directory; see the
+/// [module documentation](self).
+#[derive(Debug)]
+pub struct OverlayDir {
+    lower: Dir,
+    upper: Dir,
+}
+
+/// The name of the whiteout for a path.
+fn whiteout_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(WHITEOUT_PREFIX);
+    // SAFETY(unwrap): Paths are validated to have a final normal component.
+    name.push(path.file_name().unwrap());
+    path.with_file_name(name)
+}
+
+fn is_whiteout(name: &OsStr) -> bool {
+    name.as_bytes().starts_with(WHITEOUT_PREFIX.as_bytes())
+}
+
+/// Check that the path is relative, and has no `..` components or whiteout names,
+/// returning it with `.` components and redundant separators removed.
+fn normalize(path: &Path) -> Result<PathBuf> {
+    let mut r = PathBuf::new();
+    for c in path.components() {
+        match c {
+            Component::Normal(c) if !is_whiteout(c) => r.push(c),
+            Component::CurDir => {}
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Invalid path in overlay: {}", path.display()),
+                ))
+            }
+        }
+    }
+    Ok(r)
+}
+
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("Not found in overlay: {}", path.display()),
+    )
+}
+
+/// Query the metadata of an entry in a layer without following symbolic links,
+/// returning `None` if it (or a parent directory) does not exist.
+fn entry_metadata(d: &Dir, path: &Path) -> Result<Option<Metadata>> {
+    match d.symlink_metadata_optional(path) {
+        // A parent is not a directory, so this cannot exist
+        Err(e) if e.raw_os_error() == Some(libc::ENOTDIR) => Ok(None),
+        r => r,
+    }
+}
+
+/// The path to pass to a layer for a (possibly empty) normalized path.
+fn layer_path(path: &Path) -> &Path {
+    if path.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        path
+    }
+}
+
+impl OverlayDir {
+    /// Create a view of `upper` layered over `lower`.
+    pub fn new(lower: Dir, upper: Dir) -> Self {
+        Self { lower, upper }
+    }
+
+    /// The lower (read-only) directory.
+    pub fn lower(&self) -> &Dir {
+        &self.lower
+    }
+
+    /// The upper directory, to which changes are written.
+    pub fn upper(&self) -> &Dir {
+        &self.upper
+    }
+
+    /// Find the layer containing a normalized path, if it exists in the merged view.
+    fn locate(&self, path: &Path) -> Result<Option<Layer>> {
+        let mut lower_visible = true;
+        let mut cur = PathBuf::new();
+        let mut layer = Layer::Upper;
+        for name in path.iter() {
+            cur.push(name);
+            if let Some(meta) = entry_metadata(&self.upper, &cur)? {
+                layer = Layer::Upper;
+                lower_visible &= meta.is_dir()
+                    && !self.upper.try_exists(cur.join(OPAQUE_WHITEOUT))?
+                    && entry_metadata(&self.lower, &cur)?.is_some_and(|m| m.is_dir());
+            } else if lower_visible
+                && !self.upper.try_exists(whiteout_path(&cur))?
+                && entry_metadata(&self.lower, &cur)?.is_some()
+            {
+                layer = Layer::Lower;
+            } else {
+                return Ok(None);
+            }
+        }
+        Ok(Some(layer))
+    }
+
+    fn layer(&self, layer: Layer) -> &Dir {
+        match layer {
+            Layer::Upper => &self.upper,
+            Layer::Lower => &self.lower,
+        }
+    }
+
+    /// Find the directory containing a path, returning an error if it does not exist.
+    fn resolve(&self, path: &Path) -> Result<(PathBuf, &Dir)> {
+        let path = normalize(path)?;
+        let layer = self.locate(&path)?.ok_or_else(|| not_found(&path))?;
+        Ok((path, self.layer(layer)))
+    }
+
+    /// Returns `true` if the path exists in the merged view.
+    pub fn try_exists(&self, path: impl AsRef<Path>) -> Result<bool> {
+        Ok(self.locate(&normalize(path.as_ref())?)?.is_some())
+    }
+
+    /// Open a file for reading.
+    pub fn open(&self, path: impl AsRef<Path>) -> Result<File> {
+        let (path, d) = self.resolve(path.as_ref())?;
+        d.open(path)
+    }
+
+    /// Read the contents of a file.
+    pub fn read(&self, path: impl AsRef<Path>) -> Result<Vec<u8>> {
+        let (path, d) = self.resolve(path.as_ref())?;
+        d.read(path)
+    }
+
+    /// Query the metadata of an entry, following a symbolic link within its layer.
+    pub fn metadata(&self, path: impl AsRef<Path>) -> Result<Metadata> {
+        let (path, d) = self.resolve(path.as_ref())?;
+        d.metadata(layer_path(&path))
+    }
+
+    /// Query the metadata of an entry, without following symbolic links.
+    pub fn symlink_metadata(&self, path: impl AsRef<Path>) -> Result<Metadata> {
+        let (path, d) = self.resolve(path.as_ref())?;
+        d.symlink_metadata(layer_path(&path))
+    }
+
+    /// Read the target of a symbolic link.
+    pub fn read_link(&self, path: impl AsRef<Path>) -> Result<PathBuf> {
+        let (path, d) = self.resolve(path.as_ref())?;
+        d.read_link_contents(path)
+    }
+
+    /// List the names of the entries in a directory (use `.` or an empty path for
+    /// the root), merged from both layers and sorted.
+    pub fn entries(&self, path: impl AsRef<Path>) -> Result<Vec<OsString>> {
+        let path = normalize(path.as_ref())?;
+        let mut names = BTreeSet::new();
+        let mut whiteouts = BTreeSet::new();
+        let mut lower_visible = true;
+        match self.locate(&path)? {
+            None => return Err(not_found(&path)),
+            Some(Layer::Upper) => {
+                for e in self.upper.read_dir(layer_path(&path))? {
+                    let name = e?.file_name();
+                    if name == OPAQUE_WHITEOUT {
+                        lower_visible = false;
+                    } else if is_whiteout(&name) {
+                        whiteouts.insert(name.as_bytes()[WHITEOUT_PREFIX.len()..].to_vec());
+                    } else {
+                        names.insert(name);
+                    }
+                }
+                lower_visible &= self.locate_lower_visible(&path)?;
+            }
+            Some(Layer::Lower) => {}
+        }
+        if lower_visible {
+            for e in self.lower.read_dir(layer_path(&path))? {
+                let name = e?.file_name();
+                if !whiteouts.contains(name.as_bytes()) {
+                    names.insert(name);
+                }
+            }
+        }
+        Ok(names.into_iter().collect())
+    }
+
+    /// Returns `true` if the lower layer has a directory at the path, and it is not
+    /// hidden by an opaque directory in the upper layer.
+    fn locate_lower_visible(&self, path: &Path) -> Result<bool> {
+        let mut cur = PathBuf::new();
+        for name in path.iter() {
+            cur.push(name);
+            let lower_is_dir = entry_metadata(&self.lower, &cur)?.is_some_and(|m| m.is_dir());
+            if !lower_is_dir || self.upper.try_exists(cur.join(OPAQUE_WHITEOUT))? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Ensure that the parent directories of a normalized path exist in the upper
+    /// layer, copying them up as needed.
+    fn copy_up_parents(&self, path: &Path) -> Result<()> {
+        let Some(parent) = path.parent() else {
+            return Ok(());
+        };
+        let mut cur = PathBuf::new();
+        for name in parent.iter() {
+            cur.push(name);
+            match self.locate(&cur)? {
+                None => return Err(not_found(&cur)),
+                Some(Layer::Upper) => {}
+                Some(Layer::Lower) => self.copy_up_entry(&cur)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Copy an entry (but not the contents of a directory) from the lower layer to
+    /// the upper layer.
+    fn copy_up_entry(&self, path: &Path) -> Result<()> {
+        let meta = self.lower.symlink_metadata(path)?;
+        if meta.is_dir() {
+            self.upper.create_dir(path)?;
+        } else if meta.is_symlink() {
+            let target = self.lower.read_link_contents(path)?;
+            return self.upper.symlink_contents(target, path);
+        } else if meta.is_file() {
+            self.lower.copy(path, &self.upper, path)?;
+        } else {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("Cannot copy up special file: {}", path.display()),
+            ));
+        }
+        self.upper.set_permissions(path, meta.permissions())
+    }
+
+    /// Copy a file, symbolic link or directory (without its contents) up to the
+    /// upper layer if it is only present in the lower one, along with its parent
+    /// directories, so that it can be modified there via [`Self::upper`].
+    pub fn copy_up(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = normalize(path.as_ref())?;
+        match self.locate(&path)? {
+            None => Err(not_found(&path)),
+            Some(Layer::Upper) => Ok(()),
+            Some(Layer::Lower) => {
+                self.copy_up_parents(&path)?;
+                self.copy_up_entry(&path)
+            }
+        }
+    }
+
+    /// Prepare to create an entry at the path in the upper layer, returning the
+    /// normalized path and whether the entry hides a removed lower entry.
+    fn prepare_create(&self, path: &Path) -> Result<(PathBuf, bool)> {
+        let path = normalize(path)?;
+        if path.as_os_str().is_empty() {
+            return Err(io::Error::from(io::ErrorKind::AlreadyExists));
+        }
+        self.copy_up_parents(&path)?;
+        let whiteout = whiteout_path(&path);
+        let replaces_removed = self.upper.remove_file_optional(&whiteout)?;
+        Ok((path, replaces_removed))
+    }
+
+    /// Create (or truncate) a file in the upper layer, copying up its parent
+    /// directories as needed.  This does not copy up its current contents; see
+    /// [`Self::copy_up`].  The mode of a file being replaced is kept, including
+    /// when it is in the lower layer.  It is an error (`EISDIR`) if the path is a
+    /// directory in either layer.
+    pub fn create(&self, path: impl AsRef<Path>) -> Result<File> {
+        use cap_std::fs::{OpenOptions, OpenOptionsExt, PermissionsExt};
+
+        let path = normalize(path.as_ref())?;
+        let existing = match self.locate(&path)? {
+            Some(layer) => Some((
+                layer,
+                self.layer(layer).symlink_metadata(layer_path(&path))?,
+            )),
+            None => None,
+        };
+        if existing.as_ref().is_some_and(|(_, m)| m.is_dir()) {
+            return Err(io::Error::from_raw_os_error(libc::EISDIR));
+        }
+        let lower_perms = match existing {
+            Some((Layer::Lower, m)) if m.is_file() => Some(m.permissions()),
+            _ => None,
+        };
+        let (path, _) = self.prepare_create(&path)?;
+        let mut opts = OpenOptions::new();
+        opts.write(true).create(true).truncate(true);
+        if let Some(perms) = &lower_perms {
+            opts.mode(perms.mode());
+        }
+        let f = self.upper.open_with(path, &opts)?;
+        // The mode at creation is subject to the umask
+        if let Some(perms) = lower_perms {
+            f.set_permissions(perms)?;
+        }
+        Ok(f)
+    }
+
+    /// Write a file in the upper layer, replacing its contents.  As with
+    /// [`Self::create`], the mode of a file being replaced is kept.
+    pub fn write(&self, path: impl AsRef<Path>, contents: impl AsRef<[u8]>) -> Result<()> {
+        let mut f = self.create(path)?;
+        io::Write::write_all(&mut f, contents.as_ref())
+    }
+
+    /// Create a directory.  If it replaces a removed directory of the lower layer,
+    /// it is marked opaque so that the lower directory's contents remain hidden.
+    pub fn create_dir(&self, path: impl AsRef<Path>) -> Result<()> {
+        if self.try_exists(path.as_ref())? {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("Already exists: {}", path.as_ref().display()),
+            ));
+        }
+        let (path, replaces_removed) = self.prepare_create(path.as_ref())?;
+        self.upper.create_dir(&path)?;
+        if replaces_removed || entry_metadata(&self.lower, &path)?.is_some() {
+            self.upper.write(path.join(OPAQUE_WHITEOUT), "")?;
+        }
+        Ok(())
+    }
+
+    /// Remove an entry from the upper layer, and hide it in the lower layer.
+    fn remove_entry(&self, path: &Path, is_dir: bool) -> Result<()> {
+        let layer = self.locate(path)?.ok_or_else(|| not_found(path))?;
+        if self.layer(layer).symlink_metadata(path)?.is_dir() != is_dir {
+            return Err(io::Error::from_raw_os_error(if is_dir {
+                libc::ENOTDIR
+            } else {
+                libc::EISDIR
+            }));
+        }
+        let in_lower = match layer {
+            Layer::Lower => true,
+            Layer::Upper => {
+                let in_lower = entry_metadata(&self.lower, path)?.is_some()
+                    && path
+                        .parent()
+                        .map_or(Ok(true), |p| self.locate_lower_visible(p))?;
+                // Any remaining contents of a directory are whiteouts
+                self.upper.remove_all_optional(path)?;
+                in_lower
+            }
+        };
+        if in_lower {
+            self.copy_up_parents(path)?;
+            self.upper.write(whiteout_path(path), "")?;
+        }
+        Ok(())
+    }
+
+    /// Remove a file or symbolic link.
+    pub fn remove_file(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = normalize(path.as_ref())?;
+        self.remove_entry(&path, false)
+    }
+
+    /// Remove an empty directory.
+    pub fn remove_dir(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = normalize(path.as_ref())?;
+        if path.as_os_str().is_empty() {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+        if !self.entries(&path)?.is_empty() {
+            return Err(io::Error::from_raw_os_error(libc::ENOTEMPTY));
+        }
+        self.remove_entry(&path, true)
+    }
+}
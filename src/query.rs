@@ -0,0 +1,160 @@
+//! Finding files matching criteria, similar to `find(1)`.
+//!
+//! The key API here is [`crate::dirext::CapStdExtDirExt::find`], which returns a
+//! [`Query`] builder; [`Query::run`] walks the directory and returns the matching
+//! entries.
+
+use std::io::Result;
+use std::ops::ControlFlow;
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+use cap_std::fs::{Dir, FileType, Metadata};
+use cap_tempfile::cap_std;
+
+use crate::dirext::{glob_component_matches, CapStdExtDirExt, WalkConfiguration};
+
+/// A type of file to match; see [`Query::file_type`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum FileKind {
+    /// A regular file.
+    File,
+    /// A directory.
+    Dir,
+    /// A symbolic link.
+    Symlink,
+}
+
+impl FileKind {
+    fn matches(self, file_type: FileType) -> bool {
+        match self {
+            FileKind::File => file_type.is_file(),
+            FileKind::Dir => file_type.is_dir(),
+            FileKind::Symlink => file_type.is_symlink(),
+        }
+    }
+}
+
+/// A query for entries beneath a directory; see
+/// [`crate::dirext::CapStdExtDirExt::find`].
+///
+/// Symbolic links are not followed; as with `find -type`, the type, size and
+/// modification time of a link are those of the link itself.
+#[derive(Debug)]
+pub struct Query<'d> {
+    dir: &'d Dir,
+    file_type: Option<FileKind>,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    modified_within: Option<Duration>,
+    name: Option<String>,
+    noxdev: bool,
+}
+
+/// An entry matched by a [`Query`].
+#[derive(Debug)]
+pub struct QueryMatch {
+    /// The path to the entry, relative to the directory.
+    pub path: PathBuf,
+    /// The file type of the entry.
+    pub file_type: FileType,
+    /// The metadata of the entry.
+    pub metadata: Metadata,
+}
+
+impl<'d> Query<'d> {
+    pub(crate) fn new(dir: &'d Dir) -> Self {
+        Self {
+            dir,
+            file_type: None,
+            min_size: None,
+            max_size: None,
+            modified_within: None,
+            name: None,
+            noxdev: false,
+        }
+    }
+
+    /// Only match entries of the given type.
+    pub fn file_type(mut self, kind: FileKind) -> Self {
+        self.file_type = Some(kind);
+        self
+    }
+
+    /// Only match entries of at least this many bytes.
+    pub fn min_size(mut self, size: u64) -> Self {
+        self.min_size = Some(size);
+        self
+    }
+
+    /// Only match entries of at most this many bytes.
+    pub fn max_size(mut self, size: u64) -> Self {
+        self.max_size = Some(size);
+        self
+    }
+
+    /// Only match entries modified no longer ago than the given duration, relative
+    /// to when the query is run.
+    pub fn modified_within(mut self, age: Duration) -> Self {
+        self.modified_within = Some(age);
+        self
+    }
+
+    /// Only match entries whose file name matches the glob pattern, where `*`
+    /// matches any sequence of characters and `?` any single character.  Calling
+    /// this again replaces the pattern.
+    pub fn name_matches(mut self, pattern: impl Into<String>) -> Self {
+        self.name = Some(pattern.into());
+        self
+    }
+
+    /// Do not cross mount points.
+    pub fn noxdev(mut self) -> Self {
+        self.noxdev = true;
+        self
+    }
+
+    /// Walk the directory, returning the matching entries sorted by path.
+    pub fn run(&self) -> Result<Vec<QueryMatch>> {
+        let cutoff = self.modified_within.map(|age| {
+            SystemTime::now()
+                .checked_sub(age)
+                .unwrap_or(SystemTime::UNIX_EPOCH)
+        });
+        let mut config = WalkConfiguration::default().sort_by_file_name().metadata();
+        if self.noxdev {
+            config = config.noxdev();
+        }
+        let mut r = Vec::new();
+        self.dir.walk(&config, |e| -> Result<_> {
+            // SAFETY(unwrap): We requested metadata.
+            let metadata = e.metadata.unwrap();
+            if self
+                .file_type
+                .is_some_and(|kind| !kind.matches(e.file_type))
+                || self.min_size.is_some_and(|n| metadata.len() < n)
+                || self.max_size.is_some_and(|n| metadata.len() > n)
+                || self
+                    .name
+                    .as_ref()
+                    .is_some_and(|p| !glob_component_matches(p.as_bytes(), e.filename.as_bytes()))
+            {
+                return Ok(ControlFlow::Continue(()));
+            }
+            if let Some(cutoff) = cutoff {
+                if metadata.modified()?.into_std() < cutoff {
+                    return Ok(ControlFlow::Continue(()));
+                }
+            }
+            r.push(QueryMatch {
+                path: e.path.to_owned(),
+                file_type: e.file_type,
+                metadata: metadata.clone(),
+            });
+            Ok(ControlFlow::Continue(()))
+        })?;
+        Ok(r)
+    }
+}
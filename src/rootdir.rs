@@ -1,41 +1,132 @@
+use std::ffi::{OsStr, OsString};
 use std::fs;
 use std::io;
 use std::io::Read;
-use std::path::Path;
+use std::os::unix::ffi::OsStringExt;
+use std::path::{Component, Path, PathBuf};
 
 use cap_std::fs::Dir;
 use cap_tempfile::cap_std;
 use rustix::fd::AsFd;
 use rustix::fd::BorrowedFd;
+use rustix::fd::OwnedFd;
 use rustix::fs::OFlags;
+#[cfg(any(target_os = "android", target_os = "linux"))]
 use rustix::fs::ResolveFlags;
+#[cfg(target_os = "freebsd")]
 use rustix::path::Arg;
 
-pub(crate) fn open_beneath_rdonly(start: &BorrowedFd, path: &Path) -> io::Result<fs::File> {
-    // We loop forever on EAGAIN right now. The cap-std version loops just 4 times,
-    // which seems really arbitrary.
-    let r = path.into_with_c_str(|path_c_str| 'start: loop {
-        match rustix::fs::openat2(
+use crate::dirext::CapStdExtDirExt;
+
+/// Open a file read-only using `openat2` with the provided resolution flags.
+#[cfg(any(target_os = "android", target_os = "linux"))]
+pub(crate) fn open_rdonly_with(
+    start: &BorrowedFd,
+    path: &Path,
+    resolve: ResolveFlags,
+) -> io::Result<fs::File> {
+    let r = crate::dirext::openat2_with_retry(
+        start,
+        path,
+        OFlags::CLOEXEC | OFlags::RDONLY,
+        rustix::fs::Mode::empty(),
+        resolve,
+    )?;
+    Ok(r.into())
+}
+
+/// Open a file read-only in the root, with additional flags.
+#[cfg(any(target_os = "android", target_os = "linux"))]
+pub(crate) fn open_beneath_with(
+    start: &BorrowedFd,
+    path: &Path,
+    oflags: OFlags,
+) -> io::Result<fs::File> {
+    let r = crate::dirext::openat2_with_retry(
+        start,
+        path,
+        OFlags::CLOEXEC | OFlags::RDONLY | oflags,
+        rustix::fs::Mode::empty(),
+        ResolveFlags::IN_ROOT | ResolveFlags::NO_MAGICLINKS,
+    )?;
+    Ok(r.into())
+}
+
+/// FreeBSD has no equivalent of `RESOLVE_IN_ROOT`, but `O_RESOLVE_BENEATH`
+/// provides the containment guarantee; paths which would escape (including
+/// absolute symbolic links) fail instead of being resolved relative to the root.
+#[cfg(target_os = "freebsd")]
+pub(crate) fn open_beneath_with(
+    start: &BorrowedFd,
+    path: &Path,
+    oflags: OFlags,
+) -> io::Result<fs::File> {
+    // Absolute paths are always relative to the root here, matching the
+    // behavior of RESOLVE_IN_ROOT on Linux for the initial path.
+    let path = path.strip_prefix("/").unwrap_or(path);
+    let path = if path.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        path
+    };
+    let r = path.into_with_c_str(|path_c_str| loop {
+        match rustix::fs::openat(
             start,
             path_c_str,
-            OFlags::CLOEXEC | OFlags::RDONLY,
+            OFlags::CLOEXEC | OFlags::RDONLY | OFlags::RESOLVE_BENEATH | oflags,
             rustix::fs::Mode::empty(),
-            ResolveFlags::IN_ROOT | ResolveFlags::NO_MAGICLINKS,
         ) {
-            Ok(file) => {
-                return Ok(file);
-            }
-            Err(rustix::io::Errno::AGAIN | rustix::io::Errno::INTR) => {
-                continue 'start;
-            }
-            Err(e) => {
-                return Err(e);
-            }
+            Err(rustix::io::Errno::INTR) => continue,
+            r => return r,
         }
     })?;
     Ok(r.into())
 }
 
+/// The maximum number of symbolic links followed by [`RootDir::resolve`], matching
+/// the Linux kernel.
+const MAX_SYMLINKS: usize = 40;
+
+/// Read the target of the symbolic link `name` in the directory.  Unlike
+/// [`cap_std::fs::Dir::read_link`], absolute targets are returned as is.
+fn read_link_at(dir: impl AsFd, name: &Path) -> io::Result<PathBuf> {
+    let target = rustix::fs::readlinkat(dir, name, Vec::new())?;
+    Ok(OsString::from_vec(target.into_bytes()).into())
+}
+
+/// Return an error if the file is on procfs or sysfs.
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn check_not_pseudofs(fd: impl AsFd, path: &Path) -> io::Result<()> {
+    const PROC_SUPER_MAGIC: i64 = 0x9fa0;
+    const SYSFS_MAGIC: i64 = 0x62656572;
+    // The type of `f_type` varies by architecture
+    #[allow(clippy::unnecessary_cast)]
+    let name = match rustix::fs::fstatfs(fd)?.f_type as i64 {
+        PROC_SUPER_MAGIC => "procfs",
+        SYSFS_MAGIC => "sysfs",
+        _ => return Ok(()),
+    };
+    Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("Found {name} mount in hardened root: {}", path.display()),
+    ))
+}
+
+/// Options for [`RootDir::copy_to`].
+#[derive(Debug, Clone, Default)]
+pub struct CopyOptions {
+    flatten_symlinks: bool,
+}
+
+impl CopyOptions {
+    /// Copy what symbolic links refer to (resolved in the root) instead of the links
+    /// themselves.  Dangling links are still copied as links.
+    pub fn flatten_symlinks(mut self) -> Self {
+        self.flatten_symlinks = true;
+        self
+    }
+}
+
 /// Wrapper for a [`cap_std::fs::Dir`] that is defined to use `RESOLVE_IN_ROOT``
 /// semantics when opening files and subdirectories. This currently only
 /// offers a subset of the methods, primarily reading.
@@ -46,18 +137,76 @@ pub(crate) fn open_beneath_rdonly(start: &BorrowedFd, path: &Path) -> io::Result
 /// absolute symlinks, or relative symlinks that may go outside the provided
 /// directory, you will need to use this API instead of [`cap_std::fs::Dir`].
 ///
+/// # Platform support
+///
+/// | Operation                         | Linux | FreeBSD |
+/// |-----------------------------------|-------|---------|
+/// | Relative paths, `..` within root  | Yes   | Yes     |
+/// | Absolute paths (relative to root) | Yes   | Yes     |
+/// | `..` above the root               | Clamped to the root | Error |
+/// | Absolute symbolic links           | Resolved in root | Error |
+/// | Magic links (e.g. `/proc/self/fd`)| Error | N/A     |
+///
+/// On Linux this uses `openat2` with `RESOLVE_IN_ROOT`.  FreeBSD has no
+/// equivalent of `RESOLVE_IN_ROOT`, so `O_RESOLVE_BENEATH` is used instead;
+/// this provides the same guarantee that files outside the root are never
+/// opened, but symbolic links which would need to be resolved relative to
+/// the root result in an error rather than being followed.
+///
 /// # Performing writes
 ///
-/// If you want to simultaneously perform other operations (such as writing), at the moment
-/// it requires explicitly maintaining a duplicate copy of a [`cap_std::fs::Dir`]
-/// instance, or using direct [`rustix::fs`] APIs.
+/// Files can be opened for writing (or created) with [`Self::open_with`] (or
+/// [`Self::open_with_oflags`] on Linux), and [`crate::capfs::CapFsWrite`] offers common write operations.  Other
+/// operations at the moment require explicitly maintaining a duplicate copy of a
+/// [`cap_std::fs::Dir`] instance, or using direct [`rustix::fs`] APIs.
 #[derive(Debug)]
-pub struct RootDir(Dir);
+pub struct RootDir {
+    dir: Dir,
+    /// Whether this was created via [`RootDir::hardened`].
+    hardened: bool,
+}
 
 impl RootDir {
     /// Create a new instance from an existing [`cap_std::fs::Dir`] instance.
     pub fn new(src: &Dir, path: impl AsRef<Path>) -> io::Result<Self> {
-        src.open_dir(path).map(Self)
+        src.open_dir(path).map(Self::from)
+    }
+
+    /// Create a new instance for a root whose contents are not trusted, such as an
+    /// extracted OS image.  In addition to the usual behavior:
+    ///
+    /// - It is an error (of kind [`std::io::ErrorKind::InvalidInput`]) if the root,
+    ///   or any directory directly beneath it, is a procfs or sysfs mount.
+    /// - Paths are resolved with `RESOLVE_NO_XDEV`, so opening a path which crosses a
+    ///   mount point fails with `EXDEV`.
+    /// - [`Self::walk`] requires [`crate::dirext::WalkConfiguration::noxdev`].
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    pub fn hardened(src: &Dir, path: impl AsRef<Path>) -> io::Result<Self> {
+        let dir = src.open_dir(path)?;
+        check_not_pseudofs(&dir, Path::new("."))?;
+        for entry in dir.entries()? {
+            let entry = entry?;
+            if !entry.file_type()?.is_dir() {
+                continue;
+            }
+            let name = entry.file_name();
+            // Don't follow links, or trigger automounts
+            let fd = match rustix::fs::openat(
+                &dir,
+                &name,
+                OFlags::PATH | OFlags::DIRECTORY | OFlags::NOFOLLOW | OFlags::CLOEXEC,
+                rustix::fs::Mode::empty(),
+            ) {
+                Ok(fd) => fd,
+                Err(rustix::io::Errno::NOENT) => continue,
+                Err(e) => return Err(e.into()),
+            };
+            check_not_pseudofs(fd, Path::new(&name))?;
+        }
+        Ok(Self {
+            dir,
+            hardened: true,
+        })
     }
 
     /// Create a new instance from an ambient path.
@@ -65,13 +214,80 @@ impl RootDir {
         path: impl AsRef<Path>,
         authority: cap_std::AmbientAuthority,
     ) -> io::Result<Self> {
-        Dir::open_ambient_dir(path, authority).map(Self)
+        crate::audit::open_ambient_dir(path.as_ref(), authority).map(Self::from)
     }
 
     /// Open a file in this root, read-only.
     pub fn open(&self, path: impl AsRef<Path>) -> io::Result<fs::File> {
-        let path = path.as_ref();
-        open_beneath_rdonly(&self.0.as_fd(), path)
+        self.open_in_root(path.as_ref(), OFlags::empty())
+    }
+
+    /// Open a file in this root with the provided flags (e.g. `O_RDWR | O_CREAT`,
+    /// `O_WRONLY | O_TRUNC` or `O_APPEND`), as with
+    /// [`crate::dirext::CapStdExtDirExt::open_with_oflags`].  `O_CLOEXEC` is always
+    /// added, and if `O_CREAT` is specified, the file is created with mode `0o666`
+    /// (as modified by the process umask).
+    ///
+    /// Note that with `O_CREAT`, a dangling symbolic link is followed (in the root),
+    /// and the file is created at its target; add `O_NOFOLLOW` or `O_EXCL` to
+    /// prevent this.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    pub fn open_with_oflags(&self, path: impl AsRef<Path>, flags: OFlags) -> io::Result<fs::File> {
+        // openat2 rejects a mode unless a file may be created.
+        let mode = if flags.contains(OFlags::CREATE) || flags.contains(OFlags::TMPFILE) {
+            rustix::fs::Mode::from_raw_mode(0o666)
+        } else {
+            rustix::fs::Mode::empty()
+        };
+        let r = crate::dirext::openat2_with_retry(
+            &self.dir,
+            path.as_ref(),
+            flags | OFlags::CLOEXEC,
+            mode,
+            self.resolve_flags(),
+        )?;
+        Ok(r.into())
+    }
+
+    /// Open a file in this root with the provided options, as with
+    /// [`cap_std::fs::Dir::open_with`].  Symbolic links (including absolute ones)
+    /// are resolved in the root.
+    ///
+    /// The final component is opened by `cap_std`, which follows symbolic links
+    /// that stay beneath its parent directory; a link which leads elsewhere is
+    /// read and resolved again from the root, up to 40 times, after which the
+    /// open fails with `ELOOP`.
+    ///
+    /// As with [`Self::open_with_oflags`], when creating a file a dangling symbolic
+    /// link is followed (in the root), unless `create_new` is set.
+    pub fn open_with(
+        &self,
+        path: impl AsRef<Path>,
+        options: &cap_std::fs::OpenOptions,
+    ) -> io::Result<fs::File> {
+        let mut path = path.as_ref().to_owned();
+        for _ in 0..=MAX_SYMLINKS {
+            let (parent, name) = self.open_parent_in_root(&path)?;
+            match parent.open_with(name, options) {
+                // The parent is opened in the root, but the final component is
+                // opened beneath the parent; a symbolic link there which leads
+                // outside of it fails, and is resolved here instead.
+                Err(e)
+                    if e.kind() == io::ErrorKind::PermissionDenied
+                        && parent
+                            .symlink_metadata_optional(name)?
+                            .is_some_and(|m| m.is_symlink()) =>
+                {
+                    let target = read_link_at(&parent, Path::new(name))?;
+                    path = match path.parent() {
+                        Some(p) => p.join(target),
+                        None => target,
+                    };
+                }
+                r => return r.map(|f| f.into_std()),
+            }
+        }
+        Err(rustix::io::Errno::LOOP.into())
     }
 
     /// Open a file read-only, but return `Ok(None)` if it does not exist.
@@ -79,6 +295,28 @@ impl RootDir {
         crate::dirext::map_optional(self.open(path))
     }
 
+    /// Query the metadata of a file, following symbolic links in the root.
+    pub fn metadata(&self, path: impl AsRef<Path>) -> io::Result<cap_std::fs::Metadata> {
+        // With O_PATH, no permission on the file itself is required.
+        let f = self.open_in_root(path.as_ref(), OFlags::PATH)?;
+        cap_std::fs::Metadata::from_file(&f)
+    }
+
+    /// Return `true` if the path exists (following symbolic links in the root), and
+    /// `false` if it does not.  Other errors (such as permission denied, or too many
+    /// levels of symbolic links) are returned.
+    pub fn exists(&self, path: impl AsRef<Path>) -> io::Result<bool> {
+        // With O_PATH, no permission on the file itself is required.
+        let f = self.open_in_root(path.as_ref(), OFlags::PATH);
+        Ok(crate::dirext::map_optional(f)?.is_some())
+    }
+
+    /// The same as [`Self::exists`], named for consistency with
+    /// [`cap_std::fs::Dir::try_exists`].
+    pub fn try_exists(&self, path: impl AsRef<Path>) -> io::Result<bool> {
+        self.exists(path)
+    }
+
     /// Read the contents of a file into a vector.
     pub fn read(&self, path: impl AsRef<Path>) -> io::Result<Vec<u8>> {
         let mut f = self.open(path.as_ref())?;
@@ -95,25 +333,400 @@ impl RootDir {
         Ok(s)
     }
 
+    /// Read the contents of a file into a vector, but return `Ok(None)` if it does
+    /// not exist (including if it is a dangling symbolic link).
+    pub fn read_optional(&self, path: impl AsRef<Path>) -> io::Result<Option<Vec<u8>>> {
+        let Some(mut f) = self.open_optional(path)? else {
+            return Ok(None);
+        };
+        let mut r = Vec::new();
+        f.read_to_end(&mut r)?;
+        Ok(Some(r))
+    }
+
+    /// Read the contents of a file as a string, but return `Ok(None)` if it does
+    /// not exist (including if it is a dangling symbolic link).
+    pub fn read_to_string_optional(&self, path: impl AsRef<Path>) -> io::Result<Option<String>> {
+        let Some(mut f) = self.open_optional(path)? else {
+            return Ok(None);
+        };
+        let mut s = String::new();
+        f.read_to_string(&mut s)?;
+        Ok(Some(s))
+    }
+
+    /// Return the value of an extended attribute of the file or directory at the
+    /// given path (following symbolic links in the root, except in the final
+    /// component), or `None` if it is not set.
+    ///
+    /// See [`crate::dirext::CapStdExtDirExt::getxattr`] for details.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    pub fn getxattr(
+        &self,
+        path: impl AsRef<Path>,
+        key: impl AsRef<OsStr>,
+    ) -> io::Result<Option<Vec<u8>>> {
+        self.xattr_target(path.as_ref())?.get(key.as_ref())
+    }
+
+    /// Set an extended attribute of the file or directory at the given path
+    /// (following symbolic links in the root, except in the final component).
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    pub fn setxattr(
+        &self,
+        path: impl AsRef<Path>,
+        key: impl AsRef<OsStr>,
+        value: impl AsRef<[u8]>,
+    ) -> io::Result<()> {
+        self.xattr_target(path.as_ref())?
+            .set(key.as_ref(), value.as_ref())
+    }
+
+    /// List the names of the extended attributes of the file or directory at the
+    /// given path (following symbolic links in the root, except in the final component).
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    pub fn listxattrs(&self, path: impl AsRef<Path>) -> io::Result<Vec<OsString>> {
+        self.xattr_target(path.as_ref())?.list()
+    }
+
+    /// Remove an extended attribute of the file or directory at the given path
+    /// (following symbolic links in the root, except in the final component),
+    /// returning `false` if it was not set.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    pub fn removexattr(&self, path: impl AsRef<Path>, key: impl AsRef<OsStr>) -> io::Result<bool> {
+        self.xattr_target(path.as_ref())?.remove(key.as_ref())
+    }
+
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn xattr_target(&self, path: &Path) -> io::Result<crate::dirext::XattrTarget> {
+        crate::dirext::XattrTarget::open_with(&self.dir, path, self.resolve_flags())
+    }
+
+    /// Search for an executable program in the provided directories (e.g. `["/usr/bin", "/bin"]`),
+    /// in the same way as a shell would using `PATH`, but entirely within this root.
+    /// The ambient `PATH` environment variable is never consulted.
+    ///
+    /// If `name` contains a `/`, then it is checked directly instead of searching.
+    ///
+    /// A candidate is only accepted if it is a regular file (after following symbolic
+    /// links in the root) with an execute bit set, and it starts with an ELF header or
+    /// a `#!` interpreter line.  Note that the interpreter itself is not checked.
+    ///
+    /// Returns the path of the first match (as joined from the search directory),
+    /// or `Ok(None)` if there is none.
+    pub fn find_executable(
+        &self,
+        name: impl AsRef<Path>,
+        search_paths: impl IntoIterator<Item = impl AsRef<Path>>,
+    ) -> io::Result<Option<PathBuf>> {
+        // Don't block on e.g. a FIFO
+        crate::dirext::find_executable_with(name.as_ref(), search_paths, |candidate| {
+            self.open_in_root(candidate, OFlags::NONBLOCK)
+        })
+    }
+
+    /// Read the target of a symbolic link, without following it.  Symbolic links in
+    /// the parent directories are followed in the root, as with [`Self::open`].
+    ///
+    /// The target is returned as stored in the link; see [`Self::resolve`] to find
+    /// what it refers to in the root.
+    pub fn read_link(&self, path: impl AsRef<Path>) -> io::Result<PathBuf> {
+        let (parent, name) = self.open_parent_in_root(path.as_ref())?;
+        read_link_at(&parent, Path::new(name))
+    }
+
+    /// Resolve all symbolic links in a path, as if the root were `/`, returning the
+    /// path (relative to the root) of what it refers to; for example, if
+    /// `etc/auth.json` is a link to `/usr/lib/auth.json` (or `../usr/lib/auth.json`),
+    /// then resolving `/etc/auth.json` returns `usr/lib/auth.json`.  The root
+    /// itself is returned as `.`.
+    ///
+    /// Unlike [`Self::open`], this behaves identically on all platforms, and does
+    /// not open the target.  The path must exist; dangling links result in an error
+    /// of kind [`std::io::ErrorKind::NotFound`], more than 40 links in an error
+    /// with `ELOOP`, and a non-directory followed by another component (including
+    /// `..`) in an error with `ENOTDIR`.
+    ///
+    /// As this inspects the path one component at a time, the result may be
+    /// inaccurate if the directory is concurrently modified.
+    pub fn resolve(&self, path: impl AsRef<Path>) -> io::Result<PathBuf> {
+        // Push the components of a path, in reverse order; `None` is the root.
+        fn queue(pending: &mut Vec<Option<OsString>>, path: &Path) {
+            for component in path.components().rev() {
+                match component {
+                    Component::Prefix(_) | Component::RootDir => pending.push(None),
+                    Component::CurDir => {}
+                    Component::ParentDir => pending.push(Some("..".into())),
+                    Component::Normal(name) => pending.push(Some(name.to_owned())),
+                }
+            }
+        }
+        // The components remaining to be resolved; the targets of links are pushed
+        // here as they are read.
+        let mut pending = Vec::new();
+        queue(&mut pending, path.as_ref());
+        // The resolved path, none of whose components are symbolic links.
+        let mut resolved = PathBuf::new();
+        // Whether the final component of the resolved path is a directory; all of
+        // the others are.
+        let mut is_dir = true;
+        let mut nlinks = 0;
+        while let Some(component) = pending.pop() {
+            let Some(name) = component else {
+                resolved.clear();
+                is_dir = true;
+                continue;
+            };
+            if !is_dir {
+                return Err(rustix::io::Errno::NOTDIR.into());
+            }
+            if name == ".." {
+                // Like RESOLVE_IN_ROOT, `..` at the root is the root.
+                resolved.pop();
+                continue;
+            }
+            resolved.push(&name);
+            let meta = self.dir.symlink_metadata(&resolved)?;
+            if !meta.is_symlink() {
+                is_dir = meta.is_dir();
+                continue;
+            }
+            resolved.pop();
+            nlinks += 1;
+            if nlinks > MAX_SYMLINKS {
+                return Err(rustix::io::Errno::LOOP.into());
+            }
+            let target = if resolved.as_os_str().is_empty() {
+                read_link_at(&self.dir, Path::new(&name))?
+            } else {
+                read_link_at(&self.dir.open_dir(&resolved)?, Path::new(&name))?
+            };
+            queue(&mut pending, &target);
+        }
+        if resolved.as_os_str().is_empty() {
+            resolved.push(".");
+        }
+        Ok(resolved)
+    }
+
+    /// Recursively walk the root, as with [`crate::dirext::CapStdExtDirExt::walk`].
+    ///
+    /// With [`crate::dirext::WalkConfiguration::follow_symlinks`], symbolic links
+    /// (including absolute ones) are resolved in the root on Linux, as with
+    /// [`Self::open`]; on FreeBSD, links which would need this are reported as
+    /// links.  Other options, such as sorting and
+    /// [`crate::dirext::WalkConfiguration::noxdev`], behave identically.
+    ///
+    /// For a root created via [`Self::hardened`], `noxdev` is required.
+    pub fn walk<C, E>(
+        &self,
+        config: &crate::dirext::WalkConfiguration,
+        callback: C,
+    ) -> Result<(), E>
+    where
+        C: FnMut(&crate::dirext::WalkComponent) -> crate::dirext::WalkResult<E>,
+        E: From<io::Error>,
+    {
+        if self.hardened && !config.has_noxdev() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Walking a hardened root requires noxdev",
+            )
+            .into());
+        }
+        crate::dirext::walk_dir(&self.dir, config, true, callback)
+    }
+
     /// Return the directory entries.
     pub fn entries(&self) -> io::Result<cap_std::fs::ReadDir> {
-        self.0.entries()
+        self.dir.entries()
     }
 
     /// Return the directory entries of the target subdirectory.
     pub fn read_dir(&self, path: impl AsRef<Path>) -> io::Result<cap_std::fs::ReadDir> {
-        self.0.read_dir(path.as_ref())
+        self.dir.read_dir(path.as_ref())
+    }
+
+    /// Copy the contents of a directory in this root (resolved in the root, e.g.
+    /// `/etc`) into `dest`, which is typically an empty directory.  This recreates
+    /// directories, regular files (including their permission bits) and symbolic
+    /// links; other types of files are skipped.  Ownership and timestamps are not
+    /// copied.
+    ///
+    /// By default, symbolic links are copied as they are, so absolute links will
+    /// still refer to paths in the root; see [`CopyOptions::flatten_symlinks`].
+    /// When flattening, a link to a directory which is being copied (i.e. an
+    /// ancestor) results in an error.
+    pub fn copy_to(
+        &self,
+        subpath: impl AsRef<Path>,
+        dest: &Dir,
+        options: &CopyOptions,
+    ) -> io::Result<()> {
+        let mut path = subpath.as_ref().to_owned();
+        let src = self.open_dir_in_root(&path)?;
+        let mut ancestors = vec![crate::dirext::dir_id(&src)?];
+        self.copy_dir_contents(&mut path, &src, dest, options, &mut ancestors)
+    }
+
+    fn copy_dir_contents(
+        &self,
+        path: &mut PathBuf,
+        src: &Dir,
+        dest: &Dir,
+        options: &CopyOptions,
+        ancestors: &mut Vec<(u64, u64)>,
+    ) -> io::Result<()> {
+        use cap_std::fs::{Permissions, PermissionsExt};
+
+        for entry in src.entries()? {
+            let entry = entry?;
+            let name = entry.file_name();
+            path.push(&name);
+            let mut meta = entry.metadata()?;
+            // Whether this is a symbolic link which was followed; then, it is opened
+            // by its path in the root.
+            let mut followed = false;
+            if meta.is_symlink() {
+                if options.flatten_symlinks {
+                    if let Some(m) = crate::dirext::map_optional(self.metadata(&path))? {
+                        meta = m;
+                        followed = true;
+                    }
+                }
+                if !followed {
+                    let target = read_link_at(src, Path::new(&name))?;
+                    dest.symlink_contents(target, &name)?;
+                    path.pop();
+                    continue;
+                }
+            }
+            let perms = Permissions::from_mode(meta.permissions().mode() & 0o7777);
+            if meta.is_dir() {
+                let subdir = if followed {
+                    self.open_dir_in_root(path)?
+                } else {
+                    src.open_dir(&name)?
+                };
+                let id = crate::dirext::dir_id(&subdir)?;
+                if ancestors.contains(&id) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        format!("Symbolic link to an ancestor: {}", path.display()),
+                    ));
+                }
+                dest.create_dir(&name)?;
+                let subdest = dest.open_dir(&name)?;
+                ancestors.push(id);
+                self.copy_dir_contents(path, &subdir, &subdest, options, ancestors)?;
+                ancestors.pop();
+                // This is done last, in case the directory is not writable.
+                dest.set_permissions(&name, perms)?;
+            } else if meta.is_file() {
+                let mut f = if followed {
+                    self.open(&path)?
+                } else {
+                    src.open(&name)?.into_std()
+                };
+                let mut destf = dest.create(&name)?;
+                io::copy(&mut f, &mut destf)?;
+                destf.set_permissions(perms)?;
+            }
+            path.pop();
+        }
+        Ok(())
+    }
+
+    /// Open a path read-only with additional flags, resolving it in the root.
+    fn open_in_root(&self, path: &Path, oflags: OFlags) -> io::Result<fs::File> {
+        #[cfg(any(target_os = "android", target_os = "linux"))]
+        {
+            let r = crate::dirext::openat2_with_retry(
+                &self.dir,
+                path,
+                OFlags::CLOEXEC | OFlags::RDONLY | oflags,
+                rustix::fs::Mode::empty(),
+                self.resolve_flags(),
+            )?;
+            Ok(r.into())
+        }
+        #[cfg(target_os = "freebsd")]
+        open_beneath_with(&self.dir.as_fd(), path, oflags)
+    }
+
+    /// The flags used to resolve paths in the root.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn resolve_flags(&self) -> ResolveFlags {
+        let mut flags = ResolveFlags::IN_ROOT | ResolveFlags::NO_MAGICLINKS;
+        if self.hardened {
+            flags |= ResolveFlags::NO_XDEV;
+        }
+        flags
+    }
+
+    /// Open a directory, resolving the path in the root.
+    pub(crate) fn open_dir_in_root(&self, path: &Path) -> io::Result<Dir> {
+        let d = self.open_in_root(path, OFlags::DIRECTORY)?;
+        Ok(Dir::from_std_file(d))
+    }
+
+    /// Open the parent directory of a path, resolving it in the root, and return it
+    /// along with the final component of the path.
+    pub(crate) fn open_parent_in_root<'p>(&self, path: &'p Path) -> io::Result<(Dir, &'p OsStr)> {
+        let name = path
+            .file_name()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Path has no file name"))?;
+        let parent = match path.parent() {
+            Some(p) if !p.as_os_str().is_empty() => p,
+            _ => Path::new("."),
+        };
+        Ok((self.open_dir_in_root(parent)?, name))
+    }
+
+    /// Create a new instance referring to the same directory (with a duplicated file
+    /// descriptor).
+    pub fn try_clone(&self) -> io::Result<Self> {
+        let dir = self.dir.try_clone()?;
+        Ok(Self {
+            dir,
+            hardened: self.hardened,
+        })
     }
 
     /// Create a [`cap_std::fs::Dir`] pointing to the same directory as `self`.
     /// This view will *not* use `RESOLVE_IN_ROOT`.
     pub fn reopen_cap_std(&self) -> io::Result<Dir> {
-        Dir::reopen_dir(&self.0.as_fd())
+        Dir::reopen_dir(&self.dir.as_fd())
     }
 }
 
 impl From<Dir> for RootDir {
     fn from(dir: Dir) -> Self {
-        Self(dir)
+        Self {
+            dir,
+            hardened: false,
+        }
+    }
+}
+
+/// The file descriptor must refer to a directory; otherwise an error with `ENOTDIR`
+/// is returned, and it is closed.
+impl TryFrom<OwnedFd> for RootDir {
+    type Error = io::Error;
+
+    fn try_from(fd: OwnedFd) -> io::Result<Self> {
+        crate::interop::dir_from_fd(fd).map(Self::from)
+    }
+}
+
+impl From<RootDir> for OwnedFd {
+    fn from(root: RootDir) -> Self {
+        root.dir.into_std_file().into()
+    }
+}
+
+impl AsFd for RootDir {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.dir.as_fd()
     }
 }
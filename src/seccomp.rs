@@ -0,0 +1,218 @@
+//! Filtering the system calls of child processes using seccomp.
+//!
+//! The key API here is [`crate::cmdext::CapStdExtCommandExt::seccomp_filter`],
+//! which applies a [`SeccompPolicy`].  A policy is either a list of per-syscall
+//! actions with a default, compiled to a BPF program for the current
+//! architecture, or a BPF program compiled elsewhere (e.g. with libseccomp).
+
+use std::io::{self, Result};
+
+/// What to do when a system call matches a rule.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum SeccompAction {
+    /// Allow the system call.
+    Allow,
+    /// Fail the system call with the given `errno` value.
+    Errno(u16),
+    /// Kill the process.
+    KillProcess,
+    /// Send `SIGSYS` to the thread.
+    Trap,
+    /// Allow the system call, and log it.
+    Log,
+}
+
+impl SeccompAction {
+    fn ret(self) -> u32 {
+        match self {
+            Self::Allow => libc::SECCOMP_RET_ALLOW,
+            Self::Errno(e) => libc::SECCOMP_RET_ERRNO | (u32::from(e) & libc::SECCOMP_RET_DATA),
+            Self::KillProcess => libc::SECCOMP_RET_KILL_PROCESS,
+            Self::Trap => libc::SECCOMP_RET_TRAP,
+            Self::Log => libc::SECCOMP_RET_LOG,
+        }
+    }
+}
+
+/// `struct sock_filter`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub(crate) struct Instruction {
+    code: u16,
+    jt: u8,
+    jf: u8,
+    k: u32,
+}
+
+impl Instruction {
+    const fn new(code: u16, jt: u8, jf: u8, k: u32) -> Self {
+        Self { code, jt, jf, k }
+    }
+}
+
+/// `BPF_LD | BPF_W | BPF_ABS`
+const LD_W_ABS: u16 = 0x20;
+/// `BPF_JMP | BPF_JEQ | BPF_K`
+const JEQ_K: u16 = 0x15;
+/// `BPF_JMP | BPF_JGE | BPF_K`
+const JGE_K: u16 = 0x35;
+/// `BPF_RET | BPF_K`
+const RET_K: u16 = 0x06;
+/// `BPF_MAXINSNS`
+const MAX_INSTRUCTIONS: usize = 4096;
+/// The offsets of the fields of `struct seccomp_data`.
+const DATA_NR: u32 = 0;
+const DATA_ARCH: u32 = 4;
+
+/// The `AUDIT_ARCH_*` value for the current architecture, if known.
+const AUDIT_ARCH: Option<u32> = if cfg!(target_arch = "x86_64") {
+    Some(0xc000_003e)
+} else if cfg!(target_arch = "aarch64") {
+    Some(0xc000_00b7)
+} else if cfg!(target_arch = "x86") {
+    Some(0x4000_0003)
+} else if cfg!(target_arch = "arm") {
+    Some(0x4000_0028)
+} else if cfg!(target_arch = "riscv64") {
+    Some(0xc000_00f3)
+} else if cfg!(target_arch = "s390x") {
+    Some(0x8000_0016)
+} else if cfg!(all(target_arch = "powerpc64", target_endian = "little")) {
+    Some(0xc000_0015)
+} else if cfg!(all(target_arch = "powerpc64", target_endian = "big")) {
+    Some(0x8000_0015)
+} else {
+    None
+};
+
+#[derive(Debug, Clone)]
+enum Policy {
+    Rules {
+        default: SeccompAction,
+        rules: Vec<(libc::c_long, SeccompAction)>,
+    },
+    Bpf(Vec<Instruction>),
+}
+
+/// A seccomp filter; see the [module documentation](self).
+///
+/// Note that the filter applies to the `execve` performed to start the process,
+/// so that must be allowed.
+#[derive(Debug, Clone)]
+pub struct SeccompPolicy(Policy);
+
+impl SeccompPolicy {
+    /// Create a policy which takes the given action for system calls without a
+    /// rule, e.g. [`SeccompAction::Errno`] with `ENOSYS` for an allow list.
+    pub fn new(default: SeccompAction) -> Self {
+        Self(Policy::Rules {
+            default,
+            rules: Vec::new(),
+        })
+    }
+
+    /// Use a BPF program compiled elsewhere, as an array of `struct sock_filter`
+    /// in native byte order.  It is not checked for the current architecture.
+    pub fn from_bpf(bytes: &[u8]) -> Result<Self> {
+        const SIZE: usize = std::mem::size_of::<Instruction>();
+        if bytes.is_empty() || bytes.len() % SIZE != 0 || bytes.len() / SIZE > MAX_INSTRUCTIONS {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Invalid BPF program length",
+            ));
+        }
+        let program = bytes
+            .chunks_exact(SIZE)
+            .map(|c| {
+                // SAFETY(unwrap): The chunks have the right size.
+                let code = u16::from_ne_bytes(c[0..2].try_into().unwrap());
+                let k = u32::from_ne_bytes(c[4..8].try_into().unwrap());
+                Instruction::new(code, c[2], c[3], k)
+            })
+            .collect();
+        Ok(Self(Policy::Bpf(program)))
+    }
+
+    /// Allow the system call (e.g. `libc::SYS_read`).
+    pub fn allow(self, syscall: libc::c_long) -> Self {
+        self.rule(syscall, SeccompAction::Allow)
+    }
+
+    /// Take the given action for the system call.  The first matching rule
+    /// applies.  This has no effect on a policy from [`Self::from_bpf`].
+    pub fn rule(mut self, syscall: libc::c_long, action: SeccompAction) -> Self {
+        if let Policy::Rules { rules, .. } = &mut self.0 {
+            rules.push((syscall, action));
+        }
+        self
+    }
+
+    /// Compile the policy to a BPF program.
+    pub(crate) fn compile(&self) -> Result<Vec<Instruction>> {
+        let (default, rules) = match &self.0 {
+            Policy::Bpf(program) => return Ok(program.clone()),
+            Policy::Rules { default, rules } => (default, rules),
+        };
+        let arch = AUDIT_ARCH.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Compiling seccomp policies is not supported on this architecture",
+            )
+        })?;
+        let kill = SeccompAction::KillProcess.ret();
+        let mut r = vec![
+            // System call numbers differ by architecture, so kill anything else.
+            Instruction::new(LD_W_ABS, 0, 0, DATA_ARCH),
+            Instruction::new(JEQ_K, 1, 0, arch),
+            Instruction::new(RET_K, 0, 0, kill),
+            Instruction::new(LD_W_ABS, 0, 0, DATA_NR),
+        ];
+        if cfg!(target_arch = "x86_64") {
+            // Likewise for the x32 ABI, which has the same architecture value.
+            r.push(Instruction::new(JGE_K, 0, 1, 0x4000_0000));
+            r.push(Instruction::new(RET_K, 0, 0, kill));
+        }
+        for &(nr, action) in rules {
+            let nr = u32::try_from(nr).map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidInput, "Invalid system call number")
+            })?;
+            r.push(Instruction::new(JEQ_K, 0, 1, nr));
+            r.push(Instruction::new(RET_K, 0, 0, action.ret()));
+        }
+        r.push(Instruction::new(RET_K, 0, 0, default.ret()));
+        if r.len() > MAX_INSTRUCTIONS {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Too many seccomp rules",
+            ));
+        }
+        Ok(r)
+    }
+}
+
+/// Install the filter for the current thread; this is called after fork.
+pub(crate) fn apply(program: &[Instruction]) -> Result<()> {
+    // This is required for unprivileged processes.
+    rustix::thread::set_no_new_privs(true)?;
+    let prog = libc::sock_fprog {
+        // SAFETY(unwrap): The length was checked when compiling.
+        len: program.len().try_into().unwrap(),
+        // The kernel does not modify the program.
+        filter: program.as_ptr().cast::<libc::sock_filter>().cast_mut(),
+    };
+    // SAFETY: The program is valid for the duration of the call, and
+    // `Instruction` has the layout of `struct sock_filter`.
+    #[allow(unsafe_code)]
+    let r = unsafe {
+        libc::prctl(
+            libc::PR_SET_SECCOMP,
+            libc::SECCOMP_MODE_FILTER as libc::c_ulong,
+            &prog as *const libc::sock_fprog,
+        )
+    };
+    if r < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
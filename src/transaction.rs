@@ -0,0 +1,147 @@
+//! Staging a set of changes to a directory tree and applying them together.
+//!
+//! The key API here is [`crate::dirext::CapStdExtDirExt::transaction`], which
+//! returns a [`DirTransaction`].
+
+use std::io::Result;
+use std::path::{Path, PathBuf};
+
+use cap_std::fs::Dir;
+use cap_tempfile::cap_std;
+
+use crate::dirext::{copy_mode_and_owner, sync_dir, CapStdExtDirExt, Durability};
+
+/// A change staged in a [`DirTransaction`].
+#[derive(Debug)]
+enum Op {
+    CreateDir(PathBuf),
+    Write { staged: String, dest: PathBuf },
+    Remove(PathBuf),
+    Rename { from: PathBuf, to: PathBuf },
+}
+
+/// A set of changes to a directory, which are staged and then applied with
+/// [`DirTransaction::commit`], or discarded with [`DirTransaction::abort`] (or by
+/// dropping the transaction).
+///
+/// New file contents are written and synchronized to a temporary directory
+/// beneath the target directory as they are staged, so committing only performs
+/// renames: each file is replaced atomically, and is never visible partially
+/// written.  Changes are applied in the order they were staged, and each is made
+/// durable (by synchronizing the directories it affects) before the next one is
+/// applied, so after a crash the changes which took effect are always a prefix of
+/// those staged.
+///
+/// Note that the transaction as a whole is not atomic, and there is no recovery:
+/// if committing fails (or the system crashes) part way through, only some of the
+/// changes will have been applied.  See [`crate::journal`] for changes which can
+/// be completed after a crash.
+#[derive(Debug)]
+pub struct DirTransaction<'d> {
+    dir: &'d Dir,
+    staging: cap_tempfile::TempDir,
+    ops: Vec<Op>,
+}
+
+/// The parent directory of a path, relative to the transaction's directory.
+fn parent_of(path: &Path) -> PathBuf {
+    match path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p.to_owned(),
+        _ => PathBuf::from("."),
+    }
+}
+
+impl<'d> DirTransaction<'d> {
+    pub(crate) fn new(dir: &'d Dir) -> Result<Self> {
+        Ok(Self {
+            dir,
+            staging: cap_tempfile::TempDir::new_in(dir)?,
+            ops: Vec::new(),
+        })
+    }
+
+    /// Stage the creation of a directory; its parent must exist when the
+    /// transaction is committed (possibly having been created earlier in it).
+    pub fn create_dir(&mut self, path: impl AsRef<Path>) {
+        self.ops.push(Op::CreateDir(path.as_ref().to_owned()));
+    }
+
+    /// Stage writing a file with the given contents, replacing it if it exists.
+    /// The contents are written (and synchronized) immediately.
+    ///
+    /// If the file exists at this point, its mode and ownership are preserved.
+    pub fn write(&mut self, path: impl AsRef<Path>, contents: impl AsRef<[u8]>) -> Result<()> {
+        let path = path.as_ref();
+        let staged = format!("write-{}", self.ops.len());
+        let f = self.staging.create(&staged)?;
+        if let Some(m) = self
+            .dir
+            .symlink_metadata_optional(path)?
+            .filter(|m| m.is_file())
+        {
+            copy_mode_and_owner(&f, &m)?;
+        }
+        std::io::Write::write_all(&mut &f, contents.as_ref())?;
+        f.sync_all()?;
+        self.ops.push(Op::Write {
+            staged,
+            dest: path.to_owned(),
+        });
+        Ok(())
+    }
+
+    /// Stage removing a file, symbolic link or directory (including its contents).
+    pub fn remove(&mut self, path: impl AsRef<Path>) {
+        self.ops.push(Op::Remove(path.as_ref().to_owned()));
+    }
+
+    /// Stage renaming a file or directory.
+    pub fn rename(&mut self, from: impl AsRef<Path>, to: impl AsRef<Path>) {
+        self.ops.push(Op::Rename {
+            from: from.as_ref().to_owned(),
+            to: to.as_ref().to_owned(),
+        });
+    }
+
+    /// Apply the staged changes in order, synchronizing the affected directories
+    /// after each one, so that the changes are durable once this returns.
+    pub fn commit(self) -> Result<()> {
+        let dir = self.dir;
+        let staging = self.staging;
+        sync_dir(&staging, Durability::Standard)?;
+        let sync_parent =
+            |path: &Path| sync_dir(&dir.open_dir(parent_of(path))?, Durability::Standard);
+        for (i, op) in self.ops.into_iter().enumerate() {
+            match op {
+                Op::CreateDir(path) => {
+                    dir.create_dir(&path)?;
+                    sync_parent(&path)?;
+                }
+                Op::Write { staged, dest } => {
+                    staging.rename(&staged, dir, &dest)?;
+                    sync_parent(&dest)?;
+                }
+                Op::Remove(path) => {
+                    // Removed entries are moved aside, and deleted along with the
+                    // staging directory once everything else is done.
+                    dir.rename(&path, &staging, format!("remove-{i}"))?;
+                    sync_parent(&path)?;
+                }
+                Op::Rename { from, to } => {
+                    dir.rename(&from, dir, &to)?;
+                    sync_parent(&from)?;
+                    if parent_of(&from) != parent_of(&to) {
+                        sync_parent(&to)?;
+                    }
+                }
+            }
+        }
+        staging.close()
+    }
+
+    /// Discard the staged changes.  This is equivalent to dropping the
+    /// transaction, but reports errors removing the temporary directory.
+    pub fn abort(self) -> Result<()> {
+        self.staging.close()
+    }
+}
@@ -0,0 +1,407 @@
+//! Watching for changes in a directory using `inotify`.
+//!
+//! The key API here is [`crate::dirext::CapStdExtDirExt::watch`], which returns
+//! a [`Watcher`] yielding events with paths relative to the directory.
+//!
+//! Watches are always added via file descriptors opened beneath the directory, and
+//! when a watched subdirectory is moved away, its watches (and those of its
+//! children) are removed; so events are never reported for files outside of it.
+//!
+//! Most consumers will want to use [`Watcher::debounce`], which coalesces bursts of
+//! events (e.g. from a package installation) into a single batch per path.
+
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::io::{self, Result};
+use std::mem::MaybeUninit;
+use std::ops::ControlFlow;
+use std::os::fd::{AsFd, BorrowedFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use cap_std::fs::Dir;
+use cap_tempfile::cap_std;
+use rustix::fs::inotify;
+
+use crate::dirext::{CapStdExtDirExt, WalkConfiguration};
+
+/// Configuration for [`crate::dirext::CapStdExtDirExt::watch`].
+#[derive(Debug, Default, Clone)]
+pub struct WatchConfig {
+    recursive: bool,
+    noxdev: bool,
+}
+
+impl WatchConfig {
+    /// Also watch all subdirectories, including ones created later.
+    pub fn recursive(mut self) -> Self {
+        self.recursive = true;
+        self
+    }
+
+    /// When watching recursively, do not cross mount points.
+    pub fn noxdev(mut self) -> Self {
+        self.noxdev = true;
+        self
+    }
+}
+
+/// The type of a [`WatchEvent`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum WatchEventKind {
+    /// A file or directory was created.
+    Created,
+    /// A file or directory was removed.
+    Removed,
+    /// The contents of a file were modified.
+    Modified,
+    /// The metadata (e.g. permissions, timestamps, extended attributes) of a file
+    /// or directory changed.
+    Metadata,
+    /// A file or directory was renamed away from this path.
+    MovedFrom,
+    /// A file or directory was renamed to this path.
+    MovedTo,
+    /// The kernel event queue overflowed, and events were lost.  The tree
+    /// should be rescanned.
+    Overflow,
+}
+
+/// An event for a path beneath the watched directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WatchEvent {
+    /// The path, relative to the watched directory.  This is empty for
+    /// events on the directory itself, or an overflow.
+    pub path: PathBuf,
+    /// The type of the event.
+    pub kind: WatchEventKind,
+    /// Whether the path is a directory.
+    pub is_dir: bool,
+}
+
+/// A set of `inotify` watches on a directory; see [`crate::dirext::CapStdExtDirExt::watch`].
+#[derive(Debug)]
+pub struct Watcher {
+    inotify: OwnedFd,
+    root: Dir,
+    config: WatchConfig,
+    /// Maps a watch descriptor to the directory path.
+    watches: HashMap<i32, PathBuf>,
+    buf: Vec<MaybeUninit<u8>>,
+}
+
+/// The events we request for each directory.
+fn watch_flags() -> inotify::WatchFlags {
+    use inotify::WatchFlags;
+    WatchFlags::CREATE
+        | WatchFlags::DELETE
+        | WatchFlags::MODIFY
+        | WatchFlags::ATTRIB
+        | WatchFlags::MOVED_FROM
+        | WatchFlags::MOVED_TO
+        | WatchFlags::ONLYDIR
+}
+
+impl Watcher {
+    pub(crate) fn new(d: &Dir, config: &WatchConfig) -> Result<Self> {
+        let inotify = inotify::init(inotify::CreateFlags::CLOEXEC)?;
+        let mut r = Self {
+            inotify,
+            root: d.try_clone()?,
+            config: config.clone(),
+            watches: HashMap::new(),
+            // Enough for many events with names of maximal length
+            buf: vec![MaybeUninit::uninit(); 64 * 1024],
+        };
+        r.add_watch(Path::new(""), &r.root.try_clone()?)?;
+        if r.config.recursive {
+            r.add_tree(Path::new(""))?;
+        }
+        Ok(r)
+    }
+
+    fn add_watch(&mut self, path: &Path, d: &Dir) -> Result<()> {
+        // inotify only accepts paths; go via procfs to ensure we watch
+        // exactly the directory we opened.
+        let procpath = crate::audit::proc_self_fd(d.as_fd())?;
+        let wd = inotify::add_watch(&self.inotify, procpath, watch_flags())?;
+        self.watches.insert(wd, path.to_owned());
+        Ok(())
+    }
+
+    /// Add watches for all subdirectories of the given (already watched) directory.
+    fn add_tree(&mut self, path: &Path) -> Result<()> {
+        let d = if path.as_os_str().is_empty() {
+            self.root.try_clone()?
+        } else {
+            match crate::dirext::map_optional(self.root.open_dir(path))? {
+                Some(d) => d,
+                // Raced with removal
+                None => return Ok(()),
+            }
+        };
+        let mut config = WalkConfiguration::default().path_base(path);
+        if self.config.noxdev {
+            config = config.noxdev();
+        }
+        let mut subdirs = Vec::new();
+        d.walk(&config, |e| -> io::Result<_> {
+            if !e.file_type.is_dir() {
+                return Ok(ControlFlow::Continue(()));
+            }
+            let sub = if self.config.noxdev {
+                e.dir.open_dir_noxdev(e.filename)?
+            } else {
+                crate::dirext::map_optional(e.dir.open_dir(e.filename))?
+            };
+            if let Some(sub) = sub {
+                subdirs.push((e.path.to_owned(), sub));
+            }
+            Ok(ControlFlow::Continue(()))
+        })?;
+        for (path, d) in subdirs {
+            self.add_watch(&path, &d)?;
+        }
+        Ok(())
+    }
+
+    /// Remove the watches for the directory and all of its children.
+    fn remove_tree(&mut self, path: &Path) {
+        let inotify = &self.inotify;
+        self.watches.retain(|&wd, p| {
+            if p.starts_with(path) {
+                // This may fail if the watch was already removed by the kernel
+                let _ = inotify::remove_watch(inotify, wd);
+                false
+            } else {
+                true
+            }
+        });
+    }
+
+    /// Read at least one event, blocking until available.  The file descriptor
+    /// (via [`AsFd`]) can be used with e.g. `poll` to wait for events.
+    ///
+    /// Note that this may return an empty vector if the only events received
+    /// were internal ones (such as the removal of a watch).
+    pub fn read_events(&mut self) -> Result<Vec<WatchEvent>> {
+        use inotify::ReadFlags;
+
+        let mut raw = Vec::new();
+        {
+            let mut reader = inotify::Reader::new(&self.inotify, &mut self.buf);
+            loop {
+                let e = match reader.next() {
+                    Ok(e) => e,
+                    Err(rustix::io::Errno::INTR) => continue,
+                    Err(e) => return Err(e.into()),
+                };
+                let name = e
+                    .file_name()
+                    .map(|n| OsStr::from_bytes(n.to_bytes()).to_owned());
+                raw.push((e.wd(), e.events(), name));
+                if reader.is_buffer_empty() {
+                    break;
+                }
+            }
+        }
+        let mut r = Vec::new();
+        for (wd, flags, name) in raw {
+            if flags.contains(ReadFlags::QUEUE_OVERFLOW) {
+                r.push(WatchEvent {
+                    path: PathBuf::new(),
+                    kind: WatchEventKind::Overflow,
+                    is_dir: false,
+                });
+                continue;
+            }
+            if flags.contains(ReadFlags::IGNORED) {
+                self.watches.remove(&wd);
+                continue;
+            }
+            let Some(dir) = self.watches.get(&wd) else {
+                continue;
+            };
+            let path = match name {
+                Some(name) => dir.join(name),
+                None => dir.clone(),
+            };
+            let is_dir = flags.contains(ReadFlags::ISDIR);
+            let kind = if flags.contains(ReadFlags::CREATE) {
+                WatchEventKind::Created
+            } else if flags.contains(ReadFlags::DELETE) {
+                WatchEventKind::Removed
+            } else if flags.contains(ReadFlags::MODIFY) {
+                WatchEventKind::Modified
+            } else if flags.contains(ReadFlags::ATTRIB) {
+                WatchEventKind::Metadata
+            } else if flags.contains(ReadFlags::MOVED_FROM) {
+                WatchEventKind::MovedFrom
+            } else if flags.contains(ReadFlags::MOVED_TO) {
+                WatchEventKind::MovedTo
+            } else {
+                continue;
+            };
+            if self.config.recursive && is_dir {
+                match kind {
+                    WatchEventKind::Created | WatchEventKind::MovedTo => {
+                        if let Some(d) = self.open_subdir(&path)? {
+                            self.add_watch(&path, &d)?;
+                            self.add_tree(&path)?;
+                        }
+                    }
+                    // The directory may have been moved outside of the root; stop
+                    // watching it.  If it was moved elsewhere inside, it will be
+                    // watched again under the new path.
+                    WatchEventKind::MovedFrom => self.remove_tree(&path),
+                    _ => {}
+                }
+            }
+            r.push(WatchEvent { path, kind, is_dir });
+        }
+        Ok(r)
+    }
+
+    /// Wrap this watcher in a [`Debouncer`] with the provided quiet period.
+    pub fn debounce(self, quiet: Duration) -> Debouncer {
+        Debouncer {
+            watcher: self,
+            quiet,
+            max_delay: None,
+        }
+    }
+
+    fn open_subdir(&self, path: &Path) -> Result<Option<Dir>> {
+        if self.config.noxdev {
+            self.root.open_dir_noxdev(path)
+        } else {
+            crate::dirext::map_optional(self.root.open_dir(path))
+        }
+    }
+}
+
+impl AsFd for Watcher {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.inotify.as_fd()
+    }
+}
+
+/// Coalesces events from a [`Watcher`]; see [`Watcher::debounce`].
+///
+/// Events are gathered until none have arrived for the quiet period, and then returned
+/// as a batch with (at most) one event per path, in the order in which each path was
+/// first seen.  Events for the same path are combined as follows:
+///
+/// - Modifications following the creation of a file are reported as its creation.
+/// - A file which was created and then removed (or moved away) is not reported.
+/// - A file which was removed (or moved away) and then replaced is reported as modified.
+/// - Otherwise, the most recent event is reported, except that a modification is not
+///   superseded by a metadata change.
+///
+/// An [`WatchEventKind::Overflow`] event is reported at the start of the batch.
+#[derive(Debug)]
+pub struct Debouncer {
+    watcher: Watcher,
+    quiet: Duration,
+    max_delay: Option<Duration>,
+}
+
+/// Combine a new event for a path with the pending one, returning `None` if
+/// the two cancel out.
+fn coalesce(pending: WatchEventKind, new: WatchEventKind) -> Option<WatchEventKind> {
+    use WatchEventKind::*;
+    match (pending, new) {
+        (Created, Modified | Metadata) => Some(Created),
+        (Created, Removed | MovedFrom) => None,
+        (Removed | MovedFrom, Created | MovedTo) => Some(Modified),
+        (Modified, Metadata) => Some(Modified),
+        (_, new) => Some(new),
+    }
+}
+
+impl Debouncer {
+    /// Return a batch even if events are still arriving, once this much time has
+    /// passed since the first event in the batch.  By default, there is no limit.
+    pub fn max_delay(mut self, max_delay: Duration) -> Self {
+        self.max_delay = Some(max_delay);
+        self
+    }
+
+    /// Wait for at least one event, then gather events until the quiet period
+    /// passes, and return them coalesced.
+    pub fn read_events(&mut self) -> Result<Vec<WatchEvent>> {
+        let mut batch: Vec<Option<WatchEvent>> = Vec::new();
+        let mut index: HashMap<PathBuf, usize> = HashMap::new();
+        let mut overflow = false;
+        let mut start: Option<Instant> = None;
+        loop {
+            if let Some(start) = start {
+                let mut timeout = self.quiet;
+                if let Some(max) = self.max_delay {
+                    timeout = timeout.min(max.saturating_sub(start.elapsed()));
+                }
+                if timeout.is_zero() || !self.wait_readable(timeout)? {
+                    break;
+                }
+            }
+            for e in self.watcher.read_events()? {
+                start.get_or_insert_with(Instant::now);
+                if e.kind == WatchEventKind::Overflow {
+                    overflow = true;
+                    continue;
+                }
+                match index.get(&e.path) {
+                    Some(&i) => {
+                        let kind = batch[i].as_ref().map(|p| p.kind);
+                        batch[i] = match kind {
+                            Some(kind) => {
+                                coalesce(kind, e.kind).map(|kind| WatchEvent { kind, ..e })
+                            }
+                            None => Some(e),
+                        };
+                    }
+                    None => {
+                        index.insert(e.path.clone(), batch.len());
+                        batch.push(Some(e));
+                    }
+                }
+            }
+        }
+        let overflow = overflow.then(|| WatchEvent {
+            path: PathBuf::new(),
+            kind: WatchEventKind::Overflow,
+            is_dir: false,
+        });
+        Ok(overflow
+            .into_iter()
+            .chain(batch.into_iter().flatten())
+            .collect())
+    }
+
+    /// Wait for the watcher to become readable, returning `false` on timeout.
+    fn wait_readable(&self, timeout: Duration) -> Result<bool> {
+        use rustix::event::{PollFd, PollFlags};
+        let timeout = i32::try_from(timeout.as_millis()).unwrap_or(i32::MAX);
+        let mut fds = [PollFd::new(&self.watcher.inotify, PollFlags::IN)];
+        loop {
+            match rustix::event::poll(&mut fds, timeout) {
+                Ok(n) => return Ok(n > 0),
+                Err(rustix::io::Errno::INTR) => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// Return the underlying watcher.
+    pub fn into_inner(self) -> Watcher {
+        self.watcher
+    }
+}
+
+impl AsFd for Debouncer {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.watcher.as_fd()
+    }
+}
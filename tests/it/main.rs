@@ -2,10 +2,13 @@ use anyhow::Result;
 
 use cap_std::fs::{Dir, File, Permissions, PermissionsExt};
 use cap_std_ext::cmdext::CapStdExtCommandExt;
-use cap_std_ext::dirext::CapStdExtDirExt;
+use cap_std_ext::dirext::{
+    AtomicWriteOptions, CapStdExtDirExt, Durability, WalkConfiguration, WalkCursor,
+};
 use cap_std_ext::{cap_std, RootDir};
 use std::io::Write;
-use std::path::Path;
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
 use std::{process::Command, sync::Arc};
 
 #[test]
@@ -26,6 +29,525 @@ fn take_fd() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn take_fds() -> Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let (r1, w1) = rustix::pipe::pipe()?;
+    let (r2, w2) = rustix::pipe::pipe()?;
+    let (n1, n2) = (r1.as_raw_fd(), r2.as_raw_fd());
+    let mut w1: File = w1.into();
+    let mut w2: File = w2.into();
+    write!(w1, "one")?;
+    write!(w2, "two")?;
+    drop((w1, w2));
+    // Swap the two pipes; the first is also passed at an unrelated number, but
+    // is empty by the time it is read there.
+    let r1 = Arc::new(r1);
+    let mut c = Command::new("/bin/bash");
+    c.arg("-c");
+    c.arg(format!("cat <&{n2}; echo; cat <&{n1}; echo; cat <&9"));
+    c.take_fds([(Arc::clone(&r1), n2), (Arc::new(r2), n1), (r1, 9)]);
+    c.stdout(std::process::Stdio::piped());
+    let s = c.output()?;
+    assert!(s.status.success());
+    assert_eq!(s.stdout.as_slice(), b"one\ntwo\n");
+    Ok(())
+}
+
+#[test]
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn close_fds_above() -> Result<()> {
+    use std::os::fd::AsRawFd;
+
+    // Leak a file descriptor without O_CLOEXEC
+    let leaked = rustix::io::fcntl_dupfd_cloexec(std::io::stdin(), 100)?;
+    rustix::io::fcntl_setfd(&leaked, rustix::io::FdFlags::empty())?;
+    let leaked = leaked.as_raw_fd().to_string();
+    let (r, w) = rustix::pipe::pipe()?;
+    drop(w);
+    let fds = |close: bool| -> Result<Vec<String>> {
+        let mut c = Command::new("ls");
+        c.arg("/proc/self/fd");
+        c.take_fd_n(Arc::new(r.try_clone()?), 5);
+        if close {
+            c.close_fds_above(5);
+        }
+        let out = c.output()?;
+        assert!(out.status.success());
+        Ok(String::from_utf8(out.stdout)?
+            .lines()
+            .map(ToOwned::to_owned)
+            .collect())
+    };
+    let all = fds(false)?;
+    assert!(all.contains(&leaked));
+    let some = fds(true)?;
+    assert!(!some.contains(&leaked));
+    assert!(some.iter().any(|n| n == "5"));
+    Ok(())
+}
+
+#[test]
+fn chroot_dir() -> Result<()> {
+    if !rustix::process::geteuid().is_root() {
+        eprintln!("skipping: not root");
+        return Ok(());
+    }
+    let td = cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    // Nothing exists in the new root, so not even the program can be found
+    let e = Command::new("/bin/sh")
+        .chroot_dir(td.try_clone()?)
+        .status()
+        .unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::NotFound);
+    Ok(())
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn pivot_root_dir() -> Result<()> {
+    use std::ffi::CString;
+    use std::os::fd::{AsFd, AsRawFd};
+    use std::os::unix::ffi::OsStrExt;
+
+    if !rustix::process::geteuid().is_root() {
+        eprintln!("skipping: not root");
+        return Ok(());
+    }
+    let td = cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    td.create_dir("sub")?;
+    let path = std::fs::read_link(format!("/proc/self/fd/{}", td.as_fd().as_raw_fd()))?;
+    // Mount namespaces are per-thread, so set up a private one to bind mount
+    // the directory onto itself in, and spawn the command from there.
+    std::thread::spawn(move || -> Result<()> {
+        if unsafe { libc::unshare(libc::CLONE_NEWNS) } < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        let mount = |src: *const libc::c_char, dest: &CString, flags: libc::c_ulong| {
+            let r = unsafe {
+                libc::mount(
+                    src,
+                    dest.as_ptr(),
+                    std::ptr::null(),
+                    flags,
+                    std::ptr::null(),
+                )
+            };
+            anyhow::ensure!(r == 0, std::io::Error::last_os_error());
+            Ok(())
+        };
+        let dest = CString::new(path.as_os_str().as_bytes())?;
+        mount(
+            std::ptr::null(),
+            &CString::new("/")?,
+            libc::MS_REC | libc::MS_PRIVATE,
+        )?;
+
+        // Not a mount point
+        let e = Command::new("/bin/sh")
+            .pivot_root_dir(td.open_dir("sub")?)
+            .status()
+            .unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::EINVAL));
+
+        mount(dest.as_ptr(), &dest, libc::MS_BIND)?;
+        let root = Dir::open_ambient_dir(&path, cap_std::ambient_authority())?;
+        assert_eq!(root.is_mountpoint(".")?, Some(true));
+        // Nothing exists in the new root, so not even the program can be found
+        let e = Command::new("/bin/sh")
+            .pivot_root_dir(root)
+            .status()
+            .unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::NotFound);
+        // The old root was only detached in the child's own namespace
+        assert!(Path::new("/bin/sh").exists());
+        Ok(())
+    })
+    .join()
+    .unwrap()
+}
+
+#[test]
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn unshare() -> Result<()> {
+    use cap_std_ext::cmdext::Namespaces;
+
+    let ns = |c: &mut Command| -> Result<String> {
+        let out = c
+            .args(["/proc/self/ns/uts", "/proc/self/ns/ipc"])
+            .output()?;
+        anyhow::ensure!(out.status.success(), "readlink failed");
+        Ok(String::from_utf8(out.stdout)?)
+    };
+    let orig = ns(&mut Command::new("readlink"))?;
+    let mut c = Command::new("readlink");
+    c.unshare(Namespaces::USER | Namespaces::UTS | Namespaces::IPC);
+    let unshared = match ns(&mut c) {
+        Ok(v) => v,
+        // Creating namespaces may be forbidden, e.g. in a container
+        Err(e) => {
+            eprintln!("skipping: {e}");
+            return Ok(());
+        }
+    };
+    let (orig, unshared): (Vec<_>, Vec<_>) = (orig.lines().collect(), unshared.lines().collect());
+    assert_eq!(unshared.len(), 2);
+    for (a, b) in orig.iter().zip(unshared.iter()) {
+        assert_ne!(a, b);
+    }
+    Ok(())
+}
+
+#[test]
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn uid_gid_map() -> Result<()> {
+    use cap_std_ext::cmdext::IdMapConfig;
+
+    let maps = |config: &IdMapConfig| -> Result<Vec<Vec<u32>>> {
+        let out = Command::new("cat")
+            .args(["/proc/self/uid_map", "/proc/self/gid_map"])
+            .uid_gid_map(config)
+            .output()?;
+        anyhow::ensure!(out.status.success(), "cat failed");
+        String::from_utf8(out.stdout)?
+            .lines()
+            .map(|l| {
+                Ok(l.split_whitespace()
+                    .map(str::parse)
+                    .collect::<Result<_, _>>()?)
+            })
+            .collect()
+    };
+    let uid = rustix::process::geteuid().as_raw();
+    let gid = rustix::process::getegid().as_raw();
+    let r = match maps(&IdMapConfig::root_as_current_user()) {
+        Ok(r) => r,
+        // Creating namespaces may be forbidden, e.g. in a container
+        Err(e) => {
+            eprintln!("skipping: {e}");
+            return Ok(());
+        }
+    };
+    assert_eq!(r, [vec![0, uid, 1], vec![0, gid, 1]]);
+
+    if rustix::process::geteuid().is_root() {
+        let config = IdMapConfig::new()
+            .uid_range(0, 1000, 1)
+            .uid_range(1, 100000, 65536)
+            .gid_range(0, 1000, 1);
+        let r = maps(&config)?;
+        assert_eq!(
+            r,
+            [vec![0, 1000, 1], vec![1, 100000, 65536], vec![0, 1000, 1]]
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn run_as() -> Result<()> {
+    use cap_std::fs::PermissionsExt;
+
+    if !rustix::process::geteuid().is_root() {
+        eprintln!("skipping: not root");
+        return Ok(());
+    }
+    let td = cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    td.create_dir("home")?;
+    td.write("home/file", "contents")?;
+    // Only accessible to the user
+    let st = Command::new("chown")
+        .args(["-R", "1234:1234", "."])
+        .cwd_dir(td.open_dir("home")?)
+        .status()?;
+    assert!(st.success());
+    td.set_permissions("home", Permissions::from_mode(0o700))?;
+    let out = Command::new("/bin/sh")
+        .args(["-c", "id -u; id -g; id -G; cat file"])
+        .run_as(1234, 1234, &[1234, 5678], Some(td.open_dir("home")?))
+        .output()?;
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8(out.stdout)?,
+        "1234\n1234\n1234 5678\ncontents"
+    );
+    Ok(())
+}
+
+#[test]
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn keep_capabilities() -> Result<()> {
+    use cap_std_ext::cmdext::Capability;
+
+    if !rustix::process::geteuid().is_root() {
+        eprintln!("skipping: not root");
+        return Ok(());
+    }
+    let caps = |c: &mut Command| -> Result<Vec<(String, u64)>> {
+        let out = c.args(["^Cap", "/proc/self/status"]).output()?;
+        assert!(out.status.success());
+        String::from_utf8(out.stdout)?
+            .lines()
+            .map(|l| {
+                let (k, v) = l.split_once(":\t").unwrap();
+                Ok((k.to_owned(), u64::from_str_radix(v, 16)?))
+            })
+            .collect()
+    };
+    for (k, v) in caps(Command::new("grep").drop_capabilities())? {
+        assert_eq!(v, 0, "{k}");
+    }
+    let bind = 1 << Capability::NetBindService as u32;
+    let kept = caps(Command::new("grep").keep_capabilities(&[Capability::NetBindService]))?;
+    for (k, v) in kept {
+        assert_eq!(v, bind, "{k}");
+    }
+    // Capabilities are kept when changing user
+    let kept = caps(
+        Command::new("grep")
+            .run_as(1234, 1234, &[], None)
+            .keep_capabilities(&[Capability::NetBindService]),
+    )?;
+    for (k, v) in kept {
+        assert_eq!(v, bind, "{k}");
+    }
+    // Without them, changing user drops all but the bounding set
+    for (k, v) in caps(Command::new("grep").run_as(1234, 1234, &[], None))? {
+        if k != "CapBnd" {
+            assert_eq!(v, 0, "{k}");
+        }
+    }
+    // A capability which isn't permitted can't be kept
+    let e = Command::new("true")
+        .drop_capabilities()
+        .keep_capabilities(&[Capability::NetBindService])
+        .status()
+        .unwrap_err();
+    assert_eq!(e.raw_os_error(), Some(libc::EPERM));
+    Ok(())
+}
+
+#[test]
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn no_new_privs() -> Result<()> {
+    let nnp = |c: &mut Command| -> Result<String> {
+        let out = c.args(["^NoNewPrivs", "/proc/self/status"]).output()?;
+        assert!(out.status.success());
+        Ok(String::from_utf8(out.stdout)?)
+    };
+    assert_eq!(
+        nnp(Command::new("grep").no_new_privs())?,
+        "NoNewPrivs:\t1\n"
+    );
+    Ok(())
+}
+
+#[test]
+#[cfg(all(feature = "landlock", any(target_os = "android", target_os = "linux")))]
+fn landlock_restrict() -> Result<()> {
+    use cap_std_ext::landlock::AccessRights;
+
+    let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    td.create_dir("rw")?;
+    td.create_dir("ro")?;
+    let host = Dir::open_ambient_dir("/", cap_std::ambient_authority())?;
+    let rules = [
+        (host, AccessRights::READ),
+        (td.open_dir("rw")?, AccessRights::READ_WRITE),
+    ];
+    let mut c = Command::new("/bin/sh");
+    c.args(["-c", "echo a > rw/file && ! (echo b > ro/file) 2>/dev/null"]);
+    c.cwd_dir(td.try_clone()?);
+    match c.landlock_restrict(&rules) {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::Unsupported => {
+            eprintln!("skipping: {e}");
+            return Ok(());
+        }
+        Err(e) => return Err(e.into()),
+    }
+    assert!(c.status()?.success());
+    assert_eq!(td.read_to_string("rw/file")?, "a\n");
+    assert!(!td.try_exists("ro/file")?);
+    Ok(())
+}
+
+#[test]
+#[cfg(all(feature = "seccomp", any(target_os = "android", target_os = "linux")))]
+fn seccomp_filter() -> Result<()> {
+    use cap_std_ext::seccomp::{SeccompAction, SeccompPolicy};
+
+    let run = |policy: &SeccompPolicy| -> Result<std::process::Output> {
+        Ok(Command::new("uname")
+            .seccomp_filter(policy)?
+            .stderr(std::process::Stdio::null())
+            .output()?)
+    };
+    let policy = SeccompPolicy::new(SeccompAction::Allow)
+        .rule(libc::SYS_uname, SeccompAction::Errno(libc::EPERM as u16));
+    assert!(!run(&policy)?.status.success());
+    let policy = SeccompPolicy::new(SeccompAction::Allow).allow(libc::SYS_uname);
+    assert!(run(&policy)?.status.success());
+
+    // A program which allows everything: BPF_RET | BPF_K, SECCOMP_RET_ALLOW
+    let mut allow = 0x06u16.to_ne_bytes().to_vec();
+    allow.extend_from_slice(&[0, 0]);
+    allow.extend_from_slice(&libc::SECCOMP_RET_ALLOW.to_ne_bytes());
+    assert!(run(&SeccompPolicy::from_bpf(&allow)?)?.status.success());
+    let e = SeccompPolicy::from_bpf(&allow[..6]).unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput);
+    Ok(())
+}
+
+#[test]
+fn limit() -> Result<()> {
+    use cap_std_ext::cmdext::Resource;
+
+    let out = Command::new("/bin/sh")
+        .args(["-c", "ulimit -Sn; ulimit -Hn; ulimit -c"])
+        .limit(Resource::Nofile, Some(64), Some(128))
+        .limit(Resource::Core, Some(0), None)
+        .output()?;
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8(out.stdout)?, "64\n128\n0\n");
+    Ok(())
+}
+
+#[test]
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn scheduling() -> Result<()> {
+    use cap_std_ext::cmdext::{IoPriorityClass, SchedPolicy};
+
+    // Fields 19 (nice) and 41 (policy) of /proc/self/stat
+    let stat = |c: &mut Command| -> Result<(i32, i32)> {
+        let out = c.arg("/proc/self/stat").output()?;
+        assert!(out.status.success());
+        let stat = String::from_utf8(out.stdout)?;
+        // The fields after the command name, which may contain spaces
+        let fields: Vec<_> = stat
+            .rsplit_once(')')
+            .unwrap()
+            .1
+            .split_whitespace()
+            .collect();
+        Ok((fields[16].parse()?, fields[38].parse()?))
+    };
+    let (nice, policy) = stat(
+        Command::new("cat")
+            .nice(10)
+            .sched_policy(SchedPolicy::Batch)
+            .ioprio(IoPriorityClass::Idle, 0),
+    )?;
+    assert_eq!(nice, 10);
+    assert_eq!(policy, libc::SCHED_BATCH);
+    Ok(())
+}
+
+#[test]
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn cpu_affinity() -> Result<()> {
+    use cap_std_ext::cmdext::CpuSet;
+
+    // Pick one of the CPUs we're allowed to use
+    let allowed = rustix::process::sched_getaffinity(None)?;
+    let cpu = (0..CpuSet::MAX_CPU).find(|&n| allowed.is_set(n)).unwrap();
+    let mut cpus = CpuSet::new();
+    cpus.set(cpu);
+    let out = Command::new("grep")
+        .args(["^Cpus_allowed_list", "/proc/self/status"])
+        .cpu_affinity(&cpus)
+        .output()?;
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8(out.stdout)?,
+        format!("Cpus_allowed_list:\t{cpu}\n")
+    );
+    Ok(())
+}
+
+#[test]
+fn umask() -> Result<()> {
+    let td = cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    let st = Command::new("/bin/sh")
+        .args(["-c", "umask > out; mkdir d"])
+        .umask(0o027)
+        .cwd_dir(td.try_clone()?)
+        .status()?;
+    assert!(st.success());
+    assert_eq!(td.read_to_string("out")?, "0027\n");
+    assert_eq!(td.metadata("d")?.permissions().mode() & 0o777, 0o750);
+    Ok(())
+}
+
+#[test]
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn oom_score_adj() -> Result<()> {
+    let out = Command::new("cat")
+        .arg("/proc/self/oom_score_adj")
+        .oom_score_adj(500)
+        .output()?;
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8(out.stdout)?, "500\n");
+    Ok(())
+}
+
+#[test]
+fn stdio_dir_path() -> Result<()> {
+    let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    td.write("input", "hello world")?;
+    td.write("log", "previous\n")?;
+    let mut out = cap_std::fs::OpenOptions::new();
+    out.write(true).create(true).truncate(true);
+    let mut log = cap_std::fs::OpenOptions::new();
+    log.write(true).create(true).append(true);
+    let st = Command::new("/bin/bash")
+        .args(["-c", "cat; echo error >&2"])
+        .stdin_dir_path(td, "input")?
+        .stdout_dir_path(td, "output", &out)?
+        .stderr_dir_path(td, "log", &log)?
+        .status()?;
+    assert!(st.success());
+    assert_eq!(td.read_to_string("output")?, "hello world");
+    assert_eq!(td.read_to_string("log")?, "previous\nerror\n");
+
+    let e = Command::new("true")
+        .stdin_dir_path(td, "nonexistent")
+        .unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::NotFound);
+    Ok(())
+}
+
+#[test]
+fn stdout_atomic_to() -> Result<()> {
+    let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    td.create_dir("etc")?;
+    let st = Command::new("echo")
+        .arg("generated")
+        .stdout_atomic_to(td, "etc/config")?;
+    assert!(st.success());
+    assert_eq!(td.read_to_string("etc/config")?, "generated\n");
+
+    let st = Command::new("/bin/bash")
+        .args(["-c", "echo partial; exit 3"])
+        .stdout_atomic_to(td, "etc/config")?;
+    assert_eq!(st.code(), Some(3));
+    assert_eq!(td.read_to_string("etc/config")?, "generated\n");
+    assert_eq!(td.read_dir("etc")?.count(), 1);
+
+    // Spawning the command again doesn't write to the published file
+    let mut c = Command::new("/bin/bash");
+    c.args([
+        "-c",
+        "if [ /dev/stdout -ef etc/config ]; then exit 1; fi; [ -n \"$QUIET\" ] || echo generated",
+    ])
+    .cwd_dir(td.try_clone()?);
+    assert!(c.stdout_atomic_to(td, "etc/config")?.success());
+    assert!(c.env("QUIET", "1").status()?.success());
+    assert_eq!(td.read_to_string("etc/config")?, "generated\n");
+    Ok(())
+}
+
 #[test]
 fn fchdir() -> Result<()> {
     static CONTENTS: &[u8] = b"hello world";
@@ -234,6 +756,13 @@ fn link_tempfile_with() -> Result<()> {
     td.atomic_write_with_perms(p, "self-only file v3", Permissions::from_mode(0o640))
         .unwrap();
     assert_eq!(td.metadata(p).unwrap().permissions().mode() & 0o777, 0o640);
+
+    // Including for an existing file in a subdirectory
+    td.create_dir("subdir")?;
+    let p = Path::new("subdir/foo");
+    td.atomic_write_with_perms(p, "self-only file", Permissions::from_mode(0o600))?;
+    td.atomic_write(p, "self-only file v2")?;
+    assert_eq!(td.metadata(p)?.permissions().mode() & 0o777, 0o600);
     Ok(())
 }
 
@@ -264,7 +793,7 @@ fn ensuredir_utf8() -> Result<()> {
     use cap_std::fs_utf8::camino::Utf8Path;
     use cap_std_ext::dirext::CapStdExtDirExtUtf8;
     let td = cap_tempfile::tempdir(cap_std::ambient_authority())?;
-    let td = &cap_std::fs_utf8::Dir::from_cap_std((&*td).try_clone()?);
+    let td = &cap_std::fs_utf8::Dir::from_cap_std(td.try_clone()?);
 
     let p = Utf8Path::new("somedir");
     let b = &cap_std::fs::DirBuilder::new();
@@ -369,26 +898,3252 @@ fn test_rootdir_open() -> Result<()> {
 }
 
 #[test]
-fn test_rootdir_entries() -> Result<()> {
+fn test_rootdir_read_optional() -> Result<()> {
     let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
     let root = RootDir::new(td, ".").unwrap();
 
+    td.create_dir_all("usr/lib")?;
     td.create_dir("etc")?;
+    td.write("usr/lib/auth.json", "auth contents")?;
+    td.symlink_contents("/usr/lib/auth.json", "etc/auth.json")?;
+    td.symlink_contents("/usr/lib/nonexistent", "etc/dangling")?;
+
+    assert_eq!(
+        root.read_optional("/etc/auth.json")?.as_deref(),
+        Some(b"auth contents".as_slice())
+    );
+    assert_eq!(
+        root.read_to_string_optional("/etc/auth.json")?.as_deref(),
+        Some("auth contents")
+    );
+    assert!(root.read_optional("/etc/nonexistent")?.is_none());
+    assert!(root.read_to_string_optional("/etc/dangling")?.is_none());
+    // Other errors are propagated
+    assert!(root.read_optional("/etc/auth.json/foo").is_err());
+    assert!(root.read_to_string_optional("/usr").is_err());
+    Ok(())
+}
+
+#[test]
+fn test_rootdir_read_link_resolve() -> Result<()> {
+    let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    let root = RootDir::new(td, ".").unwrap();
+
+    td.create_dir_all("etc/sub")?;
     td.create_dir_all("usr/lib")?;
+    td.write("usr/lib/auth.json", "auth contents")?;
+    td.symlink_contents("/usr/lib/auth.json", "etc/auth.json")?;
+    td.symlink_contents("../usr/lib/auth.json", "etc/auth-rel.json")?;
+    td.symlink_contents("../../../../usr", "etc/sub/usr")?;
+    td.symlink_contents("/etc", "etc-link")?;
+    td.symlink_contents("loop2", "loop1")?;
+    td.symlink_contents("loop1", "loop2")?;
+    td.symlink_contents("/nonexistent", "dangling")?;
 
-    let ents = root
-        .entries()
-        .unwrap()
-        .collect::<std::io::Result<Vec<_>>>()?;
-    assert_eq!(ents.len(), 2);
+    assert_eq!(
+        root.read_link("etc/auth.json")?,
+        Path::new("/usr/lib/auth.json")
+    );
+    // The parent is resolved in the root
+    assert_eq!(
+        root.read_link("/etc-link/auth-rel.json")?,
+        Path::new("../usr/lib/auth.json")
+    );
+    assert!(root.read_link("usr/lib/auth.json").is_err());
+    assert!(root.read_link("/").is_err());
+
+    let expected = Path::new("usr/lib/auth.json");
+    assert_eq!(root.resolve("/etc/auth.json")?, expected);
+    assert_eq!(root.resolve("etc/auth-rel.json")?, expected);
+    assert_eq!(root.resolve("etc-link/auth.json")?, expected);
+    assert_eq!(root.resolve("etc/sub/usr/lib/auth.json")?, expected);
+    assert_eq!(root.resolve("/../etc/./sub/../auth.json")?, expected);
+    assert_eq!(root.resolve("usr/lib")?, Path::new("usr/lib"));
+    assert_eq!(root.resolve("/")?, Path::new("."));
+    assert_eq!(root.resolve("etc-link/..")?, Path::new("."));
+
+    assert_eq!(
+        root.resolve("dangling").unwrap_err().kind(),
+        std::io::ErrorKind::NotFound
+    );
+    assert_eq!(
+        root.resolve("loop1").unwrap_err().raw_os_error(),
+        Some(libc::ELOOP)
+    );
+    // A file is not a directory, even when followed by `..`
+    for p in ["usr/lib/auth.json/..", "etc/auth.json/../auth.json"] {
+        assert_eq!(
+            root.resolve(p).unwrap_err().raw_os_error(),
+            Some(libc::ENOTDIR)
+        );
+    }
     Ok(())
 }
 
 #[test]
-fn test_mountpoint() -> Result<()> {
-    let root = &Dir::open_ambient_dir("/", cap_std::ambient_authority())?;
-    assert_eq!(root.is_mountpoint(".").unwrap(), Some(true));
+fn test_rootdir_try_exists() -> Result<()> {
     let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
-    assert_eq!(td.is_mountpoint(".").unwrap(), Some(false));
+    let root = RootDir::new(td, ".").unwrap();
+
+    td.create_dir_all("usr/lib")?;
+    td.write("usr/lib/auth.json", "auth contents")?;
+    td.create_dir("etc")?;
+    td.symlink_contents("/usr/lib/auth.json", "etc/auth.json")?;
+    td.symlink_contents("/nonexistent", "dangling")?;
+    td.symlink_contents("loop2", "loop1")?;
+    td.symlink_contents("loop1", "loop2")?;
+
+    assert!(root.try_exists("/usr/lib/auth.json")?);
+    assert!(root.try_exists("/etc/auth.json")?);
+    assert!(root.try_exists("/")?);
+    assert!(root.exists("etc/auth.json")?);
+    assert!(!root.try_exists("nonexistent")?);
+    assert!(!root.try_exists("dangling")?);
+    assert!(!root.try_exists("etc/nonexistent/foo")?);
+    // Looping links are an error, not nonexistence
+    assert_eq!(
+        root.try_exists("loop1").unwrap_err().raw_os_error(),
+        Some(libc::ELOOP)
+    );
+    assert_eq!(
+        root.exists("loop1").unwrap_err().raw_os_error(),
+        Some(libc::ELOOP)
+    );
+    // A file in place of a directory is an error too
+    assert_eq!(
+        root.try_exists("usr/lib/auth.json/foo")
+            .unwrap_err()
+            .raw_os_error(),
+        Some(libc::ENOTDIR)
+    );
+    Ok(())
+}
+
+#[test]
+fn test_rootdir_walk() -> Result<()> {
+    let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    let root = RootDir::new(td, ".").unwrap();
+
+    td.create_dir_all("usr/lib/foo")?;
+    td.write("usr/lib/foo/file", "contents")?;
+    td.symlink_contents("/usr/lib", "lib")?;
+    td.symlink_contents("../../..", "usr/lib/up")?;
+    td.symlink_contents("/usr/lib/foo/file", "file-link")?;
+
+    let walk = |config: &WalkConfiguration, in_root: bool| -> Result<Vec<String>> {
+        let mut r = Vec::new();
+        let mut cb = |e: &cap_std_ext::dirext::WalkComponent| -> std::io::Result<_> {
+            let kind = if e.file_type.is_dir() {
+                "d"
+            } else if e.file_type.is_symlink() {
+                "l"
+            } else {
+                "f"
+            };
+            r.push(format!("{kind} {}", e.path.display()));
+            Ok(ControlFlow::Continue(()))
+        };
+        if in_root {
+            root.walk(config, &mut cb)?;
+        } else {
+            td.walk(config, &mut cb)?;
+        }
+        Ok(r)
+    };
+
+    // Without following links, this is the same as a plain walk
+    let config = WalkConfiguration::default().sort_by_file_name();
+    assert_eq!(walk(&config, true)?, walk(&config, false)?);
+
+    // Absolute links are followed in the root, and the link to the root itself
+    // is not descended into again.
+    let config = config.follow_symlinks();
+    assert_eq!(
+        walk(&config, true)?,
+        [
+            "f file-link",
+            "d lib",
+            "d lib/foo",
+            "f lib/foo/file",
+            "d lib/up",
+            "d usr",
+            "d usr/lib",
+            "d usr/lib/foo",
+            "f usr/lib/foo/file",
+            "d usr/lib/up",
+        ]
+    );
+    // In contrast to a plain walk
+    let plain = walk(&config, false)?;
+    assert!(plain.contains(&"l lib".to_string()));
+    assert!(plain.contains(&"l file-link".to_string()));
+    Ok(())
+}
+
+#[test]
+fn test_capfs() -> Result<()> {
+    use cap_std_ext::capfs::{CapFsRead, CapFsWrite};
+
+    // Generic code, which resolves /etc/config via the link in etc/
+    fn update_config<D: CapFsRead + CapFsWrite>(d: &D) -> std::io::Result<String> {
+        assert!(d.try_exists("etc")?);
+        assert!(d.metadata("etc")?.is_dir());
+        let names = d
+            .read_dir("etc")?
+            .map(|e| Ok(e?.file_name().into_string().unwrap()))
+            .collect::<std::io::Result<std::collections::BTreeSet<_>>>()?;
+        assert!(names.contains("config"));
+        let old = d.read_to_string_optional("etc/config")?.unwrap_or_default();
+        d.atomic_write("etc/config", format!("{old}updated\n"))?;
+        assert!(d.read_optional("etc/missing")?.is_none());
+        d.create_dir("etc/sub")?;
+        d.write("etc/sub/f", "x")?;
+        assert_eq!(d.read("etc/sub/f")?, b"x");
+        d.remove_file("etc/sub/f")?;
+        d.read_to_string("etc/config")
+    }
+
+    let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    td.create_dir_all("usr/etc")?;
+    td.write("usr/etc/config", "initial\n")?;
+    td.symlink_contents("/usr/etc", "etc")?;
+    let root = RootDir::new(td, ".")?;
+    assert_eq!(update_config(&root)?, "initial\nupdated\n");
+    td.remove_dir("usr/etc/sub")?;
+
+    // The same code works with a plain Dir, which can't follow the absolute link
+    update_config(&td.open_dir("usr")?)?;
+    assert!(CapFsRead::read_to_string(&**td, "etc/config").is_err());
+    assert_eq!(
+        td.read_to_string("usr/etc/config")?,
+        "initial\nupdated\nupdated\n"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_rootdir_xattrs() -> Result<()> {
+    let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    let root = RootDir::new(td, ".")?;
+    td.create_dir_all("usr/lib")?;
+    td.create_dir("etc")?;
+    td.write("usr/lib/auth.json", "")?;
+    td.symlink_contents("/usr/lib", "lib")?;
+    td.symlink_contents("/usr/lib/auth.json", "etc/auth.json")?;
+
+    match root.setxattr("/lib/auth.json", "user.foo", "bar") {
+        Ok(()) => {}
+        // Not all filesystems support user xattrs
+        Err(e) if e.kind() == std::io::ErrorKind::Unsupported => return Ok(()),
+        Err(e) => return Err(e.into()),
+    }
+    // The link was followed in the root
+    assert_eq!(
+        td.getxattr("usr/lib/auth.json", "user.foo")?.as_deref(),
+        Some(b"bar".as_slice())
+    );
+    assert_eq!(
+        root.getxattr("/lib/auth.json", "user.foo")?.as_deref(),
+        Some(b"bar".as_slice())
+    );
+    assert_eq!(root.getxattr("/lib/auth.json", "user.nosuch")?, None);
+    let names = root.listxattrs("lib/auth.json")?;
+    assert!(names.iter().any(|n| n == "user.foo"));
+    // But not in the final component
+    assert_eq!(root.getxattr("/etc/auth.json", "user.foo")?, None);
+    assert!(!root
+        .listxattrs("/etc/auth.json")?
+        .iter()
+        .any(|n| n == "user.foo"));
+    assert!(root.setxattr("/etc/auth.json", "user.foo", "baz").is_err());
+    assert!(root.removexattr("/lib/auth.json", "user.foo")?);
+    assert!(!root.removexattr("/lib/auth.json", "user.foo")?);
+    assert_eq!(td.getxattr("usr/lib/auth.json", "user.foo")?, None);
+    // A plain Dir can't follow the absolute link
+    assert!(td.getxattr("lib/auth.json", "user.foo").is_err());
+    Ok(())
+}
+
+#[test]
+fn test_rootdir_open_with_oflags() -> Result<()> {
+    use rustix::fs::OFlags;
+    use std::io::{Read, Seek};
+
+    let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    let root = RootDir::new(td, ".")?;
+    td.create_dir_all("usr/etc")?;
+    td.symlink_contents("/usr/etc", "etc")?;
+
+    // Create via an absolute link
+    let mut f = root.open_with_oflags("/etc/config", OFlags::WRONLY | OFlags::CREATE)?;
+    f.write_all(b"hello")?;
+    drop(f);
+    assert_eq!(td.read_to_string("usr/etc/config")?, "hello");
+    let mode = td.metadata("usr/etc/config")?.permissions().mode();
+    assert_eq!(mode & 0o111, 0);
+
+    let mut f = root.open_with_oflags("etc/config", OFlags::WRONLY | OFlags::APPEND)?;
+    f.write_all(b" world")?;
+    drop(f);
+    assert_eq!(td.read_to_string("usr/etc/config")?, "hello world");
+
+    let mut f = root.open_with_oflags("/etc/config", OFlags::RDWR)?;
+    let mut s = String::new();
+    f.read_to_string(&mut s)?;
+    assert_eq!(s, "hello world");
+    f.rewind()?;
+    f.write_all(b"HELLO")?;
+    drop(f);
+    assert_eq!(td.read_to_string("usr/etc/config")?, "HELLO world");
+
+    root.open_with_oflags("/etc/config", OFlags::WRONLY | OFlags::TRUNC)?;
+    assert_eq!(td.read_to_string("usr/etc/config")?, "");
+
+    let e = root
+        .open_with_oflags(
+            "/etc/config",
+            OFlags::WRONLY | OFlags::CREATE | OFlags::EXCL,
+        )
+        .unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::AlreadyExists);
+    // `..` can't escape
+    root.open_with_oflags("../../new", OFlags::WRONLY | OFlags::CREATE)?;
+    assert!(td.try_exists("new")?);
+    Ok(())
+}
+
+#[test]
+fn test_rootdir_open_with() -> Result<()> {
+    use cap_std::fs::OpenOptions;
+    use std::io::{Read, Seek};
+
+    let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    let root = RootDir::new(td, ".")?;
+    td.create_dir_all("usr/etc")?;
+    td.create_dir_all("usr/lib")?;
+    td.symlink_contents("/usr/etc", "etc")?;
+    td.symlink_contents("../etc/config", "usr/lib/config")?;
+
+    // Create via an absolute link
+    let mut f = root.open_with("/etc/config", OpenOptions::new().write(true).create(true))?;
+    f.write_all(b"hello")?;
+    drop(f);
+    assert_eq!(td.read_to_string("usr/etc/config")?, "hello");
+
+    let mut f = root.open_with("etc/config", OpenOptions::new().append(true))?;
+    f.write_all(b" world")?;
+    drop(f);
+    assert_eq!(td.read_to_string("usr/etc/config")?, "hello world");
+
+    // A final relative link which leads outside of its parent directory
+    let mut f = root.open_with("/usr/lib/config", OpenOptions::new().read(true).write(true))?;
+    let mut s = String::new();
+    f.read_to_string(&mut s)?;
+    assert_eq!(s, "hello world");
+    f.rewind()?;
+    f.write_all(b"HELLO")?;
+    drop(f);
+    assert_eq!(td.read_to_string("usr/etc/config")?, "HELLO world");
+
+    root.open_with("/etc/config", OpenOptions::new().write(true).truncate(true))?;
+    assert_eq!(td.read_to_string("usr/etc/config")?, "");
+
+    // A dangling absolute link is followed when creating...
+    td.symlink_contents("/usr/etc/other", "other")?;
+    root.open_with("other", OpenOptions::new().write(true).create(true))?;
+    assert!(td.try_exists("usr/etc/other")?);
+    // ...but not with `create_new`
+    let e = root
+        .open_with(
+            "/etc/config",
+            OpenOptions::new().write(true).create_new(true),
+        )
+        .unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::AlreadyExists);
+    td.symlink_contents("/usr/etc/new", "new")?;
+    let e = root
+        .open_with("new", OpenOptions::new().write(true).create_new(true))
+        .unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::AlreadyExists);
+    assert!(!td.try_exists("usr/etc/new")?);
+
+    td.symlink_contents("loop2", "loop1")?;
+    td.symlink_contents("/loop1", "loop2")?;
+    let e = root
+        .open_with("loop1", OpenOptions::new().read(true))
+        .unwrap_err();
+    assert_eq!(e.raw_os_error(), Some(libc::ELOOP));
+
+    // `..` can't escape
+    root.open_with("../../new2", OpenOptions::new().write(true).create(true))?;
+    assert!(td.try_exists("new2")?);
+    Ok(())
+}
+
+#[test]
+fn test_rootdir_copy_to() -> Result<()> {
+    use cap_std_ext::CopyOptions;
+
+    let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    let root = RootDir::new(td, ".")?;
+    td.create_dir_all("usr/etc/sub")?;
+    td.create_dir_all("usr/lib/data")?;
+    td.write("usr/etc/a.conf", "a")?;
+    td.write("usr/etc/sub/b.conf", "b")?;
+    td.set_permissions("usr/etc/sub/b.conf", Permissions::from_mode(0o600))?;
+    td.write("usr/lib/x", "x")?;
+    td.write("usr/lib/data/y", "y")?;
+    td.symlink_contents("/usr/etc", "etc")?;
+    td.symlink_contents("a.conf", "usr/etc/rel")?;
+    td.symlink_contents("/usr/lib/x", "usr/etc/abs")?;
+    td.symlink_contents("../lib/data", "usr/etc/data")?;
+    td.symlink_contents("/nonexistent", "usr/etc/dangling")?;
+
+    let check_common = |dest: &Dir| -> Result<()> {
+        assert_eq!(dest.read_to_string("a.conf")?, "a");
+        assert_eq!(dest.read_to_string("sub/b.conf")?, "b");
+        let mode = dest.metadata("sub/b.conf")?.permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(
+            dest.read_link_contents("dangling")?,
+            Path::new("/nonexistent")
+        );
+        Ok(())
+    };
+
+    let dest = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    root.copy_to("/etc", dest, &CopyOptions::default())?;
+    check_common(dest)?;
+    assert_eq!(dest.read_link_contents("rel")?, Path::new("a.conf"));
+    assert_eq!(dest.read_link_contents("abs")?, Path::new("/usr/lib/x"));
+    assert_eq!(dest.read_link_contents("data")?, Path::new("../lib/data"));
+
+    let dest = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    root.copy_to("/etc", dest, &CopyOptions::default().flatten_symlinks())?;
+    check_common(dest)?;
+    assert!(dest.symlink_metadata("rel")?.is_file());
+    assert_eq!(dest.read_to_string("rel")?, "a");
+    assert_eq!(dest.read_to_string("abs")?, "x");
+    assert!(dest.symlink_metadata("data")?.is_dir());
+    assert_eq!(dest.read_to_string("data/y")?, "y");
+
+    // A link to an ancestor can't be flattened
+    td.symlink_contents("/usr", "usr/etc/sub/up")?;
+    let dest = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    root.copy_to("/etc", dest, &CopyOptions::default())?;
+    let dest = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    assert!(root
+        .copy_to("/etc", dest, &CopyOptions::default().flatten_symlinks())
+        .is_err());
+    Ok(())
+}
+
+#[test]
+fn test_rootdir_fd_conversions() -> Result<()> {
+    use std::os::fd::{AsFd, OwnedFd};
+
+    let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    td.create_dir_all("usr/lib")?;
+    td.write("usr/lib/auth.json", "auth contents")?;
+    td.symlink_contents("/usr/lib", "lib")?;
+
+    let fd: OwnedFd = td.try_clone()?.into_std_file().into();
+    let root = RootDir::try_from(fd)?;
+    assert_eq!(root.read_to_string("/lib/auth.json")?, "auth contents");
+    let st = rustix::fs::fstat(root.as_fd())?;
+    assert_eq!(st.st_ino, rustix::fs::fstat(&**td)?.st_ino);
+
+    let clone = root.try_clone()?;
+    let contents = std::thread::spawn(move || clone.read_to_string("/lib/auth.json"))
+        .join()
+        .unwrap()?;
+    assert_eq!(contents, "auth contents");
+
+    let fd = OwnedFd::from(root);
+    let root = RootDir::try_from(fd)?;
+    assert!(root.try_exists("lib")?);
+
+    let fd: OwnedFd = td.open("usr/lib/auth.json")?.into_std().into();
+    let e = RootDir::try_from(fd).unwrap_err();
+    assert_eq!(e.raw_os_error(), Some(libc::ENOTDIR));
+    Ok(())
+}
+
+#[test]
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn test_rootdir_hardened() -> Result<()> {
+    let host = Dir::open_ambient_dir("/", cap_std::ambient_authority())?;
+    for path in [".", "proc"] {
+        if !host.try_exists(path)? {
+            continue;
+        }
+        let e = RootDir::hardened(&host, path).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    td.create_dir_all("usr/lib")?;
+    td.write("usr/lib/auth.json", "auth contents")?;
+    td.symlink_contents("/usr/lib", "lib")?;
+    let root = RootDir::hardened(td, ".")?;
+    assert_eq!(root.read_to_string("/lib/auth.json")?, "auth contents");
+    assert_eq!(
+        root.try_clone()?.read_to_string("lib/auth.json")?,
+        "auth contents"
+    );
+
+    let e = root
+        .walk(&WalkConfiguration::default(), |_| -> std::io::Result<_> {
+            Ok(ControlFlow::Continue(()))
+        })
+        .unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput);
+    let mut n = 0;
+    root.walk(
+        &WalkConfiguration::default().noxdev(),
+        |_| -> std::io::Result<_> {
+            n += 1;
+            Ok(ControlFlow::Continue(()))
+        },
+    )?;
+    assert_eq!(n, 4);
+    Ok(())
+}
+
+#[test]
+fn test_rootdir_entries() -> Result<()> {
+    let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    let root = RootDir::new(td, ".").unwrap();
+
+    td.create_dir("etc")?;
+    td.create_dir_all("usr/lib")?;
+
+    let ents = root
+        .entries()
+        .unwrap()
+        .collect::<std::io::Result<Vec<_>>>()?;
+    assert_eq!(ents.len(), 2);
+    Ok(())
+}
+
+#[test]
+fn test_mountpoint() -> Result<()> {
+    let root = &Dir::open_ambient_dir("/", cap_std::ambient_authority())?;
+    assert_eq!(root.is_mountpoint(".").unwrap(), Some(true));
+    let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    assert_eq!(td.is_mountpoint(".").unwrap(), Some(false));
+    Ok(())
+}
+
+#[test]
+fn test_readahead() -> Result<()> {
+    let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    td.write("somefile", "some contents")?;
+    td.readahead("somefile", 0, 4096).unwrap();
+    // Offsets past the end of the file are fine
+    td.readahead("somefile", 1 << 20, 4096).unwrap();
+    assert!(td.readahead("nosuchfile", 0, 4096).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_walk() -> Result<()> {
+    let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    td.create_dir_all("usr/lib/foo")?;
+    td.create_dir_all("usr/share/bar")?;
+    td.create_dir_all("etc")?;
+    td.write("usr/lib/foo/somefile", "contents")?;
+    td.write("usr/lib/afile", "contents")?;
+    td.write("etc/passwd", "contents")?;
+    td.symlink("usr/lib", "lib")?;
+
+    let mut n = 0;
+    td.walk(&WalkConfiguration::default(), |e| -> std::io::Result<_> {
+        n += 1;
+        // Symlinks are not followed
+        if e.path.starts_with("lib") {
+            assert_eq!(e.path, Path::new("lib"));
+            assert!(e.file_type.is_symlink());
+        }
+        if e.file_type.is_file() {
+            assert_eq!(e.dir.read_to_string(e.filename)?, "contents");
+        }
+        Ok(ControlFlow::Continue(()))
+    })
+    .unwrap();
+    assert_eq!(n, 10);
+
+    // Skipping a directory
+    let mut paths = Vec::new();
+    td.walk(
+        &WalkConfiguration::default().sort_by_file_name(),
+        |e| -> std::io::Result<_> {
+            paths.push(e.path.to_owned());
+            if e.path == Path::new("usr/lib") {
+                return Ok(ControlFlow::Break(()));
+            }
+            Ok(ControlFlow::Continue(()))
+        },
+    )
+    .unwrap();
+    let expected = [
+        "etc",
+        "etc/passwd",
+        "lib",
+        "usr",
+        "usr/lib",
+        "usr/share",
+        "usr/share/bar",
+    ];
+    assert_eq!(
+        paths,
+        expected.iter().map(PathBuf::from).collect::<Vec<_>>()
+    );
+
+    // Path base
+    let base = Path::new("/sysroot");
+    let mut paths = Vec::new();
+    td.walk(
+        &WalkConfiguration::default().path_base(base),
+        |e| -> std::io::Result<_> {
+            paths.push(e.path.to_owned());
+            Ok(ControlFlow::Continue(()))
+        },
+    )
+    .unwrap();
+    assert!(paths.iter().all(|p| p.starts_with(base)));
+    assert!(paths.contains(&PathBuf::from("/sysroot/usr/lib/foo/somefile")));
+
+    // Errors are propagated
+    let r = td.walk(&WalkConfiguration::default(), |e| {
+        if e.file_type.is_file() {
+            anyhow::bail!("oops");
+        }
+        Ok(ControlFlow::Continue(()))
+    });
+    assert!(r.unwrap_err().to_string().contains("oops"));
+    Ok(())
+}
+
+#[test]
+fn test_walk_deterministic() -> Result<()> {
+    let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    for name in ["b", "a", "B", "a.d", "\u{e9}", "_"] {
+        td.create_dir(name)?;
+        td.write(format!("{name}/z"), "")?;
+        td.write(format!("{name}/0"), "")?;
+    }
+    let walk = || -> Result<Vec<PathBuf>> {
+        let mut r = Vec::new();
+        td.walk(
+            &WalkConfiguration::default().deterministic(),
+            |e| -> std::io::Result<_> {
+                r.push(e.path.to_owned());
+                Ok(ControlFlow::Continue(()))
+            },
+        )?;
+        Ok(r)
+    };
+    let a = walk()?;
+    let b = walk()?;
+    assert_eq!(a, b);
+    let toplevel = a
+        .iter()
+        .filter(|p| p.components().count() == 1)
+        .map(|p| p.to_str().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(toplevel, ["B", "_", "a", "a.d", "b", "\u{e9}"]);
+    assert_eq!(a[1], Path::new("B/0"));
+    assert_eq!(a[2], Path::new("B/z"));
+    Ok(())
+}
+
+#[test]
+fn test_walk_parallel() -> Result<()> {
+    use std::collections::BTreeSet;
+    use std::sync::Mutex;
+
+    let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    for i in 0..10 {
+        for j in 0..10 {
+            td.create_dir_all(format!("d{i}/e{j}"))?;
+            td.write(format!("d{i}/e{j}/f"), "")?;
+        }
+        td.write(format!("d{i}/g"), "")?;
+    }
+    let base = Path::new("base");
+
+    let mut expected = BTreeSet::new();
+    td.walk(
+        &WalkConfiguration::default().path_base(base),
+        |e| -> std::io::Result<_> {
+            expected.insert(e.path.to_owned());
+            Ok(ControlFlow::Continue(()))
+        },
+    )?;
+    assert_eq!(expected.len(), 10 * (1 + 10 * 2 + 1));
+    let seen = Mutex::new(BTreeSet::new());
+    td.walk_parallel(
+        &WalkConfiguration::default().path_base(base),
+        |e| -> std::io::Result<_> {
+            assert!(e.dir.try_exists(e.filename)?);
+            seen.lock().unwrap().insert(e.path.to_owned());
+            Ok(ControlFlow::Continue(()))
+        },
+    )?;
+    assert_eq!(seen.into_inner().unwrap(), expected);
+
+    // Skipping a directory
+    let seen = Mutex::new(BTreeSet::new());
+    td.walk_parallel(&WalkConfiguration::default(), |e| -> std::io::Result<_> {
+        seen.lock().unwrap().insert(e.path.to_owned());
+        if e.path == Path::new("d0") {
+            return Ok(ControlFlow::Break(()));
+        }
+        Ok(ControlFlow::Continue(()))
+    })?;
+    let seen = seen.into_inner().unwrap();
+    assert!(seen.contains(Path::new("d0")));
+    assert!(!seen.iter().any(|p| p.parent() == Some(Path::new("d0"))));
+    assert!(seen.contains(Path::new("d1/e1/f")));
+
+    // Errors are propagated
+    let r = td.walk_parallel(&WalkConfiguration::default(), |e| {
+        if e.filename == "f" {
+            anyhow::bail!("oops");
+        }
+        Ok(ControlFlow::Continue(()))
+    });
+    assert!(r.unwrap_err().to_string().contains("oops"));
+
+    // Sorting is not supported
+    let e = td
+        .walk_parallel(
+            &WalkConfiguration::default().sort_by_file_name(),
+            |_| -> std::io::Result<_> { Ok(ControlFlow::Continue(())) },
+        )
+        .unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput);
+    Ok(())
+}
+
+#[test]
+fn test_walk_metadata() -> Result<()> {
+    let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    td.create_dir("d")?;
+    td.write("d/f", "abc")?;
+
+    td.walk(&WalkConfiguration::default(), |e| -> std::io::Result<_> {
+        assert!(e.metadata.is_none());
+        Ok(ControlFlow::Continue(()))
+    })?;
+    let mut n = 0;
+    td.walk(
+        &WalkConfiguration::default().metadata(),
+        |e| -> std::io::Result<_> {
+            let meta = e.metadata.unwrap();
+            assert_eq!(meta.is_dir(), e.file_type.is_dir());
+            if e.filename == "f" {
+                assert_eq!(meta.len(), 3);
+            }
+            n += 1;
+            Ok(ControlFlow::Continue(()))
+        },
+    )?;
+    assert_eq!(n, 2);
+    td.walk_parallel(
+        &WalkConfiguration::default().metadata(),
+        |e| -> std::io::Result<_> {
+            assert!(e.metadata.is_some());
+            Ok(ControlFlow::Continue(()))
+        },
+    )?;
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn test_walk_track_hardlinks() -> Result<()> {
+    use std::sync::Mutex;
+
+    let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    td.create_dir("d")?;
+    td.write("a", "abc")?;
+    td.hard_link("a", td, "d/b")?;
+    td.hard_link("a", td, "d/c")?;
+    td.write("single", "")?;
+
+    let mut repeats = Vec::new();
+    td.walk(
+        &WalkConfiguration::default()
+            .track_hardlinks()
+            .sort_by_file_name(),
+        |e| -> std::io::Result<_> {
+            // Metadata is only provided if requested
+            assert!(e.metadata.is_none());
+            assert_eq!(e.first_seen, e.first_path.is_none());
+            if let Some(first) = e.first_path {
+                repeats.push((e.path.to_owned(), first.to_owned()));
+            }
+            Ok(ControlFlow::Continue(()))
+        },
+    )?;
+    assert_eq!(
+        repeats,
+        [
+            (PathBuf::from("d/b"), PathBuf::from("a")),
+            (PathBuf::from("d/c"), PathBuf::from("a"))
+        ]
+    );
+
+    let repeats = Mutex::new(0);
+    td.walk_parallel(
+        &WalkConfiguration::default().track_hardlinks(),
+        |e| -> std::io::Result<_> {
+            if !e.first_seen {
+                *repeats.lock().unwrap() += 1;
+            }
+            Ok(ControlFlow::Continue(()))
+        },
+    )?;
+    assert_eq!(repeats.into_inner().unwrap(), 2);
+
+    // Without tracking, everything is seen for the first time
+    td.walk(&WalkConfiguration::default(), |e| -> std::io::Result<_> {
+        assert!(e.first_seen);
+        Ok(ControlFlow::Continue(()))
+    })?;
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn test_walk_patterns() -> Result<()> {
+    use std::collections::BTreeSet;
+    use std::sync::Mutex;
+
+    let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    td.create_dir_all(".git/objects")?;
+    td.write(".git/config", "")?;
+    td.create_dir_all("etc/foo/.git")?;
+    td.write("etc/foo/.git/x.conf", "")?;
+    td.write("etc/foo/a.conf", "")?;
+    td.write("etc/foo/b.txt", "")?;
+    td.write("etc/c.conf", "")?;
+    td.write("etc/d.conf.bak", "")?;
+    td.write("top.conf", "")?;
+
+    let walk = |config: WalkConfiguration| -> Result<BTreeSet<String>> {
+        let seen = Mutex::new(BTreeSet::new());
+        td.walk(&config, |e| -> std::io::Result<_> {
+            seen.lock()
+                .unwrap()
+                .insert(e.path.to_str().unwrap().to_owned());
+            Ok(ControlFlow::Continue(()))
+        })?;
+        Ok(seen.into_inner().unwrap())
+    };
+    let set = |v: &[&str]| v.iter().map(|&s| s.to_owned()).collect::<BTreeSet<_>>();
+
+    let r = walk(WalkConfiguration::default().exclude("**/.git"))?;
+    assert_eq!(
+        r,
+        set(&[
+            "etc",
+            "etc/c.conf",
+            "etc/d.conf.bak",
+            "etc/foo",
+            "etc/foo/a.conf",
+            "etc/foo/b.txt",
+            "top.conf"
+        ])
+    );
+    // Patterns without a slash match the file name
+    let r = walk(
+        WalkConfiguration::default()
+            .exclude(".git")
+            .include("*.conf"),
+    )?;
+    assert_eq!(
+        r,
+        set(&["etc", "etc/c.conf", "etc/foo", "etc/foo/a.conf", "top.conf"])
+    );
+    let r = walk(
+        WalkConfiguration::default()
+            .path_base(Path::new("/base"))
+            .include("etc/*.con?"),
+    )?;
+    assert_eq!(
+        r,
+        set(&[
+            "/base/.git",
+            "/base/.git/objects",
+            "/base/etc",
+            "/base/etc/c.conf",
+            "/base/etc/foo",
+            "/base/etc/foo/.git"
+        ])
+    );
+
+    let r = walk(WalkConfiguration::default().skip_hidden())?;
+    assert_eq!(
+        r,
+        set(&[
+            "etc",
+            "etc/c.conf",
+            "etc/d.conf.bak",
+            "etc/foo",
+            "etc/foo/a.conf",
+            "etc/foo/b.txt",
+            "top.conf"
+        ])
+    );
+
+    let seen = Mutex::new(BTreeSet::new());
+    td.walk_parallel(
+        &WalkConfiguration::default()
+            .exclude("**/.git")
+            .exclude("etc/foo"),
+        |e| -> std::io::Result<_> {
+            seen.lock()
+                .unwrap()
+                .insert(e.path.to_str().unwrap().to_owned());
+            Ok(ControlFlow::Continue(()))
+        },
+    )?;
+    assert_eq!(
+        seen.into_inner().unwrap(),
+        set(&["etc", "etc/c.conf", "etc/d.conf.bak", "top.conf"])
+    );
+    Ok(())
+}
+
+#[test]
+fn test_walk_include_root() -> Result<()> {
+    let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    td.create_dir("d")?;
+    td.write("d/f", "")?;
+
+    let walk = |config: WalkConfiguration| -> Result<Vec<PathBuf>> {
+        let mut seen = Vec::new();
+        td.walk(&config.sort_by_file_name(), |e| -> std::io::Result<_> {
+            if e.filename == "." {
+                assert!(e.file_type.is_dir());
+                assert!(e.dir.metadata(e.filename)?.is_dir());
+            }
+            seen.push(e.path.to_owned());
+            Ok(ControlFlow::Continue(()))
+        })?;
+        Ok(seen)
+    };
+    let r = walk(WalkConfiguration::default().include_root())?;
+    assert_eq!(r, ["", "d", "d/f"].map(PathBuf::from));
+    let r = walk(
+        WalkConfiguration::default()
+            .include_root()
+            .contents_first()
+            .path_base(Path::new("/x")),
+    )?;
+    assert_eq!(r, ["/x/d/f", "/x/d", "/x"].map(PathBuf::from));
+
+    // Breaking at the root skips everything else
+    let n = std::sync::atomic::AtomicUsize::new(0);
+    td.walk_parallel(
+        &WalkConfiguration::default().include_root().metadata(),
+        |e| -> std::io::Result<_> {
+            assert!(e.metadata.unwrap().is_dir());
+            n.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Ok(ControlFlow::Break(()))
+        },
+    )?;
+    assert_eq!(n.into_inner(), 1);
+    Ok(())
+}
+
+#[test]
+fn test_entries_sorted() -> Result<()> {
+    let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    for name in ["c", "a", "B", "b"] {
+        td.write(name, name)?;
+    }
+    let names = td
+        .entries_sorted()?
+        .map(|e| e.file_name())
+        .collect::<Vec<_>>();
+    assert_eq!(names, ["B", "a", "b", "c"]);
+    let names = td
+        .entries_sorted_by(|a, b| b.file_name().cmp(&a.file_name()))?
+        .map(|e| e.file_name())
+        .collect::<Vec<_>>();
+    assert_eq!(names, ["c", "b", "a", "B"]);
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn test_subdirs() -> Result<()> {
+    let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    td.create_dir_all("a/x")?;
+    td.create_dir("b")?;
+    td.write("f", "")?;
+    td.symlink("a", "link")?;
+    let mut subdirs = td.subdirs()?.collect::<std::io::Result<Vec<_>>>()?;
+    subdirs.sort_by(|a, b| a.0.cmp(&b.0));
+    let names = subdirs
+        .iter()
+        .map(|(n, _)| n.as_os_str())
+        .collect::<Vec<_>>();
+    assert_eq!(names, ["a", "b"]);
+    assert!(subdirs[0].1.try_exists("x")?);
+    Ok(())
+}
+
+#[test]
+fn test_walk_contents_first() -> Result<()> {
+    let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    td.create_dir_all("a/b")?;
+    td.write("a/b/f", "x")?;
+    td.write("a/g", "yy")?;
+    td.write("h", "zzz")?;
+
+    let mut seen = Vec::new();
+    td.walk(
+        &WalkConfiguration::default()
+            .contents_first()
+            .sort_by_file_name(),
+        |e| -> std::io::Result<_> {
+            seen.push(e.path.to_owned());
+            Ok(ControlFlow::Continue(()))
+        },
+    )?;
+    let expected = ["a/b/f", "a/b", "a/g", "a", "h"];
+    assert_eq!(seen, expected.map(PathBuf::from));
+
+    // Breaking skips the rest of the containing directory, but not the directory itself
+    let mut seen = Vec::new();
+    td.walk(
+        &WalkConfiguration::default()
+            .contents_first()
+            .sort_by_file_name(),
+        |e| -> std::io::Result<_> {
+            seen.push(e.path.to_owned());
+            if e.path == Path::new("a/b") {
+                return Ok(ControlFlow::Break(()));
+            }
+            Ok(ControlFlow::Continue(()))
+        },
+    )?;
+    let expected = ["a/b/f", "a/b", "a", "h"];
+    assert_eq!(seen, expected.map(PathBuf::from));
+
+    // Remove the whole tree from the walk
+    td.walk(
+        &WalkConfiguration::default().contents_first(),
+        |e| -> std::io::Result<_> {
+            if e.file_type.is_dir() {
+                e.dir.remove_dir(e.filename)?;
+            } else {
+                e.dir.remove_file(e.filename)?;
+            }
+            Ok(ControlFlow::Continue(()))
+        },
+    )?;
+    assert_eq!(td.entries()?.count(), 0);
+    Ok(())
+}
+
+#[test]
+fn test_walk_max_open_dirs() -> Result<()> {
+    let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    let mut p = PathBuf::new();
+    for i in 0..300 {
+        p.push("d");
+        td.create_dir_all(&p)?;
+        td.write(p.join(format!("f{i}")), "x")?;
+        td.write(p.with_file_name(format!("g{i}")), "y")?;
+    }
+
+    let walk = |config: WalkConfiguration| -> Result<Vec<PathBuf>> {
+        let mut seen = Vec::new();
+        td.walk(&config, |e| -> std::io::Result<_> {
+            seen.push(e.path.to_owned());
+            Ok(ControlFlow::Continue(()))
+        })?;
+        Ok(seen)
+    };
+    let unbounded = walk(WalkConfiguration::default().sort_by_file_name())?;
+    assert_eq!(unbounded.len(), 900);
+    for n in [0, 1, 2, 7] {
+        let seen = walk(
+            WalkConfiguration::default()
+                .sort_by_file_name()
+                .max_open_dirs(n),
+        )?;
+        assert_eq!(seen, unbounded);
+    }
+    let seen = walk(WalkConfiguration::default().max_open_dirs(3))?;
+    assert_eq!(seen.len(), unbounded.len());
+
+    // Directories are reopened before being passed to the callback
+    td.walk(
+        &WalkConfiguration::default()
+            .contents_first()
+            .max_open_dirs(2),
+        |e| -> std::io::Result<_> {
+            if e.file_type.is_dir() {
+                e.dir.remove_dir(e.filename)?;
+            } else {
+                e.dir.remove_file(e.filename)?;
+            }
+            Ok(ControlFlow::Continue(()))
+        },
+    )?;
+    assert_eq!(td.entries()?.count(), 0);
+    Ok(())
+}
+
+#[test]
+fn test_walk_resume() -> Result<()> {
+    let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    td.create_dir_all("a/b")?;
+    td.write("a/b/f", "x")?;
+    td.write("a/b/g", "x")?;
+    td.write("a/c", "x")?;
+    td.write("d", "x")?;
+    td.create_dir("e")?;
+    td.write("e/h", "x")?;
+
+    let walk = |config: WalkConfiguration| -> std::io::Result<Vec<PathBuf>> {
+        let mut seen = Vec::new();
+        td.walk(&config, |e| -> std::io::Result<_> {
+            seen.push(e.cursor().path().to_owned());
+            Ok(ControlFlow::Continue(()))
+        })?;
+        Ok(seen)
+    };
+    for contents_first in [false, true] {
+        let config = || {
+            let config = WalkConfiguration::default()
+                .path_base(Path::new("base"))
+                .include_root()
+                .sort_by_file_name();
+            if contents_first {
+                config.contents_first()
+            } else {
+                config
+            }
+        };
+        let all = walk(config())?;
+        assert_eq!(all.len(), 9);
+        for (i, p) in all.iter().enumerate() {
+            let seen = walk(config().resume_after(WalkCursor::new(p)))?;
+            assert_eq!(seen, &all[i + 1..]);
+        }
+    }
+
+    // The walk resumes at the same position if the entry was removed
+    td.remove_dir_all("a/b")?;
+    let seen = walk(
+        WalkConfiguration::default()
+            .sort_by_file_name()
+            .resume_after(WalkCursor::new("a/b/f")),
+    )?;
+    assert_eq!(seen, ["a/c", "d", "e", "e/h"].map(PathBuf::from));
+
+    let e = walk(WalkConfiguration::default().resume_after(WalkCursor::new("a"))).unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput);
+    let e = walk(
+        WalkConfiguration::default()
+            .path_base(Path::new("base"))
+            .sort_by_file_name()
+            .resume_after(WalkCursor::new("a")),
+    )
+    .unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput);
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn test_walk_follow_symlinks() -> Result<()> {
+    let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    td.create_dir_all("a/b")?;
+    td.write("a/b/file", "")?;
+    td.symlink("b", "a/link")?;
+    td.symlink("..", "a/b/parent")?;
+    td.symlink("file", "a/b/filelink")?;
+    td.symlink("nosuchfile", "a/dangling")?;
+    td.symlink("../../..", "a/escape")?;
+
+    let walk = |config: WalkConfiguration| -> Result<Vec<(String, &'static str)>> {
+        let mut r = Vec::new();
+        td.walk(&config.sort_by_file_name(), |e| -> std::io::Result<_> {
+            let ty = if e.file_type.is_dir() {
+                "dir"
+            } else if e.file_type.is_symlink() {
+                "symlink"
+            } else {
+                "file"
+            };
+            r.push((e.path.to_str().unwrap().to_owned(), ty));
+            Ok(ControlFlow::Continue(()))
+        })?;
+        Ok(r)
+    };
+    let physical = walk(WalkConfiguration::default())?;
+    assert!(physical.contains(&("a/link".into(), "symlink")));
+    assert!(!physical.iter().any(|(p, _)| p.starts_with("a/link/")));
+
+    let logical = walk(WalkConfiguration::default().follow_symlinks())?;
+    let expected = [
+        ("a", "dir"),
+        ("a/b", "dir"),
+        ("a/b/file", "file"),
+        ("a/b/filelink", "file"),
+        // An ancestor, so not descended into
+        ("a/b/parent", "dir"),
+        ("a/dangling", "symlink"),
+        ("a/escape", "symlink"),
+        ("a/link", "dir"),
+        ("a/link/file", "file"),
+        ("a/link/filelink", "file"),
+        ("a/link/parent", "dir"),
+    ];
+    assert_eq!(
+        logical,
+        expected
+            .iter()
+            .map(|&(p, t)| (p.to_owned(), t))
+            .collect::<Vec<_>>()
+    );
+    Ok(())
+}
+
+#[test]
+fn test_open_dir_noxdev() -> Result<()> {
+    let root = &Dir::open_ambient_dir("/", cap_std::ambient_authority())?;
+    assert!(root.open_dir_noxdev("proc").unwrap().is_none());
+    let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    td.create_dir_all("foo/bar")?;
+    assert!(td.open_dir_noxdev("foo/bar").unwrap().is_some());
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "mmap")]
+fn test_map_readonly() -> Result<()> {
+    let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    td.write("somefile", "some contents")?;
+    // SAFETY: Nothing else modifies these files.
+    let m = unsafe { td.map_readonly("somefile") }.unwrap();
+    assert_eq!(&*m, b"some contents");
+    assert_eq!(m.len(), 13);
+    td.write("empty", "")?;
+    let m = unsafe { td.map_readonly("empty") }.unwrap();
+    assert!(m.is_empty());
+    assert_eq!(&*m, b"");
+    assert!(unsafe { td.map_readonly("nosuchfile") }.is_err());
+    Ok(())
+}
+
+#[test]
+fn test_open_with_oflags() -> Result<()> {
+    use rustix::fs::OFlags;
+
+    let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    td.write("somefile", "some contents")?;
+    td.symlink("somefile", "link")?;
+
+    let mut f = td.open_with_oflags("somefile", OFlags::RDONLY | OFlags::NOATIME)?;
+    let mut buf = String::new();
+    std::io::Read::read_to_string(&mut f, &mut buf)?;
+    assert_eq!(buf, "some contents");
+
+    // O_NOFOLLOW refuses to open a symlink
+    let e = td
+        .open_with_oflags("link", OFlags::RDONLY | OFlags::NOFOLLOW)
+        .unwrap_err();
+    assert_eq!(e.raw_os_error(), Some(libc::ELOOP));
+    // But O_PATH | O_NOFOLLOW opens the link itself
+    let f = td.open_with_oflags("link", OFlags::PATH | OFlags::NOFOLLOW)?;
+    assert!(f.metadata()?.is_symlink());
+
+    let mut f = td.open_with_oflags("newfile", OFlags::WRONLY | OFlags::CREATE | OFlags::EXCL)?;
+    f.write_all(b"new")?;
+    assert_eq!(td.read_to_string("newfile")?, "new");
+
+    td.create_dir("subdir")?;
+    let f = td.open_with_oflags("subdir", OFlags::RDONLY | OFlags::DIRECTORY)?;
+    assert!(f.metadata()?.is_dir());
+
+    // Escapes are still forbidden
+    assert!(td.open_with_oflags("../foo", OFlags::RDONLY).is_err());
+    assert!(td
+        .open_with_oflags("/etc/hostname", OFlags::RDONLY)
+        .is_err());
+    Ok(())
+}
+
+#[test]
+fn test_open_beneath() -> Result<()> {
+    let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    td.create_dir_all("usr/lib")?;
+    td.create_dir("etc")?;
+    td.write("usr/lib/auth.json", "auth contents")?;
+    td.symlink("../usr/lib/auth.json", "etc/auth.json")?;
+    td.symlink_contents("/usr/lib/auth.json", "etc/auth-abs.json")?;
+    td.symlink_contents("../../etc/hostname", "etc/escape")?;
+
+    let mut buf = String::new();
+    let mut f = td.open_beneath("etc/auth.json").unwrap();
+    std::io::Read::read_to_string(&mut f, &mut buf)?;
+    assert_eq!(buf, "auth contents");
+
+    assert!(td.open_beneath("etc/auth-abs.json").is_err());
+    assert!(td.open_beneath("etc/escape").is_err());
+    assert!(td.open_beneath("/usr/lib/auth.json").is_err());
+    assert!(td.open_beneath("../foo").is_err());
+    assert!(td.open_beneath_optional("nosuchfile").unwrap().is_none());
+    assert!(td
+        .open_beneath_optional("usr/lib/auth.json")
+        .unwrap()
+        .is_some());
+    Ok(())
+}
+
+#[test]
+fn test_atomic_write_durability() -> Result<()> {
+    let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    td.create_dir("subdir")?;
+    for durability in [
+        Durability::None,
+        Durability::Standard,
+        Durability::FullBarrier,
+    ] {
+        let opts = AtomicWriteOptions::default().durability(durability);
+        for p in ["foo", "subdir/foo"] {
+            td.atomic_replace_with_options(p, &opts, |f| write!(f, "{durability:?}"))
+                .unwrap();
+            assert_eq!(td.read_to_string(p)?, format!("{durability:?}"));
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_atomic_write_vectored_and_reader() -> Result<()> {
+    use std::io::IoSlice;
+    let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+
+    // More buffers than can be written in a single call
+    let parts = (0..3000).map(|i| i.to_string()).collect::<Vec<_>>();
+    let mut bufs = parts
+        .iter()
+        .map(|p| IoSlice::new(p.as_bytes()))
+        .collect::<Vec<_>>();
+    bufs.insert(0, IoSlice::new(b""));
+    td.atomic_write_vectored("vectored", &bufs)?;
+    assert_eq!(td.read_to_string("vectored")?, parts.concat());
+    td.atomic_write_vectored("vectored", &[])?;
+    assert_eq!(td.read("vectored")?.len(), 0);
+
+    let data = vec![42u8; 1 << 20];
+    let n = td.atomic_write_from_reader("reader", &data[..], Some(data.len() as u64))?;
+    assert_eq!(n, data.len() as u64);
+    assert_eq!(td.read("reader")?, data);
+    // An inaccurate hint doesn't affect the contents
+    let n = td.atomic_write_from_reader("reader", &b"short"[..], Some(1 << 20))?;
+    assert_eq!(n, 5);
+    assert_eq!(td.read("reader")?, b"short");
+    Ok(())
+}
+
+#[test]
+fn test_open_buffered() -> Result<()> {
+    use std::io::BufRead;
+
+    let td = &cap_tempfile::tempdir(cap_std::ambient_authority())?;
+    td.write("f", "one\ntwo\n")?;
+    let lines = td
+        .open_buffered("f")?
+        .lines()
+        .collect::<std::io::Result<Vec<_>>>()?;
+    assert_eq!(lines, ["one", "two"]);
+    let lines = td
+        .open_buffered_optional("f")?
+        .unwrap()
+        .lines()
+        .collect::<std::io::Result<Vec<_>>>()?;
+    assert_eq!(lines, ["one", "two"]);
+    assert!(td.open_buffered_optional("missing")?.is_none());
+    assert_eq!(
+        td.open_buffered("missing").unwrap_err().kind(),
+        std::io::ErrorKind::NotFound
+    );
+    Ok(())
+}
+
+#[test]
+fn test_read_exact_to_vec() -> Result<()> {
+    let td = &cap_tempfile::tempdir(cap_std::ambient_authority())?;
+    td.write("empty", "")?;
+    assert!(td.read_exact_to_vec("empty")?.is_empty());
+    let contents = (0..3_000_000u32).map(|i| i as u8).collect::<Vec<_>>();
+    td.write("big", &contents)?;
+    let buf = td.read_exact_to_vec("big")?;
+    assert_eq!(buf, contents);
+    assert_eq!(
+        td.read_exact_to_vec("missing").unwrap_err().kind(),
+        std::io::ErrorKind::NotFound
+    );
+    Ok(())
+}
+
+#[test]
+fn test_edit_file() -> Result<()> {
+    let td = &cap_tempfile::tempdir(cap_std::ambient_authority())?;
+    let append = |existing: Option<Vec<u8>>| {
+        let mut buf = existing.unwrap_or_default();
+        buf.extend_from_slice(b"line\n");
+        Ok(Some(buf))
+    };
+    assert!(td.edit_file("f", append)?);
+    assert!(td.edit_file("f", append)?);
+    assert_eq!(td.read_to_string("f")?, "line\nline\n");
+
+    // Returning None leaves the file alone
+    assert!(!td.edit_file("f", |_| Ok(None))?);
+    assert!(!td.edit_file("missing", |existing| {
+        assert!(existing.is_none());
+        Ok(None)
+    })?);
+    assert!(!td.try_exists("missing")?);
+
+    // Errors are propagated, and the file is unchanged
+    let e = td
+        .edit_file("f", |_| {
+            Err(std::io::Error::new(std::io::ErrorKind::Other, "oops"))
+        })
+        .unwrap_err();
+    assert_eq!(e.to_string(), "oops");
+    assert_eq!(td.read_to_string("f")?, "line\nline\n");
+
+    #[cfg(unix)]
+    {
+        use cap_std::fs::PermissionsExt;
+        td.set_permissions("f", cap_std::fs::Permissions::from_mode(0o640))?;
+        td.edit_file("f", |_| Ok(Some(b"new".to_vec())))?;
+        assert_eq!(td.metadata("f")?.permissions().mode() & 0o777, 0o640);
+        assert_eq!(td.read_to_string("f")?, "new");
+    }
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "digest")]
+fn test_digests() -> Result<()> {
+    use cap_std_ext::digest::Algorithm;
+
+    const EMPTY_SHA256: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+    const ABC_SHA256: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+    const ABC_SHA512: &str = "ddaf35a193617abacc417349ae20413112e6fa4e89a97ea20a9eeee64b55d39a\
+                              2192992a274fc1a836ba3c23a3feebbd454d4423643ce80e2a9ac94fa54ca49f";
+
+    let td = &cap_tempfile::tempdir(cap_std::ambient_authority())?;
+    td.write("empty", "")?;
+    td.write("abc", "abc")?;
+    let d = td.file_digest("abc", Algorithm::Sha256)?;
+    assert_eq!(d.algorithm(), Algorithm::Sha256);
+    assert_eq!(d.as_bytes().len(), Algorithm::Sha256.output_len());
+    assert_eq!(d.to_hex(), ABC_SHA256);
+    let d = td.file_digest("abc", Algorithm::Sha512)?;
+    assert_eq!(d.as_bytes().len(), Algorithm::Sha512.output_len());
+    assert_eq!(d.to_string(), ABC_SHA512);
+    assert!(td.file_digest("missing", Algorithm::Sha256).is_err());
+
+    // Larger than the read buffer
+    let big = vec![b'x'; 200 * 1024];
+    td.write("big", &big)?;
+    let big = td.file_digest("big", Algorithm::Sha256)?.to_hex();
+    assert_ne!(big, EMPTY_SHA256);
+
+    td.create_dir_all("sub/dir")?;
+    td.write("sub/dir/abc", "abc")?;
+    #[cfg(unix)]
+    td.symlink("abc", "link")?;
+    let digests = td.tree_digests(Algorithm::Sha256)?;
+    let digests = digests
+        .iter()
+        .map(|(k, v)| (k.to_str().unwrap(), v.to_hex()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        digests,
+        [
+            ("abc", ABC_SHA256.to_string()),
+            ("big", big),
+            ("empty", EMPTY_SHA256.to_string()),
+            ("sub/dir/abc", ABC_SHA256.to_string()),
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "serde")]
+fn test_serde_helpers() -> Result<()> {
+    use std::collections::BTreeMap;
+    type Map = BTreeMap<String, u32>;
+
+    let td = &cap_tempfile::tempdir(cap_std::ambient_authority())?;
+    assert!(td.read_json_optional::<Map>("config.json")?.is_none());
+    assert!(td.read_toml_optional::<Map>("config.toml")?.is_none());
+
+    let m: Map = [("a".to_string(), 1), ("b".to_string(), 2)].into();
+    td.atomic_write_json("config.json", &m, false)?;
+    assert_eq!(td.read_to_string("config.json")?, "{\"a\":1,\"b\":2}\n");
+    assert_eq!(td.read_json_optional::<Map>("config.json")?.unwrap(), m);
+    td.atomic_write_json("config.json", &m, true)?;
+    assert!(td
+        .read_to_string("config.json")?
+        .contains("\n  \"a\": 1,\n"));
+    assert_eq!(td.read_json_optional::<Map>("config.json")?.unwrap(), m);
+
+    td.write("config.toml", "a = 1\nb = 2\n")?;
+    assert_eq!(td.read_toml_optional::<Map>("config.toml")?.unwrap(), m);
+
+    td.write("bad.json", "{")?;
+    let e = td.read_json_optional::<Map>("bad.json").unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+    td.write("bad.toml", "a = ")?;
+    let e = td.read_toml_optional::<Map>("bad.toml").unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+    Ok(())
+}
+
+#[test]
+fn test_atomic_write_backups() -> Result<()> {
+    let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    td.create_dir("subdir")?;
+    let opts = AtomicWriteOptions::default().backups(3);
+    for i in 0..5 {
+        td.atomic_replace_with_options("subdir/foo", &opts, |f| write!(f, "{i}"))?;
+    }
+    assert_eq!(td.read_to_string("subdir/foo")?, "4");
+    assert_eq!(td.read_to_string("subdir/foo.bak")?, "3");
+    assert_eq!(td.read_to_string("subdir/foo.bak.1")?, "2");
+    assert_eq!(td.read_to_string("subdir/foo.bak.2")?, "1");
+    assert_eq!(td.open_dir("subdir")?.entries()?.count(), 4);
+
+    // No backup is made if writing fails
+    let r = td.atomic_replace_with_options("subdir/foo", &opts, |_| -> std::io::Result<()> {
+        Err(std::io::Error::new(std::io::ErrorKind::Other, "oops"))
+    });
+    assert!(r.is_err());
+    assert_eq!(td.read_to_string("subdir/foo.bak")?, "3");
+
+    let opts = AtomicWriteOptions::default().backups(1);
+    td.atomic_replace_with_options("subdir/foo", &opts, |f| write!(f, "5"))?;
+    assert_eq!(td.read_to_string("subdir/foo.bak")?, "4");
+    Ok(())
+}
+
+#[test]
+fn test_canonicalize_beneath() -> Result<()> {
+    let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    td.create_dir_all("usr/lib/foo")?;
+    td.create_dir("etc")?;
+    td.write("usr/lib/foo/somefile", "")?;
+    td.symlink("usr/lib", "lib")?;
+    td.symlink("../lib/foo/somefile", "etc/somefile")?;
+    td.symlink("./foo", "usr/lib/foo-link")?;
+    td.symlink("loop2", "loop1")?;
+    td.symlink("loop1", "loop2")?;
+    td.symlink_contents("/usr/lib", "abslib")?;
+    td.symlink_contents("../..", "usr/up")?;
+
+    let c = |p: &str| td.canonicalize_beneath(p);
+    assert_eq!(c(".")?, Path::new("."));
+    assert_eq!(c("")?, Path::new("."));
+    assert_eq!(c("usr")?, Path::new("usr"));
+    assert_eq!(c("./usr/../usr/lib")?, Path::new("usr/lib"));
+    assert_eq!(c("lib/foo")?, Path::new("usr/lib/foo"));
+    assert_eq!(c("etc/somefile")?, Path::new("usr/lib/foo/somefile"));
+    assert_eq!(
+        c("lib/foo-link/somefile")?,
+        Path::new("usr/lib/foo/somefile")
+    );
+    assert_eq!(c("lib/..")?, Path::new("usr"));
+
+    for p in ["..", "usr/../..", "/usr", "abslib", "usr/up"] {
+        let e = c(p).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::PermissionDenied, "{p}");
+    }
+    assert_eq!(c("loop1").unwrap_err().raw_os_error(), Some(libc::ELOOP));
+    assert_eq!(
+        c("usr/nosuchfile").unwrap_err().kind(),
+        std::io::ErrorKind::NotFound
+    );
+    assert_eq!(
+        c("etc/somefile/foo").unwrap_err().raw_os_error(),
+        Some(libc::ENOTDIR)
+    );
+    Ok(())
+}
+
+#[test]
+fn test_open_or_create_dir_all() -> Result<()> {
+    let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    let b = &cap_std::fs::DirBuilder::new();
+
+    let d = td.open_or_create_dir_all("a/b/c", b).unwrap();
+    d.write("somefile", "contents")?;
+    assert_eq!(td.read_to_string("a/b/c/somefile")?, "contents");
+    // Already exists
+    let d = td.open_or_create_dir_all("a/b/c", b).unwrap();
+    assert!(d.try_exists("somefile")?);
+    let d = td.open_or_create_dir_all("a/b/d", b).unwrap();
+    assert!(!d.try_exists("somefile")?);
+
+    td.write("a/file", "")?;
+    assert!(td.open_or_create_dir_all("a/file/foo", b).is_err());
+    assert!(td.open_or_create_dir_all("../foo", b).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_resolve_symlinks() -> Result<()> {
+    let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    td.create_dir_all("usr/lib")?;
+    td.create_dir("etc")?;
+    td.write("usr/lib/somefile", "")?;
+    td.symlink("usr/lib", "lib")?;
+    td.symlink("../lib/somefile", "etc/somefile")?;
+    td.symlink("nosuchfile", "etc/dangling")?;
+    td.symlink("loop2", "loop1")?;
+    td.symlink("loop1", "loop2")?;
+    td.symlink_contents("/usr/lib", "abslib")?;
+
+    let r = td.resolve_symlinks("etc/somefile", 10)?;
+    assert_eq!(r.target, Path::new("usr/lib/somefile"));
+    assert_eq!(
+        r.chain,
+        [PathBuf::from("etc/somefile"), PathBuf::from("lib")]
+    );
+    assert!(!(r.escaped || r.looped || r.dangling));
+
+    // Regular files have an empty chain
+    let r = td.resolve_symlinks("usr/lib/somefile", 10)?;
+    assert_eq!(r.target, Path::new("usr/lib/somefile"));
+    assert!(r.chain.is_empty());
+
+    // Limited hops
+    let r = td.resolve_symlinks("etc/somefile", 1)?;
+    assert!(r.looped);
+    assert_eq!(r.chain, [PathBuf::from("etc/somefile")]);
+
+    let r = td.resolve_symlinks("etc/dangling", 10)?;
+    assert!(r.dangling);
+    assert_eq!(r.target, Path::new("etc/nosuchfile"));
+
+    let r = td.resolve_symlinks("loop1", 10)?;
+    assert!(r.looped);
+    assert_eq!(r.chain.len(), 10);
+
+    let r = td.resolve_symlinks("abslib", 10)?;
+    assert!(r.escaped);
+    assert_eq!(r.chain, [PathBuf::from("abslib")]);
+    Ok(())
+}
+
+#[test]
+fn test_create_unique_dir() -> Result<()> {
+    use std::collections::BTreeSet;
+    let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    let (name, d) = td.create_unique_dir("job-")?;
+    assert!(name.starts_with("job-"));
+    d.write("somefile", "contents")?;
+    assert_eq!(td.read_to_string(format!("{name}/somefile"))?, "contents");
+
+    let names = std::thread::scope(|s| {
+        let handles = (0..8)
+            .map(|_| s.spawn(|| td.create_unique_dir("job-").map(|(name, _)| name)))
+            .collect::<Vec<_>>();
+        handles
+            .into_iter()
+            .map(|h| h.join().unwrap())
+            .collect::<Result<BTreeSet<_>, _>>()
+    })?;
+    assert_eq!(names.len(), 8);
+    assert!(!names.contains(&name));
+
+    assert!(td.create_unique_dir("foo/").is_err());
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn test_cleanup_stale_tempfiles() -> Result<()> {
+    use std::time::Duration;
+    let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    td.write(".tmpold", "")?;
+    td.create_dir_all(".tmpolddir/sub")?;
+    td.write(".tmpnew", "")?;
+    td.write("other", "")?;
+    td.symlink("target", ".tmplink")?;
+    let past = rustix::fs::Timespec {
+        tv_sec: 1000,
+        tv_nsec: 0,
+    };
+    let times = rustix::fs::Timestamps {
+        last_access: past,
+        last_modification: past,
+    };
+    for p in [".tmpold", ".tmpolddir", "other"] {
+        rustix::fs::utimensat(&**td, p, &times, rustix::fs::AtFlags::empty())?;
+    }
+    rustix::fs::utimensat(
+        &**td,
+        ".tmplink",
+        &times,
+        rustix::fs::AtFlags::SYMLINK_NOFOLLOW,
+    )?;
+
+    let hour = Duration::from_secs(3600);
+    assert_eq!(td.cleanup_stale_tempfiles(".tmp", hour)?, 2);
+    assert!(!td.try_exists(".tmpold")?);
+    assert!(!td.try_exists(".tmpolddir")?);
+    assert!(td.try_exists(".tmpnew")?);
+    assert!(td.try_exists("other")?);
+    assert!(td.symlink_metadata(".tmplink").is_ok());
+    assert_eq!(td.cleanup_stale_tempfiles(".tmp", hour)?, 0);
+    assert_eq!(td.cleanup_stale_tempfiles(".tmp", Duration::ZERO)?, 1);
+    assert!(!td.try_exists(".tmpnew")?);
+    assert!(td.cleanup_stale_tempfiles("", hour).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_tempdir_in_with_prefix() -> Result<()> {
+    let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    let t = td.tempdir_in_with_prefix("staging-")?;
+    assert!(t.name().starts_with("staging-"));
+    t.write("somefile", "contents")?;
+    let name = t.name().to_owned();
+    assert!(td.try_exists(format!("{name}/somefile"))?);
+    drop(t);
+    assert!(!td.try_exists(&name)?);
+
+    let t = td.tempdir_in_with_prefix("staging-")?;
+    let name = t.name().to_owned();
+    t.close()?;
+    assert!(!td.try_exists(&name)?);
+
+    let t = td.tempdir_in_with_prefix("staging-")?;
+    t.create_dir("sub")?;
+    let (name, d) = t.keep();
+    assert!(d.try_exists("sub")?);
+    assert!(td.try_exists(format!("{name}/sub"))?);
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn test_is_same_file() -> Result<()> {
+    use cap_std_ext::dirext::is_same_file_fd;
+    let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    td.create_dir("subdir")?;
+    td.write("a", "contents")?;
+    td.write("b", "contents")?;
+    td.hard_link("a", td, "subdir/hardlink")?;
+    td.symlink("a", "link")?;
+
+    assert!(td.is_same_file("a", "a")?);
+    assert!(!td.is_same_file("a", "b")?);
+    assert!(td.is_same_file("a", "subdir/hardlink")?);
+    assert!(td.is_same_file("subdir", "subdir/../subdir")?);
+    assert!(td.is_same_file("a", "link")?);
+    assert!(!td.is_same_file_nofollow("a", "link")?);
+    assert!(td.is_same_file_nofollow("a", "subdir/hardlink")?);
+    assert!(td.is_same_file("a", "nosuchfile").is_err());
+
+    assert!(is_same_file_fd(td.open("a")?, td.open("subdir/hardlink")?)?);
+    assert!(!is_same_file_fd(td.open("a")?, td.open("b")?)?);
+    Ok(())
+}
+
+#[test]
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn test_statx() -> Result<()> {
+    use cap_std::fs::MetadataExt;
+    use cap_std_ext::dirext::StatxAttribute;
+    use rustix::fs::AtFlags;
+
+    let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    td.write("somefile", "contents")?;
+    td.symlink("somefile", "link")?;
+    let meta = td.metadata("somefile")?;
+    let st = td.statx("somefile", AtFlags::empty())?;
+    assert_eq!(st.ino(), meta.ino());
+    assert_eq!(st.as_raw().stx_size, 8);
+    // Not all filesystems support these, but if they do, the values should be sane
+    if let Some(btime) = st.birth_time() {
+        assert!(btime <= std::time::SystemTime::now());
+    }
+    assert_ne!(st.attribute(StatxAttribute::Immutable), Some(true));
+    assert_eq!(td.statx("link", AtFlags::empty())?.ino(), st.ino());
+    assert_ne!(td.statx("link", AtFlags::SYMLINK_NOFOLLOW)?.ino(), st.ino());
+    assert!(td.statx("nosuchfile", AtFlags::empty()).is_err());
+
+    // Paths can't escape the directory
+    td.symlink_contents("/etc/passwd", "abslink")?;
+    td.symlink_contents("../x", "rellink")?;
+    for p in ["/etc/passwd", "../x", "abslink", "rellink"] {
+        let e = td.statx(p, AtFlags::empty()).unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::EXDEV), "{p}");
+    }
+    assert!(td.statx("abslink", AtFlags::SYMLINK_NOFOLLOW).is_ok());
+    assert!(td.is_mountpoint("/proc").is_err());
+
+    let root = Dir::open_ambient_dir("/", cap_std::ambient_authority())?;
+    let proc = root.statx("proc", AtFlags::empty())?;
+    assert_ne!(proc.attribute(StatxAttribute::MountRoot), Some(false));
+    if let (Some(a), Some(b)) = (proc.mnt_id(), root.statx(".", AtFlags::empty())?.mnt_id()) {
+        assert_ne!(a, b);
+    }
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn test_entries_snapshot() -> Result<()> {
+    let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    td.write("b", "")?;
+    td.write("a", "")?;
+    // Set the modification time into the past so the snapshot is not racy
+    let past = rustix::fs::Timespec {
+        tv_sec: 1000,
+        tv_nsec: 0,
+    };
+    let times = rustix::fs::Timestamps {
+        last_access: past,
+        last_modification: past,
+    };
+    rustix::fs::utimensat(&**td, ".", &times, rustix::fs::AtFlags::empty())?;
+    let snapshot = td.entries_snapshot()?;
+    assert_eq!(snapshot.names, ["a", "b"]);
+    assert!(!td.has_changed_since(&snapshot.generation)?);
+    // Content changes don't affect the generation
+    td.write("a", "new contents")?;
+    assert!(!td.has_changed_since(&snapshot.generation)?);
+    td.write("c", "")?;
+    assert!(td.has_changed_since(&snapshot.generation)?);
+
+    // A snapshot taken right after a modification is conservatively treated as changed
+    let snapshot = td.entries_snapshot()?;
+    assert_eq!(snapshot.names.len(), 3);
+    assert!(td.has_changed_since(&snapshot.generation)?);
+    Ok(())
+}
+
+#[test]
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn test_file_handle() -> Result<()> {
+    use rustix::fs::OFlags;
+    use std::io::Read;
+
+    let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    td.create_dir("subdir")?;
+    td.write("subdir/somefile", "contents")?;
+    let h = match td.file_handle("subdir/somefile") {
+        Ok(h) => h,
+        Err(e) if e.kind() == std::io::ErrorKind::Unsupported => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    assert!(!h.as_bytes().is_empty());
+    assert_eq!(td.file_handle("subdir/somefile")?, h);
+    assert_ne!(td.file_handle("subdir")?, h);
+    assert!(td.file_handle("../somefile").is_err());
+
+    // Opening by handle requires privileges
+    match td.open_by_handle(&h, OFlags::RDONLY) {
+        Ok(mut f) => {
+            let mut buf = String::new();
+            f.read_to_string(&mut buf)?;
+            assert_eq!(buf, "contents");
+        }
+        Err(e) => assert_eq!(e.kind(), std::io::ErrorKind::PermissionDenied),
+    }
+    Ok(())
+}
+
+#[test]
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn test_write_nofollow() -> Result<()> {
+    let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    td.create_dir("subdir")?;
+    td.write("target", "original")?;
+    td.symlink("target", "link")?;
+    td.symlink("subdir", "linkdir")?;
+
+    td.write_nofollow("somefile", "contents")?;
+    assert_eq!(td.read_to_string("somefile")?, "contents");
+    td.write_nofollow("somefile", "new")?;
+    assert_eq!(td.read_to_string("somefile")?, "new");
+    // Intermediate symlinks are followed
+    td.write_nofollow("linkdir/somefile", "contents")?;
+    assert_eq!(td.read_to_string("subdir/somefile")?, "contents");
+
+    let e = td.write_nofollow("link", "evil").unwrap_err();
+    assert_eq!(e.raw_os_error(), Some(libc::ELOOP));
+    let e = td.atomic_write_nofollow("link", "evil").unwrap_err();
+    assert_eq!(e.raw_os_error(), Some(libc::ELOOP));
+    assert_eq!(td.read_to_string("target")?, "original");
+    assert!(td.symlink_metadata("link")?.is_symlink());
+
+    td.atomic_write_nofollow("somefile", "atomic")?;
+    assert_eq!(td.read_to_string("somefile")?, "atomic");
+    td.atomic_write_nofollow("newfile", "atomic")?;
+    assert_eq!(td.read_to_string("newfile")?, "atomic");
+    Ok(())
+}
+
+#[test]
+fn test_file_locks() -> Result<()> {
+    use cap_std_ext::fileext::{CapStdExtFileLockExt, LockMode};
+    let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    td.write("lockfile", "")?;
+    let mut opts = cap_std::fs::OpenOptions::new();
+    opts.read(true).write(true);
+    let a = td.open_with("lockfile", &opts)?;
+    let b = td.open_with("lockfile", &opts)?;
+
+    let g = a.lock_exclusive()?;
+    assert_eq!(g.mode(), LockMode::Exclusive);
+    assert!(b.try_lock_shared()?.is_none());
+    assert!(b.try_lock_exclusive()?.is_none());
+    drop(g);
+
+    let g1 = a.lock_shared()?;
+    let g2 = b.try_lock_shared()?.unwrap();
+    assert_eq!(g2.mode(), LockMode::Shared);
+    assert!(b.try_lock_exclusive()?.is_none());
+    g1.unlock()?;
+    drop(g2);
+    let g = b.try_lock_exclusive()?.unwrap();
+
+    // Blocking acquisition waits for the holder to release the lock
+    std::thread::scope(|s| {
+        let h = s.spawn(|| a.lock_exclusive().map(|g| g.mode()));
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        assert!(!h.is_finished());
+        drop(g);
+        assert_eq!(h.join().unwrap().unwrap(), LockMode::Exclusive);
+    });
+
+    // Timed acquisition gives up while the lock is held, and succeeds once released
+    let g = a.lock_exclusive()?;
+    let timeout = std::time::Duration::from_millis(50);
+    let start = std::time::Instant::now();
+    assert!(b.lock_shared_timeout(timeout)?.is_none());
+    assert!(start.elapsed() >= timeout);
+    assert!(td
+        .lockfile_timeout("lockfile", LockMode::Exclusive, timeout)?
+        .is_none());
+    std::thread::scope(|s| {
+        let h = s.spawn(|| {
+            b.lock_exclusive_timeout(std::time::Duration::from_secs(10))
+                .map(|g| g.map(|g| g.mode()))
+        });
+        std::thread::sleep(timeout);
+        drop(g);
+        assert_eq!(h.join().unwrap().unwrap(), Some(LockMode::Exclusive));
+    });
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn test_sticky_safe_creation() -> Result<()> {
+    use cap_std::fs::PermissionsExt;
+    use std::io::{Read, Seek};
+
+    let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    td.set_permissions(".", Permissions::from_mode(0o1777))?;
+
+    let mut f = td.create_or_open_owned("somefile")?;
+    assert_eq!(f.metadata()?.permissions().mode() & 0o777, 0o600);
+    f.write_all(b"contents")?;
+    let mut f = td.create_or_open_owned("somefile")?;
+    let mut buf = String::new();
+    f.read_to_string(&mut buf)?;
+    assert_eq!(buf, "contents");
+    f.rewind()?;
+
+    fn denied<T>(r: std::io::Result<T>) {
+        let e = r.err().unwrap();
+        assert_eq!(e.kind(), std::io::ErrorKind::PermissionDenied);
+    }
+    td.symlink("somefile", "link")?;
+    denied(td.create_or_open_owned("link"));
+    td.hard_link("somefile", td, "hardlink")?;
+    denied(td.create_or_open_owned("somefile"));
+    td.write("shared", "")?;
+    td.set_permissions("shared", Permissions::from_mode(0o666))?;
+    denied(td.create_or_open_owned("shared"));
+    td.create_dir("dir")?;
+    denied(td.create_or_open_owned("dir"));
+
+    let d = td.ensure_owned_dir("private")?;
+    d.write("foo", "bar")?;
+    assert_eq!(td.metadata("private")?.permissions().mode() & 0o777, 0o700);
+    assert!(td.ensure_owned_dir("private")?.try_exists("foo")?);
+    td.symlink("private", "linkdir")?;
+    denied(td.ensure_owned_dir("linkdir"));
+    denied(td.ensure_owned_dir("shared"));
+    td.set_permissions("dir", Permissions::from_mode(0o777))?;
+    denied(td.ensure_owned_dir("dir"));
+    Ok(())
+}
+
+#[test]
+fn test_find_executable() -> Result<()> {
+    use cap_std::fs::PermissionsExt;
+    let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    td.create_dir_all("usr/bin")?;
+    td.create_dir_all("usr/sbin")?;
+    td.symlink("usr/bin", "bin")?;
+    let exec = Permissions::from_mode(0o755);
+    td.write("usr/bin/script", "#!/bin/sh\ntrue\n")?;
+    td.set_permissions("usr/bin/script", exec.clone())?;
+    td.write("usr/sbin/elf", b"\x7fELF\x02\x01")?;
+    td.set_permissions("usr/sbin/elf", exec.clone())?;
+    td.write("usr/bin/notexec", "#!/bin/sh\n")?;
+    td.write("usr/sbin/garbage", "not a program")?;
+    td.set_permissions("usr/sbin/garbage", exec.clone())?;
+    td.write("usr/bin/garbage", "#!/bin/sh\n")?;
+    td.set_permissions("usr/bin/garbage", exec.clone())?;
+    td.symlink_contents("/usr/sbin/elf", "usr/bin/elflink")?;
+    td.create_dir("usr/bin/somedir")?;
+    rustix::fs::mknodat(
+        &**td,
+        "usr/bin/fifo",
+        rustix::fs::FileType::Fifo,
+        rustix::fs::Mode::from_raw_mode(0o755),
+        0,
+    )?;
+
+    let rootfs = RootDir::new(td, ".")?;
+    let search = ["/usr/sbin", "/bin"];
+    let find = |name| rootfs.find_executable(name, search);
+    assert_eq!(find("script")?.unwrap(), Path::new("/bin/script"));
+    assert_eq!(find("elf")?.unwrap(), Path::new("/usr/sbin/elf"));
+    // Absolute symlinks are resolved in the root
+    assert_eq!(find("elflink")?.unwrap(), Path::new("/bin/elflink"));
+    // The invalid one in sbin is skipped
+    assert_eq!(find("garbage")?.unwrap(), Path::new("/bin/garbage"));
+    for name in ["notexec", "somedir", "fifo", "nosuchfile"] {
+        assert!(find(name)?.is_none(), "{name}");
+    }
+    assert_eq!(find("/usr/sbin/elf")?.unwrap(), Path::new("/usr/sbin/elf"));
+    assert!(find("/usr/sbin/garbage")?.is_none());
+
+    // The same, but relative to a plain directory
+    let search = ["usr/sbin", "bin"];
+    let find = |name| td.find_executable(name, search);
+    assert_eq!(find("script")?.unwrap(), Path::new("bin/script"));
+    assert_eq!(find("elf")?.unwrap(), Path::new("usr/sbin/elf"));
+    assert_eq!(find("garbage")?.unwrap(), Path::new("bin/garbage"));
+    for name in ["notexec", "somedir", "fifo", "nosuchfile"] {
+        assert!(find(name)?.is_none(), "{name}");
+    }
+    assert_eq!(find("usr/sbin/elf")?.unwrap(), Path::new("usr/sbin/elf"));
+    // Absolute symlinks may not escape the directory
+    assert!(find("elflink").is_err());
+    Ok(())
+}
+
+#[test]
+fn test_lockfile() -> Result<()> {
+    use cap_std_ext::fileext::LockMode;
+    let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    td.create_dir("run")?;
+
+    let mut l = td.lockfile("run/lock", LockMode::Exclusive)?;
+    assert_eq!(l.mode(), LockMode::Exclusive);
+    l.write_pid()?;
+    assert_eq!(
+        td.read_to_string("run/lock")?,
+        format!("{}\n", std::process::id())
+    );
+    assert!(td.try_lockfile("run/lock", LockMode::Shared)?.is_none());
+    drop(l);
+    // Cleaned up on release
+    assert!(!td.try_exists("run/lock")?);
+
+    let s1 = td.lockfile("run/lock", LockMode::Shared)?;
+    let s2 = td.try_lockfile("run/lock", LockMode::Shared)?.unwrap();
+    assert!(td.try_lockfile("run/lock", LockMode::Exclusive)?.is_none());
+    // Shared holders never remove the file, and keep their lock
+    drop(s1);
+    assert!(td.try_exists("run/lock")?);
+    assert!(td.try_lockfile("run/lock", LockMode::Exclusive)?.is_none());
+
+    drop(s2);
+
+    // A waiter which opened the file before it was removed retries on the new one
+    let l = td.lockfile("run/lock", LockMode::Exclusive)?;
+    std::thread::scope(|s| -> Result<()> {
+        let h = s.spawn(|| td.lockfile("run/lock", LockMode::Exclusive));
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        drop(l);
+        let l = h.join().unwrap()?;
+        assert!(td.try_exists("run/lock")?);
+        assert!(td.try_lockfile("run/lock", LockMode::Shared)?.is_none());
+        drop(l);
+        Ok(())
+    })?;
+    assert!(!td.try_exists("run/lock")?);
+
+    // A file left by a shared holder is removed by the next exclusive holder
+    drop(td.lockfile("run/lock", LockMode::Shared)?);
+    assert!(td.try_exists("run/lock")?);
+    drop(td.lockfile("run/lock", LockMode::Exclusive)?);
+    assert!(!td.try_exists("run/lock")?);
+    Ok(())
+}
+
+#[test]
+fn test_rewrite_shebangs() -> Result<()> {
+    use cap_std::fs::PermissionsExt;
+    use std::collections::HashMap;
+    let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    td.create_dir_all("root/usr/bin")?;
+    td.create_dir_all("root/usr/lib/foo")?;
+    td.write("root/usr/bin/a", "#!/opt/py/bin/python3 -s\nprint('a')\n")?;
+    td.set_permissions("root/usr/bin/a", Permissions::from_mode(0o750))?;
+    td.write("root/usr/lib/foo/b", "#! /bin/sh\necho b\n")?;
+    td.write("root/usr/lib/foo/c", "#!/bin/bash\necho c\n")?;
+    td.write("root/usr/lib/foo/d", "echo #!/bin/sh\n")?;
+    td.write("root/usr/lib/foo/e", "")?;
+    td.symlink("a", "root/usr/bin/link")?;
+
+    let mapping = HashMap::from([
+        ("/opt/py/bin/python3".into(), "/usr/bin/python3".into()),
+        ("/bin/sh".into(), "/usr/bin/sh".into()),
+    ]);
+    let mut r = td.rewrite_shebangs("root", &mapping)?;
+    r.sort();
+    assert_eq!(
+        r,
+        [
+            PathBuf::from("root/usr/bin/a"),
+            PathBuf::from("root/usr/lib/foo/b")
+        ]
+    );
+    assert_eq!(
+        td.read_to_string("root/usr/bin/a")?,
+        "#!/usr/bin/python3 -s\nprint('a')\n"
+    );
+    assert_eq!(
+        td.metadata("root/usr/bin/a")?.permissions().mode() & 0o777,
+        0o750
+    );
+    assert_eq!(
+        td.read_to_string("root/usr/lib/foo/b")?,
+        "#! /usr/bin/sh\necho b\n"
+    );
+    assert_eq!(
+        td.read_to_string("root/usr/lib/foo/c")?,
+        "#!/bin/bash\necho c\n"
+    );
+    assert!(td.symlink_metadata("root/usr/bin/link")?.is_symlink());
+    // Idempotent
+    assert!(td.rewrite_shebangs("root", &mapping)?.is_empty());
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "watch")]
+fn test_watch() -> Result<()> {
+    use cap_std_ext::watch::{WatchConfig, WatchEvent, WatchEventKind};
+    let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    td.create_dir_all("root/a")?;
+    let root = td.open_dir("root")?;
+    let mut w = root.watch(&WatchConfig::default().recursive())?;
+    // Read events until we have seen the expected one
+    let mut wait_for = |path: &str, kind| -> Result<Vec<WatchEvent>> {
+        let mut seen = Vec::new();
+        loop {
+            let events = w.read_events()?;
+            let found = events
+                .iter()
+                .any(|e| e.path == Path::new(path) && e.kind == kind);
+            seen.extend(events);
+            if found {
+                return Ok(seen);
+            }
+        }
+    };
+
+    root.write("a/somefile", "")?;
+    wait_for("a/somefile", WatchEventKind::Created)?;
+    // New directories are watched too
+    root.create_dir("b")?;
+    let events = wait_for("b", WatchEventKind::Created)?;
+    assert!(events.iter().any(|e| e.is_dir));
+    root.write("b/other", "")?;
+    wait_for("b/other", WatchEventKind::Created)?;
+    // Moving a directory away stops watching it
+    td.rename("root/b", td, "b-moved")?;
+    wait_for("b", WatchEventKind::MovedFrom)?;
+    td.write("b-moved/outside", "")?;
+    root.remove_file("a/somefile")?;
+    let events = wait_for("a/somefile", WatchEventKind::Removed)?;
+    assert!(events.iter().all(|e| !e.path.ends_with("outside")));
+    // And moving it back watches it again under the new name
+    td.rename("b-moved", &root, "c")?;
+    wait_for("c", WatchEventKind::MovedTo)?;
+    root.write("c/new", "")?;
+    wait_for("c/new", WatchEventKind::Created)?;
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "watch")]
+fn test_watch_debounce() -> Result<()> {
+    use cap_std_ext::watch::{WatchConfig, WatchEventKind};
+    use std::time::Duration;
+    let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    td.write("existing", "")?;
+    td.write("replaced", "")?;
+    let mut w = td
+        .watch(&WatchConfig::default())?
+        .debounce(Duration::from_millis(100));
+
+    td.write("new", "contents")?;
+    td.write("new", "more contents")?;
+    td.atomic_write("replaced", "new contents")?;
+    td.write("existing", "changed")?;
+    td.write("transient", "")?;
+    td.remove_file("transient")?;
+    let events = w.read_events()?;
+    let kinds = events
+        .iter()
+        .map(|e| (e.path.to_str().unwrap(), e.kind))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        kinds,
+        [
+            ("new", WatchEventKind::Created),
+            ("replaced", WatchEventKind::MovedTo),
+            ("existing", WatchEventKind::Modified),
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+fn test_link_tree() -> Result<()> {
+    use cap_std_ext::dirext::{link_tree, LinkConflict, LinkOptions};
+    let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    td.create_dir_all("usr/share/foo/conf.d")?;
+    td.write("usr/share/foo/main.conf", "")?;
+    td.write("usr/share/foo/conf.d/a.conf", "")?;
+    td.symlink("a.conf", "usr/share/foo/conf.d/b.conf")?;
+    td.create_dir_all("etc/foo/conf.d")?;
+    td.write("etc/foo/conf.d/local.conf", "")?;
+    let src = &td.open_dir("usr/share/foo")?;
+    let dest = &td.open_dir("etc/foo")?;
+
+    let opts = LinkOptions::relative("/usr/share/foo", "/etc/foo");
+    link_tree(src, dest, &opts)?;
+    assert_eq!(
+        dest.read_link_contents("main.conf")?,
+        Path::new("../../usr/share/foo/main.conf")
+    );
+    assert_eq!(
+        dest.read_link_contents("conf.d/a.conf")?,
+        Path::new("../../../usr/share/foo/conf.d/a.conf")
+    );
+    assert!(dest.symlink_metadata("conf.d/b.conf")?.is_symlink());
+    // Resolution works from the root
+    assert!(td.metadata("etc/foo/conf.d/a.conf")?.is_file());
+    assert!(dest.symlink_metadata("conf.d/local.conf")?.is_file());
+    // Idempotent
+    link_tree(src, dest, &opts)?;
+
+    // Conflicts
+    let dest2 = &td.open_dir("etc")?;
+    dest2.create_dir("bar")?;
+    let dest2 = &dest2.open_dir("bar")?;
+    dest2.write("main.conf", "local")?;
+    let opts = LinkOptions::absolute("/usr/share/foo");
+    let e = link_tree(src, dest2, &opts).unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::AlreadyExists);
+    link_tree(src, dest2, &opts.clone().conflict(LinkConflict::Skip))?;
+    assert_eq!(dest2.read_to_string("main.conf")?, "local");
+    assert_eq!(
+        dest2.read_link_contents("conf.d/a.conf")?,
+        Path::new("/usr/share/foo/conf.d/a.conf")
+    );
+    link_tree(src, dest2, &opts.clone().conflict(LinkConflict::Replace))?;
+    assert_eq!(
+        dest2.read_link_contents("main.conf")?,
+        Path::new("/usr/share/foo/main.conf")
+    );
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "fanotify")]
+fn test_fanotify() -> Result<()> {
+    use cap_std_ext::fanotify::{FanotifyConfig, FanotifyEventKind};
+    let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    td.create_dir("sub")?;
+    let sub = td.open_dir("sub")?;
+    let config = FanotifyConfig::default().event(FanotifyEventKind::CloseWrite);
+    // Permission events must be explicitly enabled
+    assert!(sub
+        .fanotify(&config.clone().event(FanotifyEventKind::OpenPermission))
+        .is_err());
+    let mut w = match sub.fanotify(&config) {
+        Ok(w) => w,
+        // Requires privileges
+        Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    td.write("outside", "")?;
+    sub.write("somefile", "")?;
+    loop {
+        let events = w.read_events()?;
+        // Other events on the mount may show up; ignore them
+        if let Some(e) = events
+            .iter()
+            .find(|e| e.path.as_deref() == Some(Path::new("somefile")))
+        {
+            assert!(e.is(FanotifyEventKind::CloseWrite));
+            assert!(!e.is_permission());
+            assert_eq!(e.pid, std::process::id() as i32);
+            break;
+        }
+        assert!(events
+            .iter()
+            .all(|e| e.path.as_deref() != Some(Path::new("../outside"))));
+    }
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn test_find_broken_links() -> Result<()> {
+    use cap_std_ext::dirext::{BrokenLink, BrokenLinkKind, LinkResolution};
+    let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    td.create_dir_all("usr/bin")?;
+    td.create_dir("etc")?;
+    td.write("usr/bin/foo", "foo")?;
+    td.symlink("foo", "usr/bin/ok")?;
+    td.symlink_contents("/usr/bin/foo", "usr/bin/absolute")?;
+    td.symlink("nosuchfile", "usr/bin/dangling")?;
+    td.symlink("foo/bar", "usr/bin/notdir")?;
+    td.symlink("../../../..", "usr/bin/updir")?;
+    td.symlink("loop", "etc/loop")?;
+    td.write("etc/empty", "")?;
+    td.hard_link("etc/empty", td, "etc/empty2")?;
+
+    let sorted = |mut v: Vec<BrokenLink>| {
+        v.sort_by(|a, b| a.path.cmp(&b.path));
+        v
+    };
+    let link = |path: &str, kind| BrokenLink {
+        path: path.into(),
+        kind,
+    };
+
+    let r = sorted(td.find_broken_links(".", LinkResolution::Beneath)?);
+    assert_eq!(
+        r,
+        [
+            link("etc/empty", BrokenLinkKind::EmptyHardlink),
+            link("etc/empty2", BrokenLinkKind::EmptyHardlink),
+            link("etc/loop", BrokenLinkKind::Looped),
+            link("usr/bin/absolute", BrokenLinkKind::Escaped),
+            link("usr/bin/dangling", BrokenLinkKind::Dangling),
+            link("usr/bin/notdir", BrokenLinkKind::Dangling),
+            link("usr/bin/updir", BrokenLinkKind::Escaped),
+        ]
+    );
+
+    // Absolute links and `..` resolve inside the root
+    let r = sorted(td.find_broken_links("usr", LinkResolution::Rooted)?);
+    assert_eq!(
+        r,
+        [
+            link("usr/bin/dangling", BrokenLinkKind::Dangling),
+            link("usr/bin/notdir", BrokenLinkKind::Dangling),
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn test_rewrite_symlinks() -> Result<()> {
+    use cap_std_ext::dirext::SymlinkStyle;
+    let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    td.create_dir_all("usr/lib/foo")?;
+    td.create_dir("etc")?;
+    td.write("usr/lib/foo/bar", "bar")?;
+    td.symlink_contents("/usr/lib/foo/bar", "etc/bar")?;
+    td.symlink_contents("/", "etc/root")?;
+    td.symlink_contents("/usr/lib/../lib", "etc/complex")?;
+    td.symlink("foo/bar", "usr/lib/bar")?;
+    td.symlink("../../../../usr", "usr/lib/foo/usr")?;
+
+    let rewritten = td.rewrite_symlinks(".", SymlinkStyle::Relative)?;
+    assert_eq!(rewritten.len(), 2);
+    assert_eq!(
+        td.read_link_contents("etc/bar")?,
+        Path::new("../usr/lib/foo/bar")
+    );
+    assert_eq!(td.read_link_contents("etc/root")?, Path::new(".."));
+    assert_eq!(
+        td.read_link_contents("etc/complex")?,
+        Path::new("/usr/lib/../lib")
+    );
+    assert_eq!(td.read_to_string("etc/bar")?, "bar");
+
+    // Only the given subtree is rewritten
+    let rewritten = td.rewrite_symlinks("usr", SymlinkStyle::Absolute)?;
+    assert_eq!(rewritten.len(), 2);
+    assert_eq!(
+        td.read_link_contents("usr/lib/bar")?,
+        Path::new("/usr/lib/foo/bar")
+    );
+    assert_eq!(td.read_link_contents("usr/lib/foo/usr")?, Path::new("/usr"));
+    assert_eq!(
+        td.read_link_contents("etc/bar")?,
+        Path::new("../usr/lib/foo/bar")
+    );
+    // No temporary files are left behind
+    assert_eq!(td.entries()?.count(), 2);
+    assert_eq!(td.read_dir("usr/lib")?.count(), 2);
+
+    Ok(())
+}
+
+#[test]
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn test_fsverity() -> Result<()> {
+    use cap_std_ext::dirext::{VerityHashAlgorithm, VerityOptions};
+    let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    td.write("somefile", "some contents")?;
+    td.symlink("somefile", "link")?;
+    assert!(!td.is_verity("somefile")?);
+    assert!(!td.is_verity("link")?);
+    td.create_dir("somedir")?;
+    assert!(!td.is_verity("somedir")?);
+
+    let options = VerityOptions::default().algorithm(VerityHashAlgorithm::Sha512);
+    match td.fsverity_enable("somefile", &options) {
+        Ok(()) => {}
+        // Many filesystems (e.g. tmpfs) don't support fs-verity
+        Err(e) if e.kind() == std::io::ErrorKind::Unsupported => return Ok(()),
+        Err(e) => return Err(e.into()),
+    }
+    assert!(td.is_verity("somefile")?);
+    let digest = td.fsverity_digest("somefile")?.unwrap();
+    assert_eq!(digest.algorithm, VerityHashAlgorithm::Sha512);
+    assert_eq!(digest.digest.len(), 64);
+    // The file is now read-only
+    assert!(td.write("somefile", "new contents").is_err());
+    Ok(())
+}
+
+#[test]
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn test_inode_flags() -> Result<()> {
+    use cap_std_ext::dirext::InodeFlags;
+    let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    td.write("somefile", "some contents")?;
+    let flags = match td.get_inode_flags("somefile") {
+        Ok(f) => f,
+        // e.g. tmpfs on older kernels
+        Err(e) if e.kind() == std::io::ErrorKind::Unsupported => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    assert!(!flags.contains(InodeFlags::NODUMP));
+    td.set_inode_flags("somefile", flags | InodeFlags::NODUMP)?;
+    assert_eq!(td.get_inode_flags("somefile")?, flags | InodeFlags::NODUMP);
+    td.set_inode_flags("somefile", flags)?;
+    assert_eq!(td.get_inode_flags("somefile")?, flags);
+    // Directories work too
+    td.create_dir("somedir")?;
+    td.get_inode_flags("somedir")?;
+    // Paths may not escape
+    assert!(td.get_inode_flags("../somefile").is_err());
+    Ok(())
+}
+
+#[test]
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn test_atomic_replace_dir() -> Result<()> {
+    let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    td.create_dir("sub")?;
+
+    // Creating a new directory
+    let r = td.atomic_replace_dir_with("sub/tree", |d| -> std::io::Result<_> {
+        d.write("a", "a")?;
+        d.create_dir("subdir")?;
+        Ok(42)
+    })?;
+    assert_eq!(r, 42);
+    assert_eq!(td.read_to_string("sub/tree/a")?, "a");
+
+    // Replacing it
+    let held = td.open_dir("sub/tree")?;
+    td.atomic_replace_dir_with("sub/tree", |d| d.write("b", "b"))?;
+    assert!(!td.try_exists("sub/tree/a")?);
+    assert_eq!(td.read_to_string("sub/tree/b")?, "b");
+    // The old tree was removed
+    assert!(!held.try_exists("a")?);
+    assert_eq!(td.read_dir("sub")?.count(), 1);
+
+    // Errors leave the destination untouched
+    let r = td.atomic_replace_dir_with("sub/tree", |d| -> std::io::Result<()> {
+        d.write("c", "c")?;
+        Err(std::io::Error::new(std::io::ErrorKind::Other, "oops"))
+    });
+    assert!(r.is_err());
+    assert_eq!(td.read_to_string("sub/tree/b")?, "b");
+    assert!(!td.try_exists("sub/tree/c")?);
+    assert_eq!(td.read_dir("sub")?.count(), 1);
+
+    // Non-directories are not replaced
+    td.write("sub/file", "file")?;
+    td.symlink("tree", "sub/link")?;
+    for p in ["sub/file", "sub/link"] {
+        let e = td
+            .atomic_replace_dir_with(p, |d| d.write("d", "d"))
+            .unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::ENOTDIR), "{p}");
+    }
+    assert_eq!(td.read_to_string("sub/file")?, "file");
+    assert_eq!(td.read_link("sub/link")?, Path::new("tree"));
+    assert_eq!(td.read_dir("sub")?.count(), 3);
+    Ok(())
+}
+
+#[test]
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn test_project_id() -> Result<()> {
+    let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    td.create_dir_all("quota/sub")?;
+    td.write("quota/sub/somefile", "")?;
+    td.symlink("somefile", "quota/sub/link")?;
+    let orig = match td.get_project_id("quota") {
+        Ok(id) => id,
+        Err(e) if e.kind() == std::io::ErrorKind::Unsupported => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    let id = orig + 42;
+    match td.set_project_id("quota", id, true) {
+        Ok(()) => {}
+        // Requires privileges, and filesystem support for project quotas
+        Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => return Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::Unsupported => return Ok(()),
+        Err(e) => return Err(e.into()),
+    }
+    assert_eq!(td.get_project_id("quota")?, id);
+    assert_eq!(td.get_project_id("quota/sub/somefile")?, id);
+    // New files inherit the ID
+    td.write("quota/sub/newfile", "")?;
+    assert_eq!(td.get_project_id("quota/sub/newfile")?, id);
+
+    td.set_project_id("quota/sub", orig, false)?;
+    assert_eq!(td.get_project_id("quota/sub")?, orig);
+    assert_eq!(td.get_project_id("quota/sub/somefile")?, id);
+    Ok(())
+}
+
+#[test]
+fn test_resolve_alternatives() -> Result<()> {
+    let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    td.create_dir_all("etc/alternatives")?;
+    td.create_dir_all("usr/bin")?;
+    td.write("usr/bin/vim.basic", "")?;
+    td.symlink_contents("/usr/bin/vim.basic", "etc/alternatives/editor")?;
+    td.symlink_contents("/usr/bin/nano", "etc/alternatives/pager")?;
+    td.write("etc/alternatives/README", "")?;
+    td.symlink_contents("/etc/alternatives/editor", "usr/bin/editor")?;
+
+    let r = td.resolve_alternatives([
+        "usr/bin/editor",
+        "etc/alternatives/pager",
+        "etc/alternatives/README",
+        "etc/alternatives/missing",
+    ])?;
+    assert_eq!(r.len(), 4);
+    assert_eq!(
+        r[0].target.as_deref(),
+        Some(Path::new("/etc/alternatives/editor"))
+    );
+    assert_eq!(
+        r[0].resolved.as_deref(),
+        Some(Path::new("usr/bin/vim.basic"))
+    );
+    assert_eq!(r[1].target.as_deref(), Some(Path::new("/usr/bin/nano")));
+    assert_eq!(r[1].resolved, None);
+    for e in &r[2..] {
+        assert_eq!(e.target, None);
+        assert_eq!(e.resolved, None);
+    }
+    assert_eq!(r[3].path, Path::new("etc/alternatives/missing"));
+    Ok(())
+}
+
+#[test]
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn test_filecaps() -> Result<()> {
+    use cap_std_ext::dirext::FileCaps;
+    const CAP_NET_BIND_SERVICE: u64 = 1 << 10;
+    const CAP_MAC_ADMIN: u64 = 1 << 33;
+
+    // Round trips, including capabilities in the upper word
+    let caps = FileCaps {
+        permitted: CAP_NET_BIND_SERVICE | CAP_MAC_ADMIN,
+        inheritable: CAP_NET_BIND_SERVICE,
+        effective: true,
+        rootid: None,
+    };
+    let buf = caps.encode();
+    assert_eq!(buf.len(), 20);
+    assert_eq!(&buf[..4], &[1, 0, 0, 2]);
+    assert_eq!(FileCaps::decode(&buf)?, caps);
+    let v3 = FileCaps {
+        rootid: Some(100000),
+        ..caps
+    };
+    assert_eq!(v3.encode().len(), 24);
+    assert_eq!(FileCaps::decode(&v3.encode())?, v3);
+    // Version 1
+    let v1 = [0, 0, 0, 1, 0, 4, 0, 0, 0, 0, 0, 0];
+    assert_eq!(
+        FileCaps::decode(&v1)?,
+        FileCaps {
+            permitted: CAP_NET_BIND_SERVICE,
+            ..Default::default()
+        }
+    );
+    assert!(FileCaps::decode(&buf[..16]).is_err());
+    assert!(FileCaps::decode(&[0; 20]).is_err());
+
+    let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    td.write("somebinary", "")?;
+    assert_eq!(td.get_filecaps("somebinary")?, None);
+    match td.set_filecaps("somebinary", &caps) {
+        Ok(()) => {}
+        // Requires CAP_SETFCAP
+        Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => return Ok(()),
+        Err(e) => return Err(e.into()),
+    }
+    assert_eq!(td.get_filecaps("somebinary")?, Some(caps));
+    Ok(())
+}
+
+#[test]
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn test_atomic_write_scratch_dir() -> Result<()> {
+    let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    td.create_dir("watched")?;
+    td.create_dir("scratch")?;
+    td.write("watched/somefile", "old")?;
+    td.set_permissions("watched/somefile", Permissions::from_mode(0o640))?;
+    let watched = td.open_dir("watched")?;
+    #[cfg(feature = "watch")]
+    let mut w = watched.watch(&cap_std_ext::watch::WatchConfig::default())?;
+    let options = AtomicWriteOptions::default().scratch_dir(td.open_dir("scratch")?);
+    watched.atomic_replace_with_options("somefile", &options, |f| f.write_all(b"new"))?;
+    assert_eq!(watched.read_to_string("somefile")?, "new");
+    let mode = watched.metadata("somefile")?.permissions().mode();
+    assert_eq!(mode & 0o777, 0o640);
+    assert_eq!(td.read_dir("scratch")?.count(), 0);
+    assert_eq!(watched.entries()?.count(), 1);
+    // No temporary file appeared in the destination directory; the only
+    // event is the file being moved into place
+    #[cfg(feature = "watch")]
+    {
+        let events = w.read_events()?;
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].kind, cap_std_ext::watch::WatchEventKind::MovedTo);
+    }
+
+    // The scratch directory must be on the same filesystem
+    if let Ok(shm) = Dir::open_ambient_dir("/dev/shm", cap_std::ambient_authority()) {
+        use cap_std::fs::MetadataExt;
+        let other = cap_tempfile::TempDir::new_in(&shm)?;
+        if other.dir_metadata()?.dev() != watched.dir_metadata()?.dev() {
+            let options = AtomicWriteOptions::default().scratch_dir(other.try_clone()?);
+            let err = watched
+                .atomic_replace_with_options("somefile", &options, |f| f.write_all(b"x"))
+                .unwrap_err();
+            assert_eq!(err.raw_os_error(), Some(libc::EXDEV));
+        }
+    }
+    Ok(())
+}
+
+#[test]
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn test_xattrs() -> Result<()> {
+    use cap_std_ext::dirext::XattrFilter;
+    use std::ffi::OsStr;
+
+    let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    td.write("somefile", "")?;
+    match td.setxattr("somefile", "user.foo", "bar") {
+        Ok(()) => {}
+        // Not all filesystems support user xattrs
+        Err(e) if e.kind() == std::io::ErrorKind::Unsupported => return Ok(()),
+        Err(e) => return Err(e.into()),
+    }
+    td.setxattr("somefile", "user.empty", "")?;
+    assert_eq!(
+        td.getxattr("somefile", "user.foo")?.as_deref(),
+        Some(b"bar".as_slice())
+    );
+    assert_eq!(
+        td.getxattr("somefile", "user.empty")?.as_deref(),
+        Some(b"".as_slice())
+    );
+    assert_eq!(td.getxattr("somefile", "user.nosuch")?, None);
+    let names = td.listxattrs("somefile")?;
+    assert!(names.iter().any(|n| n == "user.foo"));
+    assert!(names.iter().any(|n| n == "user.empty"));
+    let all = td.xattrs("somefile")?;
+    assert!(all.contains(&("user.foo".into(), b"bar".to_vec())));
+    assert!(all.contains(&("user.empty".into(), Vec::new())));
+
+    td.write("copy", "")?;
+    let filter = XattrFilter::default().exclude("user.empty");
+    td.copy_xattrs("somefile", td, "copy", &filter)?;
+    assert_eq!(
+        td.getxattr("copy", "user.foo")?.as_deref(),
+        Some(b"bar".as_slice())
+    );
+    assert_eq!(td.getxattr("copy", "user.empty")?, None);
+    td.copy_xattrs("somefile", td, "copy", &XattrFilter::default())?;
+    assert_eq!(
+        td.getxattr("copy", "user.empty")?.as_deref(),
+        Some(b"".as_slice())
+    );
+    let filter = XattrFilter::default().exclude_prefix("user.");
+    assert!(!filter.matches(OsStr::new("user.foo")));
+    assert!(filter.matches(OsStr::new("security.selinux")));
+
+    // A symbolic link in the final component is not followed
+    td.symlink("somefile", "link")?;
+    assert_eq!(td.getxattr("link", "user.foo")?, None);
+    assert!(!td.listxattrs("link")?.iter().any(|n| n == "user.foo"));
+    assert!(td.setxattr("link", "user.other", "x").is_err());
+    assert!(!td.removexattr("link", "user.foo").unwrap_or(false));
+    td.copy_xattrs("link", td, "copy", &XattrFilter::default())?;
+    assert_eq!(
+        td.getxattr("copy", "user.foo")?.as_deref(),
+        Some(b"bar".as_slice())
+    );
+    assert_eq!(td.getxattr("somefile", "user.other")?, None);
+
+    assert!(td.removexattr("somefile", "user.foo")?);
+    assert!(!td.removexattr("somefile", "user.foo")?);
+    assert_eq!(td.getxattr("somefile", "user.foo")?, None);
+    // Unreadable files work too
+    td.set_permissions("somefile", Permissions::from_mode(0o000))?;
+    assert!(td.removexattr("somefile", "user.empty")?);
+    assert!(td.getxattr("../somefile", "user.foo").is_err());
+    Ok(())
+}
+
+#[test]
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn test_audit_hook() -> Result<()> {
+    use cap_std_ext::audit::{set_hook, AmbientAccess};
+    use std::cell::Cell;
+
+    // The hook is process-wide, so only accesses from this thread are counted
+    // (and denied).
+    thread_local! {
+        static SEEN: Cell<usize> = const { Cell::new(0) };
+        static SEEN_PATHS: Cell<usize> = const { Cell::new(0) };
+        static SEEN_EXECS: Cell<usize> = const { Cell::new(0) };
+        static DENY: Cell<bool> = const { Cell::new(false) };
+    }
+    let incr = |c: &Cell<usize>| c.set(c.get() + 1);
+    set_hook(move |access| {
+        match access {
+            AmbientAccess::ProcSelfFd(_) => SEEN.with(incr),
+            AmbientAccess::Path(p) if p.starts_with("/proc/self") => SEEN_PATHS.with(incr),
+            AmbientAccess::Exec(_) => SEEN_EXECS.with(incr),
+            _ => {}
+        }
+        if DENY.with(Cell::get) {
+            return Err(std::io::Error::from(std::io::ErrorKind::PermissionDenied));
+        }
+        Ok(())
+    })?;
+    assert_eq!(
+        set_hook(|_| Ok(())).unwrap_err().kind(),
+        std::io::ErrorKind::AlreadyExists
+    );
+
+    let td = cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    // Extended attributes of regular files don't need procfs, but those of
+    // FIFOs do.
+    td.write("foo", "")?;
+    let _ = td.getxattr("foo", "user.foo");
+    assert_eq!(SEEN.with(Cell::get), 0);
+    rustix::fs::mknodat(
+        &*td,
+        "fifo",
+        rustix::fs::FileType::Fifo,
+        rustix::fs::Mode::from(0o644),
+        0,
+    )?;
+    let _ = td.getxattr("fifo", "user.foo");
+    assert_eq!(SEEN.with(Cell::get), 1);
+    // Accesses by child processes are reported when the command is configured
+    Command::new("true").oom_score_adj(0).close_fds_above(2);
+    assert_eq!(SEEN_PATHS.with(Cell::get), 2);
+    let helpers = cap_std_ext::cmdext::IdMapConfig::root_as_current_user().use_helpers();
+    Command::new("true").uid_gid_map(&helpers);
+    assert_eq!(SEEN_PATHS.with(Cell::get), 3);
+    assert_eq!(SEEN_EXECS.with(Cell::get), 2);
+    DENY.with(|d| d.set(true));
+    let e = td.getxattr("fifo", "user.foo").unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::PermissionDenied);
+    assert_eq!(SEEN.with(Cell::get), 2);
+    let e = Command::new("true").oom_score_adj(0).status().unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::PermissionDenied);
+    assert_eq!(SEEN_PATHS.with(Cell::get), 4);
+    let e = Command::new("true")
+        .uid_gid_map(&helpers)
+        .status()
+        .unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::PermissionDenied);
+    assert_eq!(SEEN_EXECS.with(Cell::get), 4);
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn test_interop() -> Result<()> {
+    use cap_std_ext::interop;
+    use std::io::Read;
+    use std::os::fd::OwnedFd;
+
+    let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    td.write("foo", "hello")?;
+    td.create_dir("sub")?;
+
+    let mut f = interop::into_std_file_at(td, "foo", cap_std::fs::OpenOptions::new().read(true))?;
+    let mut buf = String::new();
+    f.read_to_string(&mut buf)?;
+    assert_eq!(buf, "hello");
+
+    let fd: OwnedFd = td.open_dir("sub")?.into_std_file().into();
+    let d = interop::dir_from_fd(fd)?;
+    d.write("bar", "x")?;
+    assert!(td.try_exists("sub/bar")?);
+
+    let fd: OwnedFd = f.into();
+    let e = interop::dir_from_fd(fd).unwrap_err();
+    assert_eq!(e.raw_os_error(), Some(libc::ENOTDIR));
+
+    #[cfg(feature = "tokio")]
+    {
+        let f = interop::into_tokio_file_at(td, "foo", cap_std::fs::OpenOptions::new().read(true))?;
+        drop(f);
+    }
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn test_find() -> Result<()> {
+    use cap_std_ext::query::FileKind;
+    use std::time::Duration;
+
+    let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    td.create_dir_all("logs/old")?;
+    td.write("logs/a.log", "x".repeat(2048))?;
+    td.write("logs/b.log", "x")?;
+    td.write("logs/old/c.log", "x".repeat(4096))?;
+    td.write("logs/old/c.txt", "x".repeat(4096))?;
+    td.symlink("a.log", "logs/link.log")?;
+    let past = rustix::fs::Timespec {
+        tv_sec: 1000,
+        tv_nsec: 0,
+    };
+    let times = rustix::fs::Timestamps {
+        last_access: past,
+        last_modification: past,
+    };
+    rustix::fs::utimensat(
+        &**td,
+        "logs/old/c.log",
+        &times,
+        rustix::fs::AtFlags::empty(),
+    )?;
+
+    let paths = |q: cap_std_ext::query::Query| -> Result<Vec<PathBuf>> {
+        Ok(q.run()?.into_iter().map(|m| m.path).collect())
+    };
+    assert_eq!(
+        paths(td.find().name_matches("*.log"))?,
+        [
+            "logs/a.log",
+            "logs/b.log",
+            "logs/link.log",
+            "logs/old/c.log"
+        ]
+        .map(PathBuf::from)
+    );
+    assert_eq!(
+        paths(td.find().file_type(FileKind::File).name_matches("*.log"))?,
+        ["logs/a.log", "logs/b.log", "logs/old/c.log"].map(PathBuf::from)
+    );
+    assert_eq!(
+        paths(
+            td.find()
+                .file_type(FileKind::File)
+                .min_size(1024)
+                .max_size(3000)
+        )?,
+        ["logs/a.log"].map(PathBuf::from)
+    );
+    assert_eq!(
+        paths(
+            td.find()
+                .name_matches("c.*")
+                .modified_within(Duration::from_secs(3600))
+        )?,
+        ["logs/old/c.txt"].map(PathBuf::from)
+    );
+    assert_eq!(
+        paths(td.find().file_type(FileKind::Dir))?,
+        ["logs", "logs/old"].map(PathBuf::from)
+    );
+    let m = td.find().file_type(FileKind::Symlink).run()?;
+    assert_eq!(m.len(), 1);
+    assert!(m[0].file_type.is_symlink());
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn test_transaction() -> Result<()> {
+    let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    td.write("config", "old")?;
+    td.write("obsolete", "x")?;
+    td.create_dir_all("olddir/sub")?;
+    td.write("olddir/sub/f", "x")?;
+    td.write("from", "moved")?;
+    let initial = td.entries()?.count();
+
+    // Nothing is visible until committed, and aborting discards everything
+    let mut t = td.transaction()?;
+    t.write("config", "new")?;
+    t.remove("obsolete");
+    assert_eq!(td.read_to_string("config")?, "old");
+    assert_eq!(td.entries()?.count(), initial + 1);
+    t.abort()?;
+    assert_eq!(td.read_to_string("config")?, "old");
+    assert_eq!(td.entries()?.count(), initial);
+
+    // The mode and owner of replaced files are kept
+    td.set_permissions("config", Permissions::from_mode(0o600))?;
+    let is_root = rustix::process::geteuid().is_root();
+    if is_root {
+        let st = Command::new("chown")
+            .args(["1234:1234", "config"])
+            .cwd_dir(td.try_clone()?)
+            .status()?;
+        assert!(st.success());
+    }
+
+    let mut t = td.transaction()?;
+    t.write("config", "new")?;
+    t.create_dir("conf.d");
+    t.write("conf.d/a.conf", "a")?;
+    t.remove("obsolete");
+    t.remove("olddir");
+    t.rename("from", "conf.d/to");
+    t.commit()?;
+    assert_eq!(td.read_to_string("config")?, "new");
+    let m = td.metadata("config")?;
+    assert_eq!(m.permissions().mode() & 0o777, 0o600);
+    if is_root {
+        use cap_std::fs::MetadataExt;
+        assert_eq!((m.uid(), m.gid()), (1234, 1234));
+    }
+    assert_eq!(td.read_to_string("conf.d/a.conf")?, "a");
+    assert_eq!(td.read_to_string("conf.d/to")?, "moved");
+    assert!(!td.try_exists("obsolete")?);
+    assert!(!td.try_exists("olddir")?);
+    assert!(!td.try_exists("from")?);
+    let mut names = td
+        .entries()?
+        .map(|e| e.map(|e| e.file_name()))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    names.sort();
+    assert_eq!(names, ["conf.d", "config"]);
+
+    // Errors are reported, and changes applied before them are kept
+    let mut t = td.transaction()?;
+    t.remove("config");
+    t.remove("nonexistent");
+    assert!(t.commit().is_err());
+    assert!(!td.try_exists("config")?);
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn test_journal_recover() -> Result<()> {
+    use cap_std_ext::journal::{Journal, Recovery, JOURNAL_NAME, STAGING_NAME};
+
+    let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    let reset = || -> Result<()> {
+        for p in ["a", "b", "c", "d"] {
+            td.remove_all_optional(p)?;
+        }
+        td.write("a", "old a")?;
+        td.write("b", "old b")?;
+        td.create_dir_all("d/e")?;
+        Ok(())
+    };
+    let stage = |j: &mut Journal| -> std::io::Result<()> {
+        j.write("a", "new a")?;
+        j.rename("b", "c");
+        j.write("b", "new b")?;
+        j.remove("d/e");
+        Ok(())
+    };
+    let check = || -> Result<()> {
+        assert_eq!(td.read_to_string("a")?, "new a");
+        assert_eq!(td.read_to_string("b")?, "new b");
+        assert_eq!(td.read_to_string("c")?, "old b");
+        assert!(!td.try_exists("d/e")?);
+        assert!(!td.try_exists(JOURNAL_NAME)?);
+        assert!(!td.try_exists(STAGING_NAME)?);
+        Ok(())
+    };
+    reset()?;
+    assert_eq!(Journal::recover(td)?, Recovery::Clean);
+
+    // A crash before committing rolls back
+    let mut j = Journal::begin(td)?;
+    stage(&mut j)?;
+    std::mem::forget(j);
+    assert_eq!(
+        Journal::begin(td).unwrap_err().kind(),
+        std::io::ErrorKind::AlreadyExists
+    );
+    assert_eq!(Journal::recover(td)?, Recovery::RolledBack);
+    assert_eq!(td.read_to_string("a")?, "old a");
+    assert!(!td.try_exists(STAGING_NAME)?);
+
+    // A commit interrupted at a step (here by an obstacle making it fail) rolls
+    // forward, including if the step was then done but not marked as such
+    for (step, done) in [(0, false), (1, false), (1, true), (3, false), (3, true)] {
+        reset()?;
+        let mut j = Journal::begin(td)?;
+        stage(&mut j)?;
+        match step {
+            0 => {
+                td.remove_file("a")?;
+                td.create_dir_all("a/x")?;
+            }
+            1 => td.create_dir_all("c/x")?,
+            _ => {
+                td.remove_dir_all("d")?;
+                td.write("d", "")?;
+            }
+        }
+        assert!(j.commit().is_err());
+        assert!(td.try_exists(JOURNAL_NAME)?);
+        match step {
+            0 => td.remove_dir_all("a")?,
+            1 => td.remove_dir_all("c")?,
+            _ => {
+                td.remove_file("d")?;
+                td.create_dir_all(if done { "d" } else { "d/e" })?;
+            }
+        }
+        if done && step == 1 {
+            td.rename("b", td, "c")?;
+        }
+        assert_eq!(Journal::recover(td)?, Recovery::RolledForward);
+        check()?;
+    }
+
+    // Crashes while cleaning up after performing every step, when the journal
+    // has been emptied: before, while and after removing the staging directory
+    for removed in 0..=2 {
+        reset()?;
+        let mut j = Journal::begin(td)?;
+        stage(&mut j)?;
+        j.commit()?;
+        check()?;
+        td.write(JOURNAL_NAME, "cap-std-ext-journal 1\n")?;
+        if removed < 2 {
+            td.create_dir(STAGING_NAME)?;
+            td.write(Path::new(STAGING_NAME).join("done-3"), "")?;
+        }
+        if removed < 1 {
+            td.write(Path::new(STAGING_NAME).join("done-0"), "")?;
+        }
+        assert_eq!(Journal::recover(td)?, Recovery::RolledForward);
+        check()?;
+    }
+
+    // The mode of replaced files is kept
+    reset()?;
+    td.set_permissions("a", Permissions::from_mode(0o600))?;
+    let mut j = Journal::begin(td)?;
+    stage(&mut j)?;
+    j.commit()?;
+    check()?;
+    assert_eq!(td.metadata("a")?.permissions().mode() & 0o777, 0o600);
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn test_overlay() -> Result<()> {
+    use cap_std_ext::overlay::OverlayDir;
+    use std::ffi::OsString;
+
+    let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+    td.create_dir_all("lower/etc/conf.d")?;
+    td.write("lower/etc/a", "lower a")?;
+    td.write("lower/etc/b", "lower b")?;
+    td.write("lower/etc/conf.d/x", "x")?;
+    td.symlink("a", "lower/etc/link")?;
+    td.create_dir("upper")?;
+    let o = OverlayDir::new(td.open_dir("lower")?, td.open_dir("upper")?);
+    let names = |p: &str| -> Result<Vec<OsString>> { Ok(o.entries(p)?) };
+
+    // Reads fall through
+    assert_eq!(o.read("etc/a")?, b"lower a");
+    assert_eq!(o.read_link("etc/link")?, Path::new("a"));
+    assert_eq!(names(".")?, ["etc"]);
+    assert_eq!(names("etc")?, ["a", "b", "conf.d", "link"]);
+    assert!(o.metadata("etc/conf.d")?.is_dir());
+
+    // Writes go to the upper directory
+    o.write("etc/a", "upper a")?;
+    o.write("etc/new", "new")?;
+    assert_eq!(o.read("etc/a")?, b"upper a");
+    assert_eq!(td.read_to_string("lower/etc/a")?, "lower a");
+    assert_eq!(td.read_to_string("upper/etc/new")?, "new");
+    assert_eq!(names("etc")?, ["a", "b", "conf.d", "link", "new"]);
+    o.copy_up("etc/link")?;
+    assert_eq!(td.read_link("upper/etc/link")?, Path::new("a"));
+
+    // Removals leave whiteouts
+    o.remove_file("etc/b")?;
+    o.remove_file("etc/a")?;
+    assert!(!o.try_exists("etc/b")?);
+    assert!(!o.try_exists("etc/a")?);
+    assert!(td.try_exists("upper/etc/.wh.a")?);
+    assert!(td.try_exists("lower/etc/a")?);
+    assert_eq!(
+        o.read("etc/b").unwrap_err().kind(),
+        std::io::ErrorKind::NotFound
+    );
+    assert!(o.remove_dir("etc/conf.d").is_err());
+    o.remove_file("etc/conf.d/x")?;
+    o.remove_dir("etc/conf.d")?;
+    assert_eq!(names("etc")?, ["link", "new"]);
+
+    // A new directory replacing a removed one hides its old contents
+    o.create_dir("etc/conf.d")?;
+    assert!(names("etc/conf.d")?.is_empty());
+    assert!(!o.try_exists("etc/conf.d/x")?);
+    o.write("etc/b", "upper b")?;
+    assert_eq!(o.read("etc/b")?, b"upper b");
+    assert!(!td.try_exists("upper/etc/.wh.b")?);
+    assert_eq!(names("etc")?, ["b", "conf.d", "link", "new"]);
+
+    // Nothing exists beneath a non-directory in the upper layer, even if it
+    // does in the lower one
+    o.write("etc/conf.d/file", "")?;
+    td.create_dir("lower/etc/conf.d/file")?;
+    td.write("lower/etc/conf.d/file/x", "x")?;
+    assert!(!o.try_exists("etc/conf.d/file/x")?);
+    assert_eq!(
+        o.read("etc/conf.d/file/x").unwrap_err().kind(),
+        std::io::ErrorKind::NotFound
+    );
+    assert!(!o.try_exists("lower-only/x")?);
+
+    // The mode of a lower file is kept when it is copied up
+    td.write("lower/etc/secret", "lower")?;
+    td.set_permissions("lower/etc/secret", Permissions::from_mode(0o600))?;
+    td.write("lower/etc/script", "lower")?;
+    td.set_permissions("lower/etc/script", Permissions::from_mode(0o755))?;
+    o.write("etc/secret", "upper")?;
+    o.create("etc/script")?;
+    assert_eq!(td.read_to_string("upper/etc/secret")?, "upper");
+    let mode = |p: &str| -> Result<u32> { Ok(td.metadata(p)?.permissions().mode() & 0o777) };
+    assert_eq!(mode("upper/etc/secret")?, 0o600);
+    assert_eq!(mode("upper/etc/script")?, 0o755);
+
+    // Absolute link targets are kept as they are
+    td.symlink_contents("/usr/share/zoneinfo/UTC", "lower/etc/localtime")?;
+    assert_eq!(
+        o.read_link("etc/localtime")?,
+        Path::new("/usr/share/zoneinfo/UTC")
+    );
+    o.copy_up("etc/localtime")?;
+    assert_eq!(
+        td.read_link_contents("upper/etc/localtime")?,
+        Path::new("/usr/share/zoneinfo/UTC")
+    );
+
+    // A directory in the lower layer can't be replaced by a file
+    td.create_dir("lower/etc/dir")?;
+    let e = o.write("etc/dir", "").unwrap_err();
+    assert_eq!(e.raw_os_error(), Some(libc::EISDIR));
+    assert!(!td.try_exists("upper/etc/dir")?);
+    assert!(o.metadata("etc/dir")?.is_dir());
+
+    assert!(o.read("../lower/etc/a").is_err());
+    assert!(o.read("etc/.wh.a").is_err());
+    Ok(())
+}
+
+#[test]
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn test_memfd_sealed() -> Result<()> {
+    use cap_std_ext::memfd::{memfd_create_sealed, sealed_fd_for_command, SealFlags};
+    use std::io::Read;
+
+    let mut f = memfd_create_sealed("test", b"contents", SealFlags::WRITE)?;
+    let mut buf = String::new();
+    f.read_to_string(&mut buf)?;
+    assert_eq!(buf, "contents");
+    assert!(f.write_all(b"more").is_err());
+    // Growing is still permitted with only the write seal
+    f.set_len(100)?;
+
+    let fd = sealed_fd_for_command("config", b"somedata")?;
+    let out = Command::new("cat")
+        .arg("/proc/self/fd/5")
+        .take_fd_n(fd, 5)
+        .output()?;
+    assert!(out.status.success());
+    assert_eq!(out.stdout, b"somedata");
     Ok(())
 }